@@ -0,0 +1,28 @@
+//! Serves ledoxide under `/ledoxide` of another application, next to a route of
+//! its own, accepting keys sent as `X-Api-Key` instead of a bearer token.
+//!
+//! Run with `cargo run --example embedded`, then try
+//! `curl -H 'X-Api-Key: let-me-in' localhost:3000/ledoxide/v2/categories`.
+
+use axum::{http::request::Parts, routing::get};
+use ledoxide::{AppState, AppStateConfig, Category, error::AuthError};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    Category::load_from_names(["Food", "Transport", "No category"]);
+    let state = AppState::new(&AppStateConfig {
+        base_path: "/ledoxide".into(),
+        ..Default::default()
+    })?
+    .with_authorizer(|parts: &Parts| match parts.headers.get("X-Api-Key") {
+        Some(key) if key == "let-me-in" => Ok(()),
+        Some(_) => Err(AuthError::InvalidKey),
+        None => Err(AuthError::InvalidRequestHeader),
+    });
+    let app = ledoxide::router(&state)
+        .route("/", get(async || "hello from the host application"))
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
@@ -18,6 +18,7 @@ let
     path: type:
     (builtins.match ".*prompt/.*$" path != null)
     || (builtins.match ".*constraint/.*$" path != null)
+    || (builtins.match ".*ui/.*$" path != null)
     || (craneLib.filterCargoSources path type);
 
   nativeBuildInputs = [
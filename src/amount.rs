@@ -0,0 +1,186 @@
+use std::sync::RwLock;
+
+use smol_str::SmolStr;
+
+/// Formatting applied to every amount served, set once from `--locale`.
+static AMOUNT_FORMAT: RwLock<AmountFormat> = RwLock::new(AmountFormat::ENGLISH);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SymbolPosition {
+    Before,
+    After,
+}
+
+/// Separators and currency placement of a language, close to what CLDR
+/// prescribes for its most common region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AmountFormat {
+    decimal: char,
+    group: char,
+    symbol: SymbolPosition,
+}
+
+impl AmountFormat {
+    const ENGLISH: Self = Self {
+        decimal: '.',
+        group: ',',
+        symbol: SymbolPosition::Before,
+    };
+    const CONTINENTAL: Self = Self {
+        decimal: ',',
+        group: '.',
+        symbol: SymbolPosition::After,
+    };
+    const SPACED: Self = Self {
+        decimal: ',',
+        group: '\u{a0}',
+        symbol: SymbolPosition::After,
+    };
+
+    /// Format of a locale tag like `en-US`, `de_DE` or `zh`, looked up by its language.
+    pub fn for_locale(tag: &str) -> Option<Self> {
+        let language = tag.split(['-', '_']).next()?.to_ascii_lowercase();
+        match language.as_str() {
+            "en" | "zh" | "ja" | "ko" | "th" | "he" | "ms" => Some(Self::ENGLISH),
+            "de" | "es" | "it" | "nl" | "pt" | "da" | "id" | "tr" | "vi" | "el" => {
+                Some(Self::CONTINENTAL)
+            }
+            "fr" | "ru" | "uk" | "pl" | "cs" | "sk" | "sv" | "nb" | "fi" | "hu" => {
+                Some(Self::SPACED)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn global() -> Self {
+        *AMOUNT_FORMAT.read().unwrap()
+    }
+
+    pub fn set_global(self) {
+        *AMOUNT_FORMAT.write().unwrap() = self;
+    }
+
+    /// Formats `amount` in `currency`, an ISO 4217 code, or as a bare number
+    /// without one. Nothing comes out of amounts that aren't finite.
+    pub fn format(&self, amount: f32, currency: Option<&str>) -> Option<String> {
+        if !amount.is_finite() {
+            return None;
+        }
+        let digits = currency.map_or(2, fraction_digits);
+        let fixed = format!("{:.*}", digits, amount.abs());
+        let (integer, fraction) = fixed.split_once('.').unwrap_or((&fixed, ""));
+        let mut number = String::new();
+        if amount < 0.0 && fixed.bytes().any(|b| b.is_ascii_digit() && b != b'0') {
+            number.push('-');
+        }
+        for (index, digit) in integer.chars().enumerate() {
+            if index > 0 && (integer.len() - index) % 3 == 0 {
+                number.push(self.group);
+            }
+            number.push(digit);
+        }
+        if !fraction.is_empty() {
+            number.push(self.decimal);
+            number.push_str(fraction);
+        }
+        let Some(currency) = currency else {
+            return Some(number);
+        };
+        let symbol = symbol(currency);
+        Some(match self.symbol {
+            SymbolPosition::Before if symbol == currency => format!("{symbol}\u{a0}{number}"),
+            SymbolPosition::Before => format!("{symbol}{number}"),
+            SymbolPosition::After => format!("{number}\u{a0}{symbol}"),
+        })
+    }
+}
+
+/// Amounts a bill may plausibly have, unbounded on the sides left out. Amounts
+/// outside, like a phone or model number read as the price, get the bill reviewed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AmountBounds {
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+}
+
+impl AmountBounds {
+    pub fn contains(&self, amount: f32) -> bool {
+        self.min.is_none_or(|min| amount >= min) && self.max.is_none_or(|max| amount <= max)
+    }
+}
+
+/// Upper-cased `code` if it looks like an ISO 4217 code.
+pub fn currency_code(code: &str) -> Option<SmolStr> {
+    let code = code.trim();
+    (code.len() == 3 && code.chars().all(|c| c.is_ascii_alphabetic()))
+        .then(|| code.to_ascii_uppercase().into())
+}
+
+fn symbol(currency: &str) -> &str {
+    match currency {
+        "USD" => "$",
+        "EUR" => "€",
+        "GBP" => "£",
+        "JPY" | "CNY" => "¥",
+        "KRW" => "₩",
+        "INR" => "₹",
+        "RUB" => "₽",
+        "CAD" => "CA$",
+        "AUD" => "A$",
+        "HKD" => "HK$",
+        "TWD" => "NT$",
+        "BRL" => "R$",
+        other => other,
+    }
+}
+
+fn fraction_digits(currency: &str) -> usize {
+    match currency {
+        "JPY" | "KRW" | "VND" | "ISK" | "CLP" => 0,
+        _ => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let en = AmountFormat::for_locale("en-US").unwrap();
+        assert_eq!(en.format(1234.5, Some("USD")).unwrap(), "$1,234.50");
+        assert_eq!(en.format(1234.6, Some("JPY")).unwrap(), "¥1,235");
+        assert_eq!(en.format(-3.0, Some("CHF")).unwrap(), "CHF\u{a0}-3.00");
+        assert_eq!(en.format(0.004, None).unwrap(), "0.00");
+        assert_eq!(en.format(1e6, None).unwrap(), "1,000,000.00");
+        assert_eq!(en.format(f32::NAN, Some("USD")), None);
+
+        let de = AmountFormat::for_locale("de_DE").unwrap();
+        assert_eq!(de.format(1234.5, Some("EUR")).unwrap(), "1.234,50\u{a0}€");
+        let fr = AmountFormat::for_locale("FR").unwrap();
+        assert_eq!(fr.format(21.88, Some("EUR")).unwrap(), "21,88\u{a0}€");
+        assert_eq!(AmountFormat::for_locale("tlh"), None);
+
+        assert_eq!(currency_code(" usd ").as_deref(), Some("USD"));
+        assert_eq!(currency_code("dollars"), None);
+    }
+
+    #[test]
+    fn test_bounds() {
+        assert!(AmountBounds::default().contains(21888.0));
+        let bounds = AmountBounds {
+            min: Some(0.5),
+            max: Some(10_000.0),
+        };
+        assert!(bounds.contains(21.88) && bounds.contains(0.5) && bounds.contains(10_000.0));
+        // a model number and a phone number
+        assert!(!bounds.contains(21888.0));
+        assert!(!bounds.contains(13_800_138_000.0));
+        assert!(!bounds.contains(0.0));
+        let floor = AmountBounds {
+            min: Some(1.0),
+            max: None,
+        };
+        assert!(floor.contains(f32::MAX) && !floor.contains(0.99));
+    }
+}
@@ -1,40 +1,368 @@
-use std::time::Duration;
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
+use chrono::FixedOffset;
 use clap::Parser;
+use tracing::{Level, event};
 
-use crate::{key, task::ollama::GEMMA_4_E4B_Q4KM};
+use crate::{
+    amount::{AmountBounds, AmountFormat},
+    bill::NamedCategory,
+    error::CategoryError,
+    key,
+    listen::{
+        ConnectionLimits, DEFAULT_BODY_TIMEOUT, DEFAULT_HEADER_READ_TIMEOUT, DEFAULT_IDLE_TIMEOUT,
+        DEFAULT_MAX_CONNECTIONS,
+    },
+    schedule::DEFAULT_SWAP_CACHE_SIZE,
+    task::{
+        PromptRetention, Stage,
+        embedding::Categorizer,
+        imaging::{FrameSelection, Transcoder},
+        ollama::{
+            ChatTemplates, DEFAULT_CONCURRENT_PULLS, DEFAULT_EMBEDDING_MODEL,
+            DEFAULT_MAX_PROMPT_SIZE, DEFAULT_MAX_UPLOAD_SIZE, DEFAULT_PULL_ATTEMPTS,
+            GEMMA_4_E4B_Q4KM, Quantization,
+        },
+        quiet::{DEFAULT_QUIET_KEEP_ALIVE, DailySpan, QuietHours},
+    },
+};
+
+/// Questions a key may ask per minute unless configured otherwise.
+pub const DEFAULT_ASK_RATE_LIMIT: usize = 10;
+/// Descriptions a key may ask for per minute unless configured otherwise.
+pub const DEFAULT_DESCRIBE_RATE_LIMIT: usize = 30;
 
 #[derive(Debug, Parser)]
 #[command(version = option_env!("APP_VERSION"), about, long_about = None)]
 /// Client pulling based HTTP server to implement a VLM based bookkeeping workflow.
 pub struct Cli {
+    /// Address to listen on, ignored when systemd passes a socket. Port 0 picks a free one
     #[arg(short, long, default_value = "127.0.0.1:3100")]
     pub bind: String,
+    /// File to write the port actually listened on to
+    #[arg(long, value_name = "PATH")]
+    pub port_file: Option<PathBuf>,
+    /// PEM certificate chain to serve HTTPS with, reloaded on SIGHUP
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of --tls-cert
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
     /// Bearer token for authentication, empty to disable
     #[arg(short, long)]
     pub auth_key: Option<String>,
     #[arg(
         short, long,
-        default_values_t = ["Gorceries".to_string(), "Transport".to_string(), "Rent".to_string(), "Entertainment".to_string(), "Shopping".to_string(), "Drink".to_string(), "Food".to_string(), "Drink".to_string()])]
-    pub categories: Vec<String>,
+        default_values_t = ["Gorceries", "Transport", "Rent", "Entertainment", "Shopping", "Drink", "Food", "Drink"].map(NamedCategory::from),
+        value_parser = read_category)]
+    pub categories: Vec<NamedCategory>,
+    /// File listing the categories instead, one per line or as a JSON array
+    #[arg(long, value_name = "PATH", conflicts_with = "categories", value_parser = read_categories)]
+    pub categories_file: Option<CategoryNames>,
     /// Caption model for describing screenshots
     #[arg(long, default_value = GEMMA_4_E4B_Q4KM)]
     pub caption_model: String,
     /// Extract model for amount & category analysis
     #[arg(long, default_value = GEMMA_4_E4B_Q4KM)]
     pub extract_model: String,
-    /// Number of concurrent model executions
+    /// Model a stage (description, notes, amount or category) runs on instead of the
+    /// caption or extract model, as STAGE=MODEL. May be repeated
+    #[arg(long, value_name = "STAGE=MODEL", value_parser = read_stage_model)]
+    pub stage_model: Vec<(Stage, String)>,
+    /// How tasks are categorized unless they say otherwise: lm, the extract model
+    /// picking in a constrained generation, or embedding, the nearest category by
+    /// embedding similarity, falling back to lm when embeddings are unavailable
+    #[arg(long, value_name = "STRATEGY", default_value_t = Categorizer::Lm)]
+    pub categorizer: Categorizer,
+    /// Model embedding the descriptions and categories for --categorizer embedding
+    #[arg(long, default_value = DEFAULT_EMBEDDING_MODEL)]
+    pub embedding_model: String,
+    /// Runs every stage on the caption model, so only one model is ever loaded
+    #[arg(long, conflicts_with_all = ["extract_model", "stage_model"])]
+    pub single_model: bool,
+    /// Number of concurrent model executions, 0 to pick one from the CPU cores
     #[arg(long, default_value_t = 4)]
     pub max_concurrency: usize,
     /// How many result records until swapping to disk
     #[arg(long, default_value_t = 468_000)]
     pub max_memory_size: usize,
+    /// How many swapped records to keep at hand after looking them up, 0 to disable
+    #[arg(long, default_value_t = DEFAULT_SWAP_CACHE_SIZE)]
+    pub swap_cache_size: usize,
+    /// Directory of the swap file, the OS temporary directory if absent
+    #[arg(long, value_name = "DIR")]
+    pub swap_dir: Option<PathBuf>,
+    /// File keeping the settings changed through PATCH /admin/config, which
+    /// override the flags on startup. Changes are lost on restart if absent
+    #[arg(long, value_name = "PATH")]
+    pub runtime_config: Option<PathBuf>,
+    /// Start even if the lock of the runtime config names an instance that still runs
+    #[arg(long, default_value_t = false, requires = "runtime_config")]
+    pub force: bool,
     /// How long to wait for until an inactive model is removed from system memory
     #[arg(long, default_value_t = 5f32)]
     pub model_timeout_minutes: f32,
-    /// Offline mode, use cached models only without reaching Hugging Face hub
+    /// Fraction of the model timeout randomly added to it, so models expire at different times
+    #[arg(long, default_value_t = DEFAULT_MODEL_TIMEOUT_JITTER, value_parser = read_jitter)]
+    pub model_timeout_jitter: f64,
+    /// Seconds a stage on the caption model (description, notes) may generate for
+    /// before the task fails and can be retried, unlimited by default
+    #[arg(long, value_name = "SECS")]
+    pub vlm_stage_timeout_secs: Option<u64>,
+    /// Seconds a stage on the extract model (amount, category) may generate for,
+    /// unlimited by default
+    #[arg(long, value_name = "SECS")]
+    pub lm_stage_timeout_secs: Option<u64>,
+    /// Model kept loaded regardless of the timeout, preloaded on startup. Repeatable
+    #[arg(long = "pin-model", value_name = "MODEL")]
+    pub pinned_models: Vec<String>,
+    /// Daily hours in which models are unloaded shortly after use, like 01:00-07:00.
+    /// Pinned models stay loaded
+    #[arg(long, value_name = "HH:MM-HH:MM")]
+    pub quiet_hours: Option<DailySpan>,
+    /// UTC offset of the clock --quiet-hours is read on, like +08:00
+    #[arg(
+        long,
+        value_name = "OFFSET",
+        default_value = "+00:00",
+        requires = "quiet_hours"
+    )]
+    pub quiet_hours_tz: FixedOffset,
+    /// Seconds models stay loaded after use during quiet hours
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_QUIET_KEEP_ALIVE.as_secs(), requires = "quiet_hours")]
+    pub quiet_keep_alive_secs: u64,
+    /// Frames of animated images passed to the VLM: first, middle, last or every:N[:CAP]
+    #[arg(long, default_value = "first")]
+    pub animation_frames: FrameSelection,
+    /// Sample every stage with a fixed seed, so the same images give the same bill
+    #[arg(long, default_value_t = false)]
+    pub deterministic: bool,
+    /// Quantization level tasks may pick, like q4_K_M or q8_0. Repeatable, the first is the default
+    #[arg(long = "quantization", value_name = "LEVEL")]
+    pub quantizations: Vec<Quantization>,
+    /// Attempts at pulling a model, retrying failures on the connection or the registry
+    #[arg(long, default_value_t = DEFAULT_PULL_ATTEMPTS, value_parser = clap::value_parser!(u32).range(1..))]
+    pub pull_attempts: u32,
+    /// Models downloaded at the same time at most, the others wait their turn
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_CONCURRENT_PULLS as u16, value_parser = clap::value_parser!(u16).range(1..))]
+    pub concurrent_pulls: u16,
+    /// Offline mode, only use models already present in Ollama and never pull
     #[arg(long, default_value_t = false)]
     pub offline: bool,
+    /// Keep descriptors (images and options) of finished tasks in memory for reprocessing
+    #[arg(long, default_value_t = false)]
+    pub retain_descriptors: bool,
+    /// Serve the Model Context Protocol over stdio instead of HTTP
+    #[arg(long, default_value_t = false)]
+    pub mcp: bool,
+    /// How long /create_task_sync waits for a task before giving up
+    #[arg(long, default_value_t = 300)]
+    pub sync_timeout_secs: u64,
+    /// How long task lookups and listings may take when the client sends no
+    /// X-Request-Deadline-Ms, unbounded by default
+    #[arg(long)]
+    pub default_deadline_ms: Option<u64>,
+    /// Seconds during which resubmitting the same images returns the unfinished
+    /// task instead of starting another, 0 to disable
+    #[arg(long, default_value_t = 10)]
+    pub dedup_window_secs: u64,
+    /// Go template file used for prompting models that don't bundle one, either
+    /// for every model or, as MODEL=PATH, for a single one. May be repeated
+    #[arg(long, value_name = "[MODEL=]PATH", value_parser = read_template)]
+    pub chat_template: Vec<ChatTemplate>,
+    /// System prompt file for a stage (description, notes, amount or category), as
+    /// STAGE=PATH. May be repeated
+    #[arg(long, value_name = "STAGE=PATH", value_parser = read_system_prompt)]
+    pub system_prompt: Vec<(Stage, String)>,
+    /// Keep the rendered prompt of each stage on tasks for GET /task/{id}/prompts:
+    /// off, redacted (without the description and notes) or full
+    #[arg(long, value_name = "MODE", default_value_t = PromptRetention::Off)]
+    pub retain_prompts: PromptRetention,
+    /// Most bytes of a rendered prompt logged or kept, cut beyond
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_PROMPT_SIZE)]
+    pub max_prompt_size: usize,
+    /// Most images a single task may carry, unlimited by default
+    #[arg(long)]
+    pub max_images: Option<usize>,
+    /// Questions a key may ask about finished tasks per minute, 0 for no limit
+    #[arg(long, value_name = "PER_MINUTE", default_value_t = DEFAULT_ASK_RATE_LIMIT)]
+    pub ask_rate_limit: usize,
+    /// Descriptions a key may ask `/describe` for per minute, 0 for no limit
+    #[arg(long, value_name = "PER_MINUTE", default_value_t = DEFAULT_DESCRIBE_RATE_LIMIT)]
+    pub describe_rate_limit: usize,
+    /// Smallest plausible amount, bills below it are flagged for review
+    #[arg(long, value_name = "AMOUNT", value_parser = read_amount)]
+    pub min_amount: Option<f32>,
+    /// Largest plausible amount, bills above it are flagged for review
+    #[arg(long, value_name = "AMOUNT", value_parser = read_amount)]
+    pub max_amount: Option<f32>,
+    /// Most bytes a task upload may have, rejected as soon as it grows larger
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_UPLOAD_SIZE)]
+    pub max_upload_size: usize,
+    /// Approximate bytes tasks may hold in memory, their images for the most
+    /// part. New tasks that would exceed it get a 503. Unlimited by default
+    #[arg(long, value_name = "BYTES")]
+    pub memory_limit: Option<usize>,
+    /// Seconds a request's headers may take to arrive, or a TLS handshake to finish
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_HEADER_READ_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    pub header_read_timeout_secs: u64,
+    /// Seconds a request body may send nothing while it is read, before the
+    /// request gets a 408 and its connection is closed
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_BODY_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    pub body_timeout_secs: u64,
+    /// Seconds a connection may wait for its next request before it is closed
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_IDLE_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    pub idle_timeout_secs: u64,
+    /// Connections served at once, further ones get a 503. 0 for no limit
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_CONNECTIONS)]
+    pub max_connections: usize,
+    /// Serve a review page under /ui
+    #[arg(long, default_value_t = false)]
+    pub enable_ui: bool,
+    /// Answer GET /capabilities without a key
+    #[arg(long, default_value_t = false)]
+    pub public_capabilities: bool,
+    /// Path prefix to serve every route under, e.g. /ledoxide behind a reverse proxy
+    #[arg(long, default_value = "", value_parser = normalize_base_path)]
+    pub base_path: String,
+    /// Threads driving requests and tasks, one per core by default
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub worker_threads: Option<u16>,
+    /// Most threads for blocking work like image decoding, tokio's default if absent
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub blocking_threads: Option<u16>,
+    /// Locale amounts are formatted for, like en-US or de-DE
+    #[arg(long, default_value = "en", value_parser = read_locale)]
+    pub locale: AmountFormat,
+    /// Command converting AVIF and HEIC uploads, like `heif-convert {input} {output}`
+    #[arg(long, value_parser = Transcoder::parse)]
+    pub transcode_command: Option<Transcoder>,
+}
+
+/// Most jitter accepted, keeping models loaded at most 1.5 times the timeout.
+const MAX_MODEL_TIMEOUT_JITTER: f64 = 0.5;
+const DEFAULT_MODEL_TIMEOUT_JITTER: f64 = 0.1;
+
+fn read_jitter(value: &str) -> Result<f64, String> {
+    let jitter = value.parse::<f64>().map_err(|err| err.to_string())?;
+    if (0.0..=MAX_MODEL_TIMEOUT_JITTER).contains(&jitter) {
+        Ok(jitter)
+    } else {
+        Err(format!("must be between 0 and {MAX_MODEL_TIMEOUT_JITTER}"))
+    }
+}
+
+fn read_amount(value: &str) -> Result<f32, String> {
+    let amount = value.parse::<f32>().map_err(|err| err.to_string())?;
+    if amount.is_finite() && amount >= 0.0 {
+        Ok(amount)
+    } else {
+        Err("must be a finite amount of at least 0".into())
+    }
+}
+
+fn read_locale(value: &str) -> Result<AmountFormat, String> {
+    AmountFormat::for_locale(value).ok_or_else(|| format!("unsupported locale {value}"))
+}
+
+/// Turns `ledoxide/` and `/ledoxide` alike into `/ledoxide`, and `/` into nothing.
+fn normalize_base_path(value: &str) -> Result<String, String> {
+    let trimmed = value.trim_matches('/');
+    if trimmed.is_empty() {
+        return Ok(String::new());
+    }
+    if trimmed.contains(['{', '}', '*']) {
+        return Err("base path must not contain route parameters".into());
+    }
+    Ok(format!("/{trimmed}"))
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatTemplate {
+    /// Model the template is meant for, any model if absent
+    pub model: Option<String>,
+    pub template: String,
+}
+
+fn read_template(value: &str) -> Result<ChatTemplate, String> {
+    let (model, path) = match value.split_once('=') {
+        Some((model, path)) if !std::path::Path::new(value).is_file() => {
+            (Some(model.to_string()), path)
+        }
+        _ => (None, value),
+    };
+    let template = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+    ChatTemplates::validate(&template).map_err(|err| format!("{path}: {err}"))?;
+    Ok(ChatTemplate { model, template })
+}
+
+fn read_category(value: &str) -> Result<NamedCategory, String> {
+    value.parse().map_err(|err: CategoryError| err.to_string())
+}
+
+/// Categories read from `--categories-file`.
+#[derive(Debug, Clone)]
+pub struct CategoryNames(pub Vec<NamedCategory>);
+
+fn read_categories(path: &str) -> Result<CategoryNames, String> {
+    let content = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+    parse_categories(&content)
+        .map(CategoryNames)
+        .map_err(|err| format!("{path}: {err}"))
+}
+
+/// Takes a JSON array of names or `{"name", "hint"}` objects, or one
+/// `NAME[=HINT]` per line where blank lines and lines starting with `#` are
+/// skipped.
+fn parse_categories(content: &str) -> Result<Vec<NamedCategory>, String> {
+    let categories = if content.trim_start().starts_with('[') {
+        serde_json::from_str::<Vec<NamedCategory>>(content).map_err(|err| err.to_string())?
+    } else {
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(NamedCategory::from_str)
+            .collect::<Result<_, _>>()
+            .map_err(|err| err.to_string())?
+    };
+    if categories.is_empty() {
+        return Err("no categories listed".into());
+    }
+    for category in &categories {
+        category.check().map_err(|err| err.to_string())?;
+    }
+    Ok(categories)
+}
+
+fn read_stage_model(value: &str) -> Result<(Stage, String), String> {
+    let (stage, model) = value
+        .split_once('=')
+        .ok_or_else(|| "expected STAGE=MODEL".to_string())?;
+    let stage = stage
+        .parse()
+        .map_err(|_| format!("unknown stage {stage}"))?;
+    if model.is_empty() {
+        return Err(format!("no model for {stage}"));
+    }
+    Ok((stage, model.to_string()))
+}
+
+fn read_system_prompt(value: &str) -> Result<(Stage, String), String> {
+    let (stage, path) = value
+        .split_once('=')
+        .ok_or_else(|| "expected STAGE=PATH".to_string())?;
+    let stage = stage
+        .parse()
+        .map_err(|_| format!("unknown stage {stage}"))?;
+    let prompt = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+    Ok((stage, prompt))
+}
+
+/// A task mostly waits on Ollama, which needs a couple of cores per generation
+/// to keep up. Ollama doesn't tell how much VRAM is free, so cores are all to go by.
+fn auto_concurrency(cores: usize) -> usize {
+    (cores / 2).clamp(1, 8)
 }
 
 #[derive(Debug, Clone)]
@@ -42,10 +370,48 @@ pub struct App {
     pub auth_key: String,
     pub caption_model: String,
     pub extract_model: String,
+    /// Models stages run on instead of the caption or extract model
+    pub stage_models: Vec<(Stage, String)>,
+    pub categorizer: Categorizer,
+    pub embedding_model: String,
     pub max_concurrency: usize,
     pub max_memory_size: usize,
+    pub swap_cache_size: usize,
+    pub swap_dir: Option<PathBuf>,
+    /// Where settings changed through `PATCH /admin/config` are kept
+    pub runtime_config: Option<PathBuf>,
+    /// Takes over the lock of the runtime config from an instance that seems alive
+    pub force: bool,
     pub model_timeout: Duration,
+    pub model_timeout_jitter: f64,
+    /// Longest a stage may generate for, unlimited for stages left out
+    pub stage_timeouts: Vec<(Stage, Duration)>,
+    pub pinned_models: Vec<String>,
+    pub quiet_hours: Option<QuietHours>,
+    pub animation_frames: FrameSelection,
+    pub deterministic: bool,
+    pub quantizations: Vec<Quantization>,
+    pub pull_attempts: u32,
+    pub concurrent_pulls: usize,
     pub offline: bool,
+    pub retain_descriptors: bool,
+    pub sync_timeout: Duration,
+    pub default_deadline: Option<Duration>,
+    pub dedup_window: Option<Duration>,
+    pub chat_templates: Vec<ChatTemplate>,
+    pub system_prompts: Vec<(Stage, String)>,
+    pub retain_prompts: PromptRetention,
+    pub max_prompt_size: usize,
+    pub max_images: Option<usize>,
+    pub amount_bounds: AmountBounds,
+    pub ask_rate_limit: usize,
+    pub describe_rate_limit: usize,
+    pub max_upload_size: usize,
+    pub memory_limit: Option<usize>,
+    pub connection_limits: ConnectionLimits,
+    pub enable_ui: bool,
+    pub public_capabilities: bool,
+    pub base_path: String,
 }
 
 impl Default for App {
@@ -54,10 +420,44 @@ impl Default for App {
             auth_key: String::new(),
             caption_model: GEMMA_4_E4B_Q4KM.into(),
             extract_model: GEMMA_4_E4B_Q4KM.into(),
+            stage_models: Vec::new(),
+            categorizer: Categorizer::Lm,
+            embedding_model: DEFAULT_EMBEDDING_MODEL.into(),
             max_concurrency: 4,
             max_memory_size: 468_000,
+            swap_cache_size: DEFAULT_SWAP_CACHE_SIZE,
+            swap_dir: None,
+            runtime_config: None,
+            force: false,
             model_timeout: Duration::from_mins(5),
+            model_timeout_jitter: DEFAULT_MODEL_TIMEOUT_JITTER,
+            stage_timeouts: Vec::new(),
+            pinned_models: Vec::new(),
+            quiet_hours: None,
+            animation_frames: FrameSelection::First,
+            deterministic: false,
+            quantizations: Vec::new(),
+            pull_attempts: DEFAULT_PULL_ATTEMPTS,
+            concurrent_pulls: DEFAULT_CONCURRENT_PULLS,
             offline: false,
+            retain_descriptors: false,
+            sync_timeout: Duration::from_mins(5),
+            default_deadline: None,
+            dedup_window: None,
+            chat_templates: Vec::new(),
+            system_prompts: Vec::new(),
+            retain_prompts: PromptRetention::Off,
+            max_prompt_size: DEFAULT_MAX_PROMPT_SIZE,
+            max_images: None,
+            amount_bounds: AmountBounds::default(),
+            ask_rate_limit: DEFAULT_ASK_RATE_LIMIT,
+            describe_rate_limit: DEFAULT_DESCRIBE_RATE_LIMIT,
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            memory_limit: None,
+            connection_limits: ConnectionLimits::default(),
+            enable_ui: false,
+            public_capabilities: false,
+            base_path: String::new(),
         }
     }
 }
@@ -71,17 +471,201 @@ impl From<Cli> for App {
                     Ok(key) => key,
                     Err(_) => {
                         let random_key = key::generate_random_key();
-                        println!("missing authorization key, using a random one: {random_key}");
+                        event!(
+                            Level::WARN,
+                            "missing authorization key, using a random one: {random_key}"
+                        );
                         random_key
                     }
                 },
             },
+            extract_model: if value.single_model {
+                event!(
+                    Level::INFO,
+                    "running every stage on {}",
+                    value.caption_model
+                );
+                value.caption_model.clone()
+            } else {
+                value.extract_model
+            },
             caption_model: value.caption_model,
-            extract_model: value.extract_model,
-            max_concurrency: value.max_concurrency,
+            stage_models: value.stage_model,
+            categorizer: value.categorizer,
+            embedding_model: value.embedding_model,
+            max_concurrency: match value.max_concurrency {
+                0 => {
+                    let cores = std::thread::available_parallelism().map_or(1, usize::from);
+                    let concurrency = auto_concurrency(cores);
+                    event!(
+                        Level::INFO,
+                        "running up to {concurrency} tasks at once on {cores} cores"
+                    );
+                    concurrency
+                }
+                n => n,
+            },
             max_memory_size: value.max_memory_size,
+            swap_cache_size: value.swap_cache_size,
+            swap_dir: value.swap_dir,
+            runtime_config: value.runtime_config,
+            force: value.force,
             model_timeout: Duration::from_secs_f32(value.model_timeout_minutes * 60f32),
+            model_timeout_jitter: value.model_timeout_jitter,
+            stage_timeouts: [
+                (Stage::Description, value.vlm_stage_timeout_secs),
+                (Stage::Notes, value.vlm_stage_timeout_secs),
+                (Stage::Amount, value.lm_stage_timeout_secs),
+                (Stage::Category, value.lm_stage_timeout_secs),
+            ]
+            .into_iter()
+            .filter_map(|(stage, secs)| Some((stage, Duration::from_secs(secs?))))
+            .collect(),
+            pinned_models: value.pinned_models,
+            quiet_hours: value.quiet_hours.map(|span| QuietHours {
+                span,
+                offset: value.quiet_hours_tz,
+                keep_alive: Duration::from_secs(value.quiet_keep_alive_secs),
+            }),
+            animation_frames: value.animation_frames,
+            deterministic: value.deterministic,
+            quantizations: value.quantizations,
+            pull_attempts: value.pull_attempts,
+            concurrent_pulls: value.concurrent_pulls.into(),
             offline: value.offline,
+            retain_descriptors: value.retain_descriptors,
+            sync_timeout: Duration::from_secs(value.sync_timeout_secs),
+            default_deadline: value.default_deadline_ms.map(Duration::from_millis),
+            dedup_window: (value.dedup_window_secs > 0)
+                .then(|| Duration::from_secs(value.dedup_window_secs)),
+            chat_templates: value.chat_template,
+            system_prompts: value.system_prompt,
+            retain_prompts: value.retain_prompts,
+            max_prompt_size: value.max_prompt_size,
+            max_images: value.max_images,
+            amount_bounds: AmountBounds {
+                min: value.min_amount,
+                max: value.max_amount,
+            },
+            ask_rate_limit: value.ask_rate_limit,
+            describe_rate_limit: value.describe_rate_limit,
+            max_upload_size: value.max_upload_size,
+            memory_limit: value.memory_limit,
+            connection_limits: ConnectionLimits {
+                header_read_timeout: Duration::from_secs(value.header_read_timeout_secs),
+                body_timeout: Duration::from_secs(value.body_timeout_secs),
+                idle_timeout: Duration::from_secs(value.idle_timeout_secs),
+                max_connections: (value.max_connections > 0).then_some(value.max_connections),
+            },
+            enable_ui: value.enable_ui,
+            public_capabilities: value.public_capabilities,
+            base_path: value.base_path,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_categories() {
+        let text = "# essentials\nFood\n\n  Rent  \n# fun\nTravel\n";
+        assert_eq!(
+            parse_categories(text).unwrap(),
+            ["Food", "Rent", "Travel"].map(NamedCategory::from)
+        );
+        assert_eq!(
+            parse_categories(r#"["Food", "Eating out"]"#).unwrap(),
+            ["Food", "Eating out"].map(NamedCategory::from)
+        );
+        let hinted = parse_categories("Food\nTransport = taxis, buses, fuel\nRent =\n").unwrap();
+        assert_eq!(
+            hinted,
+            [
+                "Food".into(),
+                ("Transport", "taxis, buses, fuel").into(),
+                "Rent".into()
+            ]
+        );
+        assert_eq!(
+            parse_categories(r#"["Food", {"name": "Transport", "hint": "taxis"}]"#).unwrap(),
+            hinted[..1]
+                .iter()
+                .cloned()
+                .chain([("Transport", "taxis").into()])
+                .collect::<Vec<_>>()
+        );
+        assert!(parse_categories(r#"[{"name": "Transport", "hint": "taxis\nbuses"}]"#).is_err());
+        assert!(parse_categories("# nothing here\n\n").is_err());
+        assert!(parse_categories("[]").is_err());
+        assert!(parse_categories("[\"Food\"").is_err());
+        assert!(parse_categories(r#"["Food", "Rent\nTravel"]"#).is_err());
+        assert!(parse_categories(r#"["Food", " "]"#).is_err());
+        assert!(Cli::try_parse_from(["ledoxide", "-c", "Food", "-c", ""]).is_err());
+        let cli = Cli::try_parse_from(["ledoxide", "-c", "Food", "-c", "Transport=taxis"]).unwrap();
+        assert_eq!(cli.categories[1].hint(), Some("taxis"));
+    }
+
+    #[test]
+    fn test_auto_concurrency() {
+        assert_eq!(auto_concurrency(1), 1);
+        assert_eq!(auto_concurrency(8), 4);
+        assert_eq!(auto_concurrency(128), 8);
+    }
+
+    #[test]
+    fn test_single_model() {
+        let cli = Cli::try_parse_from([
+            "ledoxide",
+            "-a",
+            "",
+            "--single-model",
+            "--caption-model",
+            "vlm",
+        ]);
+        let app = App::from(cli.unwrap());
+        assert_eq!(app.caption_model, "vlm");
+        assert_eq!(app.extract_model, "vlm");
+        assert!(
+            Cli::try_parse_from(["ledoxide", "--single-model", "--extract-model", "lm"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_stage_models() {
+        let cli = Cli::try_parse_from([
+            "ledoxide",
+            "-a",
+            "",
+            "--stage-model",
+            "category=small",
+            "--stage-model",
+            "amount=large:70b",
+        ]);
+        let app = App::from(cli.unwrap());
+        assert_eq!(
+            app.stage_models,
+            [
+                (Stage::Category, "small".to_string()),
+                (Stage::Amount, "large:70b".to_string())
+            ]
+        );
+        for invalid in ["small", "price=small", "amount="] {
+            assert!(Cli::try_parse_from(["ledoxide", "--stage-model", invalid]).is_err());
+        }
+        assert!(
+            Cli::try_parse_from(["ledoxide", "--single-model", "--stage-model", "amount=m"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_read_jitter() {
+        assert_eq!(read_jitter("0.25"), Ok(0.25));
+        assert_eq!(read_jitter("0"), Ok(0.0));
+        assert!(read_jitter("0.6").is_err());
+        assert!(read_jitter("-0.1").is_err());
+        assert!(read_jitter("lots").is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use std::{collections::HashSet, sync::Arc};
+
+use async_stream::try_stream;
+use axum::body::Bytes;
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+use tokio::pin;
+use tracing::{Level, event};
+
+use crate::{
+    bill::Category,
+    error::RestoreError,
+    schedule::{self, Scheduler},
+    task::{RunTask, TaskDescriptor},
+};
+
+/// First bytes of every backup, followed by [BACKUP_VERSION].
+const MAGIC: &[u8; 6] = b"LDXBAK";
+/// Layout of the records in a backup. Restoring refuses any other version.
+pub const BACKUP_VERSION: u16 = 1;
+/// Tasks in one record at most.
+const RECORD_TASKS: usize = 1024;
+
+// Kinds of records, each a kind byte, a big endian u32 length and the payload
+const END: u8 = 0;
+/// JSON array of category names
+const CATEGORIES: u8 = 1;
+/// Tasks in the swap chunk layout, which carries a version of its own
+const TASKS: u8 = 2;
+/// Id of a finished task and the images of its retained descriptor, each
+/// prefixed by its length
+const IMAGES: u8 = 3;
+
+#[derive(Debug, Default, Serialize)]
+pub struct Restored {
+    pub tasks: usize,
+    /// Retained descriptors restored, see `--retain-descriptors`
+    pub descriptors: usize,
+    /// Categories of the backup the server didn't have yet
+    pub categories: usize,
+}
+
+fn record(kind: u8, payload: &[u8]) -> Bytes {
+    let mut buf = Vec::with_capacity(5 + payload.len());
+    buf.push(kind);
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf.into()
+}
+
+fn push_prefixed(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Streams the categories, every finished task and the retained descriptors.
+/// Tasks still pending or running are left out. The swap is read a chunk at a
+/// time, so a slow download doesn't hold up the tasks finishing meanwhile, and
+/// a task swapped out while archiving is archived once.
+pub fn archive<Runner>(
+    scheduler: Arc<Scheduler<Runner>>,
+) -> impl Stream<Item = anyhow::Result<Bytes>>
+where
+    Runner: RunTask + Send + Sync + Clone + 'static,
+    Runner::TaskDescriptor: Send + Sync + 'static,
+{
+    try_stream! {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&BACKUP_VERSION.to_be_bytes());
+        yield header.into();
+
+        let names = Category::all_cases()
+            .into_iter()
+            .filter_map(|category| category.name())
+            .collect::<Vec<_>>();
+        yield record(CATEGORIES, &serde_json::to_vec(&names)?);
+
+        let tasks = scheduler.finished_tasks();
+        pin!(tasks);
+        let mut chunk = Vec::with_capacity(RECORD_TASKS);
+        loop {
+            let task = tasks
+                .try_next()
+                .await
+                .inspect_err(|err| event!(Level::ERROR, "backup interrupted: {err}"))?;
+            let done = task.is_none();
+            chunk.extend(task);
+            if chunk.len() == RECORD_TASKS || done && !chunk.is_empty() {
+                let tasks = std::mem::take(&mut chunk);
+                let payload = schedule::blocking(move || schedule::encode_chunk(&tasks)).await?;
+                yield record(TASKS, &payload);
+            }
+            if done {
+                break;
+            }
+        }
+
+        for (id, descriptor) in scheduler.retained_descriptors().await {
+            let mut payload = Vec::new();
+            push_prefixed(&mut payload, id.as_bytes());
+            for image in descriptor.images() {
+                push_prefixed(&mut payload, image);
+            }
+            yield record(IMAGES, &payload);
+        }
+        yield record(END, &[]);
+    }
+}
+
+/// Reads a backup of [archive] into a scheduler holding no tasks, adding the
+/// categories it doesn't know yet. Tasks the backup holds more than once are
+/// restored once. A backup failing halfway leaves what was restored so far.
+pub async fn restore<Runner, Body, E>(
+    scheduler: &Scheduler<Runner>,
+    body: Body,
+) -> Result<Restored, RestoreError>
+where
+    Runner: RunTask + Send + Sync + Clone + 'static,
+    Runner::TaskDescriptor: From<Vec<Vec<u8>>> + Send + Sync + 'static,
+    Body: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let mut records = Records {
+        body,
+        buffer: Vec::new(),
+    };
+    let header = records
+        .take(MAGIC.len() + 2)
+        .await?
+        .ok_or(RestoreError::NotABackup)?;
+    let (magic, version) = header.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return Err(RestoreError::NotABackup);
+    }
+    let version = u16::from_be_bytes([version[0], version[1]]);
+    if version != BACKUP_VERSION {
+        return Err(RestoreError::IncompatibleVersion {
+            found: version,
+            supported: BACKUP_VERSION,
+        });
+    }
+    if !scheduler
+        .is_empty()
+        .await
+        .map_err(|err| RestoreError::Internal(err.into()))?
+    {
+        return Err(RestoreError::NotEmpty);
+    }
+
+    let truncated = || RestoreError::Malformed("the backup ends early".into());
+    let mut restored = Restored::default();
+    let mut ids = HashSet::new();
+    loop {
+        let head = records.take(5).await?.ok_or_else(truncated)?;
+        let len = u32::from_be_bytes([head[1], head[2], head[3], head[4]]);
+        let payload = records.take(len as usize).await?.ok_or_else(truncated)?;
+        match head[0] {
+            END => break,
+            CATEGORIES => {
+                let names = serde_json::from_slice::<Vec<String>>(&payload)
+                    .map_err(|err| RestoreError::Malformed(format!("categories: {err}")))?;
+                restored.categories += Category::append_categories(names);
+            }
+            TASKS => {
+                let mut tasks = schedule::blocking(move || schedule::decode_chunk(&payload))
+                    .await
+                    .map_err(|err| RestoreError::Malformed(err.to_string()))?;
+                let count = tasks.len();
+                tasks.retain(|task| ids.insert(task.id().to_string()));
+                if tasks.len() < count {
+                    event!(
+                        Level::WARN,
+                        "skipped {} tasks restored already",
+                        count - tasks.len()
+                    );
+                }
+                restored.tasks += tasks.len();
+                scheduler
+                    .restore_finished(tasks)
+                    .await
+                    .map_err(RestoreError::Internal)?;
+            }
+            IMAGES => {
+                let (id, images) = split_images(&payload)
+                    .ok_or_else(|| RestoreError::Malformed("invalid images record".into()))?;
+                if scheduler.restore_descriptor(&id, images.into()).await {
+                    restored.descriptors += 1;
+                }
+            }
+            kind => {
+                return Err(RestoreError::Malformed(format!(
+                    "unknown record kind {kind}"
+                )));
+            }
+        }
+    }
+    Ok(restored)
+}
+
+/// Task id and images of an [IMAGES] record.
+fn split_images(mut payload: &[u8]) -> Option<(String, Vec<Vec<u8>>)> {
+    let mut next = || {
+        let (len, rest) = payload.split_first_chunk::<4>()?;
+        let len = u32::from_be_bytes(*len) as usize;
+        let (bytes, rest) = rest.split_at_checked(len)?;
+        payload = rest;
+        Some(bytes.to_vec())
+    };
+    let id = String::from_utf8(next()?).ok()?;
+    let mut images = Vec::new();
+    while let Some(image) = next() {
+        images.push(image);
+    }
+    Some((id, images))
+}
+
+/// Bytes of a request body, taken as the records need them.
+struct Records<Body> {
+    body: Body,
+    buffer: Vec<u8>,
+}
+
+impl<Body, E> Records<Body>
+where
+    Body: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    /// The next `len` bytes, none if the body ends before.
+    async fn take(&mut self, len: usize) -> Result<Option<Vec<u8>>, RestoreError> {
+        while self.buffer.len() < len {
+            match self.body.next().await {
+                Some(chunk) => self.buffer.extend_from_slice(
+                    &chunk.map_err(|err| RestoreError::Malformed(err.to_string()))?,
+                ),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(self.buffer.drain(..len).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+
+    use super::*;
+    use crate::{
+        bill::Bill,
+        task::{self, TaskControlBlock, ollama::OllamaRunTask},
+    };
+
+    fn finished(amount: f32) -> TaskControlBlock {
+        let tcb = TaskControlBlock::new();
+        tcb.set_state(task::State::Finished(Ok(task::Success(Bill {
+            notes: "Tea".into(),
+            amount,
+            currency: None,
+            categories: vec!["Food".into()],
+            category_reason: None,
+            needs_review: false,
+        }))));
+        tcb
+    }
+
+    async fn collect(scheduler: Arc<Scheduler<OllamaRunTask>>) -> Vec<u8> {
+        let chunks = archive(scheduler).try_collect::<Vec<_>>().await.unwrap();
+        chunks.concat()
+    }
+
+    fn body(bytes: Vec<u8>) -> impl Stream<Item = Result<Bytes, std::convert::Infallible>> + Unpin {
+        // split across records to exercise the buffering
+        stream::iter(
+            bytes
+                .chunks(7)
+                .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let _registry = crate::bill::lock_registry().await;
+        Category::append_categories(["Food"]);
+        let source =
+            Arc::new(Scheduler::<OllamaRunTask>::default().with_retained_descriptors(true));
+        let tasks = (0..3).map(|n| finished(n as f32)).collect::<Vec<_>>();
+        tasks[1].set_deleted(true);
+        source.restore_finished(tasks.clone()).await.unwrap();
+        assert!(
+            source
+                .restore_descriptor(tasks[0].id(), vec![b"receipt".to_vec()].into())
+                .await
+        );
+        let backup = collect(source.clone()).await;
+
+        let target = Scheduler::<OllamaRunTask>::new(
+            4,
+            1,
+            std::time::Duration::from_mins(5),
+            Default::default(),
+        )
+        .unwrap()
+        .with_retained_descriptors(true);
+        let restored = restore(&target, body(backup.clone())).await.unwrap();
+        assert_eq!((restored.tasks, restored.descriptors), (3, 0));
+        let ids = target
+            .finished_tasks()
+            .map_ok(|task| (task.id().to_string(), task.is_deleted()))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(ids.len(), 3);
+        assert!(ids.contains(&(tasks[1].id().to_string(), true)));
+        let restored_task = target.get_task(tasks[2].id()).await.unwrap().unwrap();
+        assert_eq!(restored_task.finished().await.unwrap().0.amount, 2.0);
+
+        let err = restore(&target, body(backup.clone())).await.unwrap_err();
+        assert!(matches!(err, RestoreError::NotEmpty), "{err}");
+
+        let roomy = Scheduler::<OllamaRunTask>::default().with_retained_descriptors(true);
+        let restored = restore(&roomy, body(backup.clone())).await.unwrap();
+        assert_eq!(restored.descriptors, 1);
+        let descriptor = roomy.retained_descriptor(tasks[0].id()).await.unwrap();
+        assert_eq!(descriptor.images(), [b"receipt"]);
+
+        let mut future = backup.clone();
+        future[MAGIC.len() + 1] = 9;
+        let err = restore(&Scheduler::<OllamaRunTask>::default(), body(future))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "backup format version 9 is not supported, this server reads version 1"
+        );
+        let truncated = backup[..backup.len() - 5].to_vec();
+        let err = restore(&Scheduler::<OllamaRunTask>::default(), body(truncated))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RestoreError::Malformed(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_restore_duplicates_once() {
+        let tasks = [finished(1.0), finished(2.0)];
+        let mut backup = MAGIC.to_vec();
+        backup.extend_from_slice(&BACKUP_VERSION.to_be_bytes());
+        backup.extend(record(TASKS, &schedule::encode_chunk(&tasks).unwrap()));
+        backup.extend(record(TASKS, &schedule::encode_chunk(&tasks[1..]).unwrap()));
+        backup.extend(record(END, &[]));
+
+        let target = Scheduler::<OllamaRunTask>::default();
+        let restored = restore(&target, body(backup)).await.unwrap();
+        assert_eq!(restored.tasks, 2);
+        let ids = target
+            .finished_tasks()
+            .map_ok(|task| task.id().to_string())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(ids, tasks.map(|task| task.id().to_string()));
+    }
+}
@@ -1,62 +1,512 @@
-use std::sync::{Arc, LazyLock, Mutex};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    str::FromStr,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
 
 use serde::{
     Deserialize, Serialize,
     de::{Unexpected, Visitor},
+    ser::SerializeStruct,
 };
 use smol_str::SmolStr;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::{amount::AmountFormat, error::CategoryError};
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Bill {
     pub notes: SmolStr,
     pub amount: f32,
-    pub category: Option<SmolStr>,
+    /// ISO 4217 code of the currency paid in, if the model could tell
+    pub currency: Option<SmolStr>,
+    /// Every category the purchase falls into, the primary one first
+    pub categories: Vec<SmolStr>,
+    /// The model's one line on why the primary category fits, for reviewers
+    pub category_reason: Option<String>,
+    /// Set when the extraction looks unreliable and a human should double-check it
+    pub needs_review: bool,
+}
+
+impl Bill {
+    /// The amount as people of the configured locale write it.
+    pub fn formatted_amount(&self) -> Option<String> {
+        AmountFormat::global().format(self.amount, self.currency.as_deref())
+    }
+
+    /// The primary category, the one to file the bill under.
+    pub fn category(&self) -> Option<&SmolStr> {
+        self.categories.first()
+    }
+}
+
+/// Human readable formats like JSON, and the MessagePack of the API, also get
+/// the `formatted_amount`, which the swap leaves out to follow the locale of
+/// the server reading it, and the primary `category` of clients from before
+/// bills had several.
+impl Serialize for Bill {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let human_readable = serializer.is_human_readable();
+        let len = 6 + 2 * usize::from(human_readable);
+        let mut bill = serializer.serialize_struct("Bill", len)?;
+        bill.serialize_field("notes", &self.notes)?;
+        bill.serialize_field("amount", &self.amount)?;
+        bill.serialize_field("currency", &self.currency)?;
+        if human_readable {
+            bill.serialize_field("formatted_amount", &self.formatted_amount())?;
+            bill.serialize_field("category", &self.category())?;
+        }
+        bill.serialize_field("categories", &self.categories)?;
+        bill.serialize_field("category_reason", &self.category_reason)?;
+        bill.serialize_field("needs_review", &self.needs_review)?;
+        bill.end()
+    }
+}
+
+/// JSON of a bill, from before it had `categories` too.
+#[derive(Deserialize)]
+struct BillJson {
+    notes: SmolStr,
+    amount: f32,
+    #[serde(default)]
+    currency: Option<SmolStr>,
+    #[serde(default)]
+    category: Option<SmolStr>,
+    #[serde(default)]
+    categories: Option<Vec<SmolStr>>,
+    #[serde(default)]
+    category_reason: Option<String>,
+    #[serde(default)]
+    needs_review: bool,
+}
+
+/// The fields of a bill as the swap writes them.
+#[derive(Deserialize)]
+struct BillFields {
+    notes: SmolStr,
+    amount: f32,
+    currency: Option<SmolStr>,
+    categories: Vec<SmolStr>,
+    category_reason: Option<String>,
+    needs_review: bool,
 }
 
+impl<'de> Deserialize<'de> for Bill {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            let bill = BillJson::deserialize(deserializer)?;
+            Ok(Self {
+                notes: bill.notes,
+                amount: bill.amount,
+                currency: bill.currency,
+                categories: bill
+                    .categories
+                    .unwrap_or_else(|| bill.category.into_iter().collect()),
+                category_reason: bill.category_reason,
+                needs_review: bill.needs_review,
+            })
+        } else {
+            let bill = BillFields::deserialize(deserializer)?;
+            Ok(Self {
+                notes: bill.notes,
+                amount: bill.amount,
+                currency: bill.currency,
+                categories: bill.categories,
+                category_reason: bill.category_reason,
+                needs_review: bill.needs_review,
+            })
+        }
+    }
+}
+
+/// A bill as swap layouts 4 to 6 wrote it, before categories had a reason.
+#[derive(Deserialize)]
+pub(crate) struct BillV2 {
+    notes: SmolStr,
+    amount: f32,
+    currency: Option<SmolStr>,
+    categories: Vec<SmolStr>,
+    needs_review: bool,
+}
+
+impl From<BillV2> for Bill {
+    fn from(bill: BillV2) -> Self {
+        Self {
+            notes: bill.notes,
+            amount: bill.amount,
+            currency: bill.currency,
+            categories: bill.categories,
+            category_reason: None,
+            needs_review: bill.needs_review,
+        }
+    }
+}
+
+/// A bill as swap layouts before version 4 wrote it, with one category at most.
+#[derive(Deserialize)]
+pub(crate) struct BillV1 {
+    notes: SmolStr,
+    amount: f32,
+    currency: Option<SmolStr>,
+    category: Option<SmolStr>,
+    needs_review: bool,
+}
+
+impl From<BillV1> for Bill {
+    fn from(bill: BillV1) -> Self {
+        Self {
+            notes: bill.notes,
+            amount: bill.amount,
+            currency: bill.currency,
+            categories: bill.category.into_iter().collect(),
+            category_reason: None,
+            needs_review: bill.needs_review,
+        }
+    }
+}
+
+/// A category of the process-wide registry, empty until
+/// [Category::load_from_names] is called.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Category(usize);
 
 impl Category {
-    pub fn name(&self) -> String {
-        CATEGORIES.lock().unwrap().as_ref().unwrap()[self.0].clone()
+    /// None if the registry was since replaced by a shorter list.
+    pub fn name(&self) -> Option<String> {
+        registry().name(*self).map(str::to_string)
     }
 
     pub fn all_cases() -> Vec<Category> {
-        Vec::from_iter(
-            (0..CATEGORIES.lock().unwrap().as_ref().unwrap().len()).map(|idx| Category(idx)),
-        )
+        Vec::from_iter((0..registry().len()).map(Category))
     }
 
     pub fn from_name(name: impl AsRef<str>) -> Option<Category> {
-        CATEGORIES
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
+        registry().find(name)
+    }
+
+    /// What belongs in the category, told to the model next to its name.
+    pub fn hint(&self) -> Option<String> {
+        let registry = registry();
+        registry
+            .name(*self)
+            .and_then(|name| registry.hint(name))
+            .map(str::to_string)
+    }
+
+    /// Hints of the registered categories by name.
+    pub fn hints() -> BTreeMap<String, String> {
+        registry().hints.clone()
+    }
+
+    /// Takes plain names or [NamedCategory]s carrying a hint, such as
+    /// `("Transport", "taxis, buses, fuel")`.
+    pub fn load_from_names<Iter>(iter: Iter)
+    where
+        Iter: IntoIterator,
+        Iter::Item: Into<NamedCategory>,
+    {
+        *registry() = CategoryRegistry::from_names(iter);
+    }
+
+    /// Registers names not seen before at the end of the list, so every
+    /// existing [Category] keeps its index. Returns the number of names added.
+    pub fn append_categories<Iter>(iter: Iter) -> usize
+    where
+        Iter: IntoIterator,
+        Iter::Item: AsRef<str>,
+    {
+        registry().append(iter)
+    }
+
+    /// Checks `name` can be offered to the model, which sees one name per line.
+    pub fn check_name(name: &str) -> Result<(), CategoryError> {
+        if name.trim().is_empty() {
+            return Err(CategoryError::BlankName);
+        }
+        if name.chars().any(char::is_control) {
+            return Err(CategoryError::ControlCharacter(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Replaces the registered names and the hints given with them, refusing
+    /// lists that would reindex existing categories.
+    pub fn reload_from_names<Iter>(iter: Iter) -> Result<(), CategoryError>
+    where
+        Iter: IntoIterator,
+        Iter::Item: Into<NamedCategory>,
+    {
+        registry().reload(iter)
+    }
+}
+
+/// A category name with an optional hint of what belongs in it, written
+/// `NAME=HINT` on the command line, `=` and `\` in the name escaped with a `\`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "NamedCategoryJson")]
+pub struct NamedCategory {
+    pub name: String,
+    pub hint: Option<String>,
+}
+
+/// A plain name or `{"name", "hint"}`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NamedCategoryJson {
+    Name(String),
+    Hinted { name: String, hint: Option<String> },
+}
+
+impl From<NamedCategoryJson> for NamedCategory {
+    fn from(json: NamedCategoryJson) -> Self {
+        match json {
+            NamedCategoryJson::Name(name) => name.into(),
+            NamedCategoryJson::Hinted { name, hint } => NamedCategory { name, hint },
+        }
+    }
+}
+
+impl NamedCategory {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn hint(&self) -> Option<&str> {
+        self.hint.as_deref()
+    }
+
+    /// Checks the name with [Category::check_name], and that the hint fits on
+    /// its line as well.
+    pub fn check(&self) -> Result<(), CategoryError> {
+        Category::check_name(&self.name)?;
+        match self.hint() {
+            Some(hint) if hint.chars().any(char::is_control) => {
+                Err(CategoryError::ControlCharacter(hint.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<&str> for NamedCategory {
+    fn from(name: &str) -> Self {
+        name.to_string().into()
+    }
+}
+
+impl From<String> for NamedCategory {
+    fn from(name: String) -> Self {
+        NamedCategory { name, hint: None }
+    }
+}
+
+impl From<&String> for NamedCategory {
+    fn from(name: &String) -> Self {
+        name.clone().into()
+    }
+}
+
+impl From<&NamedCategory> for NamedCategory {
+    fn from(category: &NamedCategory) -> Self {
+        category.clone()
+    }
+}
+
+impl<Name, Hint> From<(Name, Hint)> for NamedCategory
+where
+    Name: Into<String>,
+    Hint: Into<String>,
+{
+    fn from((name, hint): (Name, Hint)) -> Self {
+        NamedCategory {
+            name: name.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+/// Splits `NAME=HINT` at the first `=` not escaped as `\=`, names without one
+/// carrying no hint. `\\` stands for a backslash in the name, the hint is taken
+/// as it is. Blank hints count as none.
+impl FromStr for NamedCategory {
+    type Err = CategoryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name = String::with_capacity(s.len());
+        let mut hint = None;
+        let mut chars = s.char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, escaped @ ('=' | '\\'))) => name.push(escaped),
+                    Some((_, other)) => name.extend(['\\', other]),
+                    None => name.push('\\'),
+                },
+                '=' => {
+                    hint = Some(s[index + 1..].trim());
+                    break;
+                }
+                c => name.push(c),
+            }
+        }
+        let category: NamedCategory = match hint {
+            Some(hint) if !hint.is_empty() => (name.trim(), hint).into(),
+            Some(_) => name.trim().into(),
+            None => name.into(),
+        };
+        category.check()?;
+        Ok(category)
+    }
+}
+
+/// Written back the way [NamedCategory::from_str] reads it.
+impl Display for NamedCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.name().replace('\\', "\\\\").replace('=', "\\=");
+        match self.hint() {
+            Some(hint) => write!(f, "{name}={hint}"),
+            None => f.write_str(&name),
+        }
+    }
+}
+
+/// Category names in a fixed order, the index of each being its [Category].
+///
+/// ```
+/// use ledoxide::CategoryRegistry;
+///
+/// let mut registry = CategoryRegistry::from_names(["Food", "Rent"]);
+/// let rent = registry.find("Rent").unwrap();
+/// assert_eq!(registry.append(["Transport", "Food"]), 1);
+/// assert_eq!(registry.name(rent), Some("Rent"));
+/// assert!(registry.reload(["Rent", "Food"]).is_err());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CategoryRegistry {
+    names: Vec<String>,
+    /// Hints by category name, for the categories that have one
+    hints: BTreeMap<String, String>,
+}
+
+impl CategoryRegistry {
+    pub fn from_names<Iter>(iter: Iter) -> Self
+    where
+        Iter: IntoIterator,
+        Iter::Item: Into<NamedCategory>,
+    {
+        let mut registry = Self::default();
+        for category in iter {
+            let category = category.into();
+            if let Some(hint) = category.hint() {
+                registry
+                    .hints
+                    .insert(category.name().to_string(), hint.to_string());
+            }
+            registry.names.push(category.name().to_string());
+        }
+        registry
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn name(&self, category: Category) -> Option<&str> {
+        self.names.get(category.0).map(String::as_str)
+    }
+
+    pub fn hint(&self, name: &str) -> Option<&str> {
+        self.hints.get(name).map(String::as_str)
+    }
+
+    pub fn find(&self, name: impl AsRef<str>) -> Option<Category> {
+        self.names
             .iter()
             .position(|n| n == name.as_ref())
-            .map(|idx| Category(idx))
+            .map(Category)
     }
 
-    pub fn load_from_names<Iter>(iter: Iter)
+    pub fn append<Iter>(&mut self, iter: Iter) -> usize
     where
         Iter: IntoIterator,
         Iter::Item: AsRef<str>,
     {
-        let names = Vec::from_iter(iter.into_iter().map(|name| name.as_ref().to_string()));
-        *CATEGORIES.lock().unwrap() = Some(names);
+        let original_len = self.names.len();
+        for name in iter {
+            if self.find(&name).is_none() {
+                self.names.push(name.as_ref().to_string());
+            }
+        }
+        self.names.len() - original_len
+    }
+
+    /// Takes the names and hints of `iter`, which must start with the names
+    /// registered so far. Names listed without a hint keep the one they had.
+    pub fn reload<Iter>(&mut self, iter: Iter) -> Result<(), CategoryError>
+    where
+        Iter: IntoIterator,
+        Iter::Item: Into<NamedCategory>,
+    {
+        let mut reloaded = Self::from_names(iter);
+        for (index, name) in self.names.iter().enumerate() {
+            match reloaded.names.get(index) {
+                Some(new_name) if new_name == name => {}
+                new_name => {
+                    return Err(CategoryError::PrefixMismatch {
+                        index,
+                        expected: name.clone(),
+                        found: new_name.cloned(),
+                    });
+                }
+            }
+        }
+        // plain names keep the hints they had
+        for (name, hint) in std::mem::take(&mut self.hints) {
+            reloaded.hints.entry(name).or_insert(hint);
+        }
+        *self = reloaded;
+        Ok(())
     }
 }
 
-static CATEGORIES: LazyLock<Arc<Mutex<Option<Vec<String>>>>> =
-    LazyLock::new(|| Arc::new(Mutex::new(None)));
+static CATEGORIES: Mutex<CategoryRegistry> = Mutex::new(CategoryRegistry {
+    names: Vec::new(),
+    hints: BTreeMap::new(),
+});
+
+/// The registry stays consistent even if a holder panicked, as every update
+/// replaces it in one go.
+fn registry() -> MutexGuard<'static, CategoryRegistry> {
+    CATEGORIES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Held by tests loading categories into the registry, so they don't see each
+/// other's under the parallel test runner.
+#[cfg(test)]
+pub(crate) async fn lock_registry() -> tokio::sync::MutexGuard<'static, ()> {
+    static LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    LOCK.lock().await
+}
 
 impl Serialize for Category {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        serializer.serialize_str(self.name().as_str())
+        let name = self
+            .name()
+            .ok_or_else(|| serde::ser::Error::custom("unregistered category"))?;
+        serializer.serialize_str(&name)
     }
 }
 
@@ -66,12 +516,10 @@ impl<'de> Deserialize<'de> for Category {
         D: serde::Deserializer<'de>,
     {
         let name = deserializer.deserialize_string(CategoryNameVisitor)?;
-        Ok(
-            Category::from_name(&name).ok_or(serde::de::Error::invalid_value(
-                Unexpected::Str(&name),
-                &CategoryNameVisitor,
-            ))?,
-        )
+        Category::from_name(&name).ok_or(serde::de::Error::invalid_value(
+            Unexpected::Str(&name),
+            &CategoryNameVisitor,
+        ))
     }
 }
 
@@ -97,3 +545,143 @@ impl<'de> Visitor<'de> for CategoryNameVisitor {
         Ok(v.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bill(categories: &[&str]) -> Bill {
+        Bill {
+            notes: "Groceries and a lamp".into(),
+            amount: 30.0,
+            currency: None,
+            categories: categories.iter().map(|&c| c.into()).collect(),
+            category_reason: None,
+            needs_review: false,
+        }
+    }
+
+    #[test]
+    fn test_categories_json() {
+        let single = serde_json::to_value(bill(&["Food"])).unwrap();
+        assert_eq!(single["category"], "Food");
+        assert_eq!(single["categories"], serde_json::json!(["Food"]));
+        let multi = bill(&["Food", "Household"]);
+        let json = serde_json::to_value(&multi).unwrap();
+        assert_eq!(json["category"], "Food");
+        assert_eq!(json["categories"], serde_json::json!(["Food", "Household"]));
+        assert_eq!(serde_json::from_value::<Bill>(json).unwrap(), multi);
+        let none = serde_json::to_value(bill(&[])).unwrap();
+        assert!(none["category"].is_null());
+
+        // written before bills had several categories
+        let old = serde_json::json!({"notes": "Groceries and a lamp", "amount": 30.0, "category": "Food"});
+        assert_eq!(
+            serde_json::from_value::<Bill>(old).unwrap(),
+            bill(&["Food"])
+        );
+    }
+
+    #[test]
+    fn test_categories_swap() {
+        let multi = bill(&["Food", "Household"]);
+        let bytes = postcard::to_allocvec(&multi).unwrap();
+        assert_eq!(postcard::from_bytes::<Bill>(&bytes).unwrap(), multi);
+    }
+
+    #[test]
+    fn test_append_preserves_indices() {
+        let mut registry = CategoryRegistry::from_names(["Food", "Rent"]);
+        let food = registry.find("Food").unwrap();
+        let rent = registry.find("Rent").unwrap();
+
+        assert_eq!(registry.append(["Transport", "Food", "Drink"]), 2);
+        assert_eq!(registry.len(), 4);
+        assert_eq!(registry.find("Food"), Some(food));
+        assert_eq!(registry.find("Rent"), Some(rent));
+        assert_eq!(registry.name(food), Some("Food"));
+        assert_eq!(registry.name(rent), Some("Rent"));
+        assert_eq!(registry.find("Drink").map(|c| c.0), Some(3));
+    }
+
+    #[test]
+    fn test_hints() {
+        let mut registry = CategoryRegistry::from_names([
+            NamedCategory::from("Food"),
+            ("Transport", "taxis, buses, fuel").into(),
+        ]);
+        assert_eq!(registry.hint("Transport"), Some("taxis, buses, fuel"));
+        assert_eq!(registry.hint("Food"), None);
+        registry.reload(["Food", "Transport", "Rent"]).unwrap();
+        assert_eq!(registry.hint("Transport"), Some("taxis, buses, fuel"));
+        registry
+            .reload([
+                NamedCategory::from(("Food", "groceries")),
+                "Transport".into(),
+                ("Rent", "housing").into(),
+            ])
+            .unwrap();
+        assert_eq!(registry.hint("Food"), Some("groceries"));
+        assert_eq!(registry.hint("Transport"), Some("taxis, buses, fuel"));
+        assert_eq!(registry.hint("Rent"), Some("housing"));
+
+        let parsed = "Transport = taxis, buses".parse::<NamedCategory>().unwrap();
+        assert_eq!(parsed, ("Transport", "taxis, buses").into());
+        assert_eq!(parsed.to_string(), "Transport=taxis, buses");
+        assert_eq!("Food".parse::<NamedCategory>().unwrap(), "Food".into());
+        assert!("=taxis".parse::<NamedCategory>().is_err());
+        let escaped = r"A\=B\\C=letters".parse::<NamedCategory>().unwrap();
+        assert_eq!(escaped, (r"A=B\C", "letters").into());
+        assert_eq!(
+            escaped.to_string().parse::<NamedCategory>().unwrap(),
+            escaped
+        );
+        assert_eq!(
+            r"Tax\=VAT".parse::<NamedCategory>().unwrap(),
+            "Tax=VAT".into()
+        );
+    }
+
+    #[test]
+    fn test_check_name() {
+        assert!(Category::check_name("Eating out | Bars").is_ok());
+        assert!(Category::check_name("Food (.*)").is_ok());
+        assert!(matches!(
+            Category::check_name("  "),
+            Err(CategoryError::BlankName)
+        ));
+        assert!(matches!(
+            Category::check_name("Food\n- Rent"),
+            Err(CategoryError::ControlCharacter(_))
+        ));
+    }
+
+    #[test]
+    fn test_reload_requires_prefix() {
+        let mut registry = CategoryRegistry::from_names(["Food", "Rent"]);
+        registry
+            .reload(["Food", "Rent", "Transport"])
+            .expect("extending the list keeps the prefix");
+        assert_eq!(registry.len(), 3);
+
+        let err = registry.reload(["Rent", "Food", "Transport"]).unwrap_err();
+        assert!(matches!(
+            err,
+            CategoryError::PrefixMismatch { index: 0, ref found, .. } if found.as_deref() == Some("Rent")
+        ));
+        let err = registry.reload(["Food"]).unwrap_err();
+        assert!(matches!(
+            err,
+            CategoryError::PrefixMismatch {
+                index: 1,
+                found: None,
+                ..
+            }
+        ));
+        assert_eq!(
+            registry.len(),
+            3,
+            "a rejected reload leaves the registry intact"
+        );
+    }
+}
@@ -0,0 +1,349 @@
+use std::time::{Duration, Instant};
+
+use async_stream::try_stream;
+use axum::body::Bytes;
+use futures::{Stream, StreamExt};
+use reqwest::{
+    IntoUrl, RequestBuilder, Response, Url,
+    multipart::{Form, Part},
+};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    error::ClientError,
+    task::{
+        State, TaskControlBlock, Token,
+        embedding::Categorizer,
+        imaging::{self, FrameSelection},
+        ollama::Quantization,
+        preprocess::Preprocess,
+    },
+};
+
+/// Time between polls while waiting for a task.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Talks to a ledoxide server over its `/v1` API, whose tasks deserialize into
+/// [TaskControlBlock].
+#[derive(Debug, Clone)]
+pub struct LedoxideClient {
+    http: reqwest::Client,
+    base_url: Url,
+    key: Option<String>,
+}
+
+/// Form fields of a task, left to the server's defaults if unset.
+#[derive(Debug, Clone, Default)]
+pub struct TaskOptions {
+    pub categories: Option<Vec<String>>,
+    pub animation_frames: Option<FrameSelection>,
+    pub quantization: Option<Quantization>,
+    pub preprocess: Option<Preprocess>,
+    /// Leaves the bill without a category if false, skipping the category stage
+    pub categorize: Option<bool>,
+    /// Picks the category by embedding similarity or with the LM, see `--categorizer`
+    pub categorizer: Option<Categorizer>,
+    /// Samples every stage with a fixed seed if true, see `--deterministic`
+    pub deterministic: Option<bool>,
+    /// Logs the debug events of the task on the server, like `X-Debug: 1`
+    pub debug: bool,
+}
+
+impl LedoxideClient {
+    /// A client of the server at `base_url`, including its base path if any.
+    /// An empty `key` sends no `Authorization` header.
+    pub fn new(base_url: impl IntoUrl, key: impl Into<String>) -> Result<Self, ClientError> {
+        let mut base_url = base_url.into_url()?;
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        let key = key.into();
+        Ok(Self {
+            http: reqwest::Client::new(),
+            base_url,
+            key: (!key.is_empty()).then_some(key),
+        })
+    }
+
+    pub async fn create_task(
+        &self,
+        image: impl Into<Bytes>,
+        options: &TaskOptions,
+    ) -> Result<TaskControlBlock, ClientError> {
+        self.create_task_with_images([image.into()], options).await
+    }
+
+    /// Creates a task of several images, such as the pages of one receipt.
+    pub async fn create_task_with_images(
+        &self,
+        images: impl IntoIterator<Item = Bytes>,
+        options: &TaskOptions,
+    ) -> Result<TaskControlBlock, ClientError> {
+        let mut form = Form::new();
+        for image in images {
+            let mime = imaging::detect(&image)
+                .map_or("application/octet-stream", |format| format.mime_type());
+            form = form.part("image", Part::stream(image).mime_str(mime)?);
+        }
+        if let Some(categories) = &options.categories {
+            let categories = serde_json::to_vec(categories).map_err(ClientError::Decode)?;
+            form = form.part(
+                "categories",
+                Part::bytes(categories).mime_str("application/json")?,
+            );
+        }
+        if let Some(frames) = options.animation_frames {
+            form = form.text("animation_frames", frames.to_string());
+        }
+        if let Some(quantization) = &options.quantization {
+            form = form.text("quantization", quantization.to_string());
+        }
+        if let Some(preprocess) = options.preprocess {
+            form = form.text("preprocess", preprocess.to_string());
+        }
+        if let Some(categorize) = options.categorize {
+            form = form.text("categorize", categorize.to_string());
+        }
+        if let Some(categorizer) = options.categorizer {
+            form = form.text("categorizer", categorizer.to_string());
+        }
+        if let Some(deterministic) = options.deterministic {
+            form = form.text("deterministic", deterministic.to_string());
+        }
+        let mut request = self.post("create_task")?.multipart(form);
+        if options.debug {
+            request = request.header("X-Debug", "1");
+        }
+        self.json(request).await
+    }
+
+    pub async fn get_task(&self, id: &str) -> Result<TaskControlBlock, ClientError> {
+        self.json(self.get(&format!("get_task/{id}"))?).await
+    }
+
+    /// Polls the task until it finishes, failing with [ClientError::Timeout]
+    /// if it doesn't within `timeout`. A failed task is returned as well.
+    ///
+    /// Each poll is held by the server until the task changes state, servers
+    /// without long polling being polled at a fixed interval instead.
+    pub async fn wait_for(
+        &self,
+        id: &str,
+        timeout: Duration,
+    ) -> Result<TaskControlBlock, ClientError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let polled = Instant::now();
+            let wait = deadline.saturating_duration_since(polled).as_secs();
+            let task: TaskControlBlock = self
+                .json(self.get(&format!("get_task/{id}?wait={wait}"))?)
+                .await?;
+            if matches!(task.state(), State::Finished(_)) {
+                return Ok(task);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(ClientError::Timeout(id.to_string()));
+            }
+            if polled.elapsed() < POLL_INTERVAL {
+                tokio::time::sleep(POLL_INTERVAL.min(left)).await;
+            }
+        }
+    }
+
+    /// Every task the server knows, only those whose review flag matches
+    /// `needs_review` if given.
+    pub async fn list_tasks(
+        &self,
+        needs_review: Option<bool>,
+    ) -> Result<Vec<TaskControlBlock>, ClientError> {
+        let path = match needs_review {
+            Some(needs_review) => format!("tasks?needs_review={needs_review}"),
+            None => "tasks".to_string(),
+        };
+        self.json(self.get(&path)?).await
+    }
+
+    pub async fn retry_task(&self, id: &str) -> Result<TaskControlBlock, ClientError> {
+        self.json(self.post(&format!("task/{id}/retry"))?).await
+    }
+
+    pub async fn categories(&self) -> Result<Vec<String>, ClientError> {
+        self.json(self.get("categories")?).await
+    }
+
+    /// Tokens of a running task as the models generate them, ending when the
+    /// task finishes.
+    pub async fn stream_tokens(
+        &self,
+        id: &str,
+    ) -> Result<impl Stream<Item = Result<Token, ClientError>> + use<>, ClientError> {
+        let response = self
+            .send(self.get(&format!("admin/task/{id}/stream"))?)
+            .await?;
+        let mut body = response.bytes_stream();
+        Ok(try_stream! {
+            let mut buffer = Vec::new();
+            while let Some(chunk) = body.next().await {
+                buffer.extend_from_slice(&chunk?);
+                while let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+                    let event = buffer.drain(..end + 2).collect::<Vec<_>>();
+                    if let Some(token) = parse_event(&event)? {
+                        yield token;
+                    }
+                }
+            }
+        })
+    }
+
+    fn get(&self, path: &str) -> Result<RequestBuilder, ClientError> {
+        Ok(self.authorize(self.http.get(self.url(path)?)))
+    }
+
+    fn post(&self, path: &str) -> Result<RequestBuilder, ClientError> {
+        Ok(self.authorize(self.http.post(self.url(path)?)))
+    }
+
+    fn url(&self, path: &str) -> Result<Url, ClientError> {
+        self.base_url
+            .join("v1/")
+            .and_then(|url| url.join(path))
+            .map_err(|err| ClientError::InvalidUrl(err.to_string()))
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        }
+    }
+
+    /// Sends `request`, turning error statuses into [ClientError::Api].
+    async fn send(&self, request: RequestBuilder) -> Result<Response, ClientError> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.bytes().await?;
+        let message = serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|body| body["error"].as_str().map(str::to_string))
+            .unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned());
+        Err(ClientError::Api { status, message })
+    }
+
+    async fn json<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, ClientError> {
+        let body = self.send(request).await?.bytes().await?;
+        serde_json::from_slice(&body).map_err(ClientError::Decode)
+    }
+}
+
+/// The token of a server-sent event, none for comments and keep-alives.
+fn parse_event(event: &[u8]) -> Result<Option<Token>, ClientError> {
+    let data = String::from_utf8_lossy(event)
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(str::trim_start)
+        .collect::<Vec<_>>()
+        .join("\n");
+    if data.is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_str(&data)
+        .map(Some)
+        .map_err(ClientError::Decode)
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::{
+        args,
+        bill::Category,
+        server,
+        state::AppState,
+        task::{Stage, TokenKind},
+    };
+
+    async fn serve(args: &args::App) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = server::app(AppState::new(args).unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}{}", args.base_path)
+    }
+
+    #[tokio::test]
+    async fn test_client() {
+        let _registry = crate::bill::lock_registry().await;
+        Category::load_from_names(["Food", "Rent"]);
+        let base_url = serve(&args::App {
+            auth_key: "key".into(),
+            base_path: "/ledoxide".into(),
+            ..Default::default()
+        })
+        .await;
+
+        let client = LedoxideClient::new(&base_url, "key").unwrap();
+        assert_eq!(client.categories().await.unwrap(), ["Food", "Rent"]);
+        assert!(client.list_tasks(None).await.unwrap().is_empty());
+        let err = client.get_task("nonexistent").await.unwrap_err();
+        assert!(
+            matches!(err, ClientError::Api { status: StatusCode::NOT_FOUND, ref message } if message == "task not found"),
+            "{err}"
+        );
+
+        let stranger = LedoxideClient::new(&base_url, "").unwrap();
+        assert!(matches!(
+            stranger.categories().await,
+            Err(ClientError::Api {
+                status: StatusCode::BAD_REQUEST,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_workflow() {
+        let _registry = crate::bill::lock_registry().await;
+        Category::load_from_names(["Shopping", "Food", "Transport", "Rent"]);
+        let auth_key = "WK1wJ5ipiVvSdmdCPqNx8up8qj8GCwbm_";
+        let base_url = serve(&args::App {
+            auth_key: auth_key.into(),
+            ..Default::default()
+        })
+        .await;
+        let client = LedoxideClient::new(&base_url, auth_key).unwrap();
+
+        let screenshot = include_bytes!("../asset/second-hand-horse-screenshot.jpeg");
+        let task = client
+            .create_task(&screenshot[..], &TaskOptions::default())
+            .await
+            .unwrap();
+        let task = client
+            .wait_for(task.id(), Duration::from_mins(10))
+            .await
+            .unwrap();
+        let State::Finished(result) = task.state() else {
+            unreachable!("waited for the task to finish");
+        };
+        let bill = result.unwrap().0;
+        assert_eq!(bill.amount, 2188f32);
+        assert_eq!(bill.category(), Some(&"Shopping".into()));
+    }
+
+    #[test]
+    fn test_parse_event() {
+        let event = b"event: response\ndata: {\"stage\":\"amount\",\"kind\":\"response\",\"text\":\"12\"}\n\n";
+        let token = parse_event(event).unwrap().unwrap();
+        assert_eq!(token.stage, Stage::Amount);
+        assert_eq!(token.kind, TokenKind::Response);
+        assert_eq!(token.text, "12");
+        assert!(parse_event(b": skipped 3 tokens\n\n").unwrap().is_none());
+    }
+}
@@ -0,0 +1,466 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{File, OpenOptions, TryLockError},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use smol_str::SmolStr;
+use tokio::sync::Mutex;
+use tracing::{Level, event};
+
+use crate::{
+    args,
+    error::{ConfigError, StartupError},
+    schedule::Scheduler,
+    task::ollama::{KeepAliveSettings, OllamaRunTask},
+};
+
+/// Settings that may change while serving, as `GET /admin/config` reports them
+/// and the runtime config file keeps them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RuntimeConfig {
+    pub max_concurrency: usize,
+    /// Finished tasks kept in memory before they are swapped out
+    pub max_memory_size: usize,
+    /// Keep alive of models not listed in [Self::model_timeouts]
+    pub model_timeout_secs: u64,
+    /// Keep alive of single models
+    pub model_timeouts: BTreeMap<SmolStr, u64>,
+}
+
+/// Body of `PATCH /admin/config`, leaving settings it lacks as they are.
+/// `model_timeouts` replaces the whole map.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigPatch {
+    pub max_concurrency: Option<usize>,
+    pub max_memory_size: Option<usize>,
+    pub model_timeout_secs: Option<u64>,
+    pub model_timeouts: Option<BTreeMap<SmolStr, u64>>,
+}
+
+impl RuntimeConfig {
+    /// The settings the flags start the server with.
+    pub fn from_args(args: &args::App) -> Self {
+        Self {
+            max_concurrency: args.max_concurrency,
+            max_memory_size: args.max_memory_size,
+            model_timeout_secs: args.model_timeout.as_secs(),
+            model_timeouts: BTreeMap::new(),
+        }
+    }
+
+    /// The settings `scheduler` runs with now.
+    pub fn of(scheduler: &Scheduler<OllamaRunTask>) -> Self {
+        let keep_alive = scheduler.runner().keep_alive.settings();
+        Self {
+            max_concurrency: scheduler.max_concurrency(),
+            max_memory_size: scheduler.max_memory_size(),
+            model_timeout_secs: keep_alive.default.unwrap_or_default().as_secs(),
+            model_timeouts: keep_alive
+                .models
+                .into_iter()
+                .map(|(model, timeout)| (model, timeout.as_secs()))
+                .collect(),
+        }
+    }
+
+    /// These settings with those of `patch`, failing on the first invalid one.
+    pub fn patched(mut self, patch: ConfigPatch) -> Result<Self, ConfigError> {
+        if let Some(max_concurrency) = patch.max_concurrency {
+            if max_concurrency == 0 {
+                return Err(ConfigError::Invalid {
+                    setting: "max_concurrency",
+                    reason: "must be at least 1".into(),
+                });
+            }
+            self.max_concurrency = max_concurrency;
+        }
+        if let Some(max_memory_size) = patch.max_memory_size {
+            self.max_memory_size = max_memory_size;
+        }
+        if let Some(model_timeout_secs) = patch.model_timeout_secs {
+            self.model_timeout_secs = model_timeout_secs;
+        }
+        if let Some(model_timeouts) = patch.model_timeouts {
+            if model_timeouts.keys().any(|model| model.trim().is_empty()) {
+                return Err(ConfigError::Invalid {
+                    setting: "model_timeouts",
+                    reason: "model names must not be empty".into(),
+                });
+            }
+            self.model_timeouts = model_timeouts;
+        }
+        Ok(self)
+    }
+
+    pub fn keep_alive(&self) -> KeepAliveSettings {
+        KeepAliveSettings {
+            default: Some(Duration::from_secs(self.model_timeout_secs)),
+            models: self
+                .model_timeouts
+                .iter()
+                .map(|(model, secs)| (model.clone(), Duration::from_secs(*secs)))
+                .collect(),
+        }
+    }
+
+    async fn apply(&self, scheduler: &Scheduler<OllamaRunTask>) {
+        scheduler.runner().keep_alive.set(self.keep_alive());
+        if let Err(err) = scheduler.set_max_memory_size(self.max_memory_size).await {
+            event!(target: "scheduler", Level::ERROR, "swap failed after lowering the memory limit: {err}");
+        }
+        scheduler.set_max_concurrency(self.max_concurrency).await;
+    }
+}
+
+/// Layout of the runtime config file, written next to the settings. Bumped
+/// whenever a setting is added or changes meaning, so older builds refuse the
+/// file instead of dropping what they don't know on the next save.
+pub const CONFIG_FORMAT: u32 = 1;
+
+/// The runtime config file, the settings after the format and the version of
+/// the build that wrote them.
+#[derive(Serialize)]
+struct SavedConfig<'a> {
+    format: u32,
+    written_by: &'a str,
+    #[serde(flatten)]
+    config: &'a RuntimeConfig,
+}
+
+/// The settings saved to `path` by an earlier [LiveConfig::update], none if
+/// nothing was saved yet. Files without a format are from before it was
+/// written, and read as the first one.
+pub fn load(path: &Path) -> io::Result<ConfigPatch> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(ConfigPatch::default()),
+        Err(err) => return Err(err),
+    };
+    let mut saved = serde_json::from_slice::<serde_json::Map<String, Value>>(&bytes)
+        .map_err(io::Error::other)?;
+    let format = match saved.remove("format") {
+        Some(format) => serde_json::from_value::<u32>(format).map_err(io::Error::other)?,
+        None => 1,
+    };
+    let written_by = saved.remove("written_by");
+    if format > CONFIG_FORMAT {
+        let written_by = written_by
+            .as_ref()
+            .and_then(Value::as_str)
+            .unwrap_or("an unknown version");
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "written in format {format} by ledoxide {written_by}, while this build of {} reads up to format {CONFIG_FORMAT}",
+                env!("CARGO_PKG_VERSION")
+            ),
+        ));
+    }
+    serde_json::from_value(Value::Object(saved)).map_err(io::Error::other)
+}
+
+/// Who holds the lock of a runtime config file, as its lock file says.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct LockOwner {
+    pid: u32,
+    version: String,
+    /// Host the holder runs on, telling instances sharing a volume apart
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default)]
+    since: Option<DateTime<Utc>>,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            host: hostname(),
+            since: Some(Utc::now()),
+        }
+    }
+}
+
+fn hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+}
+
+/// Held for as long as an instance may save to a runtime config file, so a
+/// second one started on the same file, like the other half of a blue/green
+/// deploy, refuses to run instead of overwriting its changes. The lock is an
+/// exclusive lock the system holds on the file with `.lock` appended, released
+/// when the holder exits however it does, so it is never left behind. The file
+/// names the pid, host and version of the holder and stays when released.
+#[derive(Debug)]
+pub struct ConfigLock {
+    _file: Arc<File>,
+}
+
+impl ConfigLock {
+    /// Fails if another instance holds the lock, unless `force`. Forced
+    /// instances run without the lock, taking it once the holder exits.
+    pub fn acquire(config: &Path, force: bool) -> Result<Self, StartupError> {
+        let mut path = OsString::from(config.as_os_str());
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        let failed = |err: io::Error| StartupError::RuntimeConfig {
+            path: config.to_path_buf(),
+            reason: format!("cannot lock {}: {err}", path.display()),
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(failed)?;
+        match file.try_lock() {
+            Ok(()) => {
+                write_owner(&file).map_err(failed)?;
+                return Ok(Self {
+                    _file: Arc::new(file),
+                });
+            }
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(err)) => return Err(failed(err)),
+        }
+        let holder = std::fs::read(&path)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<LockOwner>(&json).ok())
+            .unwrap_or_else(|| LockOwner {
+                pid: 0,
+                version: "an unknown version".into(),
+                host: None,
+                since: None,
+            });
+        if !force {
+            return Err(StartupError::ConfigLocked {
+                path: config.to_path_buf(),
+                pid: holder.pid,
+                version: holder.version,
+                host: holder.host.unwrap_or_else(|| "an unknown host".into()),
+            });
+        }
+        event!(
+            Level::WARN,
+            "running without the lock of {} held by pid {} as forced, taking it once that instance exits",
+            config.display(),
+            holder.pid
+        );
+        let file = Arc::new(file);
+        let waiting = file.clone();
+        std::thread::spawn(move || {
+            if waiting.lock().and_then(|()| write_owner(&waiting)).is_ok() {
+                event!(Level::INFO, "took the lock of the runtime config");
+            }
+        });
+        Ok(Self { _file: file })
+    }
+}
+
+/// Replaces what the lock file says with who holds it now.
+fn write_owner(mut file: &File) -> io::Result<()> {
+    let json = serde_json::to_vec(&LockOwner::current()).map_err(io::Error::other)?;
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&json)
+}
+
+/// Changes settings one patch at a time, saving them first if there is a
+/// file to, so a patch that can't be kept isn't applied either.
+#[derive(Debug, Default)]
+pub struct LiveConfig {
+    file: Option<PathBuf>,
+    lock: Mutex<()>,
+}
+
+impl LiveConfig {
+    pub fn new(file: Option<PathBuf>) -> Self {
+        Self {
+            file,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub async fn update(
+        &self,
+        scheduler: &Scheduler<OllamaRunTask>,
+        patch: ConfigPatch,
+    ) -> Result<RuntimeConfig, ConfigError> {
+        let _guard = self.lock.lock().await;
+        let config = RuntimeConfig::of(scheduler).patched(patch)?;
+        if let Some(file) = &self.file {
+            save(file, &config).await.map_err(ConfigError::Persist)?;
+        }
+        config.apply(scheduler).await;
+        event!(Level::INFO, "runtime config updated: {config:?}");
+        Ok(config)
+    }
+}
+
+/// Writes next to `path` and renames, so a crash never leaves half a file.
+async fn save(path: &Path, config: &RuntimeConfig) -> io::Result<()> {
+    let saved = SavedConfig {
+        format: CONFIG_FORMAT,
+        written_by: env!("CARGO_PKG_VERSION"),
+        config,
+    };
+    let json = serde_json::to_vec_pretty(&saved).map_err(io::Error::other)?;
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    tokio::fs::write(&temporary, json).await?;
+    tokio::fs::rename(&temporary, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::AppState;
+
+    #[tokio::test]
+    async fn test_update_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = args::App {
+            runtime_config: Some(dir.path().join("config.json")),
+            ..Default::default()
+        };
+        let state = AppState::new(&args).unwrap();
+        let patch = serde_json::from_value::<ConfigPatch>(serde_json::json!({
+            "max_concurrency": 2,
+            "model_timeouts": { "gemma3:4b": 600 }
+        }))
+        .unwrap();
+        let config = state
+            .config()
+            .update(state.scheduler(), patch)
+            .await
+            .unwrap();
+        assert_eq!(config, RuntimeConfig::of(state.scheduler()));
+        assert_eq!(config.max_concurrency, 2);
+        assert_eq!(config.max_memory_size, args.max_memory_size);
+        let keep_alive = state.scheduler().runner().keep_alive.settings();
+        assert_eq!(
+            keep_alive.models.get("gemma3:4b"),
+            Some(&Duration::from_secs(600))
+        );
+
+        let err = state
+            .config()
+            .update(
+                state.scheduler(),
+                ConfigPatch {
+                    max_concurrency: Some(0),
+                    max_memory_size: Some(1),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid {
+                setting: "max_concurrency",
+                ..
+            }
+        ));
+        assert_eq!(RuntimeConfig::of(state.scheduler()), config);
+
+        let saved = serde_json::from_slice::<Value>(
+            &std::fs::read(dir.path().join("config.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(saved["format"], CONFIG_FORMAT);
+        assert_eq!(saved["written_by"], env!("CARGO_PKG_VERSION"));
+
+        // the flags only apply until the settings were changed
+        drop(state);
+        let restarted = AppState::new(&args).unwrap();
+        assert_eq!(RuntimeConfig::of(restarted.scheduler()), config);
+        assert!(
+            serde_json::from_str::<ConfigPatch>(r#"{"rate_limit": 3}"#).is_err(),
+            "unknown settings are rejected"
+        );
+    }
+
+    #[test]
+    fn test_newer_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, r#"{"max_concurrency": 3}"#).unwrap();
+        assert_eq!(load(&path).unwrap().max_concurrency, Some(3));
+
+        std::fs::write(
+            &path,
+            r#"{"format": 2, "written_by": "9.0.0", "max_concurrency": 3, "max_batch": 8}"#,
+        )
+        .unwrap();
+        let err = load(&path).unwrap_err().to_string();
+        assert!(err.contains("format 2 by ledoxide 9.0.0"), "{err}");
+        assert!(
+            err.contains(&format!("up to format {CONFIG_FORMAT}")),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.json");
+        let lock_file = dir.path().join("config.json.lock");
+        let read_owner =
+            || serde_json::from_slice::<LockOwner>(&std::fs::read(&lock_file).unwrap()).unwrap();
+
+        // another instance, holding the lock on a file of its own
+        let other = File::create(&lock_file).unwrap();
+        other.lock().unwrap();
+        let owner = LockOwner {
+            pid: 42,
+            version: "0.9.0".into(),
+            host: Some("blue".into()),
+            since: None,
+        };
+        std::fs::write(&lock_file, serde_json::to_vec(&owner).unwrap()).unwrap();
+        let err = ConfigLock::acquire(&config, false).unwrap_err();
+        assert!(matches!(
+            err,
+            StartupError::ConfigLocked { pid: 42, ref version, ref host, .. }
+                if version == "0.9.0" && host == "blue"
+        ));
+
+        let forced = ConfigLock::acquire(&config, true).unwrap();
+        assert_eq!(read_owner().pid, 42);
+        // the forced instance takes the lock once the other one exits
+        drop(other);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while read_owner().pid != std::process::id() {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(
+            ConfigLock::acquire(&config, false),
+            Err(StartupError::ConfigLocked { .. })
+        ));
+
+        // released with the holder, even one that crashed, the file staying
+        drop(forced);
+        assert!(lock_file.exists());
+        let _lock = ConfigLock::acquire(&config, false).unwrap();
+        assert_eq!(read_owner().version, env!("CARGO_PKG_VERSION"));
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{error::DeadlineError, state::AppState};
+
+/// Milliseconds the client is willing to wait for a response.
+pub const DEADLINE_HEADER: &str = "X-Request-Deadline-Ms";
+
+/// When the response is due, for handlers that wait on purpose to answer
+/// before [bound] gives up on them.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(pub Instant);
+
+/// Answers `504` once the deadline in [DEADLINE_HEADER], or the server default,
+/// passes. Work spawned by the handler, like running a task, is unaffected.
+/// The handler finds the [Deadline] among the request extensions.
+pub async fn bound(state: State<AppState>, mut request: Request, next: Next) -> Response {
+    let deadline = match request.headers().get(DEADLINE_HEADER) {
+        Some(value) => match value.to_str().ok().and_then(|ms| ms.parse().ok()) {
+            Some(ms) => Some(Duration::from_millis(ms)),
+            None => return DeadlineError::InvalidHeader.into_response(),
+        },
+        None => state.default_deadline(),
+    };
+    // too far away to ever pass if the instant can't even be told
+    let Some((deadline, due)) =
+        deadline.and_then(|deadline| Some((deadline, Instant::now().checked_add(deadline)?)))
+    else {
+        return next.run(request).await;
+    };
+    request.extensions_mut().insert(Deadline(due));
+    tokio::time::timeout_at(due, next.run(request))
+        .await
+        .unwrap_or_else(|_| DeadlineError::Exceeded(deadline).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::StatusCode, middleware::from_fn_with_state, routing::get};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::args;
+
+    async fn call(default_deadline: Option<Duration>, header: Option<&str>) -> Response {
+        let state = AppState::new(&args::App {
+            default_deadline,
+            ..Default::default()
+        })
+        .unwrap();
+        let app = axum::Router::new()
+            .route(
+                "/slow",
+                get(async || {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    "done"
+                })
+                .layer(from_fn_with_state(state.clone(), bound)),
+            )
+            .with_state(state);
+        let mut request = Request::get("/slow");
+        if let Some(header) = header {
+            request = request.header(DEADLINE_HEADER, header);
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_deadline() {
+        assert_eq!(call(None, None).await.status(), StatusCode::OK);
+        assert_eq!(call(None, Some("5000")).await.status(), StatusCode::OK);
+        // too far away to tell when, as good as none
+        assert_eq!(
+            call(None, Some("18446744073709551615")).await.status(),
+            StatusCode::OK
+        );
+        let response = call(None, Some("10")).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "no response within 10 ms");
+        assert_eq!(
+            call(Some(Duration::from_millis(10)), None).await.status(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        // the client may allow more time than the server default
+        assert_eq!(
+            call(Some(Duration::from_millis(10)), Some("5000"))
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            call(None, Some("soon")).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -1,10 +1,21 @@
-use axum::{Json, http::StatusCode, response::IntoResponse};
+use axum::{
+    Json,
+    http::{StatusCode, header},
+    response::IntoResponse,
+};
 use axum_extra::typed_header::TypedHeaderRejection;
 use image::ImageError;
+use serde::{
+    Deserialize, Serialize,
+    de::{EnumAccess, VariantAccess, Visitor},
+};
 use serde_json::json;
-use strum::Display;
+use smol_str::SmolStr;
+use strum::{Display, EnumString, IntoStaticStr};
 use thiserror::Error;
 
+use crate::task::Stage;
+
 #[derive(Debug, Error)]
 pub enum AuthError {
     #[error("invalid key")]
@@ -32,29 +43,183 @@ impl From<TypedHeaderRejection> for AuthError {
     }
 }
 
+/// Form field or format names, listed in error messages.
+#[derive(Debug, Clone, Default)]
+pub struct Names(pub Vec<String>);
+
+impl std::fmt::Display for Names {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", self.0.join(", "))
+        }
+    }
+}
+
+/// The name a mistyped one likely meant, shown as ` (did you mean …?)`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Suggestion(pub Option<SmolStr>);
+
+impl std::fmt::Display for Suggestion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Some(name) => write!(f, " (did you mean {name}?)"),
+            None => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug, Display)]
 pub enum CreateTaskError {
     #[strum(to_string = "invalid request: {0}")]
     InvalidRequest(anyhow::Error),
-    #[strum(to_string = "missing field: {0}")]
-    MissingField(String),
-    #[strum(to_string = "unknown field: {0}")]
-    UnknownField(String),
+    #[strum(to_string = "missing field: {name}, received {received}")]
+    MissingField { name: String, received: Names },
+    #[strum(
+        to_string = "unknown field: {name}{suggestion}, expected {expected}, received {received}"
+    )]
+    UnknownField {
+        name: String,
+        /// The expected field the name is a typo of
+        suggestion: Suggestion,
+        expected: Names,
+        received: Names,
+    },
     #[strum(to_string = "invalid field: {0}")]
     InvalidField(String),
+    #[strum(to_string = "duplicate field: {0}")]
+    DuplicateField(String),
     #[strum(to_string = "unspecific content-type for {0}")]
     UnspecificContentType(String),
     #[strum(to_string = "unsupported file type: {0}")]
     UnsupportedFileType(String),
+    #[strum(to_string = "unsupported image format {detected}, supported: {supported}")]
+    UnsupportedImageFormat { detected: String, supported: Names },
+    #[strum(to_string = "unknown quantization {requested}{suggestion}, expected one of {offered}")]
+    UnknownQuantization {
+        requested: String,
+        /// The offered level the request is a typo of
+        suggestion: Suggestion,
+        offered: Names,
+    },
+    #[strum(to_string = "quantization {requested} is not offered, supported: {supported}")]
+    UnsupportedQuantization { requested: String, supported: Names },
+    #[strum(to_string = "upload larger than {limit} bytes")]
+    TooLarge { limit: usize },
+    /// Taking the task would exceed `--memory-limit`
+    #[strum(to_string = "server is near its memory limit of {limit} bytes, try again later")]
+    MemoryLimit { limit: usize },
+}
+
+impl CreateTaskError {
+    pub fn unknown_field(name: String, expected: &[&str], received: Names) -> Self {
+        Self::UnknownField {
+            suggestion: did_you_mean(&name, expected.iter().copied()),
+            name,
+            expected: Names(expected.iter().map(|name| name.to_string()).collect()),
+            received,
+        }
+    }
+
+    /// Lists only the `offered` levels, and suggests among them.
+    pub fn unknown_quantization(requested: String, offered: &[&str]) -> Self {
+        Self::UnknownQuantization {
+            suggestion: did_you_mean(&requested, offered.iter().copied()),
+            requested,
+            offered: Names(offered.iter().map(|level| level.to_string()).collect()),
+        }
+    }
+
+    /// The name [Self::UnknownField] or [Self::UnknownQuantization] likely meant.
+    fn suggestion(&self) -> Option<&str> {
+        match self {
+            CreateTaskError::UnknownField { suggestion, .. }
+            | CreateTaskError::UnknownQuantization { suggestion, .. } => suggestion.0.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// The candidate closest to `name`, ignoring case and separators, if it is a
+/// typo away.
+fn did_you_mean<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Suggestion {
+    let normalize = |name: &str| {
+        name.chars()
+            .filter(|c| !matches!(c, '_' | '-' | ' '))
+            .flat_map(char::to_lowercase)
+            .collect::<String>()
+    };
+    let name = normalize(name);
+    // one edit in short names, two in longer ones
+    let most = if name.chars().count() > 4 { 2 } else { 1 };
+    Suggestion(
+        candidates
+            .into_iter()
+            .map(|candidate| (edit_distance(&name, &normalize(candidate)), candidate))
+            .filter(|(distance, _)| *distance <= most)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| SmolStr::new(candidate)),
+    )
+}
+
+/// Levenshtein distance in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
 }
 
 impl IntoResponse for CreateTaskError {
     fn into_response(self) -> axum::response::Response {
-        let body = Json(json!({ "error": self.to_string()}));
-        let status = match self {
-            _ => StatusCode::BAD_REQUEST,
-        };
-        (status, body).into_response()
+        let mut body = json!({ "error": self.to_string()});
+        let mut status = StatusCode::BAD_REQUEST;
+        match &self {
+            CreateTaskError::MissingField { received, .. } => {
+                body["received"] = json!(received.0);
+            }
+            CreateTaskError::UnknownField {
+                expected, received, ..
+            } => {
+                body["expected"] = json!(expected.0);
+                body["received"] = json!(received.0);
+            }
+            CreateTaskError::UnknownQuantization { offered, .. } => {
+                body["supported"] = json!(offered.0);
+                status = StatusCode::UNPROCESSABLE_ENTITY;
+            }
+            CreateTaskError::UnsupportedQuantization { supported, .. } => {
+                body["supported"] = json!(supported.0);
+                status = StatusCode::UNPROCESSABLE_ENTITY;
+            }
+            CreateTaskError::UnsupportedImageFormat { supported, .. } => {
+                body["supported"] = json!(supported.0);
+                status = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+            }
+            CreateTaskError::TooLarge { limit } => {
+                body["limit"] = json!(limit);
+                status = StatusCode::PAYLOAD_TOO_LARGE;
+            }
+            CreateTaskError::MemoryLimit { .. } => {
+                status = StatusCode::SERVICE_UNAVAILABLE;
+            }
+            _ => {}
+        }
+        if let Some(suggestion) = self.suggestion() {
+            body["suggestion"] = json!(suggestion);
+        }
+        // uploads are rejected as soon as they turn out invalid, leaving the
+        // rest of the body unread, so the connection can't be reused
+        (status, [(header::CONNECTION, "close")], Json(body)).into_response()
     }
 }
 
@@ -73,16 +238,433 @@ pub enum RunTaskError {
     Prepare(#[from] ollama_rs::error::OllamaError),
     #[error("runner: {0}")]
     Runner(anyhow::Error),
+    #[error("{stage} stage on {model} failed: {}", with_causes(source))]
+    Stage {
+        stage: Stage,
+        model: SmolStr,
+        source: ollama_rs::error::OllamaError,
+    },
+    #[error("{stage} stage on {model} took longer than {} s", .timeout.as_secs_f32())]
+    StageTimeout {
+        stage: Stage,
+        model: SmolStr,
+        timeout: std::time::Duration,
+    },
     #[error("invalid image in request: {0}")]
     InvalidInputImage(#[from] ImageError),
+    #[error("task has {count} images, but at most {limit} are allowed")]
+    TooManyImages { count: usize, limit: usize },
+    #[error("model {0} has no chat template, configure one with --chat-template")]
+    MissingChatTemplate(SmolStr),
+    /// Only reached by tasks that skipped [crate::OllamaRunTask::check_quantization]
+    #[error("quantization {0} is not offered")]
+    QuantizationNotOffered(SmolStr),
     #[error("invalid LLM output for {0}")]
     InvalidOutput(String),
+    #[error("{0} stage got an empty response from the model")]
+    EmptyModelResponse(Stage),
+}
+
+/// Ollama's errors keep what went wrong to their source, e.g. "Reqwest error".
+fn with_causes(err: &(dyn std::error::Error + 'static)) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        message = format!("{message}: {cause}");
+        source = cause.source();
+    }
+    message
+}
+
+impl RunTaskError {
+    /// Pipeline stage the task failed in, if it got that far.
+    pub fn stage(&self) -> Option<Stage> {
+        match self {
+            RunTaskError::Stage { stage, .. }
+            | RunTaskError::StageTimeout { stage, .. }
+            | RunTaskError::EmptyModelResponse(stage) => Some(*stage),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> TaskErrorCode {
+        match self {
+            RunTaskError::Prepare(_) => TaskErrorCode::Prepare,
+            RunTaskError::Runner(_) => TaskErrorCode::Runner,
+            RunTaskError::Stage { .. } => TaskErrorCode::Stage,
+            RunTaskError::StageTimeout { .. } => TaskErrorCode::StageTimeout,
+            RunTaskError::InvalidInputImage(_) => TaskErrorCode::InvalidInputImage,
+            RunTaskError::TooManyImages { .. } => TaskErrorCode::TooManyImages,
+            RunTaskError::MissingChatTemplate(_) => TaskErrorCode::MissingChatTemplate,
+            RunTaskError::QuantizationNotOffered(_) => TaskErrorCode::QuantizationNotOffered,
+            RunTaskError::InvalidOutput(_) => TaskErrorCode::InvalidOutput,
+            RunTaskError::EmptyModelResponse(_) => TaskErrorCode::EmptyModelResponse,
+        }
+    }
+
+    /// Whether running the same task again may succeed. Models and Ollama
+    /// fail now and then, while the images and configuration won't change.
+    pub fn retryable(&self) -> bool {
+        match self {
+            RunTaskError::Prepare(_)
+            | RunTaskError::Runner(_)
+            | RunTaskError::Stage { .. }
+            | RunTaskError::StageTimeout { .. }
+            | RunTaskError::InvalidOutput(_)
+            | RunTaskError::EmptyModelResponse(_) => true,
+            RunTaskError::InvalidInputImage(_)
+            | RunTaskError::TooManyImages { .. }
+            | RunTaskError::MissingChatTemplate(_)
+            | RunTaskError::QuantizationNotOffered(_) => false,
+        }
+    }
+}
+
+/// Variant of the [RunTaskError] a task failed with. Serializes as its name,
+/// or its position in [TASK_ERROR_CODES] in the swap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum TaskErrorCode {
+    Prepare,
+    Runner,
+    Stage,
+    StageTimeout,
+    InvalidInputImage,
+    TooManyImages,
+    MissingChatTemplate,
+    QuantizationNotOffered,
+    InvalidOutput,
+    /// Sent by a newer server, or only known by its message
+    Unknown,
+    /// Dropped from the pending queue before it ran, see `POST /admin/clear_pending`
+    Cancelled,
+    EmptyModelResponse,
+}
+
+/// Codes by their position in the swap and backups, which new codes are only
+/// appended to so older records read back the same.
+const TASK_ERROR_CODES: [TaskErrorCode; 12] = [
+    TaskErrorCode::Prepare,
+    TaskErrorCode::Runner,
+    TaskErrorCode::Stage,
+    TaskErrorCode::StageTimeout,
+    TaskErrorCode::InvalidInputImage,
+    TaskErrorCode::TooManyImages,
+    TaskErrorCode::MissingChatTemplate,
+    TaskErrorCode::QuantizationNotOffered,
+    TaskErrorCode::InvalidOutput,
+    TaskErrorCode::Unknown,
+    TaskErrorCode::Cancelled,
+    TaskErrorCode::EmptyModelResponse,
+];
+
+impl Serialize for TaskErrorCode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let index = TASK_ERROR_CODES
+            .iter()
+            .position(|code| code == self)
+            .unwrap_or_default();
+        serializer.serialize_unit_variant("TaskErrorCode", index as u32, self.into())
+    }
+}
+
+/// Reads names and positions alike, codes it doesn't know as [TaskErrorCode::Unknown].
+struct TaskErrorCodeVisitor;
+
+impl<'de> Visitor<'de> for TaskErrorCodeVisitor {
+    type Value = TaskErrorCode;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a task error code")
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, index: u64) -> Result<TaskErrorCode, E> {
+        Ok(usize::try_from(index)
+            .ok()
+            .and_then(|index| TASK_ERROR_CODES.get(index).copied())
+            .unwrap_or(TaskErrorCode::Unknown))
+    }
+
+    fn visit_str<E: serde::de::Error>(self, name: &str) -> Result<TaskErrorCode, E> {
+        Ok(name.parse().unwrap_or(TaskErrorCode::Unknown))
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<TaskErrorCode, A::Error> {
+        struct Identifier(TaskErrorCode);
+
+        impl<'de> Deserialize<'de> for Identifier {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer
+                    .deserialize_identifier(TaskErrorCodeVisitor)
+                    .map(Identifier)
+            }
+        }
+
+        let (Identifier(code), variant) = data.variant()?;
+        variant.unit_variant()?;
+        Ok(code)
+    }
+}
+
+impl<'de> Deserialize<'de> for TaskErrorCode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        const NAMES: [&str; TASK_ERROR_CODES.len()] = [
+            "prepare",
+            "runner",
+            "stage",
+            "stage_timeout",
+            "invalid_input_image",
+            "too_many_images",
+            "missing_chat_template",
+            "quantization_not_offered",
+            "invalid_output",
+            "unknown",
+            "cancelled",
+            "empty_model_response",
+        ];
+        deserializer.deserialize_enum("TaskErrorCode", &NAMES, TaskErrorCodeVisitor)
+    }
+}
+
+/// What a failed task keeps of its [RunTaskError], whose sources can't be
+/// serialized, so it reads the same from the swap or the API.
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize, Deserialize)]
+#[error("{message}")]
+pub struct TaskError {
+    pub code: TaskErrorCode,
+    pub message: String,
+    pub retryable: bool,
+    /// Pipeline stage that failed, if any
+    pub stage: Option<Stage>,
+}
+
+impl TaskError {
+    /// Of a task cancelled while pending, which may run again when retried.
+    pub fn cancelled() -> Self {
+        Self {
+            code: TaskErrorCode::Cancelled,
+            message: "cancelled before it ran".into(),
+            retryable: true,
+            stage: None,
+        }
+    }
+
+    /// An error only known by its message, as `/v1` tasks report them.
+    pub fn from_message(message: impl Into<String>) -> Self {
+        Self {
+            code: TaskErrorCode::Unknown,
+            message: message.into(),
+            retryable: false,
+            stage: None,
+        }
+    }
+}
+
+impl From<&RunTaskError> for TaskError {
+    fn from(err: &RunTaskError) -> Self {
+        Self {
+            code: err.code(),
+            message: err.to_string(),
+            retryable: err.retryable(),
+            stage: err.stage(),
+        }
+    }
+}
+
+impl From<RunTaskError> for TaskError {
+    fn from(err: RunTaskError) -> Self {
+        Self::from(&err)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StartupError {
+    #[error("offline mode requires these models in Ollama, pull them first: {}", .0.join(", "))]
+    MissingModels(Vec<SmolStr>),
+    #[error("failed to reach Ollama: {0}")]
+    Ollama(#[from] ollama_rs::error::OllamaError),
+    #[error("failed to listen: {0}")]
+    Listen(#[from] std::io::Error),
+    #[error("invalid OLLAMA_ENDPOINT {url:?}: {reason}")]
+    InvalidOllamaEndpoint { url: String, reason: String },
+    #[error("failed to create the swap file: {0}")]
+    Swap(#[source] std::io::Error),
+    #[error("cannot swap to {}: {source}", .dir.display())]
+    SwapDir {
+        dir: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[error("--min-amount {min} is larger than --max-amount {max}")]
+    AmountBounds { min: f32, max: f32 },
+    #[error("cannot use the runtime config {}: {reason}", .path.display())]
+    RuntimeConfig {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+    #[error("the runtime config {} is in use by ledoxide {version} with pid {pid} on {host}, pass --force to start anyway", .path.display())]
+    ConfigLocked {
+        path: std::path::PathBuf,
+        pid: u32,
+        version: String,
+        host: String,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum ConnectionError {
+    #[error("too many connections, try again later")]
+    TooMany,
+    #[error("request body sent nothing for {0:?}")]
+    BodyTimeout(std::time::Duration),
+}
+
+impl IntoResponse for ConnectionError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            ConnectionError::TooMany => StatusCode::SERVICE_UNAVAILABLE,
+            ConnectionError::BodyTimeout(_) => StatusCode::REQUEST_TIMEOUT,
+        };
+        (
+            status,
+            Json(json!({
+                "error": self.to_string(),
+            })),
+        )
+            .into_response()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CategoryError {
+    #[error("category #{index} would change from {expected:?} to {found:?}")]
+    PrefixMismatch {
+        index: usize,
+        expected: String,
+        found: Option<String>,
+    },
+    #[error("category names must not be blank")]
+    BlankName,
+    /// Each name takes a line of the category prompt
+    #[error("category {0:?} contains a line break or control character")]
+    ControlCharacter(String),
+}
+
+/// A `fields` mapping of the bill that can't be applied.
+#[derive(Debug, Error)]
+pub enum MappingError {
+    #[error("the mapping keeps no field of the bill")]
+    Empty,
+    #[error("{0:?} is not a field of the bill, known: {known}", known = crate::mapping::BILL_FIELDS.join(", "))]
+    UnknownField(String),
+    #[error("field {0:?} is renamed to a blank name")]
+    BlankName(String),
+    #[error("field {0:?} is mapped more than once")]
+    RepeatedField(String),
+    #[error("name {0:?} is given to more than one field")]
+    RepeatedName(String),
+}
+
+impl IntoResponse for MappingError {
+    fn into_response(self) -> axum::response::Response {
+        let body = Json(json!({
+            "error": self.to_string(),
+        }));
+        (StatusCode::BAD_REQUEST, body).into_response()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SyncTaskError {
+    #[error("task {0} did not finish in time")]
+    Timeout(String),
+    #[error("{0}")]
+    Failed(TaskError),
+    #[error("{0}")]
+    Rejected(CreateTaskError),
+    #[error("{0}")]
+    Mapping(#[from] MappingError),
+}
+
+impl IntoResponse for SyncTaskError {
+    fn into_response(self) -> axum::response::Response {
+        let (status, body) = match self {
+            SyncTaskError::Rejected(err) => return err.into_response(),
+            SyncTaskError::Mapping(err) => return err.into_response(),
+            SyncTaskError::Timeout(ref id) => (
+                StatusCode::GATEWAY_TIMEOUT,
+                json!({
+                    "error": self.to_string(),
+                    "id": id,
+                }),
+            ),
+            SyncTaskError::Failed(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({
+                    "error": self.to_string(),
+                }),
+            ),
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DescribeError {
+    #[error("{0}")]
+    Rejected(CreateTaskError),
+    #[error("too many descriptions, try again in {} seconds", .0.as_secs())]
+    RateLimited(std::time::Duration),
+    #[error("no description within {} seconds", .0.as_secs())]
+    Timeout(std::time::Duration),
+    #[error("{0}")]
+    Failed(TaskError),
+}
+
+impl IntoResponse for DescribeError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            DescribeError::Rejected(err) => return err.into_response(),
+            DescribeError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            DescribeError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            DescribeError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = Json(json!({
+            "error": self.to_string(),
+        }));
+        let mut response = (status, body).into_response();
+        if let DescribeError::RateLimited(retry_after) = self {
+            response.headers_mut().insert(
+                axum::http::header::RETRY_AFTER,
+                retry_after_secs(retry_after).into(),
+            );
+        }
+        response
+    }
+}
+
+/// Whole seconds of a `Retry-After`, rounded up so the retry isn't turned away again.
+fn retry_after_secs(retry_after: std::time::Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
 }
 
 #[derive(Debug, Error)]
 pub enum GetTaskError {
     #[error("task not found")]
     NotFound,
+    #[error("task is not running")]
+    NotRunning,
+    #[error("image not retained")]
+    ImageNotRetained,
+    #[error("prompts not retained")]
+    PromptsNotRetained,
+    /// The `Accept` header refuses every representation of tasks
+    #[error("none of the accepted types is offered, supported: {0}")]
+    NotAcceptable(Names),
+    #[error("{0}")]
+    Mapping(#[from] MappingError),
+    /// The listing's query doesn't make a filter
+    #[error("{0}")]
+    Filter(#[from] ExportError),
     #[error("{0}")]
     Internal(#[from] anyhow::Error),
 }
@@ -90,12 +672,289 @@ pub enum GetTaskError {
 impl IntoResponse for GetTaskError {
     fn into_response(self) -> axum::response::Response {
         let status = match self {
-            GetTaskError::NotFound => StatusCode::NOT_FOUND,
+            GetTaskError::NotFound
+            | GetTaskError::ImageNotRetained
+            | GetTaskError::PromptsNotRetained => StatusCode::NOT_FOUND,
+            GetTaskError::NotRunning => StatusCode::CONFLICT,
+            GetTaskError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            GetTaskError::Mapping(err) => return err.into_response(),
+            GetTaskError::Filter(err) => return err.into_response(),
             GetTaskError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
-        let body = Json(json!({
+        let mut body = json!({
                     "error": self.to_string(),
+        });
+        if let GetTaskError::NotAcceptable(supported) = &self {
+            body["supported"] = json!(supported.0);
+        }
+        (status, Json(body)).into_response()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum UpdateTaskError {
+    #[error("task not found")]
+    NotFound,
+    #[error("task has not finished yet")]
+    NotFinished,
+    #[error("task failed without a bill")]
+    NoBill,
+    #[error("invalid tag {0:?}, tags must be 1 to 64 characters")]
+    InvalidTag(String),
+    #[error("{0}")]
+    Internal(#[from] anyhow::Error),
+}
+
+impl IntoResponse for UpdateTaskError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            UpdateTaskError::NotFound => StatusCode::NOT_FOUND,
+            UpdateTaskError::NotFinished | UpdateTaskError::NoBill => StatusCode::CONFLICT,
+            UpdateTaskError::InvalidTag(_) => StatusCode::BAD_REQUEST,
+            UpdateTaskError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = Json(json!({
+            "error": self.to_string(),
+        }));
+        (status, body).into_response()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RetryTaskError {
+    #[error("task not found")]
+    NotFound,
+    #[error("task has not finished yet")]
+    NotFinished,
+    #[error("task finished successfully, there is nothing to retry")]
+    Succeeded,
+    #[error("the images of this task are no longer retained, upload them again")]
+    NotRetained,
+    #[error("{0}")]
+    Internal(#[from] anyhow::Error),
+}
+
+impl IntoResponse for RetryTaskError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            RetryTaskError::NotFound => StatusCode::NOT_FOUND,
+            RetryTaskError::NotFinished | RetryTaskError::Succeeded => StatusCode::CONFLICT,
+            RetryTaskError::NotRetained => StatusCode::GONE,
+            RetryTaskError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = Json(json!({
+            "error": self.to_string(),
+        }));
+        (status, body).into_response()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AskError {
+    #[error("task not found")]
+    NotFound,
+    #[error("task has not finished yet")]
+    NotFinished,
+    #[error("task failed, there is no bill to ask about")]
+    Failed,
+    #[error("invalid question: {0}")]
+    InvalidQuestion(&'static str),
+    #[error("too many questions, try again in {} seconds", .0.as_secs())]
+    RateLimited(std::time::Duration),
+    #[error("no answer within {} seconds", .0.as_secs())]
+    Timeout(std::time::Duration),
+    #[error("failed to answer: {0}")]
+    Ollama(#[from] ollama_rs::error::OllamaError),
+    #[error("{0}")]
+    Internal(#[from] anyhow::Error),
+}
+
+impl IntoResponse for AskError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            AskError::NotFound => StatusCode::NOT_FOUND,
+            AskError::NotFinished | AskError::Failed => StatusCode::CONFLICT,
+            AskError::InvalidQuestion(_) => StatusCode::BAD_REQUEST,
+            AskError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AskError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AskError::Ollama(_) => StatusCode::BAD_GATEWAY,
+            AskError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = Json(json!({
+            "error": self.to_string(),
+        }));
+        let mut response = (status, body).into_response();
+        if let AskError::RateLimited(retry_after) = self {
+            response.headers_mut().insert(
+                axum::http::header::RETRY_AFTER,
+                retry_after_secs(retry_after).into(),
+            );
+        }
+        response
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PinModelError {
+    #[error("model {0} is not configured")]
+    NotConfigured(String),
+    #[error("failed to load the model: {0}")]
+    Ollama(#[from] ollama_rs::error::OllamaError),
+}
+
+impl IntoResponse for PinModelError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            PinModelError::NotConfigured(_) => StatusCode::NOT_FOUND,
+            PinModelError::Ollama(_) => StatusCode::BAD_GATEWAY,
+        };
+        let body = Json(json!({
+            "error": self.to_string(),
+        }));
+        (status, body).into_response()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BackfillError {
+    #[error("a backfill is already running")]
+    AlreadyRunning,
+    #[error("failed to read the swapped tasks: {0}")]
+    Swap(anyhow::Error),
+}
+
+impl IntoResponse for BackfillError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            BackfillError::AlreadyRunning => StatusCode::CONFLICT,
+            BackfillError::Swap(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = Json(json!({
+            "error": self.to_string(),
         }));
         (status, body).into_response()
     }
 }
+
+#[derive(Debug, Error)]
+pub enum RestoreError {
+    #[error("not a ledoxide backup")]
+    NotABackup,
+    #[error(
+        "backup format version {found} is not supported, this server reads version {supported}"
+    )]
+    IncompatibleVersion { found: u16, supported: u16 },
+    #[error("the server already holds tasks, restore onto an empty one")]
+    NotEmpty,
+    #[error("malformed backup: {0}")]
+    Malformed(String),
+    #[error("failed to restore: {0}")]
+    Internal(anyhow::Error),
+}
+
+impl IntoResponse for RestoreError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            RestoreError::NotABackup | RestoreError::Malformed(_) => StatusCode::BAD_REQUEST,
+            RestoreError::IncompatibleVersion { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            RestoreError::NotEmpty => StatusCode::CONFLICT,
+            RestoreError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = Json(json!({
+            "error": self.to_string(),
+        }));
+        (status, body).into_response()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("invalid {setting}: {reason}")]
+    Invalid {
+        setting: &'static str,
+        reason: String,
+    },
+    #[error("failed to save the runtime config: {0}")]
+    Persist(std::io::Error),
+}
+
+impl IntoResponse for ConfigError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            ConfigError::Invalid { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            ConfigError::Persist(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = Json(json!({
+            "error": self.to_string(),
+        }));
+        (status, body).into_response()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DeadlineError {
+    #[error("no response within {} ms", .0.as_millis())]
+    Exceeded(std::time::Duration),
+    #[error("invalid X-Request-Deadline-Ms header, expected milliseconds")]
+    InvalidHeader,
+}
+
+impl IntoResponse for DeadlineError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            DeadlineError::Exceeded(_) => StatusCode::GATEWAY_TIMEOUT,
+            DeadlineError::InvalidHeader => StatusCode::BAD_REQUEST,
+        };
+        let body = Json(json!({
+            "error": self.to_string(),
+        }));
+        (status, body).into_response()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("invalid date {0:?}, expected YYYY-MM-DD or RFC 3339")]
+    InvalidDate(String),
+    #[error("invalid timezone {0:?}, expected an offset like +08:00 or Z")]
+    InvalidTimezone(String),
+    #[error("invalid amount {0:?}")]
+    InvalidAmount(String),
+    #[error("since_id {0:?} is neither a cursor nor a finished task")]
+    UnknownCursor(String),
+    #[error("invalid category_reason {0:?}, expected true or false")]
+    InvalidReasonFlag(String),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl IntoResponse for ExportError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            ExportError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        let body = Json(json!({
+            "error": self.to_string(),
+        }));
+        (status, body).into_response()
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid server URL: {0}")]
+    InvalidUrl(String),
+    #[error("server answered {status}: {message}")]
+    Api {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+    #[error("unexpected response: {0}")]
+    Decode(serde_json::Error),
+    #[error("task {0} did not finish in time")]
+    Timeout(String),
+}
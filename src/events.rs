@@ -0,0 +1,132 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+use smol_str::SmolStr;
+use strum::IntoStaticStr;
+use tokio::{
+    pin,
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{Level, event};
+
+use crate::task::{Stage, TaskControlBlock, Token, TokenKind};
+
+/// Events held for subscribers falling behind, older ones are dropped.
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// What the scheduler and its runner went through, for whoever watches.
+#[derive(Debug, Clone, PartialEq, Serialize, IntoStaticStr)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SchedulerEvent {
+    TaskCreated {
+        id: String,
+    },
+    /// A stage of a running task began
+    TaskStarted {
+        id: String,
+        stage: Stage,
+    },
+    TaskFinished {
+        id: String,
+        success: bool,
+    },
+    /// Finished tasks were written to the swap file
+    TaskSwapped {
+        count: usize,
+    },
+    /// Ollama had to load a model before generating
+    ModelLoaded {
+        model: SmolStr,
+        seconds: f64,
+    },
+    /// Ollama unloaded a model loaded before, noticed when it is loaded again
+    ModelEvicted {
+        model: SmolStr,
+    },
+}
+
+impl Display for SchedulerEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchedulerEvent::TaskCreated { id } => write!(f, "created task {id}"),
+            SchedulerEvent::TaskStarted { id, stage } => write!(f, "task {id} entered {stage}"),
+            SchedulerEvent::TaskFinished { id, success } => write!(
+                f,
+                "task {id} {}",
+                if *success { "succeeded" } else { "failed" }
+            ),
+            SchedulerEvent::TaskSwapped { count } => write!(f, "swapped {count} finished tasks"),
+            SchedulerEvent::ModelLoaded { model, seconds } => {
+                write!(f, "loading {model} took {seconds:.3}s")
+            }
+            SchedulerEvent::ModelEvicted { model } => {
+                write!(f, "{model} was unloaded since its last use")
+            }
+        }
+    }
+}
+
+/// Broadcasts [SchedulerEvent]s. Subscribers that fall behind by more than
+/// [EVENT_CHANNEL_CAPACITY] events miss the oldest ones, publishing never waits.
+#[derive(Debug, Clone)]
+pub struct EventBus(broadcast::Sender<SchedulerEvent>);
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self(broadcast::Sender::new(EVENT_CHANNEL_CAPACITY))
+    }
+}
+
+impl EventBus {
+    /// Logs `event` and hands it to the current subscribers, if any.
+    pub fn publish(&self, event: SchedulerEvent) {
+        event!(target: "scheduler", Level::DEBUG, "{event}");
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SchedulerEvent> {
+        self.0.subscribe()
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        self.0.receiver_count() > 0
+    }
+
+    /// Runs `job`, publishing [SchedulerEvent::TaskStarted] for every stage
+    /// `task` begins as its runner announces them to `tokens`.
+    pub(crate) async fn watch_stages<T>(
+        &self,
+        task: &TaskControlBlock,
+        mut tokens: broadcast::Receiver<Token>,
+        job: impl Future<Output = T>,
+    ) -> T {
+        pin!(job);
+        loop {
+            tokio::select! {
+                // the task holds the sender, so this never closes
+                token = tokens.recv() => self.forward_stage(task, token),
+                output = &mut job => {
+                    while let Ok(token) = tokens.try_recv() {
+                        self.forward_stage(task, Ok(token));
+                    }
+                    return output;
+                }
+            }
+        }
+    }
+
+    fn forward_stage(&self, task: &TaskControlBlock, token: Result<Token, RecvError>) {
+        if let Ok(Token {
+            stage,
+            kind: TokenKind::Started,
+            ..
+        }) = token
+        {
+            self.publish(SchedulerEvent::TaskStarted {
+                id: task.id().to_string(),
+                stage,
+            });
+        }
+    }
+}
@@ -0,0 +1,683 @@
+use std::{io::Write, str::FromStr, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, header},
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, FixedOffset, NaiveDate, Offset, SecondsFormat, Utc};
+use flate2::{Compression, write::GzEncoder};
+use futures::{Stream, TryStreamExt};
+use serde::Serialize;
+use tokio::pin;
+use tracing::{Level, event};
+
+use crate::{
+    bill::Bill,
+    error::ExportError,
+    mapping::{BillMapping, MappedBill, REASON_FIELD},
+    schedule::Scheduler,
+    task::{self, RunTask, TaskControlBlock},
+};
+
+/// Query parameters shared by the exports. Dates are RFC 3339 timestamps or plain
+/// `YYYY-MM-DD` days in the `tz` offset, UTC by default, both ends inclusive.
+/// `category` may be repeated.
+#[derive(Debug, Default)]
+pub struct TaskQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub tz: Option<String>,
+    pub category: Vec<String>,
+    pub min_amount: Option<String>,
+    pub max_amount: Option<String>,
+    /// A [Cursor], or the id of a task standing for its own, exporting only
+    /// the bills that finished after it, see [TaskFilter::after]
+    pub since_id: Option<String>,
+    /// `false` leaves the `category_reason` of the bills out
+    pub category_reason: Option<String>,
+}
+
+impl TaskQuery {
+    /// How the bills are exported, with or without `category_reason`.
+    pub fn mapping(&self) -> Result<BillMapping, ExportError> {
+        let with_reason = match self.category_reason.as_deref() {
+            Some(flag) => flag
+                .parse::<bool>()
+                .map_err(|_| ExportError::InvalidReasonFlag(flag.to_string()))?,
+            None => true,
+        };
+        Ok(BillMapping::canonical(with_reason))
+    }
+
+    /// Reads the query string of a request, ignoring parameters it doesn't know.
+    pub fn parse(query: &str) -> Self {
+        let mut parsed = Self::default();
+        for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+            let value = value.into_owned();
+            match name.as_ref() {
+                "from" => parsed.from = Some(value),
+                "to" => parsed.to = Some(value),
+                "tz" => parsed.tz = Some(value),
+                "category" => parsed.category.push(value),
+                "min_amount" => parsed.min_amount = Some(value),
+                "max_amount" => parsed.max_amount = Some(value),
+                "since_id" => parsed.since_id = Some(value),
+                "category_reason" => parsed.category_reason = Some(value),
+                _ => {}
+            }
+        }
+        parsed
+    }
+}
+
+/// Selects finished bills by when they finished, their categories and amount.
+/// Bills carry no date of their own, so the finish time stands in for it.
+#[derive(Debug, Default, PartialEq)]
+pub struct TaskFilter {
+    from: Option<DateTime<Utc>>,
+    /// Exclusive
+    until: Option<DateTime<Utc>>,
+    /// Bills in any of them, every bill if empty
+    categories: Vec<String>,
+    min_amount: Option<f32>,
+    max_amount: Option<f32>,
+    /// Bills finishing later passing
+    after: Option<Cursor>,
+}
+
+/// Header of the exports telling the `since_id` the next export picks up at.
+pub const CURSOR_HEADER: &str = "x-export-cursor";
+
+/// A place in the order bills finish in, by finish time and then by id. Sent
+/// opaque, so clients can keep it without any task having to stay around.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+    finished_at: DateTime<Utc>,
+    id: String,
+}
+
+impl Cursor {
+    /// Where `tcb` finished, none if it hasn't.
+    pub fn of(tcb: &TaskControlBlock) -> Option<Self> {
+        Some(Self {
+            finished_at: tcb.finished_at()?,
+            id: tcb.id().to_string(),
+        })
+    }
+
+    /// Before every bill finishing at `at` or later.
+    pub fn at(at: DateTime<Utc>) -> Self {
+        Self {
+            finished_at: at,
+            id: String::new(),
+        }
+    }
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let plain = format!(
+            "{}/{}",
+            self.finished_at.to_rfc3339_opts(SecondsFormat::Nanos, true),
+            self.id
+        );
+        f.write_str(&BASE64_URL_SAFE_NO_PAD.encode(plain))
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let plain = BASE64_URL_SAFE_NO_PAD.decode(value).map_err(|_| ())?;
+        let plain = String::from_utf8(plain).map_err(|_| ())?;
+        let (finished_at, id) = plain.split_once('/').ok_or(())?;
+        Ok(Self {
+            finished_at: DateTime::parse_from_rfc3339(finished_at)
+                .map_err(|_| ())?
+                .to_utc(),
+            id: id.to_string(),
+        })
+    }
+}
+
+impl TryFrom<TaskQuery> for TaskFilter {
+    type Error = ExportError;
+
+    fn try_from(query: TaskQuery) -> Result<Self, Self::Error> {
+        let tz = match &query.tz {
+            Some(tz) => parse_offset(tz)?,
+            None => Utc.fix(),
+        };
+        let amount = |value: Option<String>| {
+            value
+                .map(|value| match value.parse::<f32>() {
+                    Ok(amount) if amount.is_finite() => Ok(amount),
+                    _ => Err(ExportError::InvalidAmount(value)),
+                })
+                .transpose()
+        };
+        Ok(Self {
+            from: query
+                .from
+                .map(|from| parse_bound(&from, false, tz))
+                .transpose()?,
+            until: query.to.map(|to| parse_bound(&to, true, tz)).transpose()?,
+            categories: query.category,
+            min_amount: amount(query.min_amount)?,
+            max_amount: amount(query.max_amount)?,
+            after: None,
+        })
+    }
+}
+
+/// `Z`, `UTC` or an offset like `+08:00`.
+fn parse_offset(value: &str) -> Result<FixedOffset, ExportError> {
+    if value.eq_ignore_ascii_case("z") || value.eq_ignore_ascii_case("utc") {
+        return Ok(Utc.fix());
+    }
+    value
+        .parse::<FixedOffset>()
+        .map_err(|_| ExportError::InvalidTimezone(value.to_string()))
+}
+
+fn parse_bound(value: &str, end: bool, tz: FixedOffset) -> Result<DateTime<Utc>, ExportError> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        let time = time.to_utc();
+        return Ok(if end {
+            time + chrono::TimeDelta::nanoseconds(1)
+        } else {
+            time
+        });
+    }
+    let day = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| ExportError::InvalidDate(value.to_string()))?;
+    let day = if end {
+        day.succ_opt().unwrap_or(day)
+    } else {
+        day
+    };
+    // fixed offsets map every local time to exactly one instant
+    Ok((day.and_time(Default::default()) - tz).and_utc())
+}
+
+impl TaskFilter {
+    /// Only passes bills finishing after `cursor`, that of `since_id`, so an
+    /// export can pick up where the last one ended.
+    pub fn after(mut self, cursor: Cursor) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    /// Only passes bills that finished before `at`, which [Cursor::at] picks
+    /// up after, so bills finishing while an export streams go to the next one.
+    pub fn before(mut self, at: DateTime<Utc>) -> Self {
+        self.until = Some(self.until.map_or(at, |until| until.min(at)));
+        self
+    }
+
+    /// Whether `tcb` passes, for listings of tasks in any state. Tasks without a
+    /// bill only pass a filter that asks for nothing.
+    pub fn passes(&self, tcb: &TaskControlBlock) -> bool {
+        if *self == Self::default() {
+            return true;
+        }
+        let task::State::Finished(Ok(success)) = tcb.state() else {
+            return false;
+        };
+        tcb.finished_at().is_some_and(|finished_at| {
+            self.matches(finished_at, &success.0) && self.follows_cursor(finished_at, tcb.id())
+        })
+    }
+
+    fn follows_cursor(&self, finished_at: DateTime<Utc>, id: &str) -> bool {
+        self.after
+            .as_ref()
+            .is_none_or(|after| (finished_at, id) > (after.finished_at, after.id.as_str()))
+    }
+
+    fn matches(&self, finished_at: DateTime<Utc>, bill: &Bill) -> bool {
+        self.from.is_none_or(|from| finished_at >= from)
+            && self.until.is_none_or(|until| finished_at < until)
+            && (self.categories.is_empty()
+                || bill
+                    .categories
+                    .iter()
+                    .any(|category| self.categories.iter().any(|c| c == category)))
+            && self.min_amount.is_none_or(|min| bill.amount >= min)
+            && self.max_amount.is_none_or(|max| bill.amount <= max)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ExportLine<'a> {
+    id: &'a str,
+    created_at: DateTime<Utc>,
+    finished_at: DateTime<Utc>,
+    #[serde(flatten)]
+    bill: MappedBill<'a>,
+}
+
+/// Layouts bills are exported in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// One [ExportLine] object per line
+    Jsonl,
+    /// A header row, then one row per bill with its categories joined by `;`
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    fn header(self, mapping: &BillMapping) -> Option<Bytes> {
+        match self {
+            ExportFormat::Jsonl => None,
+            ExportFormat::Csv => Some(Bytes::from_static(csv_header(mapping).as_bytes())),
+        }
+    }
+
+    fn line(self, line: &ExportLine) -> Bytes {
+        match self {
+            ExportFormat::Jsonl => {
+                let mut json = serde_json::to_vec(line).expect("bills serialize to JSON");
+                json.push(b'\n');
+                json.into()
+            }
+            ExportFormat::Csv => {
+                let bill = line.bill.bill();
+                let mut fields = vec![
+                    line.id.to_string(),
+                    line.created_at.to_rfc3339(),
+                    line.finished_at.to_rfc3339(),
+                    bill.notes.to_string(),
+                    bill.amount.to_string(),
+                    bill.currency.as_deref().unwrap_or_default().to_string(),
+                    bill.formatted_amount().unwrap_or_default(),
+                    bill.category().map(ToString::to_string).unwrap_or_default(),
+                    bill.categories.join(";"),
+                    bill.needs_review.to_string(),
+                ];
+                if line.bill.mapping().keeps(REASON_FIELD) {
+                    fields.push(bill.category_reason.clone().unwrap_or_default());
+                }
+                let mut row = fields
+                    .iter()
+                    .map(|field| csv_field(field))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                row.push_str("\r\n");
+                row.into()
+            }
+        }
+    }
+}
+
+const CSV_HEADER: &str = "id,created_at,finished_at,notes,amount,currency,formatted_amount,category,categories,needs_review,category_reason\r\n";
+/// [CSV_HEADER] without the `category_reason` column, last so the others keep their place.
+const CSV_HEADER_WITHOUT_REASON: &str = "id,created_at,finished_at,notes,amount,currency,formatted_amount,category,categories,needs_review\r\n";
+
+fn csv_header(mapping: &BillMapping) -> &'static str {
+    if mapping.keeps(REASON_FIELD) {
+        CSV_HEADER
+    } else {
+        CSV_HEADER_WITHOUT_REASON
+    }
+}
+
+/// `field` quoted if it holds a separator, quote or line break, as RFC 4180 has it.
+/// Text starting like a formula gets a `'` in front, so spreadsheets opening
+/// the export show it instead of evaluating it. Numbers, like negative amounts,
+/// are left as they are.
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    let formula = field.starts_with(['=', '+', '-', '@']) && field.parse::<f64>().is_err();
+    let field: std::borrow::Cow<'_, str> = if formula {
+        format!("'{field}").into()
+    } else {
+        field.into()
+    };
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field
+    }
+}
+
+/// The CSV export of `tasks`, a header and a row for each successfully
+/// finished one.
+pub(crate) fn csv(tasks: &[TaskControlBlock]) -> Vec<u8> {
+    let filter = TaskFilter::default();
+    let mapping = BillMapping::canonical(true);
+    let mut csv = CSV_HEADER.as_bytes().to_vec();
+    for task in tasks {
+        if let Some(row) = exported(task, &filter, &mapping, ExportFormat::Csv) {
+            csv.extend_from_slice(&row);
+        }
+    }
+    csv
+}
+
+/// Line of a successfully finished task matching `filter`.
+fn exported(
+    tcb: &TaskControlBlock,
+    filter: &TaskFilter,
+    mapping: &BillMapping,
+    format: ExportFormat,
+) -> Option<Bytes> {
+    let task::State::Finished(Ok(success)) = tcb.state() else {
+        return None;
+    };
+    let finished_at = tcb.finished_at()?;
+    if !filter.matches(finished_at, &success.0) || !filter.follows_cursor(finished_at, tcb.id()) {
+        return None;
+    }
+    Some(format.line(&ExportLine {
+        id: tcb.id(),
+        created_at: tcb.created_at(),
+        finished_at,
+        bill: mapping.apply(&success.0),
+    }))
+}
+
+/// Streams the finished bills matching `filter` in `format`, reading the swap
+/// chunk by chunk.
+pub fn export<Runner>(
+    scheduler: Arc<Scheduler<Runner>>,
+    filter: TaskFilter,
+    mapping: BillMapping,
+    format: ExportFormat,
+) -> impl Stream<Item = anyhow::Result<Bytes>>
+where
+    Runner: RunTask + Send + Sync + Clone + 'static,
+    Runner::TaskDescriptor: Send + Sync + 'static,
+{
+    async_stream::try_stream! {
+        if let Some(header) = format.header(&mapping) {
+            yield header;
+        }
+        let tasks = scheduler.tasks();
+        pin!(tasks);
+        while let Some(tcb) = tasks
+            .try_next()
+            .await
+            .inspect_err(|err| event!(Level::ERROR, "export interrupted: {err}"))?
+        {
+            if let Some(line) = exported(&tcb, &filter, &mapping, format) {
+                yield line;
+            }
+        }
+    }
+}
+
+/// Bytes of compressed output collected before they are sent on.
+const GZIP_CHUNK: usize = 16 * 1024;
+
+/// Whether the `Accept-Encoding` header takes gzip, by name or through `*`,
+/// with a nonzero quality.
+pub fn accepts_gzip(headers: &HeaderMap) -> bool {
+    let mut gzip = None;
+    let mut any = None;
+    for coding in headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+    {
+        let mut params = coding.split(';');
+        let name = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let accepted = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .next()
+            .is_none_or(|q| q.trim().parse::<f32>().is_ok_and(|q| q > 0.0));
+        match name.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(accepted),
+            "*" => any = Some(accepted),
+            _ => {}
+        }
+    }
+    gzip.or(any).unwrap_or(false)
+}
+
+/// Compresses `export` with gzip as it streams, sending the output on in
+/// chunks of about [GZIP_CHUNK] bytes.
+pub fn gzip(
+    export: impl Stream<Item = anyhow::Result<Bytes>>,
+) -> impl Stream<Item = anyhow::Result<Bytes>> {
+    async_stream::try_stream! {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        pin!(export);
+        while let Some(bytes) = export.try_next().await? {
+            encoder.write_all(&bytes)?;
+            if encoder.get_ref().len() >= GZIP_CHUNK {
+                yield Bytes::from(std::mem::take(encoder.get_mut()));
+            }
+        }
+        yield Bytes::from(encoder.finish()?);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bill(category: &str) -> Bill {
+        Bill {
+            notes: "Toy".into(),
+            amount: 10.0,
+            currency: None,
+            categories: vec![category.into()],
+            category_reason: None,
+            needs_review: false,
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = TaskFilter::try_from(TaskQuery {
+            from: Some("2026-03-01".into()),
+            to: Some("2026-03-31".into()),
+            category: vec!["Food".into()],
+            ..Default::default()
+        })
+        .unwrap();
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().to_utc();
+        assert!(filter.matches(at("2026-03-01T00:00:00Z"), &bill("Food")));
+        assert!(filter.matches(at("2026-03-31T23:59:59Z"), &bill("Food")));
+        assert!(!filter.matches(at("2026-04-01T00:00:00Z"), &bill("Food")));
+        assert!(!filter.matches(at("2026-02-28T23:59:59Z"), &bill("Food")));
+        assert!(!filter.matches(at("2026-03-10T00:00:00Z"), &bill("Rent")));
+
+        let err = TaskFilter::try_from(TaskQuery {
+            to: Some("March".into()),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert!(matches!(err, ExportError::InvalidDate(date) if date == "March"));
+    }
+
+    #[test]
+    fn test_query() {
+        let query = TaskQuery::parse(
+            "category=Food&category=Rent%20%26%20bills&min_amount=1.5&tz=%2B08:00&other=1",
+        );
+        assert_eq!(query.category, ["Food", "Rent & bills"]);
+        let filter = TaskFilter::try_from(query).unwrap();
+        let now = Utc::now();
+        assert!(filter.matches(now, &bill("Rent & bills")));
+        assert!(!filter.matches(now, &bill("Transport")));
+        let mut cheap = bill("Food");
+        cheap.amount = 1.0;
+        assert!(!filter.matches(now, &cheap));
+
+        let filter = TaskFilter::try_from(TaskQuery::parse("max_amount=1")).unwrap();
+        assert!(filter.matches(now, &cheap) && !filter.matches(now, &bill("Food")));
+        assert!(matches!(
+            TaskFilter::try_from(TaskQuery::parse("min_amount=lots")),
+            Err(ExportError::InvalidAmount(amount)) if amount == "lots"
+        ));
+
+        assert!(TaskQuery::parse("").mapping().unwrap().keeps(REASON_FIELD));
+        let mapping = TaskQuery::parse("category_reason=false").mapping().unwrap();
+        assert!(!mapping.keeps(REASON_FIELD));
+        assert!(matches!(
+            TaskQuery::parse("category_reason=no").mapping(),
+            Err(ExportError::InvalidReasonFlag(flag)) if flag == "no"
+        ));
+    }
+
+    #[test]
+    fn test_timezone() {
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().to_utc();
+        // a day in UTC+8 starts at 16:00 UTC of the day before
+        let filter = TaskFilter::try_from(TaskQuery::parse(
+            "from=2026-03-01&to=2026-03-01&tz=%2B08:00",
+        ))
+        .unwrap();
+        assert!(!filter.matches(at("2026-02-28T15:59:59Z"), &bill("Food")));
+        assert!(filter.matches(at("2026-02-28T16:00:00Z"), &bill("Food")));
+        assert!(filter.matches(at("2026-03-01T15:59:59Z"), &bill("Food")));
+        assert!(!filter.matches(at("2026-03-01T16:00:00Z"), &bill("Food")));
+        // timestamps carry their own offset
+        let filter = TaskFilter::try_from(TaskQuery::parse(
+            "from=2026-03-01T00:00:00-05:00&tz=%2B08:00",
+        ))
+        .unwrap();
+        assert!(!filter.matches(at("2026-03-01T04:59:59Z"), &bill("Food")));
+        assert!(filter.matches(at("2026-03-01T05:00:00Z"), &bill("Food")));
+
+        let utc = TaskFilter::try_from(TaskQuery::parse("from=2026-03-01&tz=Z")).unwrap();
+        assert!(utc.matches(at("2026-03-01T00:00:00Z"), &bill("Food")));
+        assert!(matches!(
+            TaskFilter::try_from(TaskQuery::parse("tz=Mars")),
+            Err(ExportError::InvalidTimezone(tz)) if tz == "Mars"
+        ));
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        let accepts = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, value.parse().unwrap());
+            accepts_gzip(&headers)
+        };
+        assert!(accepts("gzip"));
+        assert!(accepts("br;q=1.0, GZIP;q=0.5"));
+        assert!(accepts("*"));
+        assert!(!accepts("br, deflate"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts("*, gzip;q=0"));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn test_gzip() {
+        use std::io::Read;
+
+        let lines = (0..2000)
+            .map(|i| {
+                Ok(Bytes::from(format!(
+                    "{{\"id\": \"{i}\", \"notes\": \"Toy\"}}\n"
+                )))
+            })
+            .collect::<Vec<_>>();
+        let plain = lines
+            .iter()
+            .flat_map(|line| line.as_ref().unwrap().to_vec())
+            .collect::<Vec<_>>();
+        let chunks = gzip(futures::stream::iter(lines))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert!(chunks.iter().all(|chunk| !chunk.is_empty()));
+        let compressed = chunks.concat();
+        assert!(compressed.len() < plain.len() / 4);
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, plain);
+    }
+
+    #[test]
+    fn test_csv() {
+        let bill = Bill {
+            notes: "Lamp, \"brass\"\nand bulbs".into(),
+            amount: 12.5,
+            currency: Some("EUR".into()),
+            categories: vec!["Household".into(), "Food".into()],
+            category_reason: Some("A lamp, for the home.".into()),
+            needs_review: false,
+        };
+        let at = DateTime::parse_from_rfc3339("2026-03-01T00:00:00Z")
+            .unwrap()
+            .to_utc();
+        let row = |mapping: &BillMapping| {
+            let row = ExportFormat::Csv.line(&ExportLine {
+                id: "a",
+                created_at: at,
+                finished_at: at,
+                bill: mapping.apply(&bill),
+            });
+            String::from_utf8(row.to_vec()).unwrap()
+        };
+        let without = format!(
+            "a,2026-03-01T00:00:00+00:00,2026-03-01T00:00:00+00:00,\"Lamp, \"\"brass\"\"\nand bulbs\",12.5,EUR,{},Household,Household;Food,false",
+            csv_field(&bill.formatted_amount().unwrap())
+        );
+        assert_eq!(
+            row(&BillMapping::canonical(true)),
+            format!("{without},\"A lamp, for the home.\"\r\n")
+        );
+        assert_eq!(
+            row(&BillMapping::canonical(false)),
+            format!("{without}\r\n")
+        );
+        assert_eq!(
+            csv_header(&BillMapping::canonical(false)),
+            CSV_HEADER_WITHOUT_REASON
+        );
+
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("+1 tip"), "'+1 tip");
+        assert_eq!(csv_field("-12.5"), "-12.5");
+    }
+
+    #[test]
+    fn test_cursor() {
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().to_utc();
+        let cursor = Cursor {
+            finished_at: at("2026-03-01T12:00:00.123456789Z"),
+            id: "b".into(),
+        };
+        assert_eq!(cursor.to_string().parse::<Cursor>(), Ok(cursor.clone()));
+        assert!("b".parse::<Cursor>().is_err());
+
+        let filter = TaskFilter::default().after(cursor.clone());
+        assert!(!filter.follows_cursor(cursor.finished_at, "a"));
+        assert!(!filter.follows_cursor(cursor.finished_at, "b"));
+        assert!(filter.follows_cursor(cursor.finished_at, "c"));
+        assert!(filter.follows_cursor(at("2026-03-01T12:00:01Z"), "a"));
+
+        // what an export leaves out for finishing too late, the next one picks up
+        let started = at("2026-03-02T00:00:00Z");
+        let export = TaskFilter::default().before(started);
+        let next = TaskFilter::default().after(Cursor::at(started));
+        for finished_at in [at("2026-03-01T23:59:59Z"), started] {
+            assert_ne!(
+                export.matches(finished_at, &bill("Food")),
+                next.follows_cursor(finished_at, "a")
+            );
+        }
+    }
+}
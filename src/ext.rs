@@ -1,16 +1,21 @@
 use ollama_rs::Ollama;
 use reqwest::Url;
 
-pub trait FromEnvVars {
-    fn from_env_vars() -> Self;
+use crate::error::StartupError;
+
+pub trait FromEnvVars: Sized {
+    fn from_env_vars() -> Result<Self, StartupError>;
 }
 
 impl FromEnvVars for Ollama {
-    fn from_env_vars() -> Self {
+    fn from_env_vars() -> Result<Self, StartupError> {
         if let Ok(var) = std::env::var("OLLAMA_ENDPOINT") {
-            let url = Url::parse(&var).expect("invalid OLLAMA_ENDPOINT");
-            return Ollama::from_url(url);
+            let url = Url::parse(&var).map_err(|err| StartupError::InvalidOllamaEndpoint {
+                url: var.clone(),
+                reason: err.to_string(),
+            })?;
+            return Ok(Ollama::from_url(url));
         }
-        Ollama::new("http://127.0.0.1", 11434)
+        Ok(Ollama::new("http://127.0.0.1", 11434))
     }
 }
@@ -1,4 +1,4 @@
-use axum::{RequestPartsExt, extract::FromRequestParts};
+use axum::{RequestPartsExt, extract::FromRequestParts, http::request::Parts};
 use axum_extra::{
     TypedHeader,
     headers::{Authorization, authorization::Bearer},
@@ -7,6 +7,21 @@ use rand::{RngExt, rngs::StdRng};
 
 use crate::{error, state::AppState};
 
+/// Decides who may call the routes requiring a key, in place of the bearer
+/// key of `--auth-key`. Closures over the request parts work too.
+pub trait Authorize: Send + Sync {
+    fn authorize(&self, parts: &Parts) -> Result<(), error::AuthError>;
+}
+
+impl<F> Authorize for F
+where
+    F: Fn(&Parts) -> Result<(), error::AuthError> + Send + Sync,
+{
+    fn authorize(&self, parts: &Parts) -> Result<(), error::AuthError> {
+        self(parts)
+    }
+}
+
 pub struct ValidKey {}
 
 impl FromRequestParts<AppState> for ValidKey {
@@ -17,6 +32,9 @@ impl FromRequestParts<AppState> for ValidKey {
         state: &AppState,
     ) -> Result<Self, Self::Rejection> {
         const VALID_KEY: ValidKey = ValidKey {};
+        if let Some(authorizer) = state.authorizer() {
+            return authorizer.authorize(parts).map(|_| VALID_KEY);
+        }
         if state.auth_key().is_empty() {
             return Ok(VALID_KEY);
         }
@@ -0,0 +1,80 @@
+//! Turns photos and screenshots of receipts into categorized bills, with
+//! vision and language models served by Ollama.
+//!
+//! The [Scheduler] queues tasks for a [RunTask] runner, [OllamaRunTask] being
+//! the one the server uses, and keeps finished ones around, swapping them to
+//! disk past a limit. Categories are registered once per process with
+//! [Category::load_from_names] before any task runs.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use ledoxide::{Category, OllamaRunTask, OllamaTaskDescriptor, Scheduler};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! Category::load_from_names(["Food", "Transport", "No category"]);
+//! let scheduler = Scheduler::new(2, 1024, Duration::from_mins(5), OllamaRunTask::default())?;
+//! let receipt = std::fs::read("receipt.jpg")?;
+//! let task = scheduler
+//!     .create_task(OllamaTaskDescriptor::from_images(vec![receipt]))
+//!     .await;
+//! let bill = task.finished().await?.0;
+//! println!("{} in {:?}", bill.amount, bill.categories);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! To serve the HTTP API from another axum application, build an [AppState]
+//! and add [router] to its routes, optionally checking keys with an
+//! [Authorize] of its own. `examples/embedded.rs` shows how.
+//!
+//! Everything re-exported here and the modules marked public make up the
+//! API. The server modules are public for the `ledoxide` binary only and may
+//! change in any release.
+
+// the OpenAPI spec is one `json!` too deep for the default
+#![recursion_limit = "256"]
+
+pub mod amount;
+pub mod bill;
+#[cfg(any(test, feature = "client"))]
+pub mod client;
+pub mod error;
+pub mod events;
+pub mod schedule;
+pub mod task;
+
+#[doc(hidden)]
+pub mod args;
+mod backup;
+mod config;
+mod deadline;
+mod export;
+mod ext;
+mod key;
+mod limit;
+#[doc(hidden)]
+pub mod listen;
+#[doc(hidden)]
+pub mod logging;
+mod mapping;
+#[doc(hidden)]
+pub mod mcp;
+mod openapi;
+mod request_id;
+#[doc(hidden)]
+pub mod server;
+pub mod state;
+mod ui;
+mod version;
+
+pub use args::App as AppStateConfig;
+pub use bill::{Bill, Category, CategoryRegistry, NamedCategory};
+pub use key::Authorize;
+pub use schedule::Scheduler;
+pub use server::router;
+pub use state::AppState;
+pub use task::{
+    RunTask, State, Success, TaskControlBlock, TaskDescriptor, TokenSender,
+    ollama::{OllamaRunTask, OllamaTaskDescriptor},
+};
@@ -0,0 +1,84 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Window the calls of [RateLimiter] are counted over.
+const WINDOW: Duration = Duration::from_mins(1);
+
+/// Lets every caller make a number of calls per minute, telling the ones
+/// past it how long to wait. Callers are told apart by their key, which is
+/// only kept hashed.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    per_minute: usize,
+    calls: Mutex<HashMap<u64, VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    /// Unlimited if `per_minute` is zero.
+    pub fn new(per_minute: usize) -> Self {
+        Self {
+            per_minute,
+            calls: Default::default(),
+        }
+    }
+
+    /// Counts a call of `key`, or the time until it may call again if it
+    /// called too often within the last minute.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        if self.per_minute == 0 {
+            return Ok(());
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let mut calls = self.calls.lock().unwrap();
+        // callers gone quiet are forgotten
+        calls.retain(|_, times| times.back().is_some_and(|last| now - *last < WINDOW));
+        let times = calls.entry(hasher.finish()).or_default();
+        while times.front().is_some_and(|first| now - *first >= WINDOW) {
+            times.pop_front();
+        }
+        if times.len() >= self.per_minute {
+            return Err(WINDOW - (now - times[0]));
+        }
+        times.push_back(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = RateLimiter::new(2);
+        let start = Instant::now();
+        assert!(limiter.check_at("a", start).is_ok());
+        assert!(
+            limiter
+                .check_at("a", start + Duration::from_secs(10))
+                .is_ok()
+        );
+        assert_eq!(
+            limiter.check_at("a", start + Duration::from_secs(20)),
+            Err(Duration::from_secs(40))
+        );
+        assert!(
+            limiter
+                .check_at("b", start + Duration::from_secs(20))
+                .is_ok()
+        );
+        assert!(limiter.check_at("a", start + WINDOW).is_ok());
+
+        let unlimited = RateLimiter::new(0);
+        assert!((0..100).all(|_| unlimited.check_at("a", start).is_ok()));
+    }
+}
@@ -0,0 +1,961 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::Request,
+    response::IntoResponse,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use hyper::body::{Frame, Incoming, SizeHint};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use strum::Display;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+    time::{Instant, Sleep},
+};
+use tower::ServiceExt;
+use tracing::{Level, event};
+
+use crate::error::{ConnectionError, StartupError};
+
+/// First file descriptor systemd passes, see sd_listen_fds(3).
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Prefix of `--bind` values naming a Unix domain socket path.
+const UNIX_SCHEME: &str = "unix:";
+
+pub enum Listener {
+    Tcp(TcpListener, SocketAddr),
+    Tls(TcpListener, SocketAddr, Tls),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, std::path::PathBuf),
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Tcp(_, addr) => write!(f, "http://{addr}"),
+            Listener::Tls(_, addr, _) => write!(f, "https://{addr}"),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => write!(f, "{UNIX_SCHEME}{}", path.display()),
+        }
+    }
+}
+
+impl Listener {
+    /// Serves `app` on every connection accepted, within `limits`, counting
+    /// the connections turned away or cut off to `stats`.
+    pub async fn serve(
+        self,
+        app: axum::Router,
+        limits: ConnectionLimits,
+        stats: Arc<ConnectionStats>,
+    ) -> std::io::Result<()> {
+        let server = Arc::new(Server {
+            permits: limits
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max))),
+            rejecting: Arc::new(Semaphore::new(MAX_REJECTING)),
+            app,
+            limits,
+            stats,
+        });
+        match self {
+            Listener::Tcp(listener, _) => loop {
+                if let Some((stream, _)) = accepted(listener.accept().await).await {
+                    server.clone().spawn(stream);
+                }
+            },
+            Listener::Tls(listener, _, tls) => {
+                #[cfg(unix)]
+                tls.reload_on_hangup();
+                loop {
+                    let Some((stream, _)) = accepted(listener.accept().await).await else {
+                        continue;
+                    };
+                    // admitted before the handshake, so handshakes count against the limits too
+                    let admission = server.admit();
+                    if let Admission::Closed = admission {
+                        continue;
+                    }
+                    let server = server.clone();
+                    let acceptor = tokio_rustls::TlsAcceptor::from(tls.config.get_inner());
+                    tokio::spawn(async move {
+                        // a handshake is held to the same time as the headers after it
+                        let handshake = tokio::time::timeout(
+                            server.limits.header_read_timeout,
+                            acceptor.accept(stream),
+                        );
+                        match handshake.await {
+                            Ok(Ok(stream)) => server.serve(stream, admission).await,
+                            Ok(Err(err)) => event!(Level::DEBUG, "TLS handshake failed: {err}"),
+                            Err(_) => server.stats.timed_out(Timeout::Header),
+                        }
+                    });
+                }
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => loop {
+                if let Some((stream, _)) = accepted(listener.accept().await).await {
+                    server.clone().spawn(stream);
+                }
+            },
+        }
+    }
+}
+
+/// The accepted connection, or none after an error accepting it. Running out
+/// of file descriptors is waited out, since it clears as connections close.
+async fn accepted<T>(result: std::io::Result<T>) -> Option<T> {
+    use std::io::ErrorKind;
+
+    match result {
+        Ok(accepted) => Some(accepted),
+        Err(err)
+            if matches!(
+                err.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::ConnectionReset
+            ) =>
+        {
+            None
+        }
+        Err(err) => {
+            event!(Level::ERROR, "failed to accept a connection: {err}");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            None
+        }
+    }
+}
+
+/// Bounds on the connections served, so clients sending slowly or not at all
+/// can't hold them open.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    /// Longest the headers of a request may take to arrive, counted from when
+    /// the connection is ready for it
+    pub header_read_timeout: Duration,
+    /// Longest a request body may go without sending anything
+    pub body_timeout: Duration,
+    /// Longest a connection may wait for its next request with none in flight
+    pub idle_timeout: Duration,
+    /// Connections served at once, further ones get a 503. Unlimited if absent
+    pub max_connections: Option<usize>,
+}
+
+pub const DEFAULT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_BODY_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+/// Connections over `max_connections` answered with a 503 at once. Any further
+/// ones are closed right away.
+const MAX_REJECTING: usize = 64;
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            header_read_timeout: DEFAULT_HEADER_READ_TIMEOUT,
+            body_timeout: DEFAULT_BODY_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_connections: Some(DEFAULT_MAX_CONNECTIONS),
+        }
+    }
+}
+
+/// What a connection took too long at before it was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum Timeout {
+    /// Sending the headers of a request, or the TLS handshake
+    Header,
+    /// Sending more of a request body
+    Body,
+    /// Sending its next request
+    Idle,
+}
+
+impl Timeout {
+    pub const ALL: [Timeout; 3] = [Timeout::Header, Timeout::Body, Timeout::Idle];
+}
+
+/// Connections served, turned away and cut off since starting, for `/metrics`.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    open: AtomicUsize,
+    rejected: AtomicU64,
+    timed_out: [AtomicU64; Timeout::ALL.len()],
+}
+
+impl ConnectionStats {
+    /// Connections being served right now, rejected ones aside.
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// Connections answered with a 503 for exceeding the limit.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed for taking too long at `timeout`.
+    pub fn timeouts(&self, timeout: Timeout) -> u64 {
+        self.timed_out[timeout as usize].load(Ordering::Relaxed)
+    }
+
+    fn timed_out(&self, timeout: Timeout) {
+        event!(Level::DEBUG, "closing a connection, {timeout} timed out");
+        self.timed_out[timeout as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct Server {
+    app: axum::Router,
+    limits: ConnectionLimits,
+    stats: Arc<ConnectionStats>,
+    /// One per connection served, none if unlimited
+    permits: Option<Arc<Semaphore>>,
+    /// One per connection being answered with a 503, see [MAX_REJECTING]
+    rejecting: Arc<Semaphore>,
+}
+
+/// What becomes of a connection accepted, decided before anything is read from it.
+enum Admission {
+    /// Served, holding its permit if connections are limited
+    Serve(Option<OwnedSemaphorePermit>),
+    /// Over the limit, answered with a 503
+    Reject(OwnedSemaphorePermit),
+    /// Over the limit with too many being rejected already, closed unanswered
+    Closed,
+}
+
+impl Server {
+    /// Takes a permit for a connection just accepted, counting it as rejected
+    /// if there is none left.
+    fn admit(&self) -> Admission {
+        let Some(permits) = &self.permits else {
+            return Admission::Serve(None);
+        };
+        if let Ok(permit) = permits.clone().try_acquire_owned() {
+            return Admission::Serve(Some(permit));
+        }
+        self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+        match self.rejecting.clone().try_acquire_owned() {
+            Ok(permit) => Admission::Reject(permit),
+            Err(_) => Admission::Closed,
+        }
+    }
+
+    fn spawn<Io>(self: Arc<Self>, io: Io)
+    where
+        Io: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let admission = self.admit();
+        if let Admission::Closed = admission {
+            return;
+        }
+        tokio::spawn(async move { self.serve(io, admission).await });
+    }
+
+    async fn serve<Io>(&self, io: Io, admission: Admission)
+    where
+        Io: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match admission {
+            Admission::Serve(permit) => self.connection(io, permit).await,
+            Admission::Reject(_permit) => self.reject(io).await,
+            Admission::Closed => {}
+        }
+    }
+
+    fn builder(&self) -> auto::Builder<TokioExecutor> {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(self.limits.header_read_timeout);
+        builder
+    }
+
+    /// Answers the first request of a connection over the limit with a 503
+    /// and closes it, reading no more than its headers.
+    async fn reject<Io>(&self, io: Io)
+    where
+        Io: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let service = hyper::service::service_fn(async |_: Request<Incoming>| {
+            Ok::<_, std::convert::Infallible>(ConnectionError::TooMany.into_response())
+        });
+        let mut builder = self.builder();
+        builder.http1().keep_alive(false);
+        if let Err(err) = builder.serve_connection(TokioIo::new(io), service).await {
+            self.check_timeout(err.as_ref());
+        }
+    }
+
+    async fn connection<Io>(&self, io: Io, permit: Option<OwnedSemaphorePermit>)
+    where
+        Io: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let _open = Open::new(&self.stats, permit);
+        let activity = Arc::new(Activity::new());
+        let service = hyper::service::service_fn({
+            let activity = activity.clone();
+            let app = self.app.clone();
+            let body_timeout = self.limits.body_timeout;
+            move |request: Request<Incoming>| {
+                let in_flight = InFlight::new(activity.clone());
+                let request = request.map(|body| {
+                    Body::new(RequestBody {
+                        body: Some(body),
+                        activity: activity.clone(),
+                        timeout: body_timeout,
+                        stalling: None,
+                    })
+                });
+                let app = app.clone();
+                async move {
+                    let response = app.oneshot(request).await?;
+                    Ok::<_, std::convert::Infallible>(response.map(|body| {
+                        Body::new(ResponseBody {
+                            body,
+                            _in_flight: in_flight,
+                        })
+                    }))
+                }
+            }
+        });
+        let builder = self.builder();
+        let connection = builder.serve_connection(TokioIo::new(io), service);
+        tokio::pin!(connection);
+        let result = tokio::select! {
+            result = connection.as_mut() => result,
+            timeout = activity.closing(self.limits.idle_timeout) => {
+                if timeout == Timeout::Idle {
+                    self.stats.timed_out(timeout);
+                }
+                connection.as_mut().graceful_shutdown();
+                connection.await
+            }
+        };
+        // hyper may close the connection itself after answering a stalled body
+        if activity.stalled.load(Ordering::Relaxed) {
+            self.stats.timed_out(Timeout::Body);
+        }
+        if let Err(err) = result {
+            self.check_timeout(err.as_ref());
+        }
+    }
+
+    fn check_timeout(&self, err: &(dyn std::error::Error + 'static)) {
+        if err
+            .downcast_ref::<hyper::Error>()
+            .is_some_and(hyper::Error::is_timeout)
+        {
+            self.stats.timed_out(Timeout::Header);
+        } else {
+            event!(Level::DEBUG, "connection failed: {err}");
+        }
+    }
+}
+
+/// Counts a connection as open while served, holding its permit.
+struct Open<'a> {
+    stats: &'a ConnectionStats,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<'a> Open<'a> {
+    fn new(stats: &'a ConnectionStats, permit: Option<OwnedSemaphorePermit>) -> Self {
+        stats.open.fetch_add(1, Ordering::Relaxed);
+        Self {
+            stats,
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for Open<'_> {
+    fn drop(&mut self) {
+        self.stats.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Requests a connection is serving, and when it last served none.
+struct Activity {
+    in_flight: AtomicUsize,
+    idle_since: Mutex<Instant>,
+    /// Set once a request body stalled, closing the connection after its response
+    stalled: AtomicBool,
+    changed: Notify,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            in_flight: AtomicUsize::new(0),
+            idle_since: Mutex::new(Instant::now()),
+            stalled: AtomicBool::new(false),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Resolves once the connection is to be closed, either idle for `idle`
+    /// or with a stalled request body.
+    async fn closing(&self, idle: Duration) -> Timeout {
+        loop {
+            if self.stalled.load(Ordering::Relaxed) {
+                return Timeout::Body;
+            }
+            if self.in_flight.load(Ordering::Relaxed) == 0 {
+                let deadline = *self.idle_since.lock().unwrap() + idle;
+                if Instant::now() >= deadline {
+                    return Timeout::Idle;
+                }
+                tokio::select! {
+                    () = tokio::time::sleep_until(deadline) => {}
+                    () = self.changed.notified() => {}
+                }
+            } else {
+                self.changed.notified().await;
+            }
+        }
+    }
+
+    fn stall(&self) {
+        self.stalled.store(true, Ordering::Relaxed);
+        self.changed.notify_one();
+    }
+}
+
+/// A request being served, until its response body is sent or dropped.
+struct InFlight(Arc<Activity>);
+
+impl InFlight {
+    fn new(activity: Arc<Activity>) -> Self {
+        activity.in_flight.fetch_add(1, Ordering::Relaxed);
+        activity.changed.notify_one();
+        Self(activity)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        *self.0.idle_since.lock().unwrap() = Instant::now();
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.0.changed.notify_one();
+    }
+}
+
+/// A response body holding its request in flight.
+struct ResponseBody {
+    body: Body,
+    _in_flight: InFlight,
+}
+
+impl HttpBody for ResponseBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// A request body failing once it was waited on for its timeout without
+/// sending anything, which also has the connection closed after the response.
+struct RequestBody {
+    /// Gone once it stalled
+    body: Option<Incoming>,
+    activity: Arc<Activity>,
+    timeout: Duration,
+    /// Running while waited on
+    stalling: Option<Pin<Box<Sleep>>>,
+}
+
+impl HttpBody for RequestBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let Some(body) = self.body.as_mut() else {
+            return Poll::Ready(None);
+        };
+        match Pin::new(body).poll_frame(cx) {
+            Poll::Ready(frame) => {
+                self.stalling = None;
+                Poll::Ready(frame.map(|frame| frame.map_err(axum::Error::new)))
+            }
+            Poll::Pending => {
+                let timeout = self.timeout;
+                let stalling = self
+                    .stalling
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+                if stalling.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.body = None;
+                self.activity.stall();
+                Poll::Ready(Some(Err(axum::Error::new(ConnectionError::BodyTimeout(
+                    self.timeout,
+                )))))
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.as_ref().is_none_or(Incoming::is_end_stream)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body
+            .as_ref()
+            .map_or_else(|| SizeHint::with_exact(0), Incoming::size_hint)
+    }
+}
+
+/// Certificate and key served over TCP connections.
+pub struct Tls {
+    cert: PathBuf,
+    key: PathBuf,
+    config: RustlsConfig,
+}
+
+impl Tls {
+    pub async fn load(cert: PathBuf, key: PathBuf) -> Result<Self, StartupError> {
+        let config = RustlsConfig::from_pem_file(&cert, &key).await?;
+        Ok(Self { cert, key, config })
+    }
+
+    /// Reads the certificate and key again, keeping the current ones on failure.
+    pub async fn reload(&self) -> std::io::Result<()> {
+        self.config
+            .reload_from_pem_file(&self.cert, &self.key)
+            .await
+    }
+
+    #[cfg(unix)]
+    fn reload_on_hangup(&self) {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let tls = Self {
+            cert: self.cert.clone(),
+            key: self.key.clone(),
+            config: self.config.clone(),
+        };
+        tokio::spawn(async move {
+            let Ok(mut hangups) = signal(SignalKind::hangup()) else {
+                event!(
+                    Level::WARN,
+                    "can't listen for SIGHUP, TLS won't be reloaded"
+                );
+                return;
+            };
+            while hangups.recv().await.is_some() {
+                match tls.reload().await {
+                    Ok(()) => event!(Level::INFO, "reloaded TLS certificate"),
+                    Err(err) => event!(Level::ERROR, "failed to reload TLS certificate: {err}"),
+                }
+            }
+        });
+    }
+}
+
+/// Takes the socket systemd passed in, or binds `bind` otherwise, either a TCP
+/// address or a `unix:` path. The resolved port is written to `port_file` when
+/// given, useful with port zero. TCP connections are served over `tls` if given.
+pub async fn open(
+    bind: &str,
+    port_file: Option<&Path>,
+    tls: Option<Tls>,
+) -> Result<Listener, StartupError> {
+    if let Some(listener) = inherited()? {
+        event!(Level::INFO, "using the socket passed by systemd");
+        let addr = listener.local_addr()?;
+        return tcp(listener, addr, port_file, tls).await;
+    }
+    if let Some(path) = bind.strip_prefix(UNIX_SCHEME) {
+        if tls.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "TLS is not supported on unix sockets",
+            )
+            .into());
+        }
+        return unix(Path::new(path));
+    }
+    let listener = TcpListener::bind(bind).await?;
+    let addr = listener.local_addr()?;
+    tcp(listener, addr, port_file, tls).await
+}
+
+async fn tcp(
+    listener: TcpListener,
+    addr: SocketAddr,
+    port_file: Option<&Path>,
+    tls: Option<Tls>,
+) -> Result<Listener, StartupError> {
+    if let Some(path) = port_file {
+        tokio::fs::write(path, addr.port().to_string()).await?;
+    }
+    Ok(match tls {
+        Some(tls) => Listener::Tls(listener, addr, tls),
+        None => Listener::Tcp(listener, addr),
+    })
+}
+
+#[cfg(unix)]
+fn unix(path: &Path) -> Result<Listener, StartupError> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // a socket left behind by a previous run would make binding fail
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    Ok(Listener::Unix(listener, path.to_path_buf()))
+}
+
+#[cfg(not(unix))]
+fn unix(_: &Path) -> Result<Listener, StartupError> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "unix domain sockets are not supported on this platform",
+    )
+    .into())
+}
+
+/// The inherited descriptor if the environment says it is meant for this process.
+#[cfg(unix)]
+fn systemd_fd(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Option<i32> {
+    let for_us = listen_pid.and_then(|p| p.parse::<u32>().ok()) == Some(pid);
+    let count = listen_fds.and_then(|n| n.parse::<u32>().ok()).unwrap_or(0);
+    (for_us && count >= 1).then_some(SD_LISTEN_FDS_START)
+}
+
+#[cfg(unix)]
+fn inherited() -> Result<Option<TcpListener>, StartupError> {
+    use std::os::fd::FromRawFd;
+
+    let Some(fd) = systemd_fd(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    ) else {
+        return Ok(None);
+    };
+    // SAFETY: systemd hands the descriptor to this process, and nothing else owns it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(TcpListener::from_std(listener)?))
+}
+
+#[cfg(not(unix))]
+fn inherited() -> Result<Option<TcpListener>, StartupError> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_port_zero() {
+        let port_file = tempfile::NamedTempFile::new().unwrap();
+        let Listener::Tcp(listener, addr) = open("127.0.0.1:0", Some(port_file.path()), None)
+            .await
+            .unwrap()
+        else {
+            panic!("expected a TCP listener");
+        };
+        assert_ne!(addr.port(), 0);
+        assert_eq!(listener.local_addr().unwrap(), addr);
+        let written = std::fs::read_to_string(port_file.path()).unwrap();
+        assert_eq!(written, addr.port().to_string());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledoxide.sock");
+        let bind = format!("unix:{}", path.display());
+        // a stale socket must not get in the way
+        drop(open(&bind, None, None).await.unwrap());
+        let listener = open(&bind, None, None).await.unwrap();
+        assert_eq!(listener.to_string(), bind);
+        let app = axum::Router::new().route("/", axum::routing::get(async || "hello"));
+        tokio::spawn(listener.serve(app, ConnectionLimits::default(), Default::default()));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("hello"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        let write_cert = || {
+            let cert_key = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+            std::fs::write(&cert, cert_key.cert.pem()).unwrap();
+            std::fs::write(&key, cert_key.signing_key.serialize_pem()).unwrap();
+            cert_key.cert.der().to_vec()
+        };
+        let first = write_cert();
+        let tls = Tls::load(cert.clone(), key.clone()).await.unwrap();
+        let listener = open("127.0.0.1:0", None, Some(tls)).await.unwrap();
+        let Listener::Tls(_, addr, _) = &listener else {
+            panic!("expected a TLS listener");
+        };
+        let addr = *addr;
+        assert_eq!(listener.to_string(), format!("https://{addr}"));
+
+        let app = axum::Router::new().route("/", axum::routing::get(async || "hello"));
+        tokio::spawn(listener.serve(app, ConnectionLimits::default(), Default::default()));
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_der(&first).unwrap())
+            .resolve("localhost", addr)
+            .build()
+            .unwrap();
+        let body = client
+            .get(format!("https://localhost:{}/", addr.port()))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, "hello");
+
+        write_cert();
+        let tls = Tls::load(cert.clone(), key.clone()).await.unwrap();
+        tls.reload().await.unwrap();
+        // broken files are reported, leaving the current certificate in place
+        std::fs::write(&cert, "garbage").unwrap();
+        assert!(tls.reload().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake_permit() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        let cert_key = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        std::fs::write(&cert, cert_key.cert.pem()).unwrap();
+        std::fs::write(&key, cert_key.signing_key.serialize_pem()).unwrap();
+        let tls = Tls::load(cert, key).await.unwrap();
+        let listener = open("127.0.0.1:0", None, Some(tls)).await.unwrap();
+        let Listener::Tls(_, addr, _) = &listener else {
+            panic!("expected a TLS listener");
+        };
+        let addr = *addr;
+        let stats = Arc::new(ConnectionStats::default());
+        let app = axum::Router::new().route("/", axum::routing::get(async || "hello"));
+        let limits = ConnectionLimits {
+            max_connections: Some(1),
+            ..Default::default()
+        };
+        tokio::spawn(listener.serve(app, limits, stats.clone()));
+
+        // never starts its handshake, yet takes the only permit
+        let _held = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_der(cert_key.cert.der()).unwrap())
+            .resolve("localhost", addr)
+            .build()
+            .unwrap();
+        let response = client
+            .get(format!("https://localhost:{}/", addr.port()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!((stats.open(), stats.rejected()), (0, 1));
+    }
+
+    /// Serves a route echoing bodies over TCP within `limits`.
+    async fn serve_limited(limits: ConnectionLimits) -> (SocketAddr, Arc<ConnectionStats>) {
+        let listener = open("127.0.0.1:0", None, None).await.unwrap();
+        let Listener::Tcp(_, addr) = listener else {
+            panic!("expected a TCP listener");
+        };
+        let stats = Arc::new(ConnectionStats::default());
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::get(async || "hello").post(async |body: String| body),
+        );
+        tokio::spawn(listener.serve(app, limits, stats.clone()));
+        (addr, stats)
+    }
+
+    /// What the server sent until it closed the connection, which must happen
+    /// within `window`.
+    async fn closed(stream: &mut (impl AsyncRead + Unpin), window: Duration) -> String {
+        use tokio::io::AsyncReadExt;
+
+        let mut response = Vec::new();
+        let read = tokio::time::timeout(window, async {
+            let mut buf = [0; 1024];
+            while let Ok(n @ 1..) = stream.read(&mut buf).await {
+                response.extend_from_slice(&buf[..n]);
+            }
+        });
+        read.await.expect("connection left open");
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_slow_headers() {
+        use tokio::io::AsyncWriteExt;
+
+        let (addr, stats) = serve_limited(ConnectionLimits {
+            header_read_timeout: Duration::from_millis(300),
+            ..Default::default()
+        })
+        .await;
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (mut read, mut write) = stream.split();
+        write
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n")
+            .await
+            .unwrap();
+        let trickle = async {
+            for _ in 0..30 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                if write.write_all(b"X-Slow: 1\r\n").await.is_err() {
+                    break;
+                }
+            }
+        };
+        let started = Instant::now();
+        tokio::select! {
+            () = trickle => panic!("still reading headers after 3s"),
+            response = closed(&mut read, Duration::from_secs(2)) => {
+                assert!(!response.contains("hello"), "{response}");
+            }
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(stats.timeouts(Timeout::Header), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_body() {
+        use tokio::io::AsyncWriteExt;
+
+        let (addr, stats) = serve_limited(ConnectionLimits {
+            body_timeout: Duration::from_millis(300),
+            ..Default::default()
+        })
+        .await;
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100\r\n\r\n0123456789",
+            )
+            .await
+            .unwrap();
+        let response = closed(&mut stream, Duration::from_secs(2)).await;
+        assert!(response.starts_with("HTTP/1.1 4"), "{response}");
+        assert_eq!(stats.timeouts(Timeout::Body), 1);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (addr, stats) = serve_limited(ConnectionLimits {
+            idle_timeout: Duration::from_millis(300),
+            ..Default::default()
+        })
+        .await;
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"hello") {
+            let mut buf = [0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0, "closed before answering");
+            response.extend_from_slice(&buf[..n]);
+        }
+        // kept alive for the next request until idle too long
+        assert_eq!(closed(&mut stream, Duration::from_secs(2)).await, "");
+        assert_eq!(stats.timeouts(Timeout::Idle), 1);
+        assert_eq!(stats.open(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        use tokio::io::AsyncWriteExt;
+
+        let (addr, stats) = serve_limited(ConnectionLimits {
+            max_connections: Some(1),
+            ..Default::default()
+        })
+        .await;
+        let _held = tokio::net::TcpStream::connect(addr).await.unwrap();
+        while stats.open() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let response = closed(&mut stream, Duration::from_secs(2)).await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+        assert!(response.contains("too many connections"), "{response}");
+        assert_eq!((stats.open(), stats.rejected()), (1, 1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_systemd_fd() {
+        assert_eq!(systemd_fd(Some("42"), Some("1"), 42), Some(3));
+        assert_eq!(systemd_fd(Some("41"), Some("1"), 42), None);
+        assert_eq!(systemd_fd(Some("42"), Some("0"), 42), None);
+        assert_eq!(systemd_fd(None, None, 42), None);
+    }
+}
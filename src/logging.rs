@@ -0,0 +1,204 @@
+use tracing::{
+    Level, Metadata, Subscriber,
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span,
+    subscriber::Interest,
+};
+use tracing_subscriber::{
+    EnvFilter, Layer,
+    fmt::MakeWriter,
+    layer::{Context, Filter, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+};
+
+/// Name of the span every task runs in.
+pub const TASK_SPAN: &str = "task";
+
+/// Whether `meta` belongs to this crate, whose debug events debugging spans
+/// let through. Callsites of dependencies keep the interest `RUST_LOG` gives
+/// them, so they aren't looked at for every event.
+fn is_own(meta: &Metadata<'_>) -> bool {
+    meta.module_path()
+        .is_some_and(|path| path.split("::").next() == Some(env!("CARGO_CRATE_NAME")))
+}
+
+pub fn init<W>(writer: W)
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(writer)
+                .with_filter(RequestDebugFilter::new(EnvFilter::from_default_env())),
+        )
+        .init();
+}
+
+/// Honors `RUST_LOG`, and additionally lets debug events of this crate through
+/// inside task spans created with `debug = true`.
+pub struct RequestDebugFilter {
+    env: EnvFilter,
+}
+
+/// Marks a span whose debug events are let through.
+struct Debugging;
+
+impl RequestDebugFilter {
+    pub fn new(env: EnvFilter) -> Self {
+        Self { env }
+    }
+
+    fn mark<S>(&self, id: &span::Id, values: &impl Recordable, cx: &Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let mut visitor = DebugVisitor(false);
+        values.record(&mut visitor);
+        if visitor.0
+            && let Some(span) = cx.span(id)
+        {
+            span.extensions_mut().insert(Debugging);
+        }
+    }
+}
+
+trait Recordable {
+    fn record(&self, visitor: &mut dyn Visit);
+}
+
+impl Recordable for span::Attributes<'_> {
+    fn record(&self, visitor: &mut dyn Visit) {
+        span::Attributes::record(self, visitor)
+    }
+}
+
+impl Recordable for span::Record<'_> {
+    fn record(&self, visitor: &mut dyn Visit) {
+        span::Record::record(self, visitor)
+    }
+}
+
+struct DebugVisitor(bool);
+
+impl Visit for DebugVisitor {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == "debug" {
+            self.0 = value;
+        }
+    }
+
+    fn record_debug(&mut self, _: &Field, _: &dyn std::fmt::Debug) {}
+}
+
+impl<S> Filter<S> for RequestDebugFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, meta: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+        if Filter::<S>::enabled(&self.env, meta, cx) {
+            return true;
+        }
+        if !is_own(meta) {
+            return false;
+        }
+        if meta.is_span() {
+            // task spans must exist for their debug flag to be looked up
+            return meta.name() == TASK_SPAN;
+        }
+        *meta.level() <= Level::DEBUG
+            && cx.lookup_current().is_some_and(|span| {
+                span.scope()
+                    .any(|span| span.extensions().get::<Debugging>().is_some())
+            })
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        let interest = Filter::<S>::callsite_enabled(&self.env, meta);
+        if interest.is_never() && *meta.level() <= Level::DEBUG && is_own(meta) {
+            Interest::sometimes()
+        } else {
+            interest
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Filter::<S>::max_level_hint(&self.env).map(|level| level.max(LevelFilter::DEBUG))
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, cx: Context<'_, S>) {
+        Filter::<S>::on_new_span(&self.env, attrs, id, cx.clone());
+        self.mark(id, attrs, &cx);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, cx: Context<'_, S>) {
+        Filter::<S>::on_record(&self.env, id, values, cx.clone());
+        self.mark(id, values, &cx);
+    }
+
+    fn on_enter(&self, id: &span::Id, cx: Context<'_, S>) {
+        Filter::<S>::on_enter(&self.env, id, cx);
+    }
+
+    fn on_exit(&self, id: &span::Id, cx: Context<'_, S>) {
+        Filter::<S>::on_exit(&self.env, id, cx);
+    }
+
+    fn on_close(&self, id: span::Id, cx: Context<'_, S>) {
+        Filter::<S>::on_close(&self.env, id, cx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tracing::{event, span};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_debug_only_in_marked_spans() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(move || writer.clone())
+                .with_ansi(false)
+                .with_filter(RequestDebugFilter::new(EnvFilter::new("warn"))),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            event!(Level::DEBUG, "outside");
+            span!(Level::INFO, TASK_SPAN, debug = false).in_scope(|| {
+                event!(Level::DEBUG, "quiet task");
+            });
+            span!(Level::INFO, TASK_SPAN, debug = true).in_scope(|| {
+                event!(Level::DEBUG, "loud task");
+                event!(Level::TRACE, "too loud");
+            });
+            event!(Level::WARN, "warning");
+        });
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("loud task"), "{output}");
+        assert!(output.contains("warning"), "{output}");
+        for hidden in ["outside", "quiet task", "too loud"] {
+            assert!(!output.contains(hidden), "{output}");
+        }
+    }
+}
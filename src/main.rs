@@ -1,191 +1,96 @@
-use axum::{
-    Json,
-    extract::{DefaultBodyLimit, Path, State},
-    routing::{get, post},
-};
 use clap::Parser;
-use serde::{Deserialize, Serialize};
-use tokio::net::TcpListener;
-use tracing::{Level, event};
-
-use crate::{
-    bill::Category,
-    error::GetTaskError,
-    key::ValidKey,
-    state::AppState,
-    task::{TaskControlBlock, ollama::OllamaTaskDescriptor},
+use ledoxide::{
+    Category, args, listen, logging, mcp, server, state::AppState, task::imaging::Transcoder,
 };
+use tracing::{Level, event};
 
-mod args;
-mod bill;
-mod error;
-mod key;
-mod schedule;
-mod state;
-mod task;
-mod ext;
-
-#[tokio::main(flavor = "multi_thread")]
-async fn main() {
-    tracing_subscriber::fmt::init();
+fn main() {
     let cli = args::Cli::parse();
-    Category::load_from_names(&cli.categories);
-    let bind_addr = cli.bind.clone();
-    let args: args::App = cli.into();
-
-    let app = app(&args);
-    let listener = TcpListener::bind(bind_addr).await.expect("failed to bind");
-    event!(
-        Level::INFO,
-        "Listening on http://{}",
-        listener.local_addr().unwrap()
-    );
-    axum::serve(listener, app).await.unwrap();
-}
-
-fn app(args: &args::App) -> axum::Router {
-    axum::Router::new()
-        .route("/", get(index))
-        .route(
-            "/create_task",
-            post(create_task).layer(DefaultBodyLimit::disable()),
-        )
-        .route("/get_task/{task_id}", get(get_task))
-        .with_state(AppState::new(args))
-}
-
-async fn index() -> String {
-    return format!(
-        "{} {}",
-        env!("CARGO_PKG_NAME"),
-        option_env!("APP_VERSION").unwrap_or(env!("CARGO_PKG_VERSION"))
-    );
-}
-
-#[axum::debug_handler]
-async fn create_task(
-    _: ValidKey,
-    state: State<AppState>,
-    task: OllamaTaskDescriptor,
-) -> Json<TaskControlBlock> {
-    Json(state.scheduler().create_task(task).await)
-}
-
-async fn get_task(
-    _: ValidKey,
-    state: State<AppState>,
-    Path(GetTaskParams { task_id }): Path<GetTaskParams>,
-) -> Result<Json<TaskControlBlock>, GetTaskError> {
-    state
-        .scheduler()
-        .get_task(task_id)
-        .await?
-        .ok_or(GetTaskError::NotFound)
-        .map(Json)
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-struct GetTaskParams {
-    task_id: String,
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = cli.worker_threads {
+        runtime.worker_threads(threads.into());
+    }
+    if let Some(threads) = cli.blocking_threads {
+        runtime.max_blocking_threads(threads.into());
+    }
+    runtime
+        .build()
+        .expect("failed to build the tokio runtime")
+        .block_on(run(cli));
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{path::PathBuf, str::FromStr, time::Duration};
-
-    use axum::{body::Body, extract::Request};
-    use futures::TryStreamExt;
-    use reqwest::{StatusCode, multipart::Form};
-    use tower::{Service, util::ServiceExt};
-    use tracing_test::traced_test;
-
-    use crate::bill::Category;
-
-    use super::*;
+async fn run(cli: args::Cli) {
+    let mcp = cli.mcp;
+    if mcp {
+        // stdout belongs to the protocol
+        logging::init(std::io::stderr);
+    } else {
+        logging::init(std::io::stdout);
+    }
+    match &cli.categories_file {
+        Some(args::CategoryNames(names)) => Category::load_from_names(names),
+        None => Category::load_from_names(&cli.categories),
+    }
+    cli.locale.set_global();
+    Transcoder::set_global(cli.transcode_command.clone());
+    let bind_addr = cli.bind.clone();
+    let port_file = cli.port_file.clone();
+    let tls_files = cli.tls_cert.clone().zip(cli.tls_key.clone());
+    let args: args::App = cli.into();
 
-    #[tokio::test]
-    #[traced_test]
-    async fn test_workflow() {
-        let auth_key = "WK1wJ5ipiVvSdmdCPqNx8up8qj8GCwbm_";
-        Category::load_from_names(["Shopping", "Food", "Transport", "Rent"]);
-        fn check_finished_state(success: task::Success) {
-            let bill = success.0;
-            assert_eq!(bill.amount, 2188f32);
-            assert_eq!(bill.category, Some("Shopping".into()))
+    let state = match AppState::new(&args) {
+        Ok(state) => state,
+        Err(err) => {
+            event!(Level::ERROR, "{}", err);
+            std::process::exit(1);
         }
-
-        let mut app = app(&args::App::default()).into_service();
-        let screenshot_path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
-            .unwrap()
-            .join("asset/second-hand-horse-screenshot.jpeg");
-        let form = Form::new()
-            .file("image", screenshot_path.to_str().unwrap())
-            .await
-            .unwrap();
-        let request = Request::builder()
-            .method("POST")
-            .uri("/create_task")
-            .header(
-                "Content-Type",
-                format!("multipart/form-data; boundary={}", form.boundary()),
-            )
-            .header("Authorization", format!("Bearer {}", auth_key))
-            .body(Body::from_stream(form.into_stream()))
-            .unwrap();
-        let response = app.ready().await.unwrap().call(request).await.unwrap();
-        let status = response.status();
-        let body = response
-            .into_body()
-            .into_data_stream()
-            .map_ok(|chunk| chunk.to_vec())
-            .try_concat()
-            .await
-            .unwrap();
-        assert_eq!(
-            status,
-            StatusCode::OK,
-            "{}",
-            String::from_utf8(body).unwrap()
-        );
-        let tcb: TaskControlBlock =
-            serde_json::from_str(String::from_utf8(body).unwrap().as_str()).unwrap();
-        let task_id = tcb.id().to_string();
-        if let task::State::Finished(Ok(success)) = tcb.state() {
-            check_finished_state(success);
-            return;
+    };
+    if args.offline
+        && let Err(err) = state.scheduler().runner().check_local_models().await
+    {
+        event!(Level::ERROR, "{}", err);
+        std::process::exit(1);
+    }
+    let runner = state.scheduler().runner().clone();
+    tokio::spawn(async move {
+        if let Err(err) = runner.preload_pinned().await {
+            event!(Level::ERROR, "failed to preload pinned models: {err}");
         }
-        loop {
-            tokio::time::sleep(Duration::from_secs(1)).await;
-            let request = Request::builder()
-                .uri(format!("/get_task/{}", task_id))
-                .header("Authorization", format!("Bearer {}", auth_key))
-                .body(Body::empty())
-                .unwrap();
-            let response = app.ready().await.unwrap().call(request).await.unwrap();
-            let status = response.status();
-            let body = response
-                .into_body()
-                .into_data_stream()
-                .map_ok(|chunk| chunk.to_vec())
-                .try_concat()
-                .await
-                .unwrap();
-            assert_eq!(
-                status,
-                StatusCode::OK,
-                "{}",
-                String::from_utf8(body).unwrap()
+        if let Err(err) = runner.precompute_category_embeddings().await {
+            event!(
+                Level::WARN,
+                "failed to embed the categories ahead of the first task: {err}"
             );
-
-            let tcb: TaskControlBlock =
-                serde_json::from_str(String::from_utf8(body).unwrap().as_str()).unwrap();
-            if let task::State::Finished(state) = tcb.state() {
-                match state {
-                    Ok(success) => check_finished_state(success),
-                    Err(err) => panic!("{err}"),
-                }
-                return;
-            }
         }
+    });
+    if mcp {
+        event!(Level::INFO, "serving MCP over stdio");
+        let stdin = tokio::io::BufReader::new(tokio::io::stdin());
+        mcp::serve(state, stdin, tokio::io::stdout())
+            .await
+            .expect("MCP transport failed");
+        return;
     }
+    let connections = state.connections().clone();
+    let app = server::app(state);
+    let listener = async {
+        let tls = match tls_files {
+            Some((cert, key)) => Some(listen::Tls::load(cert, key).await?),
+            None => None,
+        };
+        listen::open(&bind_addr, port_file.as_deref(), tls).await
+    };
+    let listener = match listener.await {
+        Ok(listener) => listener,
+        Err(err) => {
+            event!(Level::ERROR, "{}", err);
+            std::process::exit(1);
+        }
+    };
+    event!(Level::INFO, "Listening on {}", listener);
+    listener
+        .serve(app, args.connection_limits, connections)
+        .await
+        .unwrap();
 }
@@ -0,0 +1,189 @@
+use serde::{Serialize, ser::SerializeMap};
+
+use crate::{bill::Bill, error::MappingError};
+
+/// Fields of the bill JSON a mapping can pick, in their canonical order.
+pub const BILL_FIELDS: [&str; 8] = [
+    "notes",
+    "amount",
+    "currency",
+    "formatted_amount",
+    "category",
+    "categories",
+    "category_reason",
+    "needs_review",
+];
+
+/// Field of the bill clients may leave out with `category_reason=false`.
+pub const REASON_FIELD: &str = "category_reason";
+
+/// Reshapes the bill JSON for clients with a schema of their own, keeping
+/// only the fields it lists, in its order and under the names it gives them.
+///
+/// Written `FIELD[:NAME],...`, such as `amount:total,notes:description,category`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BillMapping(Vec<(&'static str, String)>);
+
+impl BillMapping {
+    pub fn parse(spec: &str) -> Result<Self, MappingError> {
+        let mut fields: Vec<(&'static str, String)> = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (field, name) = match entry.split_once(':') {
+                Some((field, name)) => (field.trim(), name.trim()),
+                None => (entry, entry),
+            };
+            let field = BILL_FIELDS
+                .into_iter()
+                .find(|&known| known == field)
+                .ok_or_else(|| MappingError::UnknownField(field.to_string()))?;
+            if name.is_empty() {
+                return Err(MappingError::BlankName(field.to_string()));
+            }
+            if fields.iter().any(|(known, _)| *known == field) {
+                return Err(MappingError::RepeatedField(field.to_string()));
+            }
+            if fields.iter().any(|(_, known)| known == name) {
+                return Err(MappingError::RepeatedName(name.to_string()));
+            }
+            fields.push((field, name.to_string()));
+        }
+        if fields.is_empty() {
+            return Err(MappingError::Empty);
+        }
+        Ok(Self(fields))
+    }
+
+    /// Every field of the canonical bill, `category_reason` only if `with_reason`.
+    pub fn canonical(with_reason: bool) -> Self {
+        Self(
+            BILL_FIELDS
+                .into_iter()
+                .filter(|&field| with_reason || field != REASON_FIELD)
+                .map(|field| (field, field.to_string()))
+                .collect(),
+        )
+    }
+
+    /// The mapping of the `fields` and `category_reason` query parameters,
+    /// none if the bill keeps its canonical shape. Leaving out the reason
+    /// takes it off the listed fields too.
+    pub fn from_query(
+        fields: Option<&str>,
+        with_reason: bool,
+    ) -> Result<Option<Self>, MappingError> {
+        let Some(spec) = fields else {
+            return Ok((!with_reason).then(|| Self::canonical(false)));
+        };
+        let mut mapping = Self::parse(spec)?;
+        if !with_reason {
+            mapping.0.retain(|(field, _)| *field != REASON_FIELD);
+            if mapping.0.is_empty() {
+                return Err(MappingError::Empty);
+            }
+        }
+        Ok(Some(mapping))
+    }
+
+    pub fn keeps(&self, field: &str) -> bool {
+        self.0.iter().any(|(known, _)| *known == field)
+    }
+
+    pub fn apply<'a>(&'a self, bill: &'a Bill) -> MappedBill<'a> {
+        MappedBill {
+            mapping: self,
+            bill,
+        }
+    }
+}
+
+/// A bill serialized through a [BillMapping].
+#[derive(Debug)]
+pub struct MappedBill<'a> {
+    mapping: &'a BillMapping,
+    bill: &'a Bill,
+}
+
+impl MappedBill<'_> {
+    pub fn bill(&self) -> &Bill {
+        self.bill
+    }
+
+    pub fn mapping(&self) -> &BillMapping {
+        self.mapping
+    }
+}
+
+impl Serialize for MappedBill<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut canonical = serde_json::to_value(self.bill).map_err(serde::ser::Error::custom)?;
+        let mut map = serializer.serialize_map(Some(self.mapping.0.len()))?;
+        for (field, name) in &self.mapping.0 {
+            map.serialize_entry(name, &canonical[*field].take())?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapping() {
+        let bill = Bill {
+            notes: "Lunch".into(),
+            amount: 12.5,
+            currency: Some("EUR".into()),
+            categories: vec!["Food".into(), "Work".into()],
+            category_reason: None,
+            needs_review: false,
+        };
+        let mapping = BillMapping::parse("amount:total, notes:description,category").unwrap();
+        assert_eq!(
+            serde_json::to_string(&mapping.apply(&bill)).unwrap(),
+            r#"{"total":12.5,"description":"Lunch","category":"Food"}"#
+        );
+        let all = BillMapping::parse(&BILL_FIELDS.join(",")).unwrap();
+        assert_eq!(all, BillMapping::canonical(true));
+        assert_eq!(
+            serde_json::to_value(all.apply(&bill)).unwrap(),
+            serde_json::to_value(&bill).unwrap()
+        );
+
+        assert_eq!(BillMapping::from_query(None, true).unwrap(), None);
+        let without = BillMapping::from_query(None, false).unwrap().unwrap();
+        let json = serde_json::to_value(without.apply(&bill)).unwrap();
+        assert!(json.get(REASON_FIELD).is_none() && json.get("notes").is_some());
+        let without = BillMapping::from_query(Some("amount,category_reason:why"), false)
+            .unwrap()
+            .unwrap();
+        assert!(without.keeps("amount") && !without.keeps(REASON_FIELD));
+        assert!(matches!(
+            BillMapping::from_query(Some("category_reason"), false),
+            Err(MappingError::Empty)
+        ));
+        assert!(matches!(
+            BillMapping::parse("price"),
+            Err(MappingError::UnknownField(field)) if field == "price"
+        ));
+        assert!(matches!(
+            BillMapping::parse("amount:"),
+            Err(MappingError::BlankName(_))
+        ));
+        assert!(matches!(
+            BillMapping::parse("amount,amount:total"),
+            Err(MappingError::RepeatedField(_))
+        ));
+        assert!(matches!(
+            BillMapping::parse("amount:value,notes:value"),
+            Err(MappingError::RepeatedName(_))
+        ));
+        assert!(matches!(
+            BillMapping::parse(" , "),
+            Err(MappingError::Empty)
+        ));
+    }
+}
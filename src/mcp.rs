@@ -0,0 +1,322 @@
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{Level, event};
+
+use crate::{
+    bill::Category,
+    schedule::TASK_FOOTPRINT,
+    state::AppState,
+    task::{TaskControlBlock, ollama::OllamaTaskDescriptor},
+};
+
+const PROTOCOL_VERSION: &str = "2024-11-05";
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Value>,
+}
+
+impl Response {
+    fn result(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn error(id: Value, code: i64, message: impl Into<String>) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result: None,
+            error: Some(json!({ "code": code, "message": message.into() })),
+        }
+    }
+}
+
+/// Serves the Model Context Protocol over newline delimited JSON-RPC, until
+/// `reader` runs dry.
+///
+/// The tools are thin adapters over the same scheduler the HTTP API uses.
+pub async fn serve<R, W>(state: AppState, reader: R, mut writer: W) -> std::io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = reader.lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => handle(&state, request).await,
+            Err(err) => Some(Response::error(Value::Null, PARSE_ERROR, err.to_string())),
+        };
+        if let Some(response) = response {
+            let mut buf = serde_json::to_vec(&response)?;
+            buf.push(b'\n');
+            writer.write_all(&buf).await?;
+            writer.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+async fn handle(state: &AppState, request: Request) -> Option<Response> {
+    event!(target: "mcp", Level::DEBUG, "{}", request.method);
+    // notifications don't expect an answer
+    let id = request.id?;
+    let response = match request.method.as_str() {
+        "initialize" => Response::result(
+            id,
+            json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": { "tools": {} },
+                "serverInfo": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": option_env!("APP_VERSION").unwrap_or(env!("CARGO_PKG_VERSION")),
+                },
+            }),
+        ),
+        "ping" => Response::result(id, json!({})),
+        "tools/list" => Response::result(id, json!({ "tools": tools() })),
+        "tools/call" => match serde_json::from_value::<ToolCall>(request.params) {
+            Ok(call) => Response::result(id, call_tool(state, call).await),
+            Err(err) => Response::error(id, INVALID_PARAMS, err.to_string()),
+        },
+        method => Response::error(id, METHOD_NOT_FOUND, format!("unknown method {method}")),
+    };
+    Some(response)
+}
+
+fn tools() -> Value {
+    json!([
+        {
+            "name": "create_bookkeeping_task",
+            "description": "Extract a bill from a receipt or payment screenshot. Returns the pending task, poll it with get_task.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "image_base64": { "type": "string", "description": "Base64 encoded image" },
+                },
+                "required": ["image_base64"],
+            },
+        },
+        {
+            "name": "get_task",
+            "description": "Get the state of a bookkeeping task, including the bill once finished.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "id": { "type": "string" },
+                },
+                "required": ["id"],
+            },
+        },
+        {
+            "name": "list_categories",
+            "description": "List the categories bills are sorted into.",
+            "inputSchema": { "type": "object", "properties": {} },
+        },
+    ])
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "name", content = "arguments", rename_all = "snake_case")]
+enum ToolCall {
+    CreateBookkeepingTask { image_base64: String },
+    GetTask { id: String },
+    ListCategories {},
+}
+
+/// Checks the image like an upload to `POST /create_task` before creating the task.
+async fn create_task(state: &AppState, image_base64: String) -> Result<TaskControlBlock, String> {
+    let image = BASE64_STANDARD
+        .decode(image_base64.as_bytes())
+        .map_err(|err| format!("invalid image_base64: {err}"))?;
+    let reservation = state
+        .scheduler()
+        .reserve(image.len() + TASK_FOOTPRINT)
+        .await
+        .map_err(|err| err.to_string())?;
+    let task = OllamaTaskDescriptor::from_upload(image, state.max_upload_size())
+        .map_err(|err| err.to_string())?;
+    let _reservation = state
+        .accept(&task, reservation)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(state.scheduler().create_task(task).await)
+}
+
+async fn call_tool(state: &AppState, call: ToolCall) -> Value {
+    let result = match call {
+        ToolCall::CreateBookkeepingTask { image_base64 } => create_task(state, image_base64)
+            .await
+            .and_then(|task| serde_json::to_value(task).map_err(|err| err.to_string())),
+        ToolCall::GetTask { id } => match state.scheduler().get_task(id).await {
+            Ok(Some(task)) => serde_json::to_value(task).map_err(|err| err.to_string()),
+            Ok(None) => Err("task not found".to_string()),
+            Err(err) => Err(err.to_string()),
+        },
+        ToolCall::ListCategories {} => Ok(json!(
+            Category::all_cases()
+                .iter()
+                .map(Category::name)
+                .collect::<Vec<_>>()
+        )),
+    };
+    match result {
+        Ok(value) => json!({
+            "content": [{ "type": "text", "text": value.to_string() }],
+            "isError": false,
+        }),
+        Err(message) => json!({
+            "content": [{ "type": "text", "text": message }],
+            "isError": true,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, BufReader, DuplexStream, Lines, ReadHalf, WriteHalf};
+
+    use crate::args;
+
+    use super::*;
+
+    struct Client {
+        writer: WriteHalf<DuplexStream>,
+        lines: Lines<BufReader<ReadHalf<DuplexStream>>>,
+    }
+
+    impl Client {
+        async fn notify(&mut self, request: Value) {
+            self.writer
+                .write_all(format!("{request}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+
+        async fn call(&mut self, request: Value) -> Value {
+            self.notify(request).await;
+            serde_json::from_str(&self.lines.next_line().await.unwrap().unwrap()).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_and_tools() {
+        let _registry = crate::bill::lock_registry().await;
+        Category::load_from_names(["Food"]);
+        let (client, server) = tokio::io::duplex(4096);
+        let (server_read, server_write) = tokio::io::split(server);
+        tokio::spawn(serve(
+            AppState::new(&args::App::default()).unwrap(),
+            BufReader::new(server_read),
+            server_write,
+        ));
+        let (client_read, client_write) = tokio::io::split(client);
+        let mut client = Client {
+            writer: client_write,
+            lines: BufReader::new(client_read).lines(),
+        };
+
+        let init = client
+            .call(json!({
+                "jsonrpc": "2.0", "id": 1, "method": "initialize",
+                "params": {
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": { "name": "test", "version": "0" },
+                },
+            }))
+            .await;
+        assert_eq!(init["id"], 1);
+        assert_eq!(init["result"]["protocolVersion"], PROTOCOL_VERSION);
+        client
+            .notify(json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }))
+            .await;
+
+        // notifications are not answered, so the next line belongs to tools/list
+        let tools = client
+            .call(json!({ "jsonrpc": "2.0", "id": 2, "method": "tools/list" }))
+            .await;
+        assert_eq!(tools["id"], 2);
+        let names = tools["result"]["tools"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tool| tool["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["create_bookkeeping_task", "get_task", "list_categories"]
+        );
+
+        let categories = client
+            .call(json!({
+                "jsonrpc": "2.0", "id": 3, "method": "tools/call",
+                "params": { "name": "list_categories", "arguments": {} },
+            }))
+            .await;
+        assert_eq!(categories["result"]["isError"], false);
+
+        let missing = client
+            .call(json!({
+                "jsonrpc": "2.0", "id": 4, "method": "tools/call",
+                "params": { "name": "get_task", "arguments": { "id": "missing" } },
+            }))
+            .await;
+        assert_eq!(missing["result"]["isError"], true);
+
+        let invalid = client
+            .call(json!({
+                "jsonrpc": "2.0", "id": 5, "method": "tools/call",
+                "params": { "name": "create_bookkeeping_task", "arguments": { "image_base64": "!" } },
+            }))
+            .await;
+        assert_eq!(invalid["result"]["isError"], true);
+
+        let not_an_image = client
+            .call(json!({
+                "jsonrpc": "2.0", "id": 5, "method": "tools/call",
+                "params": {
+                    "name": "create_bookkeeping_task",
+                    "arguments": { "image_base64": BASE64_STANDARD.encode(b"not an image") },
+                },
+            }))
+            .await;
+        assert_eq!(not_an_image["result"]["isError"], true);
+        assert!(
+            not_an_image["result"]["content"][0]["text"]
+                .as_str()
+                .unwrap()
+                .starts_with("unsupported image format")
+        );
+
+        let unknown = client
+            .call(json!({ "jsonrpc": "2.0", "id": 6, "method": "resources/list" }))
+            .await;
+        assert_eq!(unknown["error"]["code"], METHOD_NOT_FOUND);
+    }
+}
@@ -0,0 +1,1523 @@
+//! Hand written OpenAPI document, since the task JSON comes from custom serializers.
+
+use serde_json::{Value, json};
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": {
+                "schema": { "$ref": "#/components/schemas/Error" }
+            }
+        }
+    })
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": { "schema": schema }
+        }
+    })
+}
+
+/// Representations of tasks offered to the `Accept` header, `schema` being the JSON one.
+fn task_content(schema: Value) -> Value {
+    json!({
+        "application/json": { "schema": schema },
+        "text/csv": {
+            "schema": {
+                "type": "string",
+                "description": "The header of GET /export.csv and a row for each successfully finished task"
+            }
+        },
+        "application/msgpack": {
+            "schema": {
+                "type": "string",
+                "format": "binary",
+                "description": "The tasks as MessagePack maps of id, state, success, error, created_at, finished_at, tags and deleted"
+            }
+        }
+    })
+}
+
+fn accept_parameter() -> Value {
+    json!({
+        "name": "Accept",
+        "in": "header",
+        "required": false,
+        "description": "application/json (the default), text/csv or application/msgpack, with quality values",
+        "schema": { "type": "string" }
+    })
+}
+
+fn task_id_parameter() -> Value {
+    json!({
+        "name": "task_id",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string" }
+    })
+}
+
+/// Query parameters of the exports, shared by every format.
+fn export_parameters() -> Value {
+    let mut parameters = filter_parameters();
+    parameters
+        .as_array_mut()
+        .expect("filter parameters are an array")
+        .push(category_reason_parameter());
+    parameters
+}
+
+/// Parameters of `GET /tasks`, the export filters among them.
+fn list_parameters() -> Value {
+    let mut parameters = json!([
+        {
+            "name": "needs_review",
+            "in": "query",
+            "required": false,
+            "schema": { "type": "boolean" }
+        },
+        deadline_parameter(),
+        accept_parameter()
+    ]);
+    let Value::Array(filters) = filter_parameters() else {
+        unreachable!("filter parameters are an array");
+    };
+    parameters
+        .as_array_mut()
+        .expect("parameters are an array")
+        .extend(filters);
+    parameters
+}
+
+/// Query parameters selecting bills, shared by the exports and `GET /tasks`.
+fn filter_parameters() -> Value {
+    json!([
+        {
+            "name": "from",
+            "in": "query",
+            "description": "Earliest finish, YYYY-MM-DD or RFC 3339, inclusive",
+            "schema": { "type": "string" }
+        },
+        {
+            "name": "to",
+            "in": "query",
+            "description": "Latest finish, YYYY-MM-DD or RFC 3339, inclusive",
+            "schema": { "type": "string" }
+        },
+        {
+            "name": "tz",
+            "in": "query",
+            "description": "Offset plain days are taken in, like +08:00, UTC by default",
+            "schema": { "type": "string" }
+        },
+        {
+            "name": "category",
+            "in": "query",
+            "description": "Only bills in one of these categories",
+            "schema": { "type": "array", "items": { "type": "string" } },
+            "explode": true
+        },
+        {
+            "name": "min_amount",
+            "in": "query",
+            "schema": { "type": "number" }
+        },
+        {
+            "name": "max_amount",
+            "in": "query",
+            "schema": { "type": "number" }
+        },
+        {
+            "name": "since_id",
+            "in": "query",
+            "description": "Only bills finishing after this cursor, the X-Export-Cursor header of the previous export, or after the task of this id while it is still known",
+            "schema": { "type": "string" }
+        }
+    ])
+}
+
+/// Header of the exports giving the `since_id` of the next one.
+fn cursor_header() -> Value {
+    json!({
+        "X-Export-Cursor": {
+            "description": "Opaque cursor passing the bills that finished after this export started, for since_id",
+            "schema": { "type": "string" }
+        }
+    })
+}
+
+fn deadline_parameter() -> Value {
+    json!({
+        "name": "X-Request-Deadline-Ms",
+        "in": "header",
+        "required": false,
+        "description": "Milliseconds to wait before answering 504, overriding the server default",
+        "schema": { "type": "integer", "minimum": 0 }
+    })
+}
+
+fn fields_parameter() -> Value {
+    json!({
+        "name": "fields",
+        "in": "query",
+        "required": false,
+        "description": "Reshapes the bill JSON as FIELD[:NAME],..., keeping only the listed fields in that order, renamed where a name is given. Other formats keep the canonical bill.",
+        "schema": { "type": "string" },
+        "example": "amount:total,notes:description,category"
+    })
+}
+
+fn category_reason_parameter() -> Value {
+    json!({
+        "name": "category_reason",
+        "in": "query",
+        "required": false,
+        "description": "false leaves the category_reason out of the bill",
+        "schema": { "type": "boolean", "default": true }
+    })
+}
+
+fn task_ref() -> Value {
+    json!({ "$ref": "#/components/schemas/Task" })
+}
+
+fn create_task_body() -> Value {
+    json!({
+        "required": true,
+        "content": {
+            "multipart/form-data": {
+                "schema": { "$ref": "#/components/schemas/CreateTaskForm" },
+                "encoding": {
+                    "image": { "contentType": "image/*, application/zip" },
+                    "lm_options": { "contentType": "application/json" },
+                    "vlm_options": { "contentType": "application/json" },
+                    "categories": { "contentType": "application/json" }
+                }
+            },
+            "image/*": {
+                "schema": { "type": "string", "format": "binary" }
+            },
+            "application/zip": {
+                "schema": { "type": "string", "format": "binary" }
+            }
+        }
+    })
+}
+
+/// The document for a server mounted under `base_path`.
+pub fn spec(base_path: &str) -> Value {
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": option_env!("APP_VERSION").unwrap_or(env!("CARGO_PKG_VERSION")),
+            "description": "Every response carries an `X-Request-Id` header, the one sent with the request or a generated one, which the server logs the request and the tasks it creates with.",
+        },
+        "servers": [
+            { "url": format!("{base_path}/v1") },
+            { "url": format!("{base_path}/"), "description": "Deprecated aliases of /v1" }
+        ],
+        "security": [{ "bearer": [] }],
+        "paths": {
+            "/create_task": {
+                "post": {
+                    "summary": "Queue a bookkeeping task",
+                    "parameters": [deadline_parameter()],
+                    "requestBody": create_task_body(),
+                    "responses": {
+                        "200": json_response("The queued task", task_ref()),
+                        "400": error_response("Malformed request or form field"),
+                        "401": error_response("Invalid key"),
+                        "413": error_response("The upload exceeds --max-upload-size"),
+                        "415": error_response("The image format was not recognized or can't be decoded"),
+                        "422": error_response("The quantization level is not offered"),
+                        "503": error_response("The task would exceed --memory-limit"),
+                        "504": error_response("The upload was not validated before the deadline"),
+                    }
+                }
+            },
+            "/create_task_sync": {
+                "post": {
+                    "summary": "Run a bookkeeping task and wait for its bill",
+                    "parameters": [fields_parameter(), category_reason_parameter()],
+                    "requestBody": create_task_body(),
+                    "responses": {
+                        "200": json_response("The extracted bill, reshaped if fields is given", json!({ "$ref": "#/components/schemas/Bill" })),
+                        "400": error_response("Malformed request, form field or fields mapping"),
+                        "401": error_response("Invalid key"),
+                        "413": error_response("The upload exceeds --max-upload-size"),
+                        "415": error_response("The image format was not recognized or can't be decoded"),
+                        "422": error_response("The quantization level is not offered"),
+                        "500": error_response("The task failed"),
+                        "503": error_response("The task would exceed --memory-limit"),
+                        "504": json_response(
+                            "The task did not finish within the sync timeout",
+                            json!({ "$ref": "#/components/schemas/TimeoutError" }),
+                        ),
+                    }
+                }
+            },
+            "/describe": {
+                "post": {
+                    "summary": "Describe images with the caption model, without extracting a bill",
+                    "description": "Runs only the description stage of a task right away, sampling with vlm_options. Fields only the later stages use are accepted and ignored.",
+                    "parameters": [
+                        {
+                            "name": "stream",
+                            "in": "query",
+                            "required": false,
+                            "description": "Streams the tokens as server-sent events, ending with a description or error event",
+                            "schema": { "type": "boolean" }
+                        }
+                    ],
+                    "requestBody": create_task_body(),
+                    "responses": {
+                        "200": {
+                            "description": "The description",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/Description" }
+                                },
+                                "text/event-stream": { "schema": { "type": "string" } }
+                            }
+                        },
+                        "400": error_response("Malformed request or form field"),
+                        "401": error_response("Invalid key"),
+                        "413": error_response("The upload exceeds --max-upload-size"),
+                        "415": error_response("The image format was not recognized or can't be decoded"),
+                        "422": error_response("The quantization level is not offered"),
+                        "429": {
+                            "description": "Too many descriptions, retry after the seconds of the Retry-After header",
+                            "headers": {
+                                "Retry-After": { "schema": { "type": "integer" } }
+                            },
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/Error" }
+                                }
+                            }
+                        },
+                        "500": error_response("The description stage failed"),
+                        "504": error_response("No runner slot or no description within the sync timeout"),
+                    }
+                }
+            },
+            "/get_task/{task_id}": {
+                "get": {
+                    "summary": "Get a task",
+                    "parameters": [
+                        task_id_parameter(),
+                        deadline_parameter(),
+                        accept_parameter(),
+                        fields_parameter(),
+                        category_reason_parameter(),
+                        {
+                            "name": "wait",
+                            "in": "query",
+                            "required": false,
+                            "description": "Seconds to hold the request until a pending or running task changes state, capped at 60. The task is returned as it is once the wait, or the request deadline, runs out.",
+                            "schema": { "type": "integer", "minimum": 0 }
+                        },
+                        {
+                            "name": "If-None-Match",
+                            "in": "header",
+                            "required": false,
+                            "description": "ETag of a finished task the client already has",
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "The task, with an ETag once finished",
+                            "headers": {
+                                "ETag": { "schema": { "type": "string" } }
+                            },
+                            "content": task_content(task_ref())
+                        },
+                        "304": { "description": "The finished task is unchanged" },
+                        "400": error_response("The fields mapping names an unknown field or a name twice, or wait isn't a number of seconds"),
+                        "401": error_response("Invalid key"),
+                        "404": error_response("Task not found"),
+                        "406": error_response("None of the accepted types is offered"),
+                        "500": error_response("Reading the swap failed"),
+                        "504": error_response("Deadline exceeded"),
+                    }
+                }
+            },
+            "/tasks": {
+                "get": {
+                    "summary": "List tasks",
+                    "description": "Any of the export filters leaves only successfully finished tasks with a matching bill.",
+                    "parameters": list_parameters(),
+                    "responses": {
+                        "200": {
+                            "description": "Matching tasks",
+                            "content": task_content(json!({ "type": "array", "items": task_ref() }))
+                        },
+                        "400": error_response("Invalid date, timezone or amount, or a since_id that is neither a cursor nor a known task"),
+                        "401": error_response("Invalid key"),
+                        "406": error_response("None of the accepted types is offered"),
+                        "500": error_response("Reading the swap failed"),
+                        "504": error_response("Deadline exceeded"),
+                    }
+                }
+            },
+            "/task/{task_id}": {
+                "patch": {
+                    "summary": "Update the bill of a finished task",
+                    "parameters": [task_id_parameter()],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": "#/components/schemas/PatchTask" }
+                            }
+                        }
+                    },
+                    "responses": {
+                        "200": json_response("The updated task", task_ref()),
+                        "401": error_response("Invalid key"),
+                        "404": error_response("Task not found"),
+                        "409": error_response("Task has not finished or failed without a bill"),
+                        "500": error_response("Rewriting the swap failed"),
+                    }
+                },
+                "delete": {
+                    "summary": "Soft delete or purge a finished task",
+                    "description": "Soft deleted tasks are left out of listings and exports, but can still be looked up, marked deleted. With purge=true the task is removed from memory, the swap file and the retained images instead. Both can be repeated safely.",
+                    "parameters": [task_id_parameter(), {
+                        "name": "purge",
+                        "in": "query",
+                        "schema": { "type": "boolean", "default": false }
+                    }],
+                    "responses": {
+                        "200": json_response("The soft deleted task", task_ref()),
+                        "204": { "description": "The task was purged, or was already gone" },
+                        "401": error_response("Invalid key"),
+                        "404": error_response("Task not found, for soft deletes"),
+                        "409": error_response("Task has not finished"),
+                        "500": error_response("Rewriting the swap failed"),
+                    }
+                }
+            },
+            "/task/{task_id}/tags": {
+                "put": {
+                    "summary": "Replace the tags of a task",
+                    "description": "Works on tasks in any state, in memory or swapped out. Tags are trimmed and deduplicated, keeping their order.",
+                    "parameters": [task_id_parameter()],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "type": "array", "items": { "type": "string" } }
+                            }
+                        }
+                    },
+                    "responses": {
+                        "200": json_response("The updated task", task_ref()),
+                        "400": error_response("A tag is empty or too long"),
+                        "401": error_response("Invalid key"),
+                        "404": error_response("Task not found"),
+                        "500": error_response("Rewriting the swap failed"),
+                    }
+                }
+            },
+            "/task/{task_id}/retry": {
+                "post": {
+                    "summary": "Resubmit a failed task as a new one",
+                    "description": "Needs the descriptor of the task, kept with --retain-descriptors until the task is swapped out.",
+                    "parameters": [task_id_parameter()],
+                    "responses": {
+                        "200": json_response("The new task", task_ref()),
+                        "401": error_response("Invalid key"),
+                        "404": error_response("Task not found"),
+                        "409": error_response("Task has not finished or succeeded"),
+                        "410": error_response("Images of the task are no longer retained"),
+                        "500": error_response("Reading the swap failed"),
+                    }
+                }
+            },
+            "/task/{task_id}/ask": {
+                "post": {
+                    "summary": "Ask a question about a finished bill",
+                    "description": "Answered by the extract model from the notes kept of the receipt, and its description if the task kept its intermediates, in a runner slot shared with the tasks and within the sync timeout. The bill is left as it is. Each key may ask --ask-rate-limit questions per minute, counted before the task is looked up.",
+                    "parameters": [task_id_parameter()],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": "#/components/schemas/Question" }
+                            }
+                        }
+                    },
+                    "responses": {
+                        "200": json_response("The answer", json!({ "$ref": "#/components/schemas/Answer" })),
+                        "400": error_response("Empty or overlong question"),
+                        "401": error_response("Invalid key"),
+                        "404": error_response("Task not found"),
+                        "409": error_response("Task has not finished or failed"),
+                        "429": {
+                            "description": "Too many questions, retry after the seconds of the Retry-After header",
+                            "headers": {
+                                "Retry-After": { "schema": { "type": "integer" } }
+                            },
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/Error" }
+                                }
+                            }
+                        },
+                        "500": error_response("Reading the swap failed"),
+                        "502": error_response("Ollama failed to answer"),
+                        "504": error_response("No runner slot or no answer within the sync timeout"),
+                    }
+                }
+            },
+            "/task/{task_id}/prompts": {
+                "get": {
+                    "summary": "Get the rendered prompt of each stage a task ran",
+                    "description": "Kept only with --retain-prompts, in redacted mode without the description and notes of the receipt. Each prompt is cut to --max-prompt-size bytes.",
+                    "parameters": [task_id_parameter()],
+                    "responses": {
+                        "200": json_response("The prompts", json!({ "$ref": "#/components/schemas/Prompts" })),
+                        "401": error_response("Invalid key"),
+                        "404": error_response("Task not found or no prompts were kept for it"),
+                        "500": error_response("Reading the swap failed"),
+                    }
+                }
+            },
+            "/export.jsonl": {
+                "get": {
+                    "summary": "Stream finished bills as JSON lines",
+                    "description": "Compressed with gzip if the Accept-Encoding header takes it.",
+                    "parameters": export_parameters(),
+                    "responses": {
+                        "200": {
+                            "description": "One ExportLine per line, in no particular order",
+                            "headers": cursor_header(),
+                            "content": {
+                                "application/x-ndjson": {
+                                    "schema": { "$ref": "#/components/schemas/ExportLine" }
+                                }
+                            }
+                        },
+                        "400": error_response("Invalid date, timezone, amount or category_reason, or a since_id that is neither a cursor nor a known task"),
+                        "401": error_response("Invalid key"),
+                    }
+                }
+            },
+            "/export.csv": {
+                "get": {
+                    "summary": "Stream finished bills as CSV",
+                    "description": "A header row, then one row per bill with the fields of ExportLine, categories joined by semicolons. Compressed with gzip if the Accept-Encoding header takes it.",
+                    "parameters": export_parameters(),
+                    "responses": {
+                        "200": {
+                            "description": "RFC 4180 CSV, rows in no particular order",
+                            "headers": cursor_header(),
+                            "content": {
+                                "text/csv": {
+                                    "schema": { "type": "string" }
+                                }
+                            }
+                        },
+                        "400": error_response("Invalid date, timezone, amount or category_reason, or a since_id that is neither a cursor nor a known task"),
+                        "401": error_response("Invalid key"),
+                    }
+                }
+            },
+            "/categories": {
+                "get": {
+                    "summary": "Categories the server chooses from by default",
+                    "parameters": [deadline_parameter()],
+                    "responses": {
+                        "200": json_response("Category names", json!({ "type": "array", "items": { "type": "string" } })),
+                        "401": error_response("Invalid key"),
+                        "504": error_response("Deadline exceeded"),
+                    }
+                }
+            },
+            "/capabilities": {
+                "get": {
+                    "summary": "Upload formats and limits, models and categories",
+                    "description": "Needs no key with --public-capabilities",
+                    "responses": {
+                        "200": json_response("Capabilities", json!({ "$ref": "#/components/schemas/Capabilities" })),
+                        "401": error_response("Invalid key"),
+                    }
+                }
+            },
+            "/admin/models": {
+                "get": {
+                    "summary": "Configured models and their pull progress",
+                    "parameters": [deadline_parameter()],
+                    "responses": {
+                        "200": json_response("Models", json!({
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/ModelStatus" }
+                        })),
+                        "401": error_response("Invalid key"),
+                        "504": error_response("Deadline exceeded"),
+                    }
+                }
+            },
+            "/admin/models/{model}": {
+                "patch": {
+                    "summary": "Pin or unpin a configured model",
+                    "description": "Pinned models are loaded right away and never expire, unpinned ones expire after the model timeout from now on.",
+                    "parameters": [{
+                        "name": "model",
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" }
+                    }],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": "#/components/schemas/PatchModel" }
+                            }
+                        }
+                    },
+                    "responses": {
+                        "200": json_response("The model", json!({ "$ref": "#/components/schemas/ModelStatus" })),
+                        "401": error_response("Invalid key"),
+                        "404": error_response("Model not configured"),
+                        "502": error_response("Ollama failed to load the model"),
+                    }
+                }
+            },
+            "/admin/config": {
+                "get": {
+                    "summary": "Settings that may change while serving",
+                    "responses": {
+                        "200": json_response("The effective settings", json!({ "$ref": "#/components/schemas/RuntimeConfig" })),
+                        "401": error_response("Invalid key"),
+                    }
+                },
+                "patch": {
+                    "summary": "Change settings while serving",
+                    "description": "Applies the given settings at once, or none of them if one is invalid, and saves them to --runtime-config if set. Lowering max_concurrency lets running tasks finish and starts no new ones until fewer run. model_timeouts replaces the whole map.",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": "#/components/schemas/ConfigPatch" }
+                            }
+                        }
+                    },
+                    "responses": {
+                        "200": json_response("The effective settings", json!({ "$ref": "#/components/schemas/RuntimeConfig" })),
+                        "401": error_response("Invalid key"),
+                        "422": error_response("Invalid or unknown setting"),
+                        "500": error_response("The settings could not be saved"),
+                    }
+                }
+            },
+            "/admin/task/{task_id}/images/{index}": {
+                "get": {
+                    "summary": "An image of a retained task",
+                    "description": "Uploads are numbered from zero as sent. With processed=true, images are numbered as the VLM saw them, one per picked frame of animations.",
+                    "parameters": [
+                        task_id_parameter(),
+                        {
+                            "name": "index",
+                            "in": "path",
+                            "required": true,
+                            "schema": { "type": "integer", "minimum": 0 }
+                        },
+                        {
+                            "name": "processed",
+                            "in": "query",
+                            "schema": { "type": "boolean", "default": false }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "The image, as uploaded or as a PNG when processed",
+                            "content": {
+                                "image/*": { "schema": { "type": "string", "format": "binary" } }
+                            }
+                        },
+                        "401": error_response("Invalid key"),
+                        "404": error_response("Task or image not retained"),
+                        "500": error_response("The image could not be decoded"),
+                    }
+                }
+            },
+            "/admin/task/{task_id}/stream": {
+                "get": {
+                    "summary": "Stream tokens of a running task",
+                    "description": "Server-sent events named after the token kind, carrying a Token as data.",
+                    "parameters": [task_id_parameter()],
+                    "responses": {
+                        "200": {
+                            "description": "Token stream, ends when the task finishes",
+                            "content": {
+                                "text/event-stream": {
+                                    "schema": { "$ref": "#/components/schemas/Token" }
+                                }
+                            }
+                        },
+                        "401": error_response("Invalid key"),
+                        "404": error_response("Task not found"),
+                        "409": error_response("Task is not running"),
+                    }
+                }
+            },
+            "/admin/events": {
+                "get": {
+                    "summary": "Stream scheduler events",
+                    "description": "Server-sent events named after the event type, carrying the event as JSON with its `type`. Events a slow client misses are skipped with a comment.",
+                    "responses": {
+                        "200": {
+                            "description": "Event stream, open until the client leaves",
+                            "content": {
+                                "text/event-stream": {
+                                    "schema": { "$ref": "#/components/schemas/SchedulerEvent" }
+                                }
+                            }
+                        },
+                        "401": error_response("Invalid key"),
+                    }
+                }
+            },
+            "/stats": {
+                "get": {
+                    "summary": "Counters of the scheduler",
+                    "responses": {
+                        "200": json_response("Statistics", json!({ "$ref": "#/components/schemas/Stats" })),
+                        "401": error_response("Invalid key"),
+                    }
+                }
+            },
+            "/admin/stats/reset": {
+                "post": {
+                    "summary": "Clear the queue wait and execution histograms",
+                    "responses": {
+                        "204": { "description": "Reset" },
+                        "401": error_response("Invalid key"),
+                    }
+                }
+            },
+            "/admin/clear_pending": {
+                "post": {
+                    "summary": "Cancel every task waiting in the queue",
+                    "description": "Running tasks are left alone.",
+                    "responses": {
+                        "200": json_response("Tasks cancelled", json!({
+                            "type": "object",
+                            "additionalProperties": false,
+                            "required": ["cleared"],
+                            "properties": { "cleared": { "type": "integer", "minimum": 0 } }
+                        })),
+                        "401": error_response("Invalid key"),
+                    }
+                }
+            },
+            "/metrics": {
+                "get": {
+                    "summary": "Model load counters and task latencies in the Prometheus text format",
+                    "description": "Per model: cache hits and misses of generations, evictions, and the time spent loading. Histograms of the time tasks waited in the queue and ran.",
+                    "responses": {
+                        "200": {
+                            "description": "Metrics",
+                            "content": { "text/plain": { "schema": { "type": "string" } } }
+                        },
+                        "401": error_response("Invalid key"),
+                    }
+                }
+            },
+            "/admin/backfill": {
+                "get": {
+                    "summary": "Progress of the latest backfill",
+                    "responses": {
+                        "200": json_response("Progress", json!({ "$ref": "#/components/schemas/BackfillProgress" })),
+                        "401": error_response("Invalid key"),
+                    }
+                },
+                "post": {
+                    "summary": "Re-run retained finished tasks",
+                    "responses": {
+                        "202": json_response("Backfill started", json!({ "$ref": "#/components/schemas/BackfillProgress" })),
+                        "401": error_response("Invalid key"),
+                        "409": error_response("A backfill is already running"),
+                        "500": error_response("Reading the swap failed"),
+                    }
+                }
+            },
+            "/admin/backup": {
+                "get": {
+                    "summary": "Archive of the finished tasks, retained images and categories",
+                    "responses": {
+                        "200": {
+                            "description": "Backup",
+                            "content": { "application/octet-stream": { "schema": { "type": "string", "format": "binary" } } }
+                        },
+                        "401": error_response("Invalid key"),
+                    }
+                }
+            },
+            "/admin/restore": {
+                "post": {
+                    "summary": "Restore a backup onto a server without tasks",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/octet-stream": { "schema": { "type": "string", "format": "binary" } } }
+                    },
+                    "responses": {
+                        "200": json_response("Restored", json!({
+                            "type": "object",
+                            "properties": {
+                                "tasks": { "type": "integer" },
+                                "descriptors": { "type": "integer" },
+                                "categories": { "type": "integer" }
+                            }
+                        })),
+                        "400": error_response("Not a backup, or a malformed one"),
+                        "401": error_response("Invalid key"),
+                        "409": error_response("The server already holds tasks"),
+                        "422": error_response("The backup is of an unsupported format version"),
+                    }
+                }
+            },
+        },
+        "components": {
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" }
+            },
+            "schemas": {
+                "Error": {
+                    "type": "object",
+                    "required": ["error"],
+                    "properties": {
+                        "error": { "type": "string" },
+                        "expected": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Form fields accepted, on unknown fields"
+                        },
+                        "received": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Form fields sent, on missing or unknown fields"
+                        },
+                        "supported": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Image formats, quantization levels or media types accepted, on unsupported ones"
+                        },
+                        "suggestion": {
+                            "type": "string",
+                            "description": "The accepted name a misspelled field or level likely meant"
+                        }
+                    },
+                    "additionalProperties": false
+                },
+                "TimeoutError": {
+                    "type": "object",
+                    "required": ["error", "id"],
+                    "properties": {
+                        "error": { "type": "string" },
+                        "id": { "type": "string", "description": "Task to keep polling" }
+                    },
+                    "additionalProperties": false
+                },
+                "CreateTaskForm": {
+                    "type": "object",
+                    "required": ["image"],
+                    "properties": {
+                        "image": {
+                            "type": "array",
+                            "description": "Images, or zip archives of images. May be repeated.",
+                            "items": { "type": "string", "format": "binary" }
+                        },
+                        "lm_options": {
+                            "type": "object",
+                            "description": "Ollama model options for the language model stages"
+                        },
+                        "vlm_options": {
+                            "type": "object",
+                            "description": "Ollama model options for the vision stages"
+                        },
+                        "categories": {
+                            "type": "array",
+                            "description": "Categories to choose from instead of the server's",
+                            "items": { "type": "string" }
+                        },
+                        "animation_frames": {
+                            "type": "string",
+                            "description": "Frames of animated images passed to the VLM instead of the server's --animation-frames",
+                            "pattern": "^(first|middle|last|every:[1-9][0-9]*(:[1-9][0-9]*)?)$"
+                        },
+                        "quantization": {
+                            "type": "string",
+                            "description": "One of the levels offered with --quantization, like q4_K_M, instead of the first one"
+                        },
+                        "preprocess": {
+                            "type": "string",
+                            "enum": ["auto", "off"],
+                            "description": "Crop uniform borders and enhance dim photos of paper receipts, off by default"
+                        },
+                        "categorize": {
+                            "type": "string",
+                            "enum": ["true", "false"],
+                            "description": "false skips the category stage, leaving the category of the bill null"
+                        },
+                        "categorizer": {
+                            "type": "string",
+                            "enum": ["lm", "embedding"],
+                            "description": "embedding picks the category nearest to the description by embedding similarity, falling back to lm if embeddings are unavailable, instead of the server's --categorizer"
+                        },
+                        "intermediates": {
+                            "type": "string",
+                            "enum": ["true", "false"],
+                            "description": "true keeps the output of each stage on the task, shown once it succeeded"
+                        },
+                        "deterministic": {
+                            "type": "string",
+                            "enum": ["true", "false"],
+                            "description": "true samples every stage with a fixed seed so the same images give the same bill, overriding the server default"
+                        }
+                    }
+                },
+                "Intermediates": {
+                    "description": "What each stage generated, present on successful tasks created with intermediates=true until they are swapped out",
+                    "type": "object",
+                    "propertyNames": { "enum": ["description", "notes", "amount", "category"] },
+                    "additionalProperties": { "type": "string" }
+                },
+                "Seeds": {
+                    "description": "Seed each stage sampled with, present on tasks run deterministically",
+                    "type": "object",
+                    "propertyNames": { "enum": ["description", "notes", "amount", "category"] },
+                    "additionalProperties": { "type": "integer" }
+                },
+                "Prompts": {
+                    "description": "Rendered prompt of each stage run so far, by stage",
+                    "type": "object",
+                    "propertyNames": { "enum": ["description", "notes", "amount", "category"] },
+                    "additionalProperties": { "type": "string" }
+                },
+                "Task": {
+                    "description": "A pending or running task carries id and state only. Finished tasks carry exactly one of success and error.",
+                    "type": "object",
+                    "required": ["id", "state"],
+                    "properties": {
+                        "id": { "type": "string" },
+                        "state": {
+                            "type": "string",
+                            "enum": ["pending", "running", "finished"]
+                        },
+                        "success": {
+                            "oneOf": [{ "$ref": "#/components/schemas/Bill" }, { "type": "null" }]
+                        },
+                        "error": { "type": ["string", "null"] },
+                        "deleted": { "const": true, "description": "Present once the task was soft deleted" },
+                        "intermediates": { "$ref": "#/components/schemas/Intermediates" },
+                        "seeds": { "$ref": "#/components/schemas/Seeds" }
+                    },
+                    "additionalProperties": false
+                },
+                "TaskV2": {
+                    "description": "Task shape served under /v2, where every path returns this instead of Task.",
+                    "type": "object",
+                    "required": ["id", "state", "needs_review", "bill", "error", "created_at", "finished_at", "tags", "deleted"],
+                    "properties": {
+                        "id": { "type": "string" },
+                        "state": {
+                            "type": "string",
+                            "enum": ["pending", "running", "finished"]
+                        },
+                        "needs_review": { "type": "boolean" },
+                        "bill": {
+                            "oneOf": [{ "$ref": "#/components/schemas/Bill" }, { "type": "null" }]
+                        },
+                        "error": {
+                            "oneOf": [
+                                {
+                                    "type": "object",
+                                    "required": ["code", "message", "retryable", "stage"],
+                                    "properties": {
+                                        "code": {
+                                            "type": "string",
+                                            "enum": [
+                                                "prepare", "runner", "stage", "stage_timeout", "invalid_input_image",
+                                                "too_many_images", "missing_chat_template", "quantization_not_offered",
+                                                "invalid_output", "cancelled", "empty_model_response"
+                                            ]
+                                        },
+                                        "message": { "type": "string" },
+                                        "retryable": {
+                                            "type": "boolean",
+                                            "description": "Whether retrying the task may succeed"
+                                        },
+                                        "stage": {
+                                            "description": "Pipeline stage that failed, null if the task failed outside of one",
+                                            "enum": ["description", "notes", "amount", "category", null]
+                                        }
+                                    },
+                                    "additionalProperties": false
+                                },
+                                { "type": "null" }
+                            ]
+                        },
+                        "created_at": { "type": "string", "format": "date-time" },
+                        "finished_at": { "type": ["string", "null"], "format": "date-time" },
+                        "tags": { "type": "array", "items": { "type": "string" } },
+                        "deleted": { "type": "boolean" },
+                        "intermediates": { "$ref": "#/components/schemas/Intermediates" },
+                        "seeds": { "$ref": "#/components/schemas/Seeds" }
+                    },
+                    "additionalProperties": false
+                },
+                "Bill": {
+                    "type": "object",
+                    "required": ["notes", "amount", "currency", "formatted_amount", "category", "categories", "needs_review"],
+                    "properties": {
+                        "notes": { "type": "string" },
+                        "amount": { "type": "number" },
+                        "currency": { "type": ["string", "null"] },
+                        "formatted_amount": { "type": ["string", "null"] },
+                        "category": {
+                            "type": ["string", "null"],
+                            "description": "The primary category, first of categories"
+                        },
+                        "categories": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Every category the purchase falls into, the best matching first"
+                        },
+                        "category_reason": {
+                            "type": ["string", "null"],
+                            "maxLength": 200,
+                            "description": "The model's one line on why the primary category fits, null if it gave none or the category stage didn't run. Left out with category_reason=false"
+                        },
+                        "needs_review": { "type": "boolean" }
+                    },
+                    "additionalProperties": false
+                },
+                "ExportLine": {
+                    "type": "object",
+                    "required": ["id", "created_at", "finished_at", "notes", "amount", "currency", "formatted_amount", "category", "categories", "needs_review"],
+                    "properties": {
+                        "id": { "type": "string" },
+                        "created_at": { "type": "string", "format": "date-time" },
+                        "finished_at": { "type": "string", "format": "date-time" },
+                        "notes": { "type": "string" },
+                        "amount": { "type": "number" },
+                        "currency": { "type": ["string", "null"] },
+                        "formatted_amount": { "type": ["string", "null"] },
+                        "category": {
+                            "type": ["string", "null"],
+                            "description": "The primary category, first of categories"
+                        },
+                        "categories": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Every category the purchase falls into, the best matching first"
+                        },
+                        "category_reason": {
+                            "type": ["string", "null"],
+                            "maxLength": 200,
+                            "description": "The model's one line on why the primary category fits, null if it gave none or the category stage didn't run. Left out with category_reason=false"
+                        },
+                        "needs_review": { "type": "boolean" }
+                    },
+                    "additionalProperties": false
+                },
+                "PatchTask": {
+                    "type": "object",
+                    "properties": {
+                        "needs_review": { "type": "boolean" }
+                    }
+                },
+                "PatchModel": {
+                    "type": "object",
+                    "required": ["pinned"],
+                    "properties": {
+                        "pinned": { "type": "boolean" }
+                    }
+                },
+                "Question": {
+                    "type": "object",
+                    "required": ["question"],
+                    "properties": {
+                        "question": { "type": "string", "maxLength": 1000 }
+                    },
+                    "additionalProperties": false
+                },
+                "Answer": {
+                    "type": "object",
+                    "required": ["answer"],
+                    "properties": {
+                        "answer": { "type": "string" }
+                    },
+                    "additionalProperties": false
+                },
+                "RuntimeConfig": {
+                    "type": "object",
+                    "required": ["max_concurrency", "max_memory_size", "model_timeout_secs", "model_timeouts"],
+                    "properties": {
+                        "max_concurrency": { "type": "integer", "minimum": 1 },
+                        "max_memory_size": {
+                            "type": "integer",
+                            "minimum": 0,
+                            "description": "Finished tasks kept in memory before they are swapped out"
+                        },
+                        "model_timeout_secs": {
+                            "type": "integer",
+                            "minimum": 0,
+                            "description": "Keep alive of models not listed in model_timeouts"
+                        },
+                        "model_timeouts": {
+                            "type": "object",
+                            "additionalProperties": { "type": "integer", "minimum": 0 },
+                            "description": "Keep alive in seconds by model"
+                        }
+                    },
+                    "additionalProperties": false
+                },
+                "ConfigPatch": {
+                    "type": "object",
+                    "properties": {
+                        "max_concurrency": { "type": "integer", "minimum": 1 },
+                        "max_memory_size": { "type": "integer", "minimum": 0 },
+                        "model_timeout_secs": { "type": "integer", "minimum": 0 },
+                        "model_timeouts": {
+                            "type": "object",
+                            "additionalProperties": { "type": "integer", "minimum": 0 }
+                        }
+                    },
+                    "additionalProperties": false
+                },
+                "ModelStatus": {
+                    "type": "object",
+                    "required": ["id", "roles", "pinned", "pull"],
+                    "properties": {
+                        "id": { "type": "string" },
+                        "roles": {
+                            "type": "array",
+                            "items": { "type": "string", "enum": ["caption", "extract"] }
+                        },
+                        "pinned": { "type": "boolean", "description": "Kept loaded regardless of the model timeout" },
+                        "pull": {
+                            "oneOf": [{ "$ref": "#/components/schemas/PullProgress" }, { "type": "null" }]
+                        }
+                    },
+                    "additionalProperties": false
+                },
+                "PullProgress": {
+                    "type": "object",
+                    "required": ["status", "digest", "completed", "total", "retries"],
+                    "properties": {
+                        "status": { "type": "string" },
+                        "digest": { "type": ["string", "null"] },
+                        "completed": { "type": ["integer", "null"] },
+                        "total": { "type": ["integer", "null"] },
+                        "retries": { "type": "integer" }
+                    },
+                    "additionalProperties": false
+                },
+                "Token": {
+                    "type": "object",
+                    "required": ["stage", "kind", "text"],
+                    "properties": {
+                        "stage": {
+                            "type": "string",
+                            "enum": ["description", "notes", "amount", "category"]
+                        },
+                        "kind": {
+                            "type": "string",
+                            "enum": ["started", "thinking", "response", "done"]
+                        },
+                        "text": { "type": "string" }
+                    },
+                    "additionalProperties": false
+                },
+                "SchedulerEvent": {
+                    "type": "object",
+                    "required": ["type"],
+                    "properties": {
+                        "type": {
+                            "type": "string",
+                            "enum": ["task_created", "task_started", "task_finished", "task_swapped", "model_loaded", "model_evicted"]
+                        },
+                        "id": { "type": "string" },
+                        "stage": {
+                            "type": "string",
+                            "enum": ["description", "notes", "amount", "category"]
+                        },
+                        "success": { "type": "boolean" },
+                        "count": { "type": "integer" },
+                        "model": { "type": "string" },
+                        "seconds": { "type": "number" }
+                    }
+                },
+                "BackfillProgress": {
+                    "type": "object",
+                    "required": ["running", "total", "submitted", "tasks", "skipped"],
+                    "properties": {
+                        "running": { "type": "boolean" },
+                        "total": { "type": "integer" },
+                        "submitted": { "type": "integer" },
+                        "tasks": { "type": "array", "items": { "type": "string" } },
+                        "skipped": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Finished tasks without a retained descriptor, such as those swapped to disk"
+                        }
+                    },
+                    "additionalProperties": false
+                },
+                "Capabilities": {
+                    "type": "object",
+                    "required": [
+                        "formats", "max_upload_size", "max_images", "caption_model",
+                        "extract_model", "quantizations", "categories"
+                    ],
+                    "properties": {
+                        "formats": { "type": "array", "items": { "type": "string" } },
+                        "max_upload_size": { "type": "integer", "description": "Bytes" },
+                        "max_images": { "type": ["integer", "null"], "description": "Images per task, null if unlimited" },
+                        "caption_model": { "type": "string" },
+                        "extract_model": { "type": "string" },
+                        "quantizations": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Levels tasks may pick, the first being the default"
+                        },
+                        "categories": { "type": "array", "items": { "type": "string" } }
+                    },
+                    "additionalProperties": false
+                },
+                "Description": {
+                    "type": "object",
+                    "required": ["description"],
+                    "properties": {
+                        "description": { "type": "string" }
+                    },
+                    "additionalProperties": false
+                },
+                "LatencySummary": {
+                    "type": "object",
+                    "description": "Percentiles in seconds, estimated from fixed histogram buckets, null before the first task",
+                    "required": ["count", "p50", "p90", "p99"],
+                    "properties": {
+                        "count": { "type": "integer" },
+                        "p50": { "type": ["number", "null"] },
+                        "p90": { "type": ["number", "null"] },
+                        "p99": { "type": ["number", "null"] }
+                    },
+                    "additionalProperties": false
+                },
+                "Stats": {
+                    "type": "object",
+                    "required": ["swap_cache", "swap_reads", "queue_wait", "execution", "residency"],
+                    "properties": {
+                        "swap_cache": {
+                            "type": "object",
+                            "required": ["hits", "misses", "size", "capacity"],
+                            "properties": {
+                                "hits": { "type": "integer" },
+                                "misses": { "type": "integer" },
+                                "size": { "type": "integer" },
+                                "capacity": { "type": "integer" }
+                            },
+                            "additionalProperties": false
+                        },
+                        "swap_reads": { "type": "integer" },
+                        "queue_wait": {
+                            "$ref": "#/components/schemas/LatencySummary",
+                            "description": "From creating a task until it starts running"
+                        },
+                        "execution": {
+                            "$ref": "#/components/schemas/LatencySummary",
+                            "description": "From starting a task until it finishes"
+                        },
+                        "residency": {
+                            "type": "string",
+                            "enum": ["normal", "quiet"],
+                            "description": "quiet during --quiet-hours, when models are unloaded shortly after use"
+                        }
+                    },
+                    "additionalProperties": false
+                },
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+    use tower::ServiceExt;
+
+    use crate::{
+        bill::Bill,
+        config::RuntimeConfig,
+        error::{CreateTaskError, GetTaskError, RunTaskError, SyncTaskError},
+        schedule::{BackfillProgress, Scheduler},
+        task::{self, Stage, TaskControlBlock, Token, TokenKind},
+        version::{ApiVersion, TaskJson},
+    };
+
+    use super::*;
+
+    fn resolve<'a>(spec: &'a Value, schema: &'a Value) -> &'a Value {
+        match schema.get("$ref").and_then(Value::as_str) {
+            Some(path) => spec
+                .pointer(path.trim_start_matches('#'))
+                .unwrap_or_else(|| panic!("dangling {path}")),
+            None => schema,
+        }
+    }
+
+    /// Checks the subset of JSON schema used by [spec].
+    fn conforms(spec: &Value, schema: &Value, value: &Value) -> bool {
+        let schema = resolve(spec, schema);
+        if let Some(options) = schema.get("oneOf").and_then(Value::as_array) {
+            return options
+                .iter()
+                .filter(|option| conforms(spec, option, value))
+                .count()
+                == 1;
+        }
+        if let Some(variants) = schema.get("enum").and_then(Value::as_array)
+            && !variants.contains(value)
+        {
+            return false;
+        }
+        let types = match schema.get("type") {
+            Some(Value::String(t)) => vec![t.as_str()],
+            Some(Value::Array(ts)) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => return true,
+        };
+        types.into_iter().any(|t| match (t, value) {
+            ("null", Value::Null) | ("boolean", Value::Bool(_)) | ("string", Value::String(_)) => {
+                true
+            }
+            ("number", Value::Number(_)) => true,
+            ("integer", Value::Number(n)) => n.is_u64() || n.is_i64(),
+            ("array", Value::Array(items)) => items
+                .iter()
+                .all(|item| conforms(spec, &schema["items"], item)),
+            ("object", Value::Object(fields)) => {
+                let properties = schema
+                    .get("properties")
+                    .and_then(Value::as_object)
+                    .cloned()
+                    .unwrap_or_default();
+                // maps describe their values rather than their keys
+                let values = schema.get("additionalProperties").filter(|s| s.is_object());
+                let required = schema
+                    .get("required")
+                    .and_then(Value::as_array)
+                    .cloned()
+                    .unwrap_or_default();
+                let names = schema.get("propertyNames");
+                required
+                    .iter()
+                    .all(|name| fields.contains_key(name.as_str().unwrap()))
+                    && names.is_none_or(|names| {
+                        fields
+                            .keys()
+                            .all(|name| conforms(spec, names, &Value::String(name.clone())))
+                    })
+                    && fields.iter().all(|(name, field)| {
+                        properties
+                            .get(name)
+                            .or(values)
+                            .is_some_and(|property| conforms(spec, property, field))
+                    })
+            }
+            _ => false,
+        })
+    }
+
+    fn assert_conforms(spec: &Value, schema: &str, value: Value) {
+        let schema = json!({ "$ref": format!("#/components/schemas/{schema}") });
+        assert!(conforms(spec, &schema, &value), "{schema} rejects {value}");
+    }
+
+    async fn response_body(response: impl IntoResponse) -> Value {
+        let body = axum::body::to_bytes(response.into_response().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_served_spec() {
+        let response =
+            crate::server::app(crate::state::AppState::new(&Default::default()).unwrap())
+                .oneshot(
+                    axum::extract::Request::get("/openapi.json")
+                        .body(axum::body::Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        let served: Value = serde_json::from_slice(
+            &axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(served, spec(""));
+        for (_, item) in served["paths"].as_object().unwrap() {
+            for (_, operation) in item.as_object().unwrap() {
+                for (_, response) in operation["responses"].as_object().unwrap() {
+                    // 304 and the like come without a body
+                    let Some(content) = response.get("content") else {
+                        continue;
+                    };
+                    for (_, media) in content.as_object().unwrap() {
+                        resolve(&served, &media["schema"]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_examples_round_trip() {
+        let spec = spec("");
+        let bill = Bill {
+            notes: "Toy \"horse\" from Xianyu".into(),
+            amount: 21.88,
+            currency: Some("CNY".into()),
+            categories: vec!["Shopping".into()],
+            category_reason: None,
+            needs_review: false,
+        };
+        let tcb = TaskControlBlock::new();
+        let v1 = async |tcb: &TaskControlBlock| {
+            response_body(TaskJson(ApiVersion::V1, tcb.clone())).await
+        };
+        assert_conforms(&spec, "Task", v1(&tcb).await);
+        tcb.set_state(task::State::Running);
+        assert_conforms(&spec, "Task", v1(&tcb).await);
+        tcb.set_state(task::State::Finished(Err(RunTaskError::InvalidOutput(
+            "price".into(),
+        )
+        .into())));
+        assert_conforms(&spec, "Task", v1(&tcb).await);
+        tcb.set_state(task::State::Finished(Ok(task::Success(bill.clone()))));
+        let value = v1(&tcb).await;
+        assert_conforms(&spec, "Task", value.clone());
+        let parsed: TaskControlBlock = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(v1(&parsed).await, value);
+        assert_conforms(
+            &spec,
+            "TaskV2",
+            response_body(TaskJson(ApiVersion::V2, tcb.clone())).await,
+        );
+        let intermediates = task::StageTexts::default();
+        intermediates.record(Stage::Description, "A receipt of a toy horse");
+        intermediates.record(Stage::Amount, r#"{"amount": 21.88}"#);
+        let kept = TaskControlBlock::new().with_intermediates(intermediates);
+        assert_eq!(
+            v1(&kept).await.get("intermediates"),
+            None,
+            "only once succeeded"
+        );
+        kept.set_state(task::State::Finished(Ok(task::Success(bill.clone()))));
+        let value = v1(&kept).await;
+        assert_eq!(value["intermediates"]["amount"], r#"{"amount": 21.88}"#);
+        assert_conforms(&spec, "Task", value);
+        assert_conforms(
+            &spec,
+            "TaskV2",
+            response_body(TaskJson(ApiVersion::V2, kept)).await,
+        );
+        let value = serde_json::to_value(&bill).unwrap();
+        assert_conforms(&spec, "Bill", value.clone());
+        let parsed: Bill = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), value);
+
+        assert_conforms(
+            &spec,
+            "Token",
+            serde_json::to_value(Token {
+                stage: Stage::Amount,
+                kind: TokenKind::Response,
+                text: "21".into(),
+            })
+            .unwrap(),
+        );
+        assert_conforms(
+            &spec,
+            "BackfillProgress",
+            serde_json::to_value(BackfillProgress::default()).unwrap(),
+        );
+        assert_conforms(
+            &spec,
+            "ModelStatus",
+            serde_json::to_value(
+                crate::task::ollama::OllamaRunTask::default().model_status()[0].clone(),
+            )
+            .unwrap(),
+        );
+        let state = crate::state::AppState::new(&crate::args::App {
+            max_images: Some(4),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_conforms(
+            &spec,
+            "Capabilities",
+            serde_json::to_value(crate::server::Capabilities::of(&state)).unwrap(),
+        );
+        let mut config = RuntimeConfig::from_args(&Default::default());
+        config.model_timeouts.insert("gemma3:4b".into(), 600);
+        assert_conforms(
+            &spec,
+            "RuntimeConfig",
+            serde_json::to_value(config).unwrap(),
+        );
+        let scheduler = Scheduler::<crate::task::ollama::OllamaRunTask>::default();
+        scheduler
+            .latency()
+            .execution
+            .record(std::time::Duration::from_secs(3));
+        let mut stats = serde_json::to_value(scheduler.stats()).unwrap();
+        stats["residency"] = json!("normal");
+        assert_eq!(stats["queue_wait"]["p50"], Value::Null);
+        assert_conforms(&spec, "Stats", stats);
+        assert_conforms(&spec, "Error", response_body(GetTaskError::NotFound).await);
+        assert_conforms(
+            &spec,
+            "Error",
+            response_body(GetTaskError::NotAcceptable(crate::error::Names(vec![
+                "application/json".into(),
+            ])))
+            .await,
+        );
+        assert_conforms(
+            &spec,
+            "Error",
+            response_body(CreateTaskError::MissingField {
+                name: "image".into(),
+                received: Default::default(),
+            })
+            .await,
+        );
+        assert_conforms(
+            &spec,
+            "TimeoutError",
+            response_body(SyncTaskError::Timeout("id".into())).await,
+        );
+        // the checker itself must reject mismatches
+        assert!(!conforms(
+            &spec,
+            &json!({ "$ref": "#/components/schemas/Task" }),
+            &value
+        ));
+    }
+}
@@ -0,0 +1,88 @@
+use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
+use tracing::{Instrument, Level, span};
+
+use crate::key;
+
+/// Identifies a request across the client, the server logs and the response.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Name of the span every request is served in.
+pub const REQUEST_SPAN: &str = "request";
+
+/// Longest id taken from a client, so logs can't be flooded through it.
+const MAX_LEN: usize = 128;
+
+/// Serves the request in a span carrying the id the client sent in
+/// [REQUEST_ID_HEADER], or a fresh one if it sent none or an unusable one,
+/// and echoes the id in the response. Tasks created by the request are logged
+/// within that span, linking both ids.
+pub async fn tag(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| valid(id))
+        .map_or_else(key::generate_random_key, str::to_string);
+    let mut response = next
+        .run(request)
+        .instrument(span!(Level::INFO, REQUEST_SPAN, id))
+        .await;
+    let value = HeaderValue::from_str(&id).expect("ids are visible ASCII");
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    response
+}
+
+fn valid(id: &str) -> bool {
+    (1..=MAX_LEN).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, middleware::from_fn, routing::get};
+    use tower::ServiceExt;
+    use tracing::event;
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    async fn call(header: Option<&str>) -> Option<String> {
+        let app = axum::Router::new()
+            .route(
+                "/",
+                get(async || {
+                    event!(Level::INFO, "handling");
+                    "ok"
+                }),
+            )
+            .layer(from_fn(tag));
+        let mut request = Request::get("/");
+        if let Some(header) = header {
+            request = request.header(REQUEST_ID_HEADER, header);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        response
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_request_id() {
+        assert_eq!(call(Some("trace-42")).await.as_deref(), Some("trace-42"));
+        assert!(logs_contain("request{id=\"trace-42\"}"));
+
+        let generated = call(None).await.unwrap();
+        assert!(valid(&generated));
+        assert_ne!(call(None).await.unwrap(), generated);
+
+        for unusable in ["", "with space", &"x".repeat(MAX_LEN + 1)] {
+            let id = call(Some(unusable)).await.unwrap();
+            assert_ne!(id, unusable);
+            assert!(valid(&id));
+        }
+    }
+}
@@ -1,13 +1,21 @@
 use std::{
+    collections::{HashMap, HashSet},
     io::{self, SeekFrom},
-    sync::Arc,
-    time::Duration,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::anyhow;
 use async_stream::try_stream;
-use futures::{Stream, StreamExt, TryStreamExt, stream};
-use tempfile::tempfile;
+use futures::{FutureExt, Stream, TryStreamExt};
+use lru::LruCache;
+use serde::Serialize;
+use tempfile::{tempfile, tempfile_in};
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
@@ -15,41 +23,209 @@ use tokio::{
     sync::Mutex,
     task::JoinHandle,
 };
-use tracing::{Level, event};
+use tracing::{Instrument, Level, event};
 
-use crate::task::{self, RunTask, TaskControlBlock};
+use crate::{
+    bill::{Bill, BillV2},
+    error::{
+        BackfillError, CreateTaskError, RetryTaskError, RunTaskError, TaskError, UpdateTaskError,
+    },
+    events::{EventBus, SchedulerEvent},
+    task::{self, RunTask, TaskControlBlock, TaskDescriptor},
+    version::Rendered,
+};
+
+const BACKFILL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How often [Scheduler::slot] checks for a free runner slot.
+const SLOT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+pub const DEFAULT_SWAP_CACHE_SIZE: usize = 256;
+/// Finished tasks whose bodies [Scheduler::rendered] keeps.
+const RENDER_CACHE_SIZE: usize = 256;
+/// Layout of the tasks in swap chunks, the first byte of each. Bumped whenever
+/// [task::SwappedTask] changes, keeping a reader of every older one.
+const SWAP_VERSION: u8 = 7;
+
+type ActiveQueue = Vec<(TaskControlBlock, JoinHandle<()>)>;
+type PendingQueue<Task> = Vec<(TaskControlBlock, Arc<Task>)>;
+
+/// Failure of a task whose runner panicked, with the panic's message.
+fn panicked(payload: Box<dyn std::any::Any + Send>) -> RunTaskError {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "no message".to_string());
+    RunTaskError::Runner(anyhow!("runner panicked: {message}"))
+}
 
 struct ScheduleQueues<Task> {
-    active: Arc<Mutex<Vec<(TaskControlBlock, JoinHandle<()>)>>>,
-    pending: Arc<Mutex<Vec<(TaskControlBlock, Arc<Task>)>>>,
+    active: Arc<Mutex<ActiveQueue>>,
+    pending: Arc<Mutex<PendingQueue<Task>>>,
     finished: Arc<Mutex<Vec<TaskControlBlock>>>,
+    /// Descriptors of finished tasks still in memory, if retention is enabled
+    retained: Arc<Mutex<HashMap<String, Arc<Task>>>>,
 }
 
 pub struct Scheduler<Runner: RunTask> {
     queues: Arc<ScheduleQueues<Runner::TaskDescriptor>>,
     swap_file: Arc<Mutex<File>>,
-    max_memory_size: usize,
-    max_concurrency: usize,
+    /// Where swap files are created, the OS temporary directory if absent
+    swap_dir: Option<PathBuf>,
+    /// Finished tasks kept in memory, tunable while serving
+    max_memory_size: Arc<AtomicUsize>,
+    /// Tasks running at once, tunable while serving
+    max_concurrency: AtomicUsize,
+    retain_descriptors: bool,
+    backfill: Arc<std::sync::Mutex<BackfillProgress>>,
+    swap_cache: SwapCache,
+    render_cache: RenderCache,
+    /// Chunks read from the swap file so far
+    swap_reads: AtomicU64,
+    /// Bumped whenever the swap file is replaced by a rewritten one, so
+    /// readers between chunks know their offset is stale
+    swap_generation: AtomicU64,
+    latency: Arc<Latency>,
+    dedup_window: Option<Duration>,
+    /// Unfinished tasks by the hash of their descriptors, with when they were submitted
+    recent_submissions: std::sync::Mutex<HashMap<u64, (Instant, TaskControlBlock)>>,
+    /// Descriptors of every task enqueued, alive while pending, running or retained
+    descriptors: std::sync::Mutex<Vec<Weak<Runner::TaskDescriptor>>>,
+    /// Bytes [Scheduler::memory_usage] may reach before new tasks are turned away
+    memory_limit: Option<usize>,
+    /// Bytes of the [Reservation]s out, for tasks on their way in
+    reserved: Arc<std::sync::Mutex<usize>>,
+    /// Runner slots taken by [Slot]s, counted against `max_concurrency`
+    /// beside the running tasks
+    slots: AtomicUsize,
+    events: EventBus,
     runner: Runner,
 }
 
+/// Rough bytes a task takes in memory besides its images, its bill and bookkeeping.
+pub const TASK_FOOTPRINT: usize = 1024;
+
+/// Bytes set aside by [Scheduler::reserve] for a task on its way in, counted
+/// by [Scheduler::memory_usage] until dropped. Once the task is created its
+/// images are counted instead, for as long as it holds them.
+#[derive(Debug)]
+pub struct Reservation {
+    reserved: Arc<std::sync::Mutex<usize>>,
+    bytes: usize,
+}
+
+impl Reservation {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        *self.reserved.lock().unwrap() -= self.bytes;
+    }
+}
+
+/// A runner slot taken by [Scheduler::slot], given back when dropped.
+pub struct Slot {
+    release: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(release) = self.release.take() {
+            release();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BackfillProgress {
+    pub running: bool,
+    pub total: usize,
+    pub submitted: usize,
+    /// Ids of the tasks created by the latest backfill
+    pub tasks: Vec<String>,
+    /// Ids of the finished tasks left out for lack of a retained descriptor,
+    /// those swapped to disk among them
+    pub skipped: Vec<String>,
+}
+
 impl<Runner> Scheduler<Runner>
 where
     Runner: RunTask,
 {
+    /// Fails if the swap file can't be created in the temporary directory.
     pub fn new(
         max_concurrency: usize,
         max_memory_size: usize,
         _model_timeout: Duration,
         runner: Runner,
-    ) -> Self {
-        Self {
+    ) -> io::Result<Self> {
+        Ok(Self {
             queues: Default::default(),
-            max_memory_size,
-            swap_file: Arc::new(Mutex::new(tempfile().map(File::from_std).unwrap())),
-            max_concurrency,
+            max_memory_size: Arc::new(AtomicUsize::new(max_memory_size)),
+            swap_file: Arc::new(Mutex::new(File::from_std(tempfile()?))),
+            swap_dir: None,
+            max_concurrency: AtomicUsize::new(max_concurrency),
+            retain_descriptors: false,
+            backfill: Default::default(),
+            swap_cache: SwapCache::new(DEFAULT_SWAP_CACHE_SIZE),
+            render_cache: RenderCache::new(RENDER_CACHE_SIZE),
+            swap_reads: AtomicU64::new(0),
+            swap_generation: AtomicU64::new(0),
+            latency: Default::default(),
+            dedup_window: None,
+            recent_submissions: Default::default(),
+            descriptors: Default::default(),
+            memory_limit: None,
+            reserved: Default::default(),
+            slots: AtomicUsize::new(0),
+            events: Default::default(),
             runner,
-        }
+        })
+    }
+
+    /// Keeps the descriptors of finished tasks until they are swapped out,
+    /// so they can be reprocessed later.
+    pub fn with_retained_descriptors(mut self, retain: bool) -> Self {
+        self.retain_descriptors = retain;
+        self
+    }
+
+    /// Swaps to an unnamed file in `dir` instead of the OS temporary directory,
+    /// failing if the file can't be created there.
+    pub fn with_swap_dir(mut self, dir: &Path) -> io::Result<Self> {
+        self.swap_file = Arc::new(Mutex::new(File::from_std(tempfile_in(dir)?)));
+        self.swap_dir = Some(dir.to_path_buf());
+        event!(target: "scheduler", Level::INFO, "swapping to {}", dir.display());
+        Ok(self)
+    }
+
+    /// Remembers up to `capacity` swapped tasks looked up recently, none if zero.
+    pub fn with_swap_cache(mut self, capacity: usize) -> Self {
+        self.swap_cache = SwapCache::new(capacity);
+        self
+    }
+
+    /// Hands out the unfinished task of the same images submitted less than
+    /// `window` ago instead of starting another one, e.g. after a double click.
+    pub fn with_dedup_window(mut self, window: Option<Duration>) -> Self {
+        self.dedup_window = window;
+        self
+    }
+
+    /// Has [Scheduler::reserve] and [Scheduler::admit] turn tasks away that
+    /// would take the memory usage past `limit` bytes, none if absent.
+    pub fn with_memory_limit(mut self, limit: Option<usize>) -> Self {
+        self.memory_limit = limit;
+        self
+    }
+
+    /// Publishes to `events` instead of a bus of its own, e.g. one shared with
+    /// the runner.
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
     }
 }
 
@@ -58,13 +234,78 @@ where
     Runner: RunTask + Send + Sync + Clone + 'static,
     Runner::TaskDescriptor: Send + Sync + 'static,
 {
+    pub fn runner(&self) -> &Runner {
+        &self.runner
+    }
+
+    /// Bus of the state transitions of tasks, and of models if the runner
+    /// shares it.
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
     pub async fn create_task(&self, descriptor: Runner::TaskDescriptor) -> TaskControlBlock {
+        self.create_task_with_debug(descriptor, false).await
+    }
+
+    /// Creates a task whose debug events are logged if `debug` is set.
+    pub async fn create_task_with_debug(
+        &self,
+        descriptor: Runner::TaskDescriptor,
+        debug: bool,
+    ) -> TaskControlBlock {
         let task = TaskControlBlock::new();
+        if debug {
+            task.enable_debug();
+        }
+        self.enqueue(task, Arc::new(descriptor), true).await
+    }
+
+    /// Queues `task` to run `descriptor`, whose buffers it shows what the
+    /// runner records in. With `coalesce`, a duplicate of a recent submission
+    /// returns that one's task instead.
+    async fn enqueue(
+        &self,
+        mut task: TaskControlBlock,
+        descriptor: Arc<Runner::TaskDescriptor>,
+        coalesce: bool,
+    ) -> TaskControlBlock {
+        if let Some(intermediates) = descriptor.intermediates() {
+            task = task.with_intermediates(intermediates.clone());
+        }
+        if let Some(prompts) = descriptor.prompts() {
+            task = task.with_prompts(prompts.clone());
+        }
+        if let Some(seeds) = descriptor.seeds() {
+            task = task.with_seeds(seeds.clone());
+        }
+        if let Some(window) = self.dedup_window.filter(|_| coalesce) {
+            let hash = descriptor.submission_hash();
+            let mut recent = self.recent_submissions.lock().unwrap();
+            recent.retain(|_, (submitted_at, task)| {
+                submitted_at.elapsed() < window && !matches!(task.state(), task::State::Finished(_))
+            });
+            if let Some((_, submitted)) = recent.get(&hash) {
+                event!(target: "scheduler", Level::INFO, "coalescing a duplicate submission into task {}", submitted.id());
+                return submitted.clone();
+            }
+            recent.insert(hash, (Instant::now(), task.clone()));
+        }
+        {
+            let mut descriptors = self.descriptors.lock().unwrap();
+            descriptors.retain(|descriptor| descriptor.strong_count() > 0);
+            descriptors.push(Arc::downgrade(&descriptor));
+        }
         self.queues
             .pending
             .lock()
             .await
-            .push((task.clone(), Arc::new(descriptor)));
+            .push((task.clone(), descriptor));
+        // within the span of the request creating it, if any
+        event!(target: "scheduler", Level::INFO, "queued task {}", task.id());
+        self.events.publish(SchedulerEvent::TaskCreated {
+            id: task.id().to_string(),
+        });
         let task_run = self.try_run_topmost().await;
         event!(target: "scheduler", Level::DEBUG, "running topmost {} tasks", task_run);
         task
@@ -75,40 +316,80 @@ where
         let mut active_queue = self.queues.active.lock().await;
         let original_active_tasks = active_queue.len();
         let mut pending_queue = self.queues.pending.lock().await;
+        let max_concurrency = self.max_concurrency();
         event!(target: "scheduler",
             Level::DEBUG,
             "try running topmost {}, active count = {}, max concurrency = {}",
-            pending_queue.len(), original_active_tasks, self.max_concurrency);
-        for _ in 0..self.max_concurrency - active_queue.len() {
+            pending_queue.len(), original_active_tasks, max_concurrency);
+        // more may be running than allowed after the limit was lowered
+        let running = active_queue.len() + self.slots.load(Ordering::Acquire);
+        for _ in 0..max_concurrency.saturating_sub(running) {
             if let Some((tcb, descriptor)) = pending_queue.pop() {
                 tcb.set_state(task::State::Running);
+                let latency = self.latency.clone();
+                latency.queue_wait.record(
+                    (chrono::Utc::now() - tcb.created_at())
+                        .to_std()
+                        .unwrap_or_default(),
+                );
                 let runner = self.runner.clone();
                 let queues = self.queues.clone();
                 let swap_file = self.swap_file.clone();
-                let max_memory_size = self.max_memory_size;
+                let max_memory_size = self.max_memory_size.clone();
+                let retain_descriptor = self.retain_descriptors;
+                let events = self.events.clone();
+                // watching stages makes the runner stream, so only when somebody listens
+                let stages = events.has_subscribers().then(|| tcb.subscribe_tokens());
                 active_queue.push((
                     tcb.clone(),
                     tokio::spawn(async move {
-                        let job = async { runner.extract(&descriptor).await }.await;
+                        let started_at = Instant::now();
+                        let job = runner
+                            .extract(&descriptor, tcb.tokens())
+                            .instrument(tcb.span().clone());
+                        // a panicking runner would otherwise leave the task running for good
+                        let job = std::panic::AssertUnwindSafe(job).catch_unwind().map(|result| {
+                            result.unwrap_or_else(|payload| {
+                                let err = panicked(payload);
+                                event!(target: "scheduler", Level::ERROR, "task {} failed: {err}", tcb.id());
+                                Err(err)
+                            })
+                        });
+                        let job = match stages {
+                            Some(tokens) => events.watch_stages(&tcb, tokens, job).await,
+                            None => job.await,
+                        };
+                        latency.execution.record(started_at.elapsed());
                         tcb.set_state(task::State::Finished(
                             match job {
                                 Ok(bill) => Ok(task::Success(bill)),
-                                Err(err) => Err(Arc::new(err)),
+                                Err(err) => Err(TaskError::from(err)),
                             },
                         ));
+                        events.publish(SchedulerEvent::TaskFinished {
+                            id: tcb.id().to_string(),
+                            success: matches!(tcb.state(), task::State::Finished(Ok(_))),
+                        });
                         let mut active_queue = queues.active.lock().await;
                         if let Some(index) = active_queue
                             .iter()
                             .position(|(task, _)| task.id() == tcb.id())
                         {
                             let (tcb, _) = active_queue.remove(index);
+                            if retain_descriptor {
+                                queues.retained.lock().await.insert(tcb.id().to_string(), descriptor);
+                            }
                             queues.finished.lock().await.push(tcb);
                             drop(active_queue);
 
                             tokio::time::sleep(Duration::from_secs(10)).await;
-                            if let Err(err) = queues.move_inactive_to_swap(&mut *swap_file.lock().await, max_memory_size).await {
-                                event!(target: "scheduler", Level::ERROR, "swap failed, inactive queue now has a crowd of {}: {}", 
-                                    queues.finished.lock().await.len(), err);
+                            match queues.move_inactive_to_swap(&mut *swap_file.lock().await, max_memory_size.load(Ordering::Relaxed)).await {
+                                Ok(0) => {}
+                                Ok(count) => events.publish(SchedulerEvent::TaskSwapped { count }),
+                                Err(err) => {
+                                    event!(target: "scheduler", Level::ERROR, "swap failed, inactive queue now has a crowd of {}: {}",
+                                        queues.finished.lock().await.len(), err);
+                                }
                             }
                         } else {
                             event!(target: "scheduler", Level::ERROR, "finished task {} not found in active queue", tcb.id());
@@ -121,176 +402,2182 @@ where
         active_queue.len() - original_active_tasks
     }
 
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency.load(Ordering::Relaxed)
+    }
+
+    /// Lets `max_concurrency` tasks run at once from now on. Raising it starts
+    /// pending tasks right away; lowering it leaves running tasks alone and
+    /// starts none until fewer than the new limit run.
+    pub async fn set_max_concurrency(&self, max_concurrency: usize) {
+        self.max_concurrency
+            .store(max_concurrency, Ordering::Relaxed);
+        self.try_run_topmost().await;
+    }
+
+    pub fn max_memory_size(&self) -> usize {
+        self.max_memory_size.load(Ordering::Relaxed)
+    }
+
+    /// Keeps `max_memory_size` finished tasks in memory from now on, swapping
+    /// out the ones past a lowered limit right away.
+    pub async fn set_max_memory_size(&self, max_memory_size: usize) -> anyhow::Result<()> {
+        self.max_memory_size
+            .store(max_memory_size, Ordering::Relaxed);
+        let mut swap_file = self.swap_file.lock().await;
+        let count = self
+            .queues
+            .move_inactive_to_swap(&mut swap_file, max_memory_size)
+            .await?;
+        if count > 0 {
+            self.events.publish(SchedulerEvent::TaskSwapped { count });
+        }
+        Ok(())
+    }
+
+    /// Descriptor of a finished task kept in memory by [Self::with_retained_descriptors].
+    pub async fn retained_descriptor(&self, id: &str) -> Option<Arc<Runner::TaskDescriptor>> {
+        self.queues.retained.lock().await.get(id).cloned()
+    }
+
+    /// Resubmits a failed task with its retained descriptor as a new task.
+    pub async fn retry_task(
+        &self,
+        task_id: impl AsRef<str>,
+    ) -> Result<TaskControlBlock, RetryTaskError> {
+        let task_id = task_id.as_ref();
+        let task = self
+            .get_task(task_id)
+            .await?
+            .ok_or(RetryTaskError::NotFound)?;
+        match task.state() {
+            task::State::Finished(Err(_)) => {}
+            task::State::Finished(Ok(_)) => return Err(RetryTaskError::Succeeded),
+            task::State::Pending | task::State::Running => {
+                return Err(RetryTaskError::NotFinished);
+            }
+        }
+        let descriptor = self
+            .retained_descriptor(task_id)
+            .await
+            .ok_or(RetryTaskError::NotRetained)?;
+        let retry = TaskControlBlock::new();
+        event!(target: "scheduler", Level::INFO, "retrying task {} as {}", task_id, retry.id());
+        Ok(self
+            .enqueue(retry, Arc::new(descriptor.rerun()), false)
+            .await)
+    }
+
+    /// Finishes every pending task as cancelled, leaving running ones alone,
+    /// and returns how many there were. Their descriptors are retained for a
+    /// retry if descriptors are.
+    pub async fn clear_pending(&self) -> usize {
+        let cleared = std::mem::take(&mut *self.queues.pending.lock().await);
+        let count = cleared.len();
+        for (tcb, descriptor) in cleared {
+            tcb.set_state(task::State::Finished(Err(TaskError::cancelled())));
+            if self.retain_descriptors {
+                self.queues
+                    .retained
+                    .lock()
+                    .await
+                    .insert(tcb.id().to_string(), descriptor);
+            }
+            self.events.publish(SchedulerEvent::TaskFinished {
+                id: tcb.id().to_string(),
+                success: false,
+            });
+            self.queues.finished.lock().await.push(tcb);
+        }
+        if count > 0 {
+            event!(target: "scheduler", Level::INFO, "cancelled {count} pending tasks");
+        }
+        count
+    }
+
+    pub fn backfill_progress(&self) -> BackfillProgress {
+        self.backfill.lock().unwrap().clone()
+    }
+
+    /// Resubmits every retained descriptor as a new task in the background.
+    /// Finished tasks without one, having been swapped out, are reported as
+    /// skipped.
+    ///
+    /// Submissions wait for the pending queue to drain and a free runner slot,
+    /// so live traffic always goes first.
+    pub async fn start_backfill(self: &Arc<Self>) -> Result<BackfillProgress, BackfillError> {
+        {
+            let mut progress = self.backfill.lock().unwrap();
+            if progress.running {
+                return Err(BackfillError::AlreadyRunning);
+            }
+            *progress = BackfillProgress {
+                running: true,
+                ..Default::default()
+            };
+        }
+        // looked at before the descriptors, so a task swapped out in between
+        // counts as skipped rather than vanishing
+        let finished = match self
+            .finished_tasks()
+            .try_filter(|task| futures::future::ready(!task.is_deleted()))
+            .map_ok(|task| task.id().to_string())
+            .try_collect::<Vec<_>>()
+            .await
+        {
+            Ok(finished) => finished,
+            Err(err) => {
+                self.backfill.lock().unwrap().running = false;
+                return Err(BackfillError::Swap(err));
+            }
+        };
+        let retained = self.queues.retained.lock().await.clone();
+        let skipped = finished
+            .into_iter()
+            .filter(|id| !retained.contains_key(id))
+            .collect::<Vec<_>>();
+        let descriptors = retained.into_values().collect::<Vec<_>>();
+        let progress = {
+            let mut progress = self.backfill.lock().unwrap();
+            progress.total = descriptors.len();
+            progress.skipped = skipped;
+            progress.clone()
+        };
+        event!(
+            target: "scheduler",
+            Level::INFO,
+            "backfilling {} tasks, skipping {} swapped out",
+            descriptors.len(),
+            progress.skipped.len()
+        );
+
+        let scheduler = self.clone();
+        tokio::spawn(async move {
+            for descriptor in descriptors {
+                scheduler.wait_for_idle_slot().await;
+                let task = scheduler
+                    .enqueue(TaskControlBlock::new(), Arc::new(descriptor.rerun()), false)
+                    .await;
+                let mut progress = scheduler.backfill.lock().unwrap();
+                progress.submitted += 1;
+                progress.tasks.push(task.id().to_string());
+            }
+            scheduler.backfill.lock().unwrap().running = false;
+            event!(target: "scheduler", Level::INFO, "backfill submitted");
+        });
+        Ok(progress)
+    }
+
+    /// Waits until no task is pending and a runner slot is free, and takes it
+    /// for work run beside the tasks rather than queued, like describing an
+    /// image or answering a question, so it shares `max_concurrency` with them.
+    pub async fn slot(self: &Arc<Self>) -> Slot {
+        loop {
+            {
+                // in the order try_run_topmost locks them
+                let active = self.queues.active.lock().await;
+                let pending = self.queues.pending.lock().await;
+                if pending.is_empty()
+                    && active.len() + self.slots.load(Ordering::Acquire) < self.max_concurrency()
+                {
+                    self.slots.fetch_add(1, Ordering::AcqRel);
+                    break;
+                }
+            }
+            tokio::time::sleep(SLOT_POLL_INTERVAL).await;
+        }
+        let scheduler = self.clone();
+        Slot {
+            release: Some(Box::new(move || {
+                scheduler.slots.fetch_sub(1, Ordering::AcqRel);
+                // tasks queued meanwhile may have waited for the slot
+                if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                    runtime.spawn(async move {
+                        scheduler.try_run_topmost().await;
+                    });
+                }
+            })),
+        }
+    }
+
+    async fn wait_for_idle_slot(&self) {
+        loop {
+            {
+                // in the order try_run_topmost locks them
+                let active = self.queues.active.lock().await;
+                let pending = self.queues.pending.lock().await;
+                if pending.is_empty()
+                    && active.len() + self.slots.load(Ordering::Acquire) < self.max_concurrency()
+                {
+                    return;
+                }
+            }
+            tokio::time::sleep(BACKFILL_POLL_INTERVAL).await;
+        }
+    }
+
     pub async fn get_task(
         &self,
         task_id: impl AsRef<str>,
     ) -> anyhow::Result<Option<TaskControlBlock>> {
-        let aq = self.queues.active.lock().await;
-        let pq = self.queues.pending.lock().await;
-        let fq = self.queues.finished.lock().await;
-        let stream = stream::iter(aq.iter().map(|(task, _)| task).cloned())
-            .chain(stream::iter(pq.iter().cloned().map(|(task, _)| task)))
-            .chain(stream::iter(fq.iter().cloned()))
-            .map(|task| Ok(task))
-            .chain(self.in_disk_queue_iter());
-        pin!(stream);
-        while let Some(task) = stream.try_next().await? {
-            if task.id() == task_id.as_ref() {
+        let task_id = task_id.as_ref();
+        let in_memory = {
+            let aq = self.queues.active.lock().await;
+            let pq = self.queues.pending.lock().await;
+            let fq = self.queues.finished.lock().await;
+            aq.iter()
+                .map(|(task, _)| task)
+                .chain(pq.iter().map(|(task, _)| task))
+                .chain(fq.iter())
+                .find(|task| task.id() == task_id)
+                .cloned()
+        };
+        if in_memory.is_some() {
+            return Ok(in_memory);
+        }
+        if let Some(task) = self.swap_cache.get(task_id) {
+            return Ok(Some(task));
+        }
+        let swapped = self.in_disk_queue_iter(HashSet::new());
+        pin!(swapped);
+        while let Some(task) = swapped.try_next().await? {
+            if task.id() == task_id {
+                self.swap_cache.put(&task);
                 return Ok(Some(task));
             }
         }
         Ok(None)
     }
 
-    fn in_disk_queue_iter(&self) -> impl Stream<Item = anyhow::Result<TaskControlBlock>> {
-        async fn get_next_chunk(file: &mut File) -> anyhow::Result<Option<Vec<TaskControlBlock>>> {
-            let len = match file.read_u32().await {
-                Ok(len) => len,
-                Err(err) => {
-                    if err.kind() == io::ErrorKind::UnexpectedEof {
-                        event!(target: "scheduler", Level::DEBUG, "end of swap file");
-                        return Ok(None);
-                    } else {
-                        return Err(anyhow!(err));
-                    }
+    /// Body of a finished task rendered in `variant` before, sparing repeated
+    /// polls the serializing and the swap file.
+    pub fn rendered(&self, task_id: &str, variant: u64) -> Option<Rendered> {
+        self.render_cache.get(task_id, variant)
+    }
+
+    /// Changes seen by the bodies kept, to look up before the task to be
+    /// rendered and hand to [Self::keep_rendered].
+    pub fn render_generation(&self) -> u64 {
+        self.render_cache.generation()
+    }
+
+    /// Keeps the body of a finished task for [Self::rendered], unless the task
+    /// was updated or purged since `generation`.
+    pub fn keep_rendered(&self, task_id: &str, variant: u64, rendered: Rendered, generation: u64) {
+        self.render_cache
+            .put(task_id, variant, rendered, generation);
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            swap_cache: self.swap_cache.stats(),
+            swap_reads: self.swap_reads.load(Ordering::Relaxed),
+            queue_wait: self.latency.queue_wait.summary(),
+            execution: self.latency.execution.summary(),
+        }
+    }
+
+    /// Histograms of how long tasks waited and ran, which only a restart or
+    /// [Scheduler::reset_latency] clears.
+    pub fn latency(&self) -> &Latency {
+        &self.latency
+    }
+
+    /// Approximate bytes held by tasks: the images of those pending, running
+    /// or retained, [TASK_FOOTPRINT] for each finished one in memory or in the
+    /// swap cache, and the [Reservation]s of those on their way in.
+    pub async fn memory_usage(&self) -> usize {
+        self.held_memory().await + *self.reserved.lock().unwrap()
+    }
+
+    /// [Self::memory_usage] but the reservations.
+    async fn held_memory(&self) -> usize {
+        let images = self
+            .descriptors
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|descriptor| descriptor.size())
+            .sum::<usize>();
+        let tasks = self.queues.finished.lock().await.len() + self.swap_cache.stats().size;
+        images + tasks * TASK_FOOTPRINT
+    }
+
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    /// Sets `bytes` aside for a task before its upload is read, so uploads
+    /// arriving at once can't all pass the check and then exceed the limit
+    /// together. Fails if they would take the memory usage past the limit.
+    pub async fn reserve(&self, bytes: usize) -> Result<Reservation, CreateTaskError> {
+        self.set_aside(bytes, None).await
+    }
+
+    /// Trades `reservation` for one of what taking `descriptor` holds, failing
+    /// if that would take the memory usage past the limit, as it may when a zip
+    /// archive unpacks to more than was reserved. Keep it until the task is
+    /// created.
+    pub async fn admit(
+        &self,
+        descriptor: &Runner::TaskDescriptor,
+        reservation: Reservation,
+    ) -> Result<Reservation, CreateTaskError> {
+        self.set_aside(descriptor.size() + TASK_FOOTPRINT, Some(reservation))
+            .await
+    }
+
+    /// Reserves `bytes` in place of `replacing`, reclaiming memory once when
+    /// they don't fit.
+    async fn set_aside(
+        &self,
+        bytes: usize,
+        mut replacing: Option<Reservation>,
+    ) -> Result<Reservation, CreateTaskError> {
+        let mut usage = 0;
+        for reclaimed in [false, true] {
+            if reclaimed {
+                self.reclaim_memory().await;
+            }
+            let held = self.held_memory().await;
+            let mut reserved = self.reserved.lock().unwrap();
+            let releasing = replacing.as_ref().map_or(0, Reservation::bytes);
+            usage = held + *reserved - releasing + bytes;
+            if self.memory_limit.is_none_or(|limit| usage <= limit) {
+                *reserved = *reserved - releasing + bytes;
+                if let Some(replacing) = &mut replacing {
+                    // handed over to the new reservation
+                    replacing.bytes = 0;
                 }
-            };
-            event!(Level::DEBUG, "len<in> = {}", len);
-            let mut buf = vec![0u8; len as usize];
-            file.read_exact(&mut buf).await?;
-            let chunk: Vec<TaskControlBlock> = postcard::from_bytes(&buf)?;
-            Ok(Some(chunk))
+                return Ok(Reservation {
+                    reserved: self.reserved.clone(),
+                    bytes,
+                });
+            }
+        }
+        let limit = self.memory_limit.unwrap_or_default();
+        event!(target: "scheduler", Level::WARN, "turning a task away, {usage} bytes would exceed the memory limit of {limit}");
+        Err(CreateTaskError::MemoryLimit { limit })
+    }
+
+    /// Frees what can be without losing anything: finished tasks are swapped
+    /// out past the usual threshold, the swap cache is emptied, and the runner
+    /// lets go of what it holds idle if no task runs.
+    async fn reclaim_memory(&self) {
+        let count = match self
+            .queues
+            .move_inactive_to_swap(&mut *self.swap_file.lock().await, 0)
+            .await
+        {
+            Ok(count) => count,
+            Err(err) => {
+                event!(target: "scheduler", Level::ERROR, "failed to swap out under memory pressure: {err}");
+                0
+            }
+        };
+        if count > 0 {
+            self.events.publish(SchedulerEvent::TaskSwapped { count });
+        }
+        self.swap_cache.clear();
+        self.render_cache.clear();
+        if self.queues.active.lock().await.is_empty() {
+            self.runner.release_memory().await;
         }
+        event!(target: "scheduler", Level::INFO, "reclaimed memory, swapping out {count} tasks");
+    }
+
+    pub fn reset_latency(&self) {
+        self.latency.queue_wait.reset();
+        self.latency.execution.reset();
+    }
 
+    /// Every known task but the soft deleted ones, in memory ones first,
+    /// followed by the swapped ones. A task swapped out meanwhile is only
+    /// yielded once.
+    pub fn tasks(&self) -> impl Stream<Item = anyhow::Result<TaskControlBlock>> {
         try_stream! {
-            let mut swap_file = self.swap_file.lock().await;
-            swap_file.rewind().await?;
-            while let Some(chunk) = get_next_chunk(&mut *swap_file).await? {
-                for task in chunk.into_iter() {
+            // don't hold the queues while a slow consumer reads the swap
+            let in_memory = {
+                let aq = self.queues.active.lock().await;
+                let pq = self.queues.pending.lock().await;
+                let fq = self.queues.finished.lock().await;
+                aq.iter()
+                    .map(|(task, _)| task)
+                    .chain(pq.iter().map(|(task, _)| task))
+                    .chain(fq.iter())
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            let seen = in_memory.iter().map(|task| task.id().to_string()).collect();
+            for task in in_memory {
+                if !task.is_deleted() {
+                    yield task;
+                }
+            }
+            let swapped = self.in_disk_queue_iter(seen);
+            pin!(swapped);
+            while let Some(task) = swapped.try_next().await? {
+                if !task.is_deleted() {
                     yield task;
                 }
             }
         }
     }
-}
 
-impl<Task> ScheduleQueues<Task> {
-    async fn move_inactive_to_swap(
-        &self,
-        fd: &mut File,
-        max_memory_size: usize,
-    ) -> anyhow::Result<usize> {
-        fd.seek(SeekFrom::End(0)).await?;
-        let mut finished_queue = self.finished.lock().await;
-        let swap_amount = finished_queue.len() as i32 - max_memory_size as i32;
-        if swap_amount <= 0 {
-            event!(target: "scheduler", Level::DEBUG, "finished queue size {} <= max memory size {}, no need to swap", finished_queue.len(), max_memory_size);
-            return Ok(0);
+    /// Every finished task, soft deleted ones included, in memory ones first,
+    /// each yielded once.
+    pub fn finished_tasks(&self) -> impl Stream<Item = anyhow::Result<TaskControlBlock>> {
+        try_stream! {
+            let in_memory = self.queues.finished.lock().await.clone();
+            let seen = in_memory.iter().map(|task| task.id().to_string()).collect();
+            for task in in_memory {
+                yield task;
+            }
+            let swapped = self.in_disk_queue_iter(seen);
+            pin!(swapped);
+            while let Some(task) = swapped.try_next().await? {
+                yield task;
+            }
         }
-        let items_left = finished_queue.split_off(swap_amount as usize);
-        let items_swapped = finished_queue.len();
-        let buf = postcard::to_allocvec(finished_queue.as_slice())?;
-        event!(Level::DEBUG, "len<out> = {}", buf.len());
-        fd.write_u32(buf.len() as u32).await?;
-        fd.write(buf.as_slice()).await?;
-        fd.flush().await?;
-        *finished_queue = items_left;
-        Ok(items_swapped)
     }
-}
 
-impl<Runner> Default for Scheduler<Runner>
-where
-    Runner: RunTask + Default,
-{
-    fn default() -> Self {
-        Self::new(
-            4,
-            468_000, // approx. 50 megabytes
-            Duration::from_mins(5),
-            Default::default(),
-        )
+    /// Whether no task is queued, running, finished or swapped out.
+    pub async fn is_empty(&self) -> io::Result<bool> {
+        if !(self.queues.active.lock().await.is_empty()
+            && self.queues.pending.lock().await.is_empty()
+            && self.queues.finished.lock().await.is_empty())
+        {
+            return Ok(false);
+        }
+        Ok(self.swap_file.lock().await.metadata().await?.len() == 0)
     }
-}
 
-impl<Task> Default for ScheduleQueues<Task> {
-    fn default() -> Self {
-        Self {
-            active: Arc::new(Mutex::new(Vec::new())),
-            pending: Arc::new(Mutex::new(Vec::new())),
-            finished: Arc::new(Mutex::new(Vec::new())),
+    /// Adds finished tasks read from a backup, swapping past the memory limit
+    /// like tasks finished here.
+    pub async fn restore_finished(&self, tasks: Vec<TaskControlBlock>) -> anyhow::Result<()> {
+        self.queues.finished.lock().await.extend(tasks);
+        let mut swap_file = self.swap_file.lock().await;
+        let count = self
+            .queues
+            .move_inactive_to_swap(&mut swap_file, self.max_memory_size())
+            .await?;
+        if count > 0 {
+            self.events.publish(SchedulerEvent::TaskSwapped { count });
         }
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use smol_str::SmolStr;
-    use tracing_test::traced_test;
 
-    use crate::{
-        bill::{Bill, Category},
-        error::RunTaskError,
-        task::TaskDescriptor,
-    };
+    /// Descriptors kept by [Self::with_retained_descriptors], by task id.
+    pub async fn retained_descriptors(&self) -> Vec<(String, Arc<Runner::TaskDescriptor>)> {
+        let retained = self.queues.retained.lock().await;
+        retained
+            .iter()
+            .map(|(id, descriptor)| (id.clone(), descriptor.clone()))
+            .collect()
+    }
 
-    use super::*;
-    #[tokio::test]
-    #[traced_test]
-    async fn test_swap() {
-        Category::load_from_names(["No category"]);
-        let scheduler = Scheduler::<MockRunner>::default();
-        for i in 0..10 {
-            let tcb = TaskControlBlock::new();
-            tcb.set_state(task::State::Finished(Ok(task::Success(
-                crate::bill::Bill {
-                    notes: "No.".into(),
-                    amount: i as f32 / 3f32,
-                    category: Some("No category".into()),
-                },
-            ))));
-            scheduler.queues.finished.lock().await.push(tcb);
+    /// Retains the descriptor of a restored task, unless retention is off or
+    /// the task isn't finished in memory. Returns whether it was retained.
+    pub async fn restore_descriptor(&self, id: &str, descriptor: Runner::TaskDescriptor) -> bool {
+        if !self.retain_descriptors {
+            return false;
         }
-        let lookup_id = scheduler
-            .queues
-            .finished
+        let finished = self.queues.finished.lock().await;
+        if !finished.iter().any(|task| task.id() == id) {
+            return false;
+        }
+        self.queues
+            .retained
             .lock()
             .await
-            .first()
-            .unwrap()
-            .id()
-            .to_string();
-        scheduler
-            .queues
-            .move_inactive_to_swap(&mut *scheduler.swap_file.lock().await, 1)
-            .await
-            .unwrap();
-        assert_eq!(scheduler.queues.finished.lock().await.len(), 1);
-        assert!(scheduler.get_task(lookup_id).await.unwrap().is_some());
+            .insert(id.to_string(), Arc::new(descriptor));
+        true
     }
 
-    struct MockTaskDescriptor;
-    #[derive(Default, Clone)]
-    struct MockRunner;
+    /// Applies `update` to the bill of a finished task, wherever it lives.
+    pub async fn update_bill(
+        &self,
+        task_id: impl AsRef<str>,
+        update: impl FnOnce(&mut Bill),
+    ) -> Result<TaskControlBlock, UpdateTaskError> {
+        self.update_task(task_id, |task| update_finished_bill(task, update))
+            .await
+    }
 
-    impl TaskDescriptor for MockTaskDescriptor {
-        fn images(&self) -> Vec<&[u8]> {
-            Vec::new()
+    /// Applies `update` to a task wherever it lives, pending, running, finished
+    /// or swapped out, and returns the task as updated. Nothing is written if
+    /// `update` fails.
+    ///
+    /// A task in memory is updated while the queues are locked, and swapping
+    /// holds the finished queue until the chunk is written, so the task is either
+    /// still in memory, where the update is swapped out along with it, or
+    /// already in the swap file, which is then rewritten with the update under
+    /// its lock. Either way no update is lost to a concurrent swap, and
+    /// lookups never see the task as it was before.
+    pub async fn update_task(
+        &self,
+        task_id: impl AsRef<str>,
+        update: impl FnOnce(&TaskControlBlock) -> Result<(), UpdateTaskError>,
+    ) -> Result<TaskControlBlock, UpdateTaskError> {
+        let task_id = task_id.as_ref();
+        {
+            let aq = self.queues.active.lock().await;
+            let pq = self.queues.pending.lock().await;
+            let fq = self.queues.finished.lock().await;
+            let in_memory = aq
+                .iter()
+                .map(|(task, _)| task)
+                .chain(pq.iter().map(|(task, _)| task))
+                .chain(fq.iter())
+                .find(|task| task.id() == task_id);
+            if let Some(task) = in_memory {
+                update(task)?;
+                self.render_cache.invalidate(task_id);
+                return Ok(task.clone());
+            }
         }
 
-        fn category_names(&self) -> Vec<SmolStr> {
-            Vec::new()
-        }
+        self.rewrite_swap(task_id, |chunk, index| {
+            update(&chunk[index])?;
+            Ok(chunk[index].clone())
+        })
+        .await?
+        .ok_or(UpdateTaskError::NotFound)
     }
 
-    impl RunTask for MockRunner {
-        type TaskDescriptor = MockTaskDescriptor;
+    /// Soft deletes a finished task: it stays resolvable by id, marked deleted,
+    /// but is left out of [Self::tasks] and so of listings and exports.
+    /// Deleting it again changes nothing.
+    pub async fn delete_task(
+        &self,
+        task_id: impl AsRef<str>,
+    ) -> Result<TaskControlBlock, UpdateTaskError> {
+        self.update_task(task_id, |task| match task.state() {
+            task::State::Finished(_) => {
+                task.set_deleted(true);
+                Ok(())
+            }
+            _ => Err(UpdateTaskError::NotFinished),
+        })
+        .await
+    }
 
-        async fn extract(&self, _: &Self::TaskDescriptor) -> Result<Bill, RunTaskError> {
+    /// Removes every trace of a finished task: its record in memory or the swap
+    /// file, its retained descriptor, its cached copy and its entry among the
+    /// recent submissions. Returns whether there was anything to remove, so
+    /// purging a task again is harmless.
+    pub async fn purge_task(&self, task_id: impl AsRef<str>) -> Result<bool, UpdateTaskError> {
+        let task_id = task_id.as_ref();
+        let in_memory = {
+            let aq = self.queues.active.lock().await;
+            let pq = self.queues.pending.lock().await;
+            let mut fq = self.queues.finished.lock().await;
+            if aq.iter().any(|(task, _)| task.id() == task_id)
+                || pq.iter().any(|(task, _)| task.id() == task_id)
+            {
+                return Err(UpdateTaskError::NotFinished);
+            }
+            fq.iter()
+                .position(|task| task.id() == task_id)
+                .map(|index| fq.remove(index))
+        };
+        let retained = self.queues.retained.lock().await.remove(task_id).is_some();
+        self.recent_submissions
+            .lock()
+            .unwrap()
+            .retain(|_, (_, task)| task.id() != task_id);
+        let swapped = match in_memory {
+            Some(_) => false,
+            None => self
+                .rewrite_swap(task_id, |chunk, index| {
+                    chunk.remove(index);
+                    Ok(())
+                })
+                .await?
+                .is_some(),
+        };
+        self.swap_cache.invalidate(task_id);
+        self.render_cache.invalidate(task_id);
+        let purged = in_memory.is_some() || retained || swapped;
+        if purged {
+            event!(target: "scheduler", Level::INFO, "purged task {task_id}");
+        }
+        Ok(purged)
+    }
+
+    /// Rewrites the swap file with `edit` applied to the chunk holding the task,
+    /// at its index in the chunk, if the task is swapped out. Nothing is written
+    /// if `edit` fails, chunks left empty are dropped.
+    async fn rewrite_swap<T>(
+        &self,
+        task_id: &str,
+        edit: impl FnOnce(&mut Vec<TaskControlBlock>, usize) -> Result<T, UpdateTaskError>,
+    ) -> Result<Option<T>, UpdateTaskError> {
+        let mut swap_file = self.swap_file.lock().await;
+        let mut rewritten = self.new_swap_file().map_err(anyhow::Error::from)?;
+        swap_file.rewind().await.map_err(anyhow::Error::from)?;
+        let mut edit = Some(edit);
+        let mut edited = None;
+        while let Some(mut chunk) = read_chunk(&mut swap_file).await? {
+            self.swap_reads.fetch_add(1, Ordering::Relaxed);
+            if let Some(index) = chunk.iter().position(|task| task.id() == task_id)
+                && let Some(edit) = edit.take()
+            {
+                edited = Some(edit(&mut chunk, index)?);
+            }
+            if !chunk.is_empty() {
+                write_chunk(&mut rewritten, &chunk).await?;
+            }
+        }
+        if edited.is_some() {
+            *swap_file = rewritten;
+            self.swap_generation.fetch_add(1, Ordering::Release);
+            self.swap_cache.invalidate(task_id);
+            self.render_cache.invalidate(task_id);
+        }
+        Ok(edited)
+    }
+
+    /// An empty file next to the swap file, to rewrite it into.
+    fn new_swap_file(&self) -> io::Result<File> {
+        let file = match &self.swap_dir {
+            Some(dir) => tempfile_in(dir)?,
+            None => tempfile()?,
+        };
+        Ok(File::from_std(file))
+    }
+
+    /// Tasks in the swap file, read a chunk at a time so the file is only
+    /// held while reading, never while the consumer is. A swap rewritten in
+    /// between is read again from the start. Tasks already yielded, or in
+    /// `yielded` to begin with, are left out.
+    fn in_disk_queue_iter(
+        &self,
+        mut yielded: HashSet<String>,
+    ) -> impl Stream<Item = anyhow::Result<TaskControlBlock>> {
+        try_stream! {
+            let mut offset = 0;
+            let mut generation = self.swap_generation.load(Ordering::Acquire);
+            loop {
+                let chunk = {
+                    let mut swap_file = self.swap_file.lock().await;
+                    let current = self.swap_generation.load(Ordering::Acquire);
+                    if current != generation {
+                        (offset, generation) = (0, current);
+                    }
+                    swap_file.seek(SeekFrom::Start(offset)).await?;
+                    let chunk = read_chunk(&mut swap_file).await?;
+                    offset = swap_file.stream_position().await?;
+                    chunk
+                };
+                let Some(chunk) = chunk else {
+                    break;
+                };
+                self.swap_reads.fetch_add(1, Ordering::Relaxed);
+                for task in chunk {
+                    if yielded.insert(task.id().to_string()) {
+                        yield task;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Swapped tasks looked up recently, sparing repeated polls a read of the swap
+/// file. The lock is never held across an await.
+struct SwapCache {
+    entries: std::sync::Mutex<Option<LruCache<String, TaskControlBlock>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Bodies of finished tasks as served, in each variant asked for, see
+/// [Scheduler::rendered]. The lock is never held across an await.
+struct RenderCache {
+    entries: std::sync::Mutex<LruCache<String, HashMap<u64, Rendered>>>,
+    /// Bumped by every invalidation, so a body rendered from a task as it was
+    /// before isn't kept after
+    generation: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SwapCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Stats {
+    pub swap_cache: SwapCacheStats,
+    pub swap_reads: u64,
+    /// From creating a task until it starts running
+    pub queue_wait: LatencySummary,
+    /// From starting a task until it finishes
+    pub execution: LatencySummary,
+}
+
+/// Upper bounds in seconds of the [Histogram] buckets, past the last one
+/// durations land in an overflow bucket.
+pub const LATENCY_BUCKETS: [f64; 15] = [
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
+];
+
+/// Durations counted in fixed buckets, so memory stays the same however long
+/// the server runs.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    /// Sum of the durations in microseconds
+    sum_micros: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+pub struct Latency {
+    pub queue_wait: Histogram,
+    pub execution: Histogram,
+}
+
+/// Percentiles in seconds, estimated from the buckets, none before the first
+/// task.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub p50: Option<f64>,
+    pub p90: Option<f64>,
+    pub p99: Option<f64>,
+}
+
+impl Histogram {
+    pub fn record(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.partition_point(|bound| *bound < seconds);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.sum_micros.store(0, Ordering::Relaxed);
+    }
+
+    /// Count of each bucket, the overflow bucket last.
+    pub fn counts(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    pub fn sum_seconds(&self) -> f64 {
+        self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let counts = self.counts();
+        LatencySummary {
+            count: counts.iter().sum(),
+            p50: quantile(&counts, 0.5),
+            p90: quantile(&counts, 0.9),
+            p99: quantile(&counts, 0.99),
+        }
+    }
+}
+
+/// Interpolates linearly within the bucket holding the `q` quantile, like
+/// Prometheus' `histogram_quantile`. Quantiles in the overflow bucket are
+/// reported as the largest bound.
+fn quantile(counts: &[u64], q: f64) -> Option<f64> {
+    let total = counts.iter().sum::<u64>();
+    if total == 0 {
+        return None;
+    }
+    let rank = q * total as f64;
+    let mut below = 0;
+    for (index, &count) in counts.iter().enumerate() {
+        if count > 0 && (below + count) as f64 >= rank {
+            let Some(&upper) = LATENCY_BUCKETS.get(index) else {
+                break;
+            };
+            let lower = index
+                .checked_sub(1)
+                .map_or(0.0, |index| LATENCY_BUCKETS[index]);
+            return Some(lower + (upper - lower) * (rank - below as f64) / count as f64);
+        }
+        below += count;
+    }
+    LATENCY_BUCKETS.last().copied()
+}
+
+impl SwapCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: std::sync::Mutex::new(NonZeroUsize::new(capacity).map(LruCache::new)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, task_id: &str) -> Option<TaskControlBlock> {
+        let task = self
+            .entries
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|entries| entries.get(task_id).cloned());
+        let counter = if task.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        task
+    }
+
+    fn put(&self, task: &TaskControlBlock) {
+        if let Some(entries) = self.entries.lock().unwrap().as_mut() {
+            entries.put(task.id().to_string(), task.clone());
+        }
+    }
+
+    fn invalidate(&self, task_id: &str) {
+        if let Some(entries) = self.entries.lock().unwrap().as_mut() {
+            entries.pop(task_id);
+        }
+    }
+
+    fn clear(&self) {
+        if let Some(entries) = self.entries.lock().unwrap().as_mut() {
+            entries.clear();
+        }
+    }
+
+    fn stats(&self) -> SwapCacheStats {
+        let entries = self.entries.lock().unwrap();
+        SwapCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: entries.as_ref().map_or(0, LruCache::len),
+            capacity: entries.as_ref().map_or(0, |entries| entries.cap().get()),
+        }
+    }
+}
+
+impl RenderCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: std::sync::Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity).expect("render cache isn't empty"),
+            )),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, task_id: &str, variant: u64) -> Option<Rendered> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(task_id)
+            .and_then(|variants| variants.get(&variant).cloned())
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    fn put(&self, task_id: &str, variant: u64, rendered: Rendered, generation: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        // checked under the lock invalidations take too
+        if self.generation() != generation {
+            return;
+        }
+        entries
+            .get_or_insert_mut(task_id.to_string(), HashMap::new)
+            .insert(variant, rendered);
+    }
+
+    fn invalidate(&self, task_id: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::Release);
+        entries.pop(task_id);
+    }
+
+    fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::Release);
+        entries.clear();
+    }
+}
+
+fn update_finished_bill(
+    task: &TaskControlBlock,
+    update: impl FnOnce(&mut Bill),
+) -> Result<(), UpdateTaskError> {
+    match task.state() {
+        task::State::Finished(Ok(mut success)) => {
+            update(&mut success.0);
+            task.set_state(task::State::Finished(Ok(success)));
+            Ok(())
+        }
+        task::State::Finished(Err(_)) => Err(UpdateTaskError::NoBill),
+        _ => Err(UpdateTaskError::NotFinished),
+    }
+}
+
+/// Runs (de)serialization of large chunks on the blocking pool.
+pub(crate) async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+async fn read_chunk(file: &mut File) -> anyhow::Result<Option<Vec<TaskControlBlock>>> {
+    let len = match file.read_u32().await {
+        Ok(len) => len,
+        Err(err) => {
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                event!(target: "scheduler", Level::DEBUG, "end of swap file");
+                return Ok(None);
+            } else {
+                return Err(anyhow!(err));
+            }
+        }
+    };
+    event!(Level::DEBUG, "len<in> = {}", len);
+    let mut buf = vec![0u8; len as usize];
+    file.read_exact(&mut buf).await?;
+    let chunk = blocking(move || decode_chunk(&buf)).await?;
+    Ok(Some(chunk))
+}
+
+/// Tasks of a chunk written in any version of the swap layout.
+pub(crate) fn decode_chunk(buf: &[u8]) -> anyhow::Result<Vec<TaskControlBlock>> {
+    let (&version, tasks) = buf
+        .split_first()
+        .ok_or_else(|| anyhow!("empty swap chunk"))?;
+    match version {
+        1 => postcard::from_bytes::<Vec<task::SwappedTaskV1>>(tasks)?
+            .into_iter()
+            .map(|task| TaskControlBlock::try_from(task).map_err(|err| anyhow!(err)))
+            .collect(),
+        2 => postcard::from_bytes::<Vec<task::SwappedTaskV2>>(tasks)?
+            .into_iter()
+            .map(|task| TaskControlBlock::try_from(task).map_err(|err| anyhow!(err)))
+            .collect(),
+        3 => Ok(postcard::from_bytes::<Vec<task::SwappedTaskV3>>(tasks)?
+            .into_iter()
+            .map(TaskControlBlock::from)
+            .collect()),
+        4 => Ok(
+            postcard::from_bytes::<Vec<task::SwappedTaskV4<BillV2>>>(tasks)?
+                .into_iter()
+                .map(TaskControlBlock::from)
+                .collect(),
+        ),
+        5 => Ok(
+            postcard::from_bytes::<Vec<task::SwappedTaskV5<BillV2>>>(tasks)?
+                .into_iter()
+                .map(TaskControlBlock::from)
+                .collect(),
+        ),
+        6 => Ok(postcard::from_bytes::<Vec<task::SwappedTaskV6>>(tasks)?
+            .into_iter()
+            .map(TaskControlBlock::from)
+            .collect()),
+        SWAP_VERSION => Ok(postcard::from_bytes::<Vec<task::SwappedTask>>(tasks)?
+            .into_iter()
+            .map(TaskControlBlock::from)
+            .collect()),
+        _ => Err(anyhow!("swap chunk of unknown version {version}")),
+    }
+}
+
+/// A chunk in the current swap layout, prefixed with its version.
+pub(crate) fn encode_chunk(chunk: &[TaskControlBlock]) -> postcard::Result<Vec<u8>> {
+    let chunk = chunk
+        .iter()
+        .map(task::SwappedTask::from)
+        .collect::<Vec<_>>();
+    postcard::to_extend(&chunk, vec![SWAP_VERSION])
+}
+
+async fn write_chunk(file: &mut File, chunk: &[TaskControlBlock]) -> anyhow::Result<()> {
+    let chunk = chunk.to_vec();
+    let buf = blocking(move || encode_chunk(&chunk)).await?;
+    event!(Level::DEBUG, "len<out> = {}", buf.len());
+    file.write_u32(buf.len() as u32).await?;
+    file.write_all(buf.as_slice()).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+impl<Task> ScheduleQueues<Task> {
+    async fn move_inactive_to_swap(
+        &self,
+        fd: &mut File,
+        max_memory_size: usize,
+    ) -> anyhow::Result<usize> {
+        fd.seek(SeekFrom::End(0)).await?;
+        let mut finished_queue = self.finished.lock().await;
+        let swap_amount = finished_queue.len() as i32 - max_memory_size as i32;
+        if swap_amount <= 0 {
+            event!(target: "scheduler", Level::DEBUG, "finished queue size {} <= max memory size {}, no need to swap", finished_queue.len(), max_memory_size);
+            return Ok(0);
+        }
+        let items_left = finished_queue.split_off(swap_amount as usize);
+        let items_swapped = finished_queue.len();
+        write_chunk(fd, finished_queue.as_slice()).await?;
+        let mut retained = self.retained.lock().await;
+        for task in finished_queue.iter() {
+            retained.remove(task.id());
+        }
+        *finished_queue = items_left;
+        Ok(items_swapped)
+    }
+}
+
+#[cfg(test)]
+impl<Runner> Default for Scheduler<Runner>
+where
+    Runner: RunTask + Default,
+{
+    fn default() -> Self {
+        Self::new(
+            4,
+            468_000, // approx. 50 megabytes
+            Duration::from_mins(5),
+            Default::default(),
+        )
+        .unwrap()
+    }
+}
+
+impl<Task> Default for ScheduleQueues<Task> {
+    fn default() -> Self {
+        Self {
+            active: Arc::new(Mutex::new(Vec::new())),
+            pending: Arc::new(Mutex::new(Vec::new())),
+            finished: Arc::new(Mutex::new(Vec::new())),
+            retained: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol_str::SmolStr;
+    use tracing_test::traced_test;
+
+    use crate::{
+        bill::Category,
+        error::RunTaskError,
+        task::{StageSeeds, StageTexts, TaskDescriptor, TokenSender},
+    };
+
+    use super::*;
+    #[tokio::test]
+    #[traced_test]
+    async fn test_swap() {
+        let _registry = crate::bill::lock_registry().await;
+        Category::load_from_names(["No category"]);
+        let scheduler = Scheduler::<MockRunner>::default();
+        for i in 0..10 {
+            let tcb = TaskControlBlock::new();
+            tcb.set_state(task::State::Finished(Ok(task::Success(
+                crate::bill::Bill {
+                    notes: "No.".into(),
+                    amount: i as f32 / 3f32,
+                    currency: None,
+                    categories: vec!["No category".into()],
+                    category_reason: None,
+                    needs_review: true,
+                },
+            ))));
+            scheduler.queues.finished.lock().await.push(tcb);
+        }
+        let lookup_id = scheduler
+            .queues
+            .finished
+            .lock()
+            .await
+            .first()
+            .unwrap()
+            .id()
+            .to_string();
+        scheduler
+            .queues
+            .move_inactive_to_swap(&mut *scheduler.swap_file.lock().await, 1)
+            .await
+            .unwrap();
+        assert_eq!(scheduler.queues.finished.lock().await.len(), 1);
+        assert!(scheduler.get_task(lookup_id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_swap_iter_releases_file() {
+        let scheduler = Scheduler::<MockRunner>::default();
+        let finished = || {
+            let tcb = TaskControlBlock::new();
+            tcb.set_state(task::State::Finished(Ok(task::Success(Bill {
+                notes: "No.".into(),
+                amount: 0f32,
+                currency: None,
+                categories: Vec::new(),
+                category_reason: None,
+                needs_review: false,
+            }))));
+            tcb
+        };
+        let swapped = [finished(), finished()];
+        scheduler
+            .queues
+            .finished
+            .lock()
+            .await
+            .extend(swapped.iter().cloned());
+        scheduler.set_max_memory_size(0).await.unwrap();
+        let kept = finished();
+        scheduler.queues.finished.lock().await.push(kept.clone());
+
+        let tasks = scheduler.finished_tasks();
+        pin!(tasks);
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            ids.push(tasks.try_next().await.unwrap().unwrap().id().to_string());
+        }
+        // swapping and rewriting while the stream sits between chunks
+        tokio::time::timeout(Duration::from_secs(5), async {
+            scheduler.set_max_memory_size(0).await.unwrap();
+            scheduler
+                .update_bill(swapped[1].id(), |bill| bill.needs_review = true)
+                .await
+                .unwrap();
+        })
+        .await
+        .unwrap();
+        while let Some(task) = tasks.try_next().await.unwrap() {
+            ids.push(task.id().to_string());
+        }
+        ids.sort();
+        let mut expected = [&swapped[0], &swapped[1], &kept]
+            .map(|task| task.id().to_string())
+            .to_vec();
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_update_swapped_bill() {
+        let scheduler = Scheduler::<MockRunner>::default();
+        for _ in 0..3 {
+            let tcb = TaskControlBlock::new();
+            tcb.set_state(task::State::Finished(Ok(task::Success(Bill {
+                notes: "No.".into(),
+                amount: 0f32,
+                currency: None,
+                categories: Vec::new(),
+                category_reason: None,
+                needs_review: true,
+            }))));
+            scheduler.queues.finished.lock().await.push(tcb);
+        }
+        let ids = scheduler
+            .queues
+            .finished
+            .lock()
+            .await
+            .iter()
+            .map(|task| task.id().to_string())
+            .collect::<Vec<_>>();
+        scheduler
+            .queues
+            .move_inactive_to_swap(&mut *scheduler.swap_file.lock().await, 1)
+            .await
+            .unwrap();
+
+        for id in &ids {
+            // remembered before the update, which must not leave it stale
+            let remembered = scheduler.get_task(id).await.unwrap().unwrap();
+            assert!(remembered.needs_review());
+            let task = scheduler
+                .update_bill(id, |bill| bill.needs_review = false)
+                .await
+                .unwrap();
+            assert!(!task.needs_review());
+            let looked_up = scheduler.get_task(id).await.unwrap().unwrap();
+            assert!(!looked_up.needs_review());
+        }
+        let flagged = scheduler
+            .tasks()
+            .try_filter(|task| futures::future::ready(task.needs_review()))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert!(flagged.is_empty());
+        assert!(matches!(
+            scheduler.update_bill("missing", |_| {}).await,
+            Err(UpdateTaskError::NotFound)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_update_while_swapping() {
+        let scheduler = Arc::new(Scheduler::<MockRunner>::default());
+        let mut ids = Vec::new();
+        for _ in 0..64 {
+            let tcb = TaskControlBlock::new();
+            tcb.set_state(task::State::Finished(Ok(task::Success(Bill {
+                notes: "No.".into(),
+                amount: 0f32,
+                currency: None,
+                categories: Vec::new(),
+                category_reason: None,
+                needs_review: true,
+            }))));
+            ids.push(tcb.id().to_string());
+            scheduler.queues.finished.lock().await.push(tcb);
+        }
+
+        // swaps a few tasks at a time, like the swap job after each finished task
+        let swapping = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move {
+                for left in (0..=60).rev().step_by(4) {
+                    scheduler
+                        .queues
+                        .move_inactive_to_swap(&mut *scheduler.swap_file.lock().await, left)
+                        .await
+                        .unwrap();
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+        let updates = futures::future::join_all(ids.iter().map(|id| {
+            let scheduler = scheduler.clone();
+            async move {
+                for round in 0..4 {
+                    scheduler
+                        .update_task(id, |task| {
+                            task.set_tags(vec![format!("round {round}").into()]);
+                            Ok(())
+                        })
+                        .await
+                        .unwrap();
+                }
+            }
+        }));
+        let (swapped, _) = tokio::join!(swapping, updates);
+        swapped.unwrap();
+
+        assert!(scheduler.queues.finished.lock().await.is_empty());
+        let tasks = scheduler.tasks().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(tasks.len(), ids.len());
+        for task in tasks {
+            assert_eq!(task.tags(), ["round 3"], "{}", task.id());
+        }
+        for id in &ids {
+            let task = scheduler.get_task(id).await.unwrap().unwrap();
+            assert_eq!(task.tags(), ["round 3"]);
+        }
+        assert!(matches!(
+            scheduler.update_task("missing", |_| Ok(())).await,
+            Err(UpdateTaskError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_delete_and_purge() {
+        let scheduler = Scheduler::<MockRunner>::default().with_retained_descriptors(true);
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            let tcb = TaskControlBlock::new();
+            tcb.set_state(task::State::Finished(Ok(task::Success(Bill {
+                notes: "No.".into(),
+                amount: 1f32,
+                currency: None,
+                categories: Vec::new(),
+                category_reason: None,
+                needs_review: false,
+            }))));
+            ids.push(tcb.id().to_string());
+            scheduler
+                .queues
+                .retained
+                .lock()
+                .await
+                .insert(tcb.id().to_string(), Arc::new(MockTaskDescriptor));
+            scheduler.queues.finished.lock().await.push(tcb);
+        }
+        // the first two are swapped out, the others stay in memory
+        scheduler
+            .queues
+            .move_inactive_to_swap(&mut *scheduler.swap_file.lock().await, 2)
+            .await
+            .unwrap();
+        let listed = async || {
+            scheduler
+                .tasks()
+                .map_ok(|task| task.id().to_string())
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap()
+        };
+
+        for id in [&ids[0], &ids[2]] {
+            for _ in 0..2 {
+                assert!(scheduler.delete_task(id).await.unwrap().is_deleted());
+            }
+            let task = scheduler.get_task(id).await.unwrap().unwrap();
+            assert!(task.is_deleted());
+        }
+        assert_eq!(listed().await, [ids[3].clone(), ids[1].clone()]);
+
+        for id in [&ids[1], &ids[3]] {
+            assert!(scheduler.purge_task(id).await.unwrap());
+            assert!(!scheduler.purge_task(id).await.unwrap());
+            assert!(scheduler.get_task(id).await.unwrap().is_none());
+            assert!(scheduler.retained_descriptor(id).await.is_none());
+        }
+        assert!(listed().await.is_empty());
+        assert!(scheduler.get_task(&ids[0]).await.unwrap().is_some());
+        assert!(matches!(
+            scheduler.delete_task("missing").await,
+            Err(UpdateTaskError::NotFound)
+        ));
+
+        let pending = TaskControlBlock::new();
+        scheduler
+            .queues
+            .pending
+            .lock()
+            .await
+            .push((pending.clone(), Arc::new(MockTaskDescriptor)));
+        assert!(matches!(
+            scheduler.purge_task(pending.id()).await,
+            Err(UpdateTaskError::NotFinished)
+        ));
+        assert!(matches!(
+            scheduler.delete_task(pending.id()).await,
+            Err(UpdateTaskError::NotFinished)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_old_chunks() {
+        /// A bill as swap layouts before version 4 wrote it
+        #[derive(Serialize)]
+        struct BillV1 {
+            notes: &'static str,
+            amount: f32,
+            currency: Option<&'static str>,
+            category: Option<&'static str>,
+            needs_review: bool,
+        }
+        /// The fields of a task as swap layout version 1 wrote them
+        #[derive(Serialize)]
+        struct TaskV1 {
+            id: String,
+            state: String,
+            success: Option<BillV1>,
+            error: Option<TaskError>,
+            created_at: chrono::DateTime<chrono::Utc>,
+            finished_at: Option<chrono::DateTime<chrono::Utc>>,
+        }
+        let finished_at = chrono::Utc::now();
+        let tasks = vec![
+            TaskV1 {
+                id: "paid".into(),
+                state: "finished".into(),
+                success: Some(BillV1 {
+                    notes: "Coffee",
+                    amount: 4.2,
+                    currency: Some("EUR"),
+                    category: Some("Food"),
+                    needs_review: false,
+                }),
+                error: None,
+                created_at: finished_at,
+                finished_at: Some(finished_at),
+            },
+            TaskV1 {
+                id: "failed".into(),
+                state: "finished".into(),
+                success: None,
+                error: Some(TaskError::from_message("out of memory")),
+                created_at: finished_at,
+                finished_at: Some(finished_at),
+            },
+        ];
+        let buf = postcard::to_extend(&tasks, vec![1]).unwrap();
+        let mut file = File::from_std(tempfile().unwrap());
+        file.write_u32(buf.len() as u32).await.unwrap();
+        file.write_all(&buf).await.unwrap();
+        // version 2 was the serde of the task itself
+        let tagged = TaskControlBlock::new();
+        tagged.set_state(task::State::Finished(Err(TaskError::from_message("gone"))));
+        tagged.set_tags(vec!["work".into()]);
+        let buf = postcard::to_extend(&vec![&tagged], vec![2]).unwrap();
+        file.write_u32(buf.len() as u32).await.unwrap();
+        file.write_all(&buf).await.unwrap();
+        /// A bill as swap layouts 4 to 6 wrote it
+        #[derive(Serialize)]
+        struct BillV2 {
+            notes: &'static str,
+            amount: f32,
+            currency: Option<&'static str>,
+            categories: Vec<&'static str>,
+            needs_review: bool,
+        }
+        #[derive(Serialize)]
+        #[allow(dead_code)]
+        enum StateV6 {
+            Pending,
+            Running,
+            Finished(Result<BillV2, TaskError>),
+        }
+        /// A task as swap layout version 6 wrote it
+        #[derive(Serialize)]
+        struct TaskV6 {
+            id: &'static str,
+            state: StateV6,
+            created_at: chrono::DateTime<chrono::Utc>,
+            finished_at: Option<chrono::DateTime<chrono::Utc>>,
+            tags: Vec<&'static str>,
+            deleted: bool,
+            prompts: std::collections::BTreeMap<task::Stage, String>,
+            seeds: std::collections::BTreeMap<task::Stage, i32>,
+        }
+        let v6 = TaskV6 {
+            id: "lamp",
+            state: StateV6::Finished(Ok(BillV2 {
+                notes: "Lamp",
+                amount: 30.0,
+                currency: None,
+                categories: vec!["Household"],
+                needs_review: false,
+            })),
+            created_at: finished_at,
+            finished_at: Some(finished_at),
+            tags: Vec::new(),
+            deleted: false,
+            prompts: std::collections::BTreeMap::new(),
+            seeds: std::collections::BTreeMap::new(),
+        };
+        let buf = postcard::to_extend(&vec![v6], vec![6]).unwrap();
+        file.write_u32(buf.len() as u32).await.unwrap();
+        file.write_all(&buf).await.unwrap();
+        let prompts = task::StageTexts::default();
+        prompts.record(task::Stage::Notes, "Take notes of [redacted]");
+        write_chunk(&mut file, &[TaskControlBlock::new().with_prompts(prompts)])
+            .await
+            .unwrap();
+        file.rewind().await.unwrap();
+
+        let old = read_chunk(&mut file).await.unwrap().unwrap();
+        assert_eq!(old.len(), 2);
+        assert_eq!(old[0].id(), "paid");
+        let bill = old[0].finished().await.unwrap().0;
+        assert_eq!((bill.amount, bill.categories), (4.2, vec!["Food".into()]));
+        assert_eq!(old[0].finished_at(), Some(finished_at));
+        assert!(old[0].tags().is_empty() && !old[0].is_deleted());
+        assert_eq!(
+            old[1].finished().await.unwrap_err().message,
+            "out of memory"
+        );
+        let v2 = read_chunk(&mut file).await.unwrap().unwrap();
+        assert_eq!(v2, [tagged]);
+        let v6 = read_chunk(&mut file).await.unwrap().unwrap();
+        let bill = v6[0].finished().await.unwrap().0;
+        assert_eq!(bill.categories, ["Household"]);
+        assert_eq!(bill.category_reason, None);
+        let current = read_chunk(&mut file).await.unwrap().unwrap();
+        assert_eq!(current[0].state(), task::State::Pending);
+        assert_eq!(
+            current[0].prompts().unwrap()[&task::Stage::Notes],
+            "Take notes of [redacted]"
+        );
+        assert!(read_chunk(&mut file).await.unwrap().is_none());
+
+        assert!(decode_chunk(&[9, 0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_slot() {
+        let scheduler = Arc::new(Scheduler::new(1, 16, Duration::ZERO, MockRunner).unwrap());
+        let slot = scheduler.slot().await;
+        // the only slot is taken, by the slot and then by the task queued
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), scheduler.slot())
+                .await
+                .is_err()
+        );
+        let task = scheduler.create_task(MockTaskDescriptor).await;
+        assert!(matches!(task.state(), task::State::Pending));
+        drop(slot);
+        tokio::time::timeout(Duration::from_secs(5), task.finished())
+            .await
+            .unwrap()
+            .unwrap();
+        drop(scheduler.slot().await);
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit() {
+        let scheduler = Scheduler::new(1, 16, Duration::ZERO, MockRunner)
+            .unwrap()
+            .with_memory_limit(Some(2 * TASK_FOOTPRINT - 1));
+        assert_eq!(scheduler.memory_usage().await, 0);
+        let upload = scheduler.reserve(TASK_FOOTPRINT + 10).await.unwrap();
+        assert_eq!(scheduler.memory_usage().await, TASK_FOOTPRINT + 10);
+        // a second upload at once doesn't fit beside the first
+        assert!(matches!(
+            scheduler.reserve(TASK_FOOTPRINT).await,
+            Err(CreateTaskError::MemoryLimit { limit }) if limit == 2 * TASK_FOOTPRINT - 1
+        ));
+        let admitted = scheduler.admit(&MockTaskDescriptor, upload).await.unwrap();
+        assert_eq!(scheduler.memory_usage().await, TASK_FOOTPRINT);
+        let task = scheduler.create_task(MockTaskDescriptor).await;
+        drop(admitted);
+        task.finished().await.unwrap();
+        // moved to the finished queue right after
+        while scheduler.memory_usage().await == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(scheduler.memory_usage().await, TASK_FOOTPRINT);
+        // the finished task is swapped out early to make room
+        let reservation = scheduler.reserve(TASK_FOOTPRINT).await.unwrap();
+        assert!(scheduler.queues.finished.lock().await.is_empty());
+        assert!(scheduler.get_task(task.id()).await.unwrap().is_some());
+        drop(reservation);
+        assert!(matches!(
+            scheduler.reserve(2 * TASK_FOOTPRINT).await,
+            Err(CreateTaskError::MemoryLimit { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let scheduler =
+            Scheduler::<MockRunner>::new(1, 0, Duration::from_mins(5), Default::default()).unwrap();
+        let mut events = scheduler.events().subscribe();
+        let task = scheduler.create_task(MockTaskDescriptor).await;
+        task.finished().await.unwrap();
+        let id = task.id().to_string();
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            received.push(events.recv().await.unwrap());
+        }
+        // rather than waiting for the delayed swap after the task
+        let restored = TaskControlBlock::new();
+        restored.set_state(task::State::Finished(Err(TaskError::from_message("gone"))));
+        scheduler.restore_finished(vec![restored]).await.unwrap();
+        received.push(events.recv().await.unwrap());
+        assert_eq!(
+            received,
+            [
+                SchedulerEvent::TaskCreated { id: id.clone() },
+                SchedulerEvent::TaskStarted {
+                    id: id.clone(),
+                    stage: task::Stage::Amount
+                },
+                SchedulerEvent::TaskFinished { id, success: true },
+                SchedulerEvent::TaskSwapped { count: 2 },
+            ]
+        );
+        let json = serde_json::to_value(&received[3]).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"type": "task_swapped", "count": 2})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_swapped() {
+        let scheduler = Arc::new(Scheduler::<MockRunner>::default());
+        for category in ["Food", "Rent", "Food"] {
+            let tcb = TaskControlBlock::new();
+            tcb.set_state(task::State::Finished(Ok(task::Success(Bill {
+                notes: "No.".into(),
+                amount: 1f32,
+                currency: None,
+                categories: vec![category.into()],
+                category_reason: None,
+                needs_review: false,
+            }))));
+            scheduler.queues.finished.lock().await.push(tcb);
+        }
+        let first = scheduler.queues.finished.lock().await[0].clone();
+        scheduler
+            .queues
+            .move_inactive_to_swap(&mut *scheduler.swap_file.lock().await, 1)
+            .await
+            .unwrap();
+
+        let filter = crate::export::TaskFilter::try_from(crate::export::TaskQuery {
+            category: vec!["Food".into()],
+            ..Default::default()
+        })
+        .unwrap();
+        let lines = crate::export::export(
+            scheduler.clone(),
+            filter,
+            crate::mapping::BillMapping::canonical(true),
+            crate::export::ExportFormat::Jsonl,
+        )
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+        assert_eq!(lines.len(), 2);
+        let lines = lines
+            .iter()
+            .map(|line| serde_json::from_slice::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let swapped = lines.iter().find(|line| line["id"] == first.id()).unwrap();
+        assert_eq!(
+            swapped["finished_at"],
+            serde_json::to_value(first.finished_at().unwrap()).unwrap()
+        );
+        assert_eq!(swapped["category"], "Food");
+
+        // picking up after the second bill to finish
+        let mut tasks = scheduler.tasks().try_collect::<Vec<_>>().await.unwrap();
+        tasks.sort_by_key(|tcb| (tcb.finished_at(), tcb.id().to_string()));
+        let after = crate::export::TaskFilter::default()
+            .after(crate::export::Cursor::of(&tasks[1]).unwrap());
+        let lines = crate::export::export(
+            scheduler,
+            after,
+            crate::mapping::BillMapping::canonical(true),
+            crate::export::ExportFormat::Jsonl,
+        )
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+        assert_eq!(lines.len(), 1);
+        let line = serde_json::from_slice::<serde_json::Value>(&lines[0]).unwrap();
+        assert_eq!(line["id"], tasks[2].id());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_finished() {
+        let scheduler = Scheduler::<MockRunner>::default();
+        let tcb = scheduler.create_task(MockTaskDescriptor).await;
+        let success = tokio::time::timeout(Duration::from_secs(5), tcb.finished())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(success.0.categories, ["No category"]);
+    }
+
+    #[tokio::test]
+    async fn test_backfill_retained() {
+        let scheduler =
+            Arc::new(Scheduler::<MockRunner>::default().with_retained_descriptors(true));
+        let swapped = scheduler.create_task(MockTaskDescriptor).await;
+        swapped.finished().await.unwrap();
+        // the job retains the descriptor right after finishing
+        while scheduler.queues.retained.lock().await.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        scheduler.set_max_memory_size(0).await.unwrap();
+        scheduler.set_max_memory_size(1).await.unwrap();
+        let original = scheduler.create_task(MockTaskDescriptor).await;
+        original.finished().await.unwrap();
+        while scheduler.queues.retained.lock().await.is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        let progress = scheduler.start_backfill().await.unwrap();
+        assert!(progress.running);
+        assert_eq!(progress.total, 1);
+        assert_eq!(progress.skipped, [swapped.id().to_string()]);
+        assert!(matches!(
+            scheduler.start_backfill().await,
+            Err(BackfillError::AlreadyRunning)
+        ));
+        let progress = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let progress = scheduler.backfill_progress();
+                if !progress.running {
+                    return progress;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(progress.submitted, 1);
+        let resubmitted = scheduler
+            .get_task(&progress.tasks[0])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(resubmitted.id(), original.id());
+        resubmitted.finished().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backfill_waits_for_slots() {
+        let scheduler = Arc::new(
+            Scheduler::new(1, 16, Duration::ZERO, MockRunner)
+                .unwrap()
+                .with_retained_descriptors(true),
+        );
+        let original = scheduler.create_task(MockTaskDescriptor).await;
+        original.finished().await.unwrap();
+        while scheduler.queues.retained.lock().await.is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        let slot = scheduler.slot().await;
+        scheduler.start_backfill().await.unwrap();
+        tokio::time::sleep(BACKFILL_POLL_INTERVAL * 2).await;
+        assert_eq!(scheduler.backfill_progress().submitted, 0);
+        drop(slot);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while scheduler.backfill_progress().running {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(scheduler.backfill_progress().submitted, 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_failed() {
+        let scheduler = Scheduler::<MockRunner>::default().with_retained_descriptors(true);
+        let failed = TaskControlBlock::new();
+        failed.set_state(task::State::Finished(Err(RunTaskError::InvalidOutput(
+            "amount".into(),
+        )
+        .into())));
+        scheduler.queues.finished.lock().await.push(failed.clone());
+        assert!(matches!(
+            scheduler.retry_task(failed.id()).await,
+            Err(RetryTaskError::NotRetained)
+        ));
+        scheduler
+            .queues
+            .retained
+            .lock()
+            .await
+            .insert(failed.id().to_string(), Arc::new(MockTaskDescriptor));
+
+        let retry = scheduler.retry_task(failed.id()).await.unwrap();
+        assert_ne!(retry.id(), failed.id());
+        retry.finished().await.unwrap();
+        assert!(matches!(
+            scheduler.retry_task(retry.id()).await,
+            Err(RetryTaskError::Succeeded)
+        ));
+        assert!(matches!(
+            scheduler.retry_task("nonexistent").await,
+            Err(RetryTaskError::NotFound)
+        ));
+
+        let stalled = Scheduler::new(0, 16, Duration::ZERO, MockRunner).unwrap();
+        let pending = stalled.create_task(MockTaskDescriptor).await;
+        assert!(matches!(
+            stalled.retry_task(pending.id()).await,
+            Err(RetryTaskError::NotFinished)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retry_own_buffers() {
+        let scheduler = Scheduler::new(1, 16, Duration::ZERO, RecordingRunner::default())
+            .unwrap()
+            .with_retained_descriptors(true);
+        let failed = scheduler
+            .create_task(RecordingTaskDescriptor::default())
+            .await;
+        failed.finished().await.unwrap_err();
+        let original = (failed.intermediates(), failed.prompts(), failed.seeds());
+        assert_eq!(original.0.as_ref().unwrap()[&task::Stage::Notes], "run 0");
+
+        let retry = scheduler.retry_task(failed.id()).await.unwrap();
+        retry.finished().await.unwrap();
+        assert_eq!(
+            (failed.intermediates(), failed.prompts(), failed.seeds()),
+            original
+        );
+        assert_eq!(retry.intermediates().unwrap()[&task::Stage::Notes], "run 1");
+        assert_eq!(retry.prompts().unwrap()[&task::Stage::Notes], "prompt 1");
+        assert_eq!(retry.seeds().unwrap()[&task::Stage::Notes], 1);
+    }
+
+    #[tokio::test]
+    async fn test_swap_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let scheduler = Scheduler::<MockRunner>::default()
+            .with_swap_dir(dir.path())
+            .unwrap();
+        let tcb = TaskControlBlock::new();
+        tcb.set_state(task::State::Running);
+        scheduler.queues.finished.lock().await.push(tcb.clone());
+        scheduler
+            .queues
+            .move_inactive_to_swap(&mut *scheduler.swap_file.lock().await, 0)
+            .await
+            .unwrap();
+        assert!(scheduler.get_task(tcb.id()).await.unwrap().is_some());
+
+        let missing = dir.path().join("missing");
+        assert!(
+            Scheduler::<MockRunner>::default()
+                .with_swap_dir(&missing)
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_render_cache() {
+        use crate::version::{ApiVersion, TaskFormat, TaskJson};
+
+        let scheduler = Scheduler::<MockRunner>::default();
+        let tcb = TaskControlBlock::new();
+        tcb.set_state(task::State::Finished(Ok(task::Success(Bill {
+            notes: "No.".into(),
+            amount: 0f32,
+            currency: None,
+            categories: Vec::new(),
+            category_reason: None,
+            needs_review: true,
+        }))));
+        scheduler.restore_finished(vec![tcb.clone()]).await.unwrap();
+        let render = || {
+            TaskJson(ApiVersion::V2, tcb.clone())
+                .rendered(TaskFormat::Json)
+                .unwrap()
+        };
+        let variant = Rendered::variant(ApiVersion::V2, TaskFormat::Json, None);
+        let generation = scheduler.render_generation();
+        scheduler.keep_rendered(tcb.id(), variant, render(), generation);
+        assert!(scheduler.rendered(tcb.id(), variant).is_some());
+        let other = Rendered::variant(ApiVersion::V1, TaskFormat::Json, None);
+        assert!(scheduler.rendered(tcb.id(), other).is_none());
+
+        scheduler
+            .update_bill(tcb.id(), |bill| bill.needs_review = false)
+            .await
+            .unwrap();
+        assert!(scheduler.rendered(tcb.id(), variant).is_none());
+        // rendered from the task as it was before the update
+        scheduler.keep_rendered(tcb.id(), variant, render(), generation);
+        assert!(scheduler.rendered(tcb.id(), variant).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_swap_cache() {
+        let scheduler = Scheduler::<MockRunner>::default().with_swap_cache(1);
+        for _ in 0..2 {
+            let tcb = TaskControlBlock::new();
+            tcb.set_state(task::State::Finished(Ok(task::Success(Bill {
+                notes: "No.".into(),
+                amount: 0f32,
+                currency: None,
+                categories: Vec::new(),
+                category_reason: None,
+                needs_review: true,
+            }))));
+            scheduler.queues.finished.lock().await.push(tcb);
+        }
+        let ids = scheduler
+            .queues
+            .finished
+            .lock()
+            .await
+            .iter()
+            .map(|task| task.id().to_string())
+            .collect::<Vec<_>>();
+        scheduler
+            .queues
+            .move_inactive_to_swap(&mut *scheduler.swap_file.lock().await, 0)
+            .await
+            .unwrap();
+        let reads = || scheduler.swap_reads.load(Ordering::Relaxed);
+
+        scheduler.get_task(&ids[0]).await.unwrap().unwrap();
+        let after_miss = reads();
+        assert!(after_miss > 0);
+        scheduler.get_task(&ids[0]).await.unwrap().unwrap();
+        assert_eq!(reads(), after_miss, "a hit must not touch the swap file");
+
+        // the only slot goes to the other task
+        scheduler.get_task(&ids[1]).await.unwrap().unwrap();
+        let before = reads();
+        scheduler.get_task(&ids[0]).await.unwrap().unwrap();
+        assert!(reads() > before);
+
+        scheduler
+            .update_bill(&ids[0], |bill| bill.needs_review = false)
+            .await
+            .unwrap();
+        let task = scheduler.get_task(&ids[0]).await.unwrap().unwrap();
+        assert!(!task.needs_review(), "updates invalidate the cached copy");
+
+        let stats = scheduler.stats().swap_cache;
+        assert_eq!((stats.hits, stats.misses), (1, 4));
+        assert_eq!((stats.size, stats.capacity), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_clear_pending() {
+        let stalled = Scheduler::new(0, 16, Duration::ZERO, MockRunner).unwrap();
+        let first = stalled.create_task(MockTaskDescriptor).await;
+        let second = stalled.create_task(MockTaskDescriptor).await;
+        assert_eq!(stalled.clear_pending().await, 2);
+        for tcb in [first, second] {
+            let err = tcb.finished().await.unwrap_err();
+            assert_eq!(err.code, crate::error::TaskErrorCode::Cancelled);
+            assert!(stalled.get_task(tcb.id()).await.unwrap().is_some());
+        }
+        assert_eq!(stalled.clear_pending().await, 0);
+
+        // cancelling leaves running tasks be
+        stalled.set_max_concurrency(1).await;
+        let running = stalled.create_task(MockTaskDescriptor).await;
+        while running.state() == task::State::Pending {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(stalled.clear_pending().await, 0);
+        assert!(running.finished().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_set_max_concurrency() {
+        let scheduler = Scheduler::new(0, 16, Duration::ZERO, MockRunner).unwrap();
+        let first = scheduler.create_task(MockTaskDescriptor).await;
+        let second = scheduler.create_task(MockTaskDescriptor).await;
+        scheduler.set_max_concurrency(1).await;
+        second.finished().await.unwrap();
+        assert_eq!(first.state(), task::State::Pending);
+
+        scheduler.set_max_concurrency(0).await;
+        let third = scheduler.create_task(MockTaskDescriptor).await;
+        assert_eq!(third.state(), task::State::Pending);
+        scheduler.set_max_concurrency(2).await;
+        first.finished().await.unwrap();
+        third.finished().await.unwrap();
+        assert_eq!(scheduler.max_concurrency(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_dedup_window() {
+        let window = Some(Duration::from_mins(1));
+        // nothing runs without concurrency, so tasks stay pending
+        let stalled = Scheduler::new(0, 16, Duration::ZERO, MockRunner)
+            .unwrap()
+            .with_dedup_window(window);
+        let first = stalled.create_task(MockTaskDescriptor).await;
+        let second = stalled.create_task(MockTaskDescriptor).await;
+        assert_eq!(first.id(), second.id());
+        assert_eq!(
+            stalled.tasks().try_collect::<Vec<_>>().await.unwrap().len(),
+            1
+        );
+
+        let expired = Scheduler::new(0, 16, Duration::ZERO, MockRunner)
+            .unwrap()
+            .with_dedup_window(Some(Duration::ZERO));
+        let first = expired.create_task(MockTaskDescriptor).await;
+        assert_ne!(
+            expired.create_task(MockTaskDescriptor).await.id(),
+            first.id()
+        );
+
+        let running = Scheduler::<MockRunner>::default().with_dedup_window(window);
+        let first = running.create_task(MockTaskDescriptor).await;
+        first.finished().await.unwrap();
+        assert_ne!(
+            running.create_task(MockTaskDescriptor).await.id(),
+            first.id()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_latency() {
+        let scheduler = Scheduler::<MockRunner>::default();
+        let empty = scheduler.stats().queue_wait;
+        assert_eq!((empty.count, empty.p50), (0, None));
+        scheduler
+            .create_task(MockTaskDescriptor)
+            .await
+            .finished()
+            .await
+            .unwrap();
+        let stats = scheduler.stats();
+        assert_eq!((stats.queue_wait.count, stats.execution.count), (1, 1));
+        assert!(stats.execution.p99.unwrap() <= LATENCY_BUCKETS[0]);
+
+        scheduler.reset_latency();
+        let stats = scheduler.stats();
+        assert_eq!((stats.queue_wait.count, stats.execution.count), (0, 0));
+        assert_eq!(scheduler.latency().execution.sum_seconds(), 0.0);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::default();
+        for seconds in 1..=100 {
+            histogram.record(Duration::from_secs(seconds));
+        }
+        histogram.record(Duration::from_secs(7200));
+        let summary = histogram.summary();
+        assert_eq!(summary.count, 101);
+        // 50.5 of the tasks took up to 60s, 20 of them more than 30s
+        let p50 = summary.p50.unwrap();
+        assert!((30.0..=60.0).contains(&p50), "{p50}");
+        let p90 = summary.p90.unwrap();
+        assert!((60.0..=120.0).contains(&p90), "{p90}");
+        assert_eq!(histogram.counts().last(), Some(&1));
+        assert_eq!(quantile(&histogram.counts(), 1.0), Some(3600.0));
+        assert_eq!(histogram.sum_seconds(), 5050.0 + 7200.0);
+    }
+
+    #[tokio::test]
+    async fn test_runner_panic() {
+        let scheduler = Scheduler::new(1, 16, Duration::ZERO, PanickingRunner).unwrap();
+        let tcb = scheduler.create_task(MockTaskDescriptor).await;
+        let err = tokio::time::timeout(Duration::from_secs(5), tcb.finished())
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.code, crate::error::TaskErrorCode::Runner);
+        assert!(err.message.contains("no choices"), "{}", err.message);
+        // the next task still runs
+        let next = scheduler.create_task(MockTaskDescriptor).await;
+        tokio::time::timeout(Duration::from_secs(5), next.finished())
+            .await
+            .unwrap()
+            .unwrap_err();
+    }
+
+    struct MockTaskDescriptor;
+    #[derive(Default, Clone)]
+    struct MockRunner;
+    #[derive(Clone)]
+    struct PanickingRunner;
+
+    impl TaskDescriptor for MockTaskDescriptor {
+        fn images(&self) -> Vec<&[u8]> {
+            Vec::new()
+        }
+
+        fn category_names(&self) -> Vec<SmolStr> {
+            Vec::new()
+        }
+
+        fn rerun(&self) -> Self {
+            MockTaskDescriptor
+        }
+    }
+
+    impl RunTask for MockRunner {
+        type TaskDescriptor = MockTaskDescriptor;
+
+        async fn extract(
+            &self,
+            _: &Self::TaskDescriptor,
+            tokens: &TokenSender,
+        ) -> Result<Bill, RunTaskError> {
+            let _ = tokens.send(task::Token {
+                stage: task::Stage::Amount,
+                kind: task::TokenKind::Started,
+                text: String::new(),
+            });
+            Ok(Bill {
+                notes: SmolStr::default(),
+                amount: 0f32,
+                currency: None,
+                categories: vec!["No category".into()],
+                category_reason: None,
+                needs_review: false,
+            })
+        }
+    }
+
+    impl RunTask for PanickingRunner {
+        type TaskDescriptor = MockTaskDescriptor;
+
+        async fn extract(
+            &self,
+            _: &Self::TaskDescriptor,
+            _: &TokenSender,
+        ) -> Result<Bill, RunTaskError> {
+            panic!("no choices in the response")
+        }
+    }
+
+    /// Records what it ran with into the buffers of the descriptor, failing
+    /// the first run.
+    #[derive(Default, Clone)]
+    struct RecordingRunner(Arc<AtomicUsize>);
+    #[derive(Default)]
+    struct RecordingTaskDescriptor {
+        intermediates: StageTexts,
+        prompts: StageTexts,
+        seeds: StageSeeds,
+    }
+
+    impl TaskDescriptor for RecordingTaskDescriptor {
+        fn images(&self) -> Vec<&[u8]> {
+            Vec::new()
+        }
+
+        fn category_names(&self) -> Vec<SmolStr> {
+            Vec::new()
+        }
+
+        fn intermediates(&self) -> Option<&StageTexts> {
+            Some(&self.intermediates)
+        }
+
+        fn prompts(&self) -> Option<&StageTexts> {
+            Some(&self.prompts)
+        }
+
+        fn seeds(&self) -> Option<&StageSeeds> {
+            Some(&self.seeds)
+        }
+
+        fn rerun(&self) -> Self {
+            Self::default()
+        }
+    }
+
+    impl RunTask for RecordingRunner {
+        type TaskDescriptor = RecordingTaskDescriptor;
+
+        async fn extract(
+            &self,
+            task: &Self::TaskDescriptor,
+            _: &TokenSender,
+        ) -> Result<Bill, RunTaskError> {
+            let run = self.0.fetch_add(1, Ordering::AcqRel);
+            task.intermediates
+                .record(task::Stage::Notes, &format!("run {run}"));
+            task.prompts
+                .record(task::Stage::Notes, &format!("prompt {run}"));
+            task.seeds.record(task::Stage::Notes, run as i32);
+            if run == 0 {
+                return Err(RunTaskError::InvalidOutput("amount".into()));
+            }
             Ok(Bill {
                 notes: SmolStr::default(),
                 amount: 0f32,
-                category: Some("No category".into()),
+                currency: None,
+                categories: Vec::new(),
+                category_reason: None,
+                needs_review: false,
             })
         }
     }
@@ -0,0 +1,1684 @@
+use axum::{
+    Extension, Json,
+    body::Body,
+    extract::{DefaultBodyLimit, FromRequestParts, Path, Query, RawQuery, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header, request::Parts},
+    middleware::{from_fn, from_fn_with_state, map_response},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{get, patch, post, put},
+};
+use futures::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use std::{collections::BTreeMap, convert::Infallible, fmt::Write, time::Duration};
+use tokio::{sync::broadcast::error::RecvError, time::Instant};
+use tracing::{Level, event};
+
+use crate::{
+    backup::{self, Restored},
+    bill::Category,
+    config::{ConfigPatch, RuntimeConfig},
+    deadline::{self, Deadline},
+    error::{
+        AskError, AuthError, BackfillError, ConfigError, CreateTaskError, DescribeError,
+        ExportError, GetTaskError, PinModelError, RestoreError, RetryTaskError, SyncTaskError,
+        TaskError, UpdateTaskError,
+    },
+    export::{self, Cursor, ExportFormat, TaskFilter, TaskQuery},
+    key::ValidKey,
+    listen::{ConnectionStats, Timeout},
+    mapping::BillMapping,
+    openapi, request_id,
+    schedule::{BackfillProgress, LATENCY_BUCKETS, Latency, Reservation, Stats, TASK_FOOTPRINT},
+    state::AppState,
+    task::{
+        self, Stage, Success, TOKEN_CHANNEL_CAPACITY, TaskControlBlock, TaskDescriptor, Token,
+        TokenSender, imaging,
+        ollama::{
+            ModelCounters, ModelStatus, OfferedQuantizations, OllamaTaskDescriptor, Quantization,
+            UploadLimit,
+        },
+        quiet::Residency,
+    },
+    ui,
+    version::{self, ApiVersion, MappedTask, Rendered, TaskFormat, TaskJson},
+};
+
+/// Longest tag accepted, in characters.
+const MAX_TAG_LEN: usize = 64;
+/// Longest question about a task accepted, in characters.
+const MAX_QUESTION_LEN: usize = 1000;
+
+/// The server as the binary runs it.
+pub fn app(state: AppState) -> axum::Router {
+    router(&state).with_state(state)
+}
+
+/// Every route of the server, with the UI if enabled, under the configured base
+/// path, for an application to add its own routes to before providing `state`.
+///
+/// ```no_run
+/// use axum::routing::get;
+/// use ledoxide::{AppState, AppStateConfig};
+///
+/// # fn build() -> Result<axum::Router, ledoxide::error::StartupError> {
+/// let state = AppState::new(&AppStateConfig {
+///     base_path: "/bills".into(),
+///     ..Default::default()
+/// })?;
+/// let app = ledoxide::router(&state)
+///     .route("/health", get(async || "ok"))
+///     .with_state(state);
+/// # Ok(app)
+/// # }
+/// ```
+pub fn router(state: &AppState) -> axum::Router<AppState> {
+    let mut router = axum::Router::new()
+        .route("/", get(index))
+        .route("/info", get(index))
+        .route("/openapi.json", get(openapi_spec))
+        .nest(ApiVersion::V1.prefix(), routes(state, ApiVersion::V1))
+        .nest(ApiVersion::V2.prefix(), routes(state, ApiVersion::V2))
+        .merge(routes(state, ApiVersion::V1).layer(map_response(version::deprecated)));
+    if state.ui_enabled() {
+        router = router.merge(ui::router());
+    }
+    if !state.base_path().is_empty() {
+        router = axum::Router::new().nest(state.base_path(), router);
+    }
+    router.layer(from_fn(request_id::tag))
+}
+
+fn routes(state: &AppState, version: ApiVersion) -> axum::Router<AppState> {
+    // bounds only the handlers, never the tasks they start
+    let deadline = from_fn_with_state(state.clone(), deadline::bound);
+    axum::Router::new()
+        .route(
+            "/create_task",
+            post(create_task)
+                .layer(DefaultBodyLimit::disable())
+                .layer(deadline.clone()),
+        )
+        .route(
+            "/create_task_sync",
+            post(create_task_sync).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/get_task/{task_id}", get(get_task).layer(deadline.clone()))
+        .route("/tasks", get(list_tasks).layer(deadline.clone()))
+        .route("/task/{task_id}", patch(patch_task).delete(delete_task))
+        .route("/task/{task_id}/tags", put(put_tags))
+        .route("/task/{task_id}/retry", post(retry_task))
+        .route("/task/{task_id}/ask", post(ask_task))
+        .route("/task/{task_id}/prompts", get(get_task_prompts))
+        .route(
+            "/describe",
+            post(describe).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/categories", get(list_categories).layer(deadline.clone()))
+        .route("/capabilities", get(capabilities))
+        .route("/export.jsonl", get(export_jsonl))
+        .route("/export.csv", get(export_csv))
+        .route("/admin/models", get(list_models).layer(deadline))
+        .route("/admin/models/{*model}", patch(patch_model))
+        .route("/admin/config", get(get_config).patch(patch_config))
+        .route("/stats", get(stats))
+        .route("/admin/stats/reset", post(reset_stats))
+        .route("/admin/clear_pending", post(clear_pending))
+        .route("/metrics", get(metrics))
+        .route("/admin/events", get(stream_events))
+        .route("/admin/task/{task_id}/stream", get(stream_task))
+        .route("/admin/task/{task_id}/images/{index}", get(get_task_image))
+        .route(
+            "/admin/backfill",
+            get(backfill_progress).post(start_backfill),
+        )
+        .route("/admin/backup", get(backup_archive))
+        .route(
+            "/admin/restore",
+            post(restore_archive).layer(DefaultBodyLimit::disable()),
+        )
+        .layer(Extension(version))
+        // uploads are streamed and checked against the limit as they arrive
+        .layer(Extension(UploadLimit(state.max_upload_size())))
+        .layer(Extension(OfferedQuantizations(
+            state.scheduler().runner().quantizations.clone().into(),
+        )))
+}
+
+async fn index(headers: HeaderMap, state: State<AppState>) -> Response {
+    let version = option_env!("APP_VERSION").unwrap_or(env!("CARGO_PKG_VERSION"));
+    let plain_text = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/plain"));
+    if plain_text {
+        return format!("{} {}", env!("CARGO_PKG_NAME"), version).into_response();
+    }
+    let runner = state.scheduler().runner();
+    Json(Info {
+        name: env!("CARGO_PKG_NAME"),
+        version,
+        commit: env!("GIT_COMMIT"),
+        engine: "ollama",
+        caption_model: runner.caption_model.to_string(),
+        extract_model: runner.extract_model.to_string(),
+        categories: Category::all_cases().len(),
+        uptime_secs: state.started_at().elapsed().as_secs(),
+        auth_enabled: state.auth_enabled(),
+    })
+    .into_response()
+}
+
+async fn openapi_spec(state: State<AppState>) -> Json<serde_json::Value> {
+    Json(openapi::spec(state.base_path()))
+}
+
+/// Memory set aside for a task before its upload is read, as much as its
+/// `Content-Length` says, or `--max-upload-size` if it doesn't.
+impl FromRequestParts<AppState> for Reservation {
+    type Rejection = CreateTaskError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let limit = state.max_upload_size();
+        let length = parts
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<usize>().ok())
+            .map_or(limit, |length| length.min(limit));
+        state.scheduler().reserve(length + TASK_FOOTPRINT).await
+    }
+}
+
+#[axum::debug_handler]
+async fn create_task(
+    _: ValidKey,
+    version: ApiVersion,
+    state: State<AppState>,
+    headers: HeaderMap,
+    reservation: Reservation,
+    task: OllamaTaskDescriptor,
+) -> Result<TaskJson<TaskControlBlock>, CreateTaskError> {
+    // released once the task counts its images itself
+    let _reservation = state.accept(&task, reservation).await?;
+    Ok(TaskJson(
+        version,
+        state
+            .scheduler()
+            .create_task_with_debug(task, debug_requested(&headers))
+            .await,
+    ))
+}
+
+/// A [BillMapping] reshaping the bill JSON, checked before anything else is done.
+#[derive(Debug, Deserialize)]
+struct FieldsQuery {
+    fields: Option<String>,
+    /// False leaves out the `category_reason` of the bill
+    category_reason: Option<bool>,
+}
+
+/// Waits in the handler itself, so a client hanging up drops the wait right
+/// away while the task carries on.
+async fn create_task_sync(
+    _: ValidKey,
+    state: State<AppState>,
+    headers: HeaderMap,
+    Query(FieldsQuery {
+        fields,
+        category_reason,
+    }): Query<FieldsQuery>,
+    reservation: Reservation,
+    task: OllamaTaskDescriptor,
+) -> Result<Response, SyncTaskError> {
+    let mapping = BillMapping::from_query(fields.as_deref(), category_reason.unwrap_or(true))?;
+    let reservation = state
+        .accept(&task, reservation)
+        .await
+        .map_err(SyncTaskError::Rejected)?;
+    let tcb = state
+        .scheduler()
+        .create_task_with_debug(task, debug_requested(&headers))
+        .await;
+    drop(reservation);
+    match tokio::time::timeout(state.sync_timeout(), tcb.finished()).await {
+        Ok(Ok(Success(bill))) => Ok(match mapping {
+            Some(mapping) => Json(mapping.apply(&bill)).into_response(),
+            None => Json(bill).into_response(),
+        }),
+        Ok(Err(err)) => Err(SyncTaskError::Failed(err)),
+        Err(_) => Err(SyncTaskError::Timeout(tcb.id().to_string())),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DescribeQuery {
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Description {
+    description: String,
+}
+
+/// Only the description stage of a task, run right away rather than queued,
+/// in a runner slot shared with the tasks and within the sync timeout.
+async fn describe(
+    _: ValidKey,
+    state: State<AppState>,
+    headers: HeaderMap,
+    Query(DescribeQuery { stream }): Query<DescribeQuery>,
+    task: OllamaTaskDescriptor,
+) -> Result<Response, DescribeError> {
+    state
+        .describe_limiter()
+        .check(&caller_key(&headers))
+        .map_err(DescribeError::RateLimited)?;
+    let runner = state.scheduler().runner().clone();
+    runner
+        .check_quantization(&task)
+        .map_err(DescribeError::Rejected)?;
+    let sync_timeout = state.sync_timeout();
+    let deadline = Instant::now() + sync_timeout;
+    let slot = tokio::time::timeout_at(deadline, state.scheduler().slot())
+        .await
+        .map_err(|_| DescribeError::Timeout(sync_timeout))?;
+    if !stream {
+        // nobody listens, so the generation isn't streamed
+        let tokens = TokenSender::new(1);
+        let description = tokio::time::timeout_at(deadline, runner.describe(&task, &tokens))
+            .await
+            .map_err(|_| DescribeError::Timeout(sync_timeout))?
+            .map_err(|err| DescribeError::Failed(err.into()))?;
+        drop(slot);
+        return Ok(Json(Description { description }).into_response());
+    }
+    // the description is generated as the client reads, and given up when it leaves
+    let stream = async_stream::stream! {
+        let _slot = slot;
+        let sender = TokenSender::new(TOKEN_CHANNEL_CAPACITY);
+        let mut tokens = sender.subscribe();
+        let description = tokio::time::timeout_at(deadline, runner.describe(&task, &sender));
+        tokio::pin!(description);
+        let result = loop {
+            tokio::select! {
+                token = tokens.recv() => match token {
+                    Ok(token) => yield Ok(token_event(&token)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        yield Ok(Event::default().comment(format!("skipped {skipped} tokens")))
+                    }
+                    Err(RecvError::Closed) => {}
+                },
+                result = &mut description => {
+                    while let Ok(token) = tokens.try_recv() {
+                        yield Ok(token_event(&token));
+                    }
+                    break result;
+                }
+            }
+        };
+        let result = match result {
+            Ok(Ok(description)) => Ok(description),
+            Ok(Err(err)) => Err(TaskError::from(err).to_string()),
+            Err(_) => Err(DescribeError::Timeout(sync_timeout).to_string()),
+        };
+        yield Ok::<_, Infallible>(match result {
+            Ok(description) => Event::default()
+                .event("description")
+                .json_data(Description { description }),
+            Err(error) => Event::default()
+                .event("error")
+                .json_data(serde_json::json!({ "error": error })),
+        }
+        .expect("descriptions serialize to JSON"));
+    };
+    Ok(Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// What rate limits tell callers apart by, their `Authorization` header.
+fn caller_key(headers: &HeaderMap) -> String {
+    let key = headers
+        .get(header::AUTHORIZATION)
+        .map(|value| value.as_bytes())
+        .unwrap_or_default();
+    String::from_utf8_lossy(key).into_owned()
+}
+
+/// Whether the client asked for debug logs of its task with `X-Debug`.
+fn debug_requested(headers: &HeaderMap) -> bool {
+    headers
+        .get("X-Debug")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| matches!(value, "1" | "true"))
+}
+
+/// Longest `get_task` holds a request until the task changes state.
+const MAX_TASK_WAIT: Duration = Duration::from_secs(60);
+/// Left of the request deadline when a wait gives up, to answer before it passes.
+const DEADLINE_MARGIN: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Deserialize)]
+struct GetTaskQuery {
+    fields: Option<String>,
+    category_reason: Option<bool>,
+    /// Seconds to hold the request until an unfinished task changes state,
+    /// capped at [MAX_TASK_WAIT]
+    wait: Option<u64>,
+}
+
+/// With `wait`, a pending or running task is answered once it moves on, or
+/// as it is when the wait or the request deadline runs out.
+async fn get_task(
+    _: ValidKey,
+    version: ApiVersion,
+    state: State<AppState>,
+    headers: HeaderMap,
+    deadline: Option<Extension<Deadline>>,
+    Path(GetTaskParams { task_id }): Path<GetTaskParams>,
+    Query(GetTaskQuery {
+        fields,
+        category_reason,
+        wait,
+    }): Query<GetTaskQuery>,
+) -> Result<Response, GetTaskError> {
+    let format = TaskFormat::negotiate(&headers)?;
+    let mapping = BillMapping::from_query(fields.as_deref(), category_reason.unwrap_or(true))?;
+    let variant = Rendered::variant(version, format, mapping.as_ref());
+    if let Some(rendered) = state.scheduler().rendered(&task_id, variant) {
+        return Ok(rendered.respond(format, &headers));
+    }
+    let generation = state.scheduler().render_generation();
+    let tcb = state
+        .scheduler()
+        .get_task(&task_id)
+        .await?
+        .ok_or(GetTaskError::NotFound)?;
+    let observed = tcb.state();
+    if let Some(wait) = wait
+        && !matches!(observed, task::State::Finished(_))
+    {
+        let mut until = Instant::now() + Duration::from_secs(wait).min(MAX_TASK_WAIT);
+        if let Some(Extension(Deadline(deadline))) = deadline {
+            until = until.min(deadline.checked_sub(DEADLINE_MARGIN).unwrap_or(deadline));
+        }
+        let _ = tokio::time::timeout_at(until, tcb.state_changed(&observed)).await;
+    }
+    let task = TaskJson(version, MappedTask(tcb, mapping));
+    match task.rendered(format) {
+        Some(rendered) => {
+            state
+                .scheduler()
+                .keep_rendered(&task_id, variant, rendered.clone(), generation);
+            Ok(rendered.respond(format, &headers))
+        }
+        None => Ok(task.negotiated(format)),
+    }
+}
+
+/// Rendered prompt of each stage a task ran, as kept by `--retain-prompts`.
+async fn get_task_prompts(
+    _: ValidKey,
+    state: State<AppState>,
+    Path(GetTaskParams { task_id }): Path<GetTaskParams>,
+) -> Result<Json<BTreeMap<Stage, String>>, GetTaskError> {
+    state
+        .scheduler()
+        .get_task(task_id)
+        .await?
+        .ok_or(GetTaskError::NotFound)?
+        .prompts()
+        .map(Json)
+        .ok_or(GetTaskError::PromptsNotRetained)
+}
+
+/// An image of a retained task, as uploaded or as the VLM saw it.
+async fn get_task_image(
+    _: ValidKey,
+    state: State<AppState>,
+    Path(ImageParams { task_id, index }): Path<ImageParams>,
+    Query(ImageQuery { processed }): Query<ImageQuery>,
+) -> Result<Response, GetTaskError> {
+    let task = state
+        .scheduler()
+        .retained_descriptor(&task_id)
+        .await
+        .ok_or(GetTaskError::ImageNotRetained)?;
+    if !processed {
+        let image = *task
+            .images()
+            .get(index)
+            .ok_or(GetTaskError::ImageNotRetained)?;
+        let mime =
+            imaging::detect(image).map_or("application/octet-stream", |format| format.mime_type());
+        return Ok(([(header::CONTENT_TYPE, mime)], image.to_vec()).into_response());
+    }
+    let mut prepared = state
+        .scheduler()
+        .runner()
+        .prepare_images(&task)
+        .await
+        .map_err(anyhow::Error::from)?;
+    if index >= prepared.len() {
+        return Err(GetTaskError::ImageNotRetained);
+    }
+    Ok((
+        [(header::CONTENT_TYPE, "image/png")],
+        prepared.swap_remove(index),
+    )
+        .into_response())
+}
+
+async fn stream_task(
+    _: ValidKey,
+    state: State<AppState>,
+    Path(GetTaskParams { task_id }): Path<GetTaskParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, GetTaskError> {
+    let tcb = state
+        .scheduler()
+        .get_task(task_id)
+        .await?
+        .ok_or(GetTaskError::NotFound)?;
+    let mut tokens = tcb.subscribe_tokens();
+    if !matches!(tcb.state(), task::State::Running) {
+        return Err(GetTaskError::NotRunning);
+    }
+    let stream = async_stream::stream! {
+        let finished = tcb.finished();
+        tokio::pin!(finished);
+        loop {
+            tokio::select! {
+                token = tokens.recv() => match token {
+                    Ok(token) => yield Ok(token_event(&token)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        yield Ok(Event::default().comment(format!("skipped {skipped} tokens")))
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = &mut finished => {
+                    while let Ok(token) = tokens.try_recv() {
+                        yield Ok(token_event(&token));
+                    }
+                    break;
+                }
+            }
+        }
+    };
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Every [crate::events::SchedulerEvent] from now on, named by its type, until the client leaves.
+async fn stream_events(
+    _: ValidKey,
+    state: State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let mut events = state.scheduler().events().subscribe();
+    let stream = async_stream::stream! {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let name: &'static str = (&event).into();
+                    yield Ok(Event::default()
+                        .event(name)
+                        .json_data(&event)
+                        .expect("events serialize to JSON"));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    yield Ok(Event::default().comment(format!("skipped {skipped} events")))
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+fn token_event(token: &Token) -> Event {
+    Event::default()
+        .event(token.kind.to_string())
+        .json_data(token)
+        .expect("tokens serialize to JSON")
+}
+
+async fn list_tasks(
+    _: ValidKey,
+    version: ApiVersion,
+    state: State<AppState>,
+    headers: HeaderMap,
+    Query(ListTasksParams { needs_review }): Query<ListTasksParams>,
+    RawQuery(query): RawQuery,
+) -> Result<Response, GetTaskError> {
+    let format = TaskFormat::negotiate(&headers)?;
+    let filter = task_filter(
+        &state,
+        TaskQuery::parse(query.as_deref().unwrap_or_default()),
+    )
+    .await?;
+    let tasks = state
+        .scheduler()
+        .tasks()
+        .try_filter(|task| {
+            futures::future::ready(
+                needs_review.is_none_or(|value| task.needs_review() == value)
+                    && filter.passes(task),
+            )
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok(TaskJson(version, tasks).negotiated(format))
+}
+
+async fn patch_task(
+    _: ValidKey,
+    version: ApiVersion,
+    state: State<AppState>,
+    Path(GetTaskParams { task_id }): Path<GetTaskParams>,
+    Json(patch): Json<PatchTaskBody>,
+) -> Result<TaskJson<TaskControlBlock>, UpdateTaskError> {
+    state
+        .scheduler()
+        .update_bill(task_id, |bill| {
+            if let Some(needs_review) = patch.needs_review {
+                bill.needs_review = needs_review;
+            }
+        })
+        .await
+        .map(|tcb| TaskJson(version, tcb))
+}
+
+/// Soft deletes a finished task, or with `?purge=true` removes every trace of it.
+async fn delete_task(
+    _: ValidKey,
+    version: ApiVersion,
+    state: State<AppState>,
+    Path(GetTaskParams { task_id }): Path<GetTaskParams>,
+    Query(DeleteTaskParams { purge }): Query<DeleteTaskParams>,
+) -> Result<Response, UpdateTaskError> {
+    if purge {
+        state.scheduler().purge_task(task_id).await?;
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+    let task = state.scheduler().delete_task(task_id).await?;
+    Ok(TaskJson(version, task).into_response())
+}
+
+/// Replaces the tags of a task in any state, in memory or swapped out.
+async fn put_tags(
+    _: ValidKey,
+    version: ApiVersion,
+    state: State<AppState>,
+    Path(GetTaskParams { task_id }): Path<GetTaskParams>,
+    Json(tags): Json<Vec<String>>,
+) -> Result<TaskJson<TaskControlBlock>, UpdateTaskError> {
+    let tags = normalize_tags(tags)?;
+    state
+        .scheduler()
+        .update_task(task_id, |task| {
+            task.set_tags(tags);
+            Ok(())
+        })
+        .await
+        .map(|tcb| TaskJson(version, tcb))
+}
+
+/// Trimmed tags without duplicates, in the order given.
+fn normalize_tags(tags: Vec<String>) -> Result<Vec<SmolStr>, UpdateTaskError> {
+    let mut normalized = Vec::<SmolStr>::with_capacity(tags.len());
+    for tag in tags {
+        let trimmed = tag.trim();
+        if trimmed.is_empty() || trimmed.chars().count() > MAX_TAG_LEN {
+            return Err(UpdateTaskError::InvalidTag(tag));
+        }
+        if !normalized.iter().any(|seen| seen == trimmed) {
+            normalized.push(trimmed.into());
+        }
+    }
+    Ok(normalized)
+}
+
+async fn retry_task(
+    _: ValidKey,
+    version: ApiVersion,
+    state: State<AppState>,
+    Path(GetTaskParams { task_id }): Path<GetTaskParams>,
+) -> Result<TaskJson<TaskControlBlock>, RetryTaskError> {
+    state
+        .scheduler()
+        .retry_task(task_id)
+        .await
+        .map(|tcb| TaskJson(version, tcb))
+}
+
+/// Answers a question about a finished bill on the extract model, within the
+/// sync timeout, leaving the bill alone.
+async fn ask_task(
+    _: ValidKey,
+    state: State<AppState>,
+    headers: HeaderMap,
+    Path(GetTaskParams { task_id }): Path<GetTaskParams>,
+    Json(AskBody { question }): Json<AskBody>,
+) -> Result<Json<Answer>, AskError> {
+    let question = question.trim();
+    if question.is_empty() {
+        return Err(AskError::InvalidQuestion("empty"));
+    }
+    if question.chars().count() > MAX_QUESTION_LEN {
+        return Err(AskError::InvalidQuestion("longer than 1000 characters"));
+    }
+    // before the lookup, so task ids can't be probed past the limit
+    state
+        .ask_limiter()
+        .check(&caller_key(&headers))
+        .map_err(AskError::RateLimited)?;
+    let task = state
+        .scheduler()
+        .get_task(&task_id)
+        .await?
+        .ok_or(AskError::NotFound)?;
+    let bill = match task.state() {
+        task::State::Finished(Ok(success)) => success.0,
+        task::State::Finished(Err(_)) => return Err(AskError::Failed),
+        task::State::Pending | task::State::Running => return Err(AskError::NotFinished),
+    };
+    let description = task
+        .intermediates()
+        .and_then(|mut texts| texts.remove(&Stage::Description));
+    event!(target: "audit", Level::INFO, task = task_id, question, "question asked");
+    let sync_timeout = state.sync_timeout();
+    let deadline = Instant::now() + sync_timeout;
+    let _slot = tokio::time::timeout_at(deadline, state.scheduler().slot())
+        .await
+        .map_err(|_| AskError::Timeout(sync_timeout))?;
+    let answer = state
+        .scheduler()
+        .runner()
+        .ask(&bill, description.as_deref(), question);
+    let answer = tokio::time::timeout_at(deadline, answer)
+        .await
+        .map_err(|_| AskError::Timeout(sync_timeout))??;
+    Ok(Json(Answer { answer }))
+}
+
+async fn export_jsonl(
+    _: ValidKey,
+    state: State<AppState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Result<Response, ExportError> {
+    export_as(&state, &headers, query, ExportFormat::Jsonl).await
+}
+
+async fn export_csv(
+    _: ValidKey,
+    state: State<AppState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Result<Response, ExportError> {
+    export_as(&state, &headers, query, ExportFormat::Csv).await
+}
+
+/// The filter of `query`, shared by the exports and the listing of tasks.
+async fn task_filter(state: &AppState, mut query: TaskQuery) -> Result<TaskFilter, ExportError> {
+    let since_id = query.since_id.take();
+    let filter = TaskFilter::try_from(query)?;
+    let Some(since) = since_id else {
+        return Ok(filter);
+    };
+    if let Ok(cursor) = since.parse::<Cursor>() {
+        return Ok(filter.after(cursor));
+    }
+    let cursor = state
+        .scheduler()
+        .get_task(&since)
+        .await?
+        .as_ref()
+        .and_then(Cursor::of)
+        .ok_or(ExportError::UnknownCursor(since))?;
+    Ok(filter.after(cursor))
+}
+
+async fn export_as(
+    state: &AppState,
+    headers: &HeaderMap,
+    query: Option<String>,
+    format: ExportFormat,
+) -> Result<Response, ExportError> {
+    let query = TaskQuery::parse(query.as_deref().unwrap_or_default());
+    let mapping = query.mapping()?;
+    let started = chrono::Utc::now();
+    let filter = task_filter(state, query).await?.before(started);
+    let lines = export::export(state.scheduler().clone(), filter, mapping, format);
+    // exports are large and repetitive, so worth compressing as they stream
+    let gzip = export::accepts_gzip(headers);
+    let body = if gzip {
+        Body::from_stream(export::gzip(lines))
+    } else {
+        Body::from_stream(lines)
+    };
+    let mut response = (
+        [
+            (header::CONTENT_TYPE, format.content_type()),
+            (header::VARY, "accept-encoding"),
+        ],
+        [(export::CURSOR_HEADER, Cursor::at(started).to_string())],
+        body,
+    )
+        .into_response();
+    if gzip {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    }
+    Ok(response)
+}
+
+/// What clients may upload and the models and categories tasks run with.
+#[derive(Debug, Serialize)]
+pub(crate) struct Capabilities {
+    /// Image formats accepted, AVIF and HEIC only with `--transcode-command`
+    formats: Vec<String>,
+    max_upload_size: usize,
+    max_images: Option<usize>,
+    caption_model: String,
+    extract_model: String,
+    /// Levels tasks may pick, the first being the default
+    quantizations: Vec<Quantization>,
+    categories: Vec<String>,
+}
+
+impl Capabilities {
+    pub(crate) fn of(state: &AppState) -> Self {
+        let runner = state.scheduler().runner();
+        Self {
+            formats: imaging::supported(),
+            max_upload_size: state.max_upload_size(),
+            max_images: runner.max_images,
+            caption_model: runner.caption_model.to_string(),
+            extract_model: runner.extract_model.to_string(),
+            quantizations: runner.quantizations.clone(),
+            categories: Category::all_cases()
+                .into_iter()
+                .filter_map(|c| c.name())
+                .collect(),
+        }
+    }
+}
+
+async fn capabilities(
+    key: Result<ValidKey, AuthError>,
+    state: State<AppState>,
+) -> Result<Json<Capabilities>, AuthError> {
+    if !state.public_capabilities() {
+        key?;
+    }
+    Ok(Json(Capabilities::of(&state)))
+}
+
+async fn list_categories(_: ValidKey) -> Json<Vec<String>> {
+    Json(
+        Category::all_cases()
+            .into_iter()
+            .filter_map(|c| c.name())
+            .collect(),
+    )
+}
+
+async fn list_models(_: ValidKey, state: State<AppState>) -> Json<Vec<ModelStatus>> {
+    Json(state.scheduler().runner().model_status())
+}
+
+/// Pins or unpins a model, loading it again either way.
+async fn patch_model(
+    _: ValidKey,
+    state: State<AppState>,
+    Path(model): Path<String>,
+    Json(patch): Json<PatchModelBody>,
+) -> Result<Json<ModelStatus>, PinModelError> {
+    state
+        .scheduler()
+        .runner()
+        .pin(&model, patch.pinned)
+        .await
+        .map(Json)
+}
+
+async fn get_config(_: ValidKey, state: State<AppState>) -> Json<RuntimeConfig> {
+    Json(RuntimeConfig::of(state.scheduler()))
+}
+
+/// Changes the given settings at once, none if one of them is invalid.
+async fn patch_config(
+    _: ValidKey,
+    state: State<AppState>,
+    Json(patch): Json<ConfigPatch>,
+) -> Result<Json<RuntimeConfig>, ConfigError> {
+    state
+        .config()
+        .update(state.scheduler(), patch)
+        .await
+        .map(Json)
+}
+
+#[derive(Debug, Serialize)]
+struct ServerStats {
+    #[serde(flatten)]
+    scheduler: Stats,
+    /// Whether quiet hours currently keep models loaded only briefly
+    residency: Residency,
+}
+
+async fn stats(_: ValidKey, state: State<AppState>) -> Json<ServerStats> {
+    let scheduler = state.scheduler();
+    Json(ServerStats {
+        scheduler: scheduler.stats(),
+        residency: scheduler.runner().residency(),
+    })
+}
+
+async fn reset_stats(_: ValidKey, state: State<AppState>) -> StatusCode {
+    state.scheduler().reset_latency();
+    StatusCode::NO_CONTENT
+}
+
+/// Cancels every task still waiting in the queue, leaving the running ones be.
+async fn clear_pending(_: ValidKey, state: State<AppState>) -> Json<serde_json::Value> {
+    let cleared = state.scheduler().clear_pending().await;
+    Json(serde_json::json!({ "cleared": cleared }))
+}
+
+/// Model cache counters and task latencies in the Prometheus text format.
+async fn metrics(_: ValidKey, state: State<AppState>) -> impl IntoResponse {
+    let scheduler = state.scheduler();
+    let models = scheduler.runner().metrics.snapshot();
+    let mut text = render_metrics(&models);
+    render_latency(&mut text, scheduler.latency());
+    render_connections(&mut text, state.connections());
+    render_memory(
+        &mut text,
+        scheduler.memory_usage().await,
+        scheduler.memory_limit(),
+    );
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], text)
+}
+
+fn render_memory(text: &mut String, usage: usize, limit: Option<usize>) {
+    let name = "ledoxide_memory_usage_bytes";
+    let _ = writeln!(
+        text,
+        "# HELP {name} Approximate bytes held by tasks, as counted against --memory-limit\n# TYPE {name} gauge\n{name} {usage}"
+    );
+    if let Some(limit) = limit {
+        let name = "ledoxide_memory_limit_bytes";
+        let _ = writeln!(
+            text,
+            "# HELP {name} Value of --memory-limit\n# TYPE {name} gauge\n{name} {limit}"
+        );
+    }
+}
+
+fn render_connections(text: &mut String, stats: &ConnectionStats) {
+    for (name, kind, help, value) in [
+        (
+            "ledoxide_connections_open",
+            "gauge",
+            "Connections being served",
+            stats.open() as u64,
+        ),
+        (
+            "ledoxide_connections_rejected_total",
+            "counter",
+            "Connections answered 503 for exceeding --max-connections",
+            stats.rejected(),
+        ),
+    ] {
+        let _ = writeln!(
+            text,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+        );
+    }
+    let name = "ledoxide_connections_timed_out_total";
+    let _ = writeln!(
+        text,
+        "# HELP {name} Connections closed for taking too long to send\n# TYPE {name} counter"
+    );
+    for timeout in Timeout::ALL {
+        let _ = writeln!(
+            text,
+            "{name}{{reason=\"{timeout}\"}} {}",
+            stats.timeouts(timeout)
+        );
+    }
+}
+
+/// Name, type, help and value of a metric family, absent values skipping the model.
+type MetricFamily = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&ModelCounters) -> Option<f64>,
+);
+
+fn render_metrics(models: &BTreeMap<impl AsRef<str>, ModelCounters>) -> String {
+    let families: [MetricFamily; 5] = [
+        (
+            "ledoxide_model_cache_hits_total",
+            "counter",
+            "Generations served by a model already loaded",
+            |counters| Some(counters.hits as f64),
+        ),
+        (
+            "ledoxide_model_cache_misses_total",
+            "counter",
+            "Generations that had Ollama load the model first",
+            |counters| Some(counters.misses as f64),
+        ),
+        (
+            "ledoxide_model_evictions_total",
+            "counter",
+            "Loads of a model Ollama had unloaded since it was last used",
+            |counters| Some(counters.evictions as f64),
+        ),
+        (
+            "ledoxide_model_load_seconds_total",
+            "counter",
+            "Time spent loading the model",
+            |counters| Some(counters.load_seconds),
+        ),
+        (
+            "ledoxide_model_last_load_seconds",
+            "gauge",
+            "Time the latest load of the model took",
+            |counters| counters.last_load_seconds,
+        ),
+    ];
+    let mut text = String::new();
+    for (name, kind, help, value) in families {
+        let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} {kind}");
+        for (model, counters) in models {
+            if let Some(value) = value(counters) {
+                let model = model.as_ref().replace('\\', "\\\\").replace('"', "\\\"");
+                let _ = writeln!(text, "{name}{{model=\"{model}\"}} {value}");
+            }
+        }
+    }
+    text
+}
+
+fn render_latency(text: &mut String, latency: &Latency) {
+    for (name, help, histogram) in [
+        (
+            "ledoxide_task_queue_wait_seconds",
+            "Time tasks waited in the pending queue",
+            &latency.queue_wait,
+        ),
+        (
+            "ledoxide_task_execution_seconds",
+            "Time tasks took to run",
+            &latency.execution,
+        ),
+    ] {
+        let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} histogram");
+        let counts = histogram.counts();
+        let mut cumulative = 0;
+        for (index, count) in counts.iter().enumerate() {
+            cumulative += count;
+            match LATENCY_BUCKETS.get(index) {
+                Some(bound) => {
+                    let _ = writeln!(text, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+                }
+                None => {
+                    let _ = writeln!(text, "{name}_bucket{{le=\"+Inf\"}} {cumulative}");
+                }
+            }
+        }
+        let _ = writeln!(text, "{name}_sum {}", histogram.sum_seconds());
+        let _ = writeln!(text, "{name}_count {cumulative}");
+    }
+}
+
+async fn backfill_progress(_: ValidKey, state: State<AppState>) -> Json<BackfillProgress> {
+    Json(state.scheduler().backfill_progress())
+}
+
+async fn start_backfill(
+    _: ValidKey,
+    state: State<AppState>,
+) -> Result<(StatusCode, Json<BackfillProgress>), BackfillError> {
+    let progress = state.scheduler().start_backfill().await?;
+    Ok((StatusCode::ACCEPTED, Json(progress)))
+}
+
+async fn backup_archive(_: ValidKey, state: State<AppState>) -> Response {
+    let archive = backup::archive(state.scheduler().clone());
+    (
+        [
+            (header::CONTENT_TYPE, "application/octet-stream"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"ledoxide.backup\"",
+            ),
+        ],
+        Body::from_stream(archive),
+    )
+        .into_response()
+}
+
+async fn restore_archive(
+    _: ValidKey,
+    state: State<AppState>,
+    body: Body,
+) -> Result<Json<Restored>, RestoreError> {
+    let restored = backup::restore(state.scheduler(), body.into_data_stream())
+        .await
+        .inspect_err(|err| event!(Level::ERROR, "restore failed: {err}"))?;
+    event!(
+        Level::INFO,
+        "restored {} tasks, {} descriptors and {} new categories",
+        restored.tasks,
+        restored.descriptors,
+        restored.categories
+    );
+    Ok(Json(restored))
+}
+
+#[derive(Debug, Serialize)]
+struct Info {
+    name: &'static str,
+    version: &'static str,
+    commit: &'static str,
+    engine: &'static str,
+    caption_model: String,
+    extract_model: String,
+    categories: usize,
+    uptime_secs: u64,
+    auth_enabled: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct GetTaskParams {
+    task_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteTaskParams {
+    #[serde(default)]
+    purge: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListTasksParams {
+    needs_review: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PatchTaskBody {
+    needs_review: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageParams {
+    task_id: String,
+    index: usize,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ImageQuery {
+    processed: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct AskBody {
+    question: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Answer {
+    answer: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PatchModelBody {
+    pinned: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, extract::Request};
+    use tower::util::ServiceExt;
+
+    use crate::{
+        args,
+        bill::{Bill, Category},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_normalize_tags() {
+        let tags = normalize_tags(vec![" trip ".into(), "shared".into(), "trip".into()]).unwrap();
+        assert_eq!(tags, ["trip", "shared"]);
+        assert!(matches!(
+            normalize_tags(vec!["  ".into()]),
+            Err(UpdateTaskError::InvalidTag(_))
+        ));
+        assert!(normalize_tags(vec!["x".repeat(MAX_TAG_LEN + 1)]).is_err());
+    }
+
+    #[test]
+    fn test_render_metrics() {
+        let models = BTreeMap::from([(
+            "gemma4:e4b",
+            ModelCounters {
+                hits: 3,
+                misses: 2,
+                evictions: 1,
+                load_seconds: 4.5,
+                last_load_seconds: Some(2.0),
+            },
+        )]);
+        let text = render_metrics(&models);
+        assert!(text.contains("# TYPE ledoxide_model_cache_hits_total counter\n"));
+        assert!(text.contains("ledoxide_model_cache_hits_total{model=\"gemma4:e4b\"} 3\n"));
+        assert!(text.contains("ledoxide_model_evictions_total{model=\"gemma4:e4b\"} 1\n"));
+        assert!(text.contains("ledoxide_model_load_seconds_total{model=\"gemma4:e4b\"} 4.5\n"));
+        assert!(text.contains("ledoxide_model_last_load_seconds{model=\"gemma4:e4b\"} 2\n"));
+        let empty = render_metrics(&BTreeMap::<&str, ModelCounters>::new());
+        assert!(!empty.contains("model="));
+    }
+
+    #[test]
+    fn test_render_latency() {
+        let latency = Latency::default();
+        latency
+            .queue_wait
+            .record(std::time::Duration::from_secs(20));
+        latency
+            .queue_wait
+            .record(std::time::Duration::from_secs(7200));
+        let mut text = String::new();
+        render_latency(&mut text, &latency);
+        assert!(text.contains("# TYPE ledoxide_task_queue_wait_seconds histogram\n"));
+        assert!(text.contains("ledoxide_task_queue_wait_seconds_bucket{le=\"10\"} 0\n"));
+        assert!(text.contains("ledoxide_task_queue_wait_seconds_bucket{le=\"30\"} 1\n"));
+        assert!(text.contains("ledoxide_task_queue_wait_seconds_bucket{le=\"3600\"} 1\n"));
+        assert!(text.contains("ledoxide_task_queue_wait_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("ledoxide_task_queue_wait_seconds_sum 7220\n"));
+        assert!(text.contains("ledoxide_task_queue_wait_seconds_count 2\n"));
+        assert!(text.contains("ledoxide_task_execution_seconds_count 0\n"));
+    }
+
+    #[test]
+    fn test_render_connections() {
+        let mut text = String::new();
+        render_connections(&mut text, &ConnectionStats::default());
+        assert!(
+            text.contains("# TYPE ledoxide_connections_open gauge\nledoxide_connections_open 0\n")
+        );
+        assert!(text.contains("ledoxide_connections_rejected_total 0\n"));
+        assert!(text.contains("ledoxide_connections_timed_out_total{reason=\"body\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_info() {
+        let _registry = crate::bill::lock_registry().await;
+        Category::load_from_names(["Shopping", "Food", "Transport", "Rent"]);
+        let app = app(AppState::new(&args::App::default()).unwrap());
+        let request = |accept: &str| {
+            Request::builder()
+                .uri("/")
+                .header("Accept", accept)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("text/plain")).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.starts_with(env!("CARGO_PKG_NAME").as_bytes()));
+
+        let response = app.oneshot(request("application/json")).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["engine"], "ollama");
+        assert_eq!(info["commit"], env!("GIT_COMMIT"));
+        assert_eq!(info["auth_enabled"], false);
+        assert!(info["categories"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_base_path() {
+        async fn get(base_path: &str, uri: &str) -> axum::response::Response {
+            let app = app(AppState::new(&args::App {
+                base_path: base_path.into(),
+                enable_ui: true,
+                ..Default::default()
+            })
+            .unwrap());
+            let request = Request::get(uri)
+                .header("Accept", "text/plain")
+                .body(Body::empty())
+                .unwrap();
+            app.oneshot(request).await.unwrap()
+        }
+
+        assert_eq!(get("", "/info").await.status(), StatusCode::OK);
+        assert_eq!(
+            get("/ledoxide", "/ledoxide/info").await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            get("/ledoxide", "/info").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get("/ledoxide", "/get_task/x").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let response = get("/ledoxide", "/ledoxide/ui").await;
+        assert_eq!(response.headers()["Location"], "/ledoxide/ui/");
+        let response = get("/ledoxide", "/ledoxide/openapi.json").await;
+        let spec: serde_json::Value = serde_json::from_slice(
+            &axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(spec["servers"][0]["url"], "/ledoxide/v1");
+    }
+
+    #[tokio::test]
+    async fn test_ask() {
+        let state = AppState::new(&args::App {
+            ask_rate_limit: 3,
+            ..Default::default()
+        })
+        .unwrap();
+        let paid = TaskControlBlock::new();
+        paid.set_state(task::State::Finished(Ok(task::Success(Bill {
+            notes: "Pizza, delivery".into(),
+            amount: 18.5,
+            currency: Some("EUR".into()),
+            categories: vec!["Food".into()],
+            category_reason: None,
+            needs_review: false,
+        }))));
+        let failed = TaskControlBlock::new();
+        failed.set_state(task::State::Finished(Err(
+            crate::error::TaskError::from_message("out of memory"),
+        )));
+        state
+            .scheduler()
+            .restore_finished(vec![paid.clone(), failed.clone()])
+            .await
+            .unwrap();
+        let app = app(state);
+        let ask = async |id: &str, question: &str| {
+            let request = Request::post(format!("/v1/task/{id}/ask"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "question": question }).to_string(),
+                ))
+                .unwrap();
+            app.clone().oneshot(request).await.unwrap()
+        };
+
+        assert_eq!(ask(paid.id(), "  ").await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            ask("nonexistent", "Was there a delivery fee?")
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            ask(failed.id(), "Was there a delivery fee?").await.status(),
+            StatusCode::CONFLICT
+        );
+        // answered or not, depending on whether Ollama is around
+        let response = ask(paid.id(), "Was there a delivery fee?").await;
+        assert_ne!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let response = ask(paid.id(), "And a tip?").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after = response.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after));
+        // limited before the lookup, so ids can't be probed either
+        assert_eq!(
+            ask("nonexistent", "And a tip?").await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_task_fields() {
+        let state = AppState::new(&args::App::default()).unwrap();
+        let paid = TaskControlBlock::new();
+        paid.set_state(task::State::Finished(Ok(task::Success(Bill {
+            notes: "Pizza, delivery".into(),
+            amount: 18.5,
+            currency: Some("EUR".into()),
+            categories: vec!["Food".into()],
+            category_reason: None,
+            needs_review: false,
+        }))));
+        state
+            .scheduler()
+            .restore_finished(vec![paid.clone()])
+            .await
+            .unwrap();
+        let app = app(state);
+        let get = async |path: String| {
+            let response = app
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            )
+        };
+
+        let fields = "amount:total,notes:description,category";
+        let (status, v1) = get(format!("/v1/get_task/{}?fields={fields}", paid.id())).await;
+        assert_eq!(status, StatusCode::OK);
+        let mapped = serde_json::json!({"total": 18.5, "description": "Pizza, delivery", "category": "Food"});
+        assert_eq!(v1["success"], mapped);
+        let (_, v2) = get(format!("/v2/get_task/{}?fields={fields}", paid.id())).await;
+        assert_eq!(v2["bill"], mapped);
+        let (_, canonical) = get(format!("/v2/get_task/{}", paid.id())).await;
+        assert_eq!(canonical["bill"]["amount"], 18.5);
+
+        let (status, error) = get(format!("/v1/get_task/{}?fields=price", paid.id())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error["error"].as_str().unwrap().contains("price"));
+    }
+
+    #[tokio::test]
+    async fn test_list_tasks_filter() {
+        let state = AppState::new(&args::App::default()).unwrap();
+        let bill = |category: &str, amount: f32| {
+            let task = TaskControlBlock::new();
+            task.set_state(task::State::Finished(Ok(task::Success(Bill {
+                notes: "Toy".into(),
+                amount,
+                currency: None,
+                categories: vec![category.into()],
+                category_reason: None,
+                needs_review: false,
+            }))));
+            task
+        };
+        let food = bill("Food", 12.0);
+        let rent = bill("Rent", 800.0);
+        let failed = TaskControlBlock::new();
+        failed.set_state(task::State::Finished(Err(
+            crate::error::TaskError::from_message("out of memory"),
+        )));
+        state
+            .scheduler()
+            .restore_finished(vec![food.clone(), rent.clone(), failed])
+            .await
+            .unwrap();
+        let app = app(state);
+        let list = async |query: &str| {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::get(format!("/v2/tasks{query}"))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            )
+        };
+        let ids = |tasks: serde_json::Value| {
+            let mut ids = tasks
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|task| task["id"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(ids(list("").await.1).len(), 3);
+        assert_eq!(ids(list("?category=Food").await.1), [food.id()]);
+        assert_eq!(ids(list("?min_amount=100").await.1), [rent.id()]);
+        let mut both = vec![food.id().to_string(), rent.id().to_string()];
+        both.sort();
+        assert_eq!(ids(list("?from=2000-01-01").await.1), both);
+        let (status, _) = list("?to=March").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_export_cursor() {
+        let state = AppState::new(&args::App::default()).unwrap();
+        let paid = TaskControlBlock::new();
+        paid.set_state(task::State::Finished(Ok(task::Success(Bill {
+            notes: "Toy".into(),
+            amount: 10.0,
+            currency: None,
+            categories: vec!["Shopping".into()],
+            category_reason: None,
+            needs_review: false,
+        }))));
+        state
+            .scheduler()
+            .restore_finished(vec![paid.clone()])
+            .await
+            .unwrap();
+        let app = app(state);
+        let export = async |query: String| {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::get(format!("/v1/export.jsonl{query}"))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let status = response.status();
+            let cursor = response
+                .headers()
+                .get(export::CURSOR_HEADER)
+                .map(|cursor| cursor.to_str().unwrap().to_string());
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, cursor, String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        let (status, cursor, lines) = export(String::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(lines.contains(paid.id()));
+        let (_, _, lines) = export(format!("?since_id={}", cursor.unwrap())).await;
+        assert_eq!(lines, "");
+        let (_, _, lines) = export(format!("?since_id={}", paid.id())).await;
+        assert_eq!(lines, "");
+        let (status, _, _) = export("?since_id=nonexistent".into()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_get_task_wait() {
+        let state = AppState::new(&args::App::default()).unwrap();
+        let running = TaskControlBlock::new();
+        running.set_state(task::State::Running);
+        let pending = TaskControlBlock::new();
+        state
+            .scheduler()
+            .restore_finished(vec![running.clone(), pending.clone()])
+            .await
+            .unwrap();
+        let app = app(state);
+        let get = async |path: String, deadline_ms: Option<&str>| {
+            let mut request = Request::get(path);
+            if let Some(ms) = deadline_ms {
+                request = request.header(deadline::DEADLINE_HEADER, ms);
+            }
+            let response = app
+                .clone()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, body)
+        };
+
+        let finishing = running.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            finishing.set_state(task::State::Finished(Err(
+                crate::error::TaskError::from_message("out of memory"),
+            )));
+        });
+        let started = Instant::now();
+        let (status, body) = get(format!("/v2/get_task/{}?wait=30", running.id()), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let task = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(task["state"], "finished");
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // answered as it is before the deadline passes
+        let started = Instant::now();
+        let (status, body) = get(
+            format!("/v2/get_task/{}?wait=30", pending.id()),
+            Some("300"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let task = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(task["state"], "pending");
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        let (status, _) = get(format!("/v2/get_task/{}?wait=soon", pending.id()), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_capabilities() {
+        let get = async |public_capabilities: bool, key: Option<&str>| {
+            let app = app(AppState::new(&args::App {
+                auth_key: "key".into(),
+                max_images: Some(3),
+                public_capabilities,
+                ..Default::default()
+            })
+            .unwrap());
+            let mut request = Request::get("/v1/capabilities");
+            if let Some(key) = key {
+                request = request.header("Authorization", format!("Bearer {key}"));
+            }
+            app.oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+        };
+
+        assert_eq!(get(false, None).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            get(false, Some("wrong")).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(get(false, Some("key")).await.status(), StatusCode::OK);
+        let response = get(true, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let capabilities: serde_json::Value = serde_json::from_slice(
+            &axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(capabilities["max_images"], 3);
+        assert!(
+            capabilities["formats"]
+                .as_array()
+                .unwrap()
+                .contains(&"JPEG".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_embedded_router() {
+        let state = AppState::new(&args::App {
+            auth_key: "unused".into(),
+            ..Default::default()
+        })
+        .unwrap()
+        .with_authorizer(|parts: &axum::http::request::Parts| {
+            match parts.headers.get("X-Api-Key") {
+                Some(key) if key == "secret" => Ok(()),
+                _ => Err(crate::error::AuthError::InvalidKey),
+            }
+        });
+        let app = router(&state)
+            .route("/host", get(async || "host"))
+            .with_state(state);
+        let status = |request: Request<Body>| {
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        let categories = || Request::get("/v2/categories");
+        assert_eq!(
+            status(categories().body(Body::empty()).unwrap()).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(
+                categories()
+                    .header("Authorization", "Bearer unused")
+                    .body(Body::empty())
+                    .unwrap()
+            )
+            .await,
+            StatusCode::UNAUTHORIZED,
+            "the authorizer replaces the key"
+        );
+        assert_eq!(
+            status(
+                categories()
+                    .header("X-Api-Key", "secret")
+                    .body(Body::empty())
+                    .unwrap()
+            )
+            .await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(Request::get("/host").body(Body::empty()).unwrap()).await,
+            StatusCode::OK
+        );
+    }
+}
@@ -1,42 +1,249 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use ollama_rs::Ollama;
 use smol_str::ToSmolStr;
+use tracing::{Level, event};
 
-use crate::{args, ext::FromEnvVars, schedule::Scheduler, task::ollama::OllamaRunTask};
+use crate::{
+    amount::AmountBounds,
+    args,
+    bill::Category,
+    config::{self, ConfigLock, LiveConfig, RuntimeConfig},
+    error::{CreateTaskError, StartupError},
+    events::EventBus,
+    ext::FromEnvVars,
+    key::Authorize,
+    limit::RateLimiter,
+    listen::ConnectionStats,
+    schedule::{Reservation, Scheduler},
+    task::ollama::{
+        ChatTemplates, DEFAULT_PULL_BACKOFF, KeepAlives, OllamaRunTask, OllamaTaskDescriptor,
+        PinnedModels,
+    },
+};
 
+/// What the routes share, built from the same configuration the CLI produces.
 #[derive(Clone)]
 pub struct AppState {
     auth_key: String,
+    authorizer: Option<Arc<dyn Authorize>>,
+    sync_timeout: Duration,
+    max_upload_size: usize,
+    default_deadline: Option<Duration>,
+    ui_enabled: bool,
+    public_capabilities: bool,
+    base_path: String,
+    started_at: Instant,
+    config: Arc<LiveConfig>,
+    /// Released once the last clone of the state is dropped
+    _config_lock: Option<Arc<ConfigLock>>,
+    ask_limiter: Arc<RateLimiter>,
+    describe_limiter: Arc<RateLimiter>,
+    connections: Arc<ConnectionStats>,
     scheduler: Arc<Scheduler<OllamaRunTask>>,
 }
 
 impl AppState {
-    pub fn new(args: &args::App) -> Self {
+    pub fn new(args: &args::App) -> Result<Self, StartupError> {
+        if let AmountBounds {
+            min: Some(min),
+            max: Some(max),
+        } = args.amount_bounds
+            && min > max
+        {
+            return Err(StartupError::AmountBounds { min, max });
+        }
+        if Category::all_cases().is_empty() {
+            event!(
+                Level::WARN,
+                "no categories are registered, tasks not sending their own stay uncategorized"
+            );
+        }
+        let config_lock = args
+            .runtime_config
+            .as_deref()
+            .map(|path| ConfigLock::acquire(path, args.force))
+            .transpose()?;
+        // settings changed while serving last time win over the flags
+        let config = match &args.runtime_config {
+            Some(path) => {
+                let invalid = |reason: String| StartupError::RuntimeConfig {
+                    path: path.clone(),
+                    reason,
+                };
+                let saved = config::load(path).map_err(|err| invalid(err.to_string()))?;
+                RuntimeConfig::from_args(args)
+                    .patched(saved)
+                    .map_err(|err| invalid(err.to_string()))?
+            }
+            None => RuntimeConfig::from_args(args),
+        };
         let caption_model = args.caption_model.to_smolstr();
         let extract_model = args.extract_model.to_smolstr();
+        // shared, so model loads show up among the task events
+        let events = EventBus::default();
         let runner = OllamaRunTask {
-            ollama: Ollama::from_env_vars(),
+            ollama: Ollama::from_env_vars()?,
             caption_model: caption_model.clone(),
             extract_model: extract_model.clone(),
+            stage_models: args
+                .stage_models
+                .iter()
+                .map(|(stage, model)| (*stage, model.to_smolstr()))
+                .collect(),
+            categorizer: args.categorizer,
+            embedding_model: args.embedding_model.to_smolstr(),
+            category_embeddings: Default::default(),
             offline: args.offline,
+            pulls: Default::default(),
+            chat_templates: ChatTemplates::from_args(&args.chat_templates),
+            system_prompts: args
+                .system_prompts
+                .iter()
+                .map(|(stage, prompt)| (*stage, prompt.as_str().into()))
+                .collect(),
+            prompt_retention: args.retain_prompts,
+            max_prompt_size: args.max_prompt_size,
+            stage_timeouts: args.stage_timeouts.iter().copied().collect(),
+            max_images: args.max_images,
+            amount_bounds: args.amount_bounds,
+            pull_backoff: DEFAULT_PULL_BACKOFF,
+            pull_attempts: args.pull_attempts,
+            concurrent_pulls: args.concurrent_pulls,
+            keep_alive: KeepAlives::default(),
+            keep_alive_jitter: args.model_timeout_jitter,
+            quiet_hours: args.quiet_hours,
+            animation_frames: args.animation_frames,
+            deterministic: args.deterministic,
+            quantizations: args.quantizations.clone(),
+            pinned: PinnedModels::new(args.pinned_models.iter().map(|model| model.to_smolstr())),
+            metrics: Default::default(),
+            events: events.clone(),
         };
-        Self {
-            auth_key: args.auth_key.clone(),
-            scheduler: Arc::new(Scheduler::new(
-                args.max_concurrency,
-                args.max_memory_size,
-                args.model_timeout,
-                runner,
-            )),
+        runner.keep_alive.set(config.keep_alive());
+        let mut scheduler = Scheduler::new(
+            config.max_concurrency,
+            config.max_memory_size,
+            args.model_timeout,
+            runner,
+        )
+        .map_err(StartupError::Swap)?
+        .with_retained_descriptors(args.retain_descriptors)
+        .with_dedup_window(args.dedup_window)
+        .with_swap_cache(args.swap_cache_size)
+        .with_memory_limit(args.memory_limit)
+        .with_events(events);
+        if let Some(dir) = &args.swap_dir {
+            scheduler = scheduler
+                .with_swap_dir(dir)
+                .map_err(|source| StartupError::SwapDir {
+                    dir: dir.clone(),
+                    source,
+                })?;
         }
+        Ok(Self {
+            auth_key: args.auth_key.clone(),
+            authorizer: None,
+            sync_timeout: args.sync_timeout,
+            max_upload_size: args.max_upload_size,
+            default_deadline: args.default_deadline,
+            ui_enabled: args.enable_ui,
+            public_capabilities: args.public_capabilities,
+            base_path: args.base_path.clone(),
+            started_at: Instant::now(),
+            config: Arc::new(LiveConfig::new(args.runtime_config.clone())),
+            _config_lock: config_lock.map(Arc::new),
+            ask_limiter: Arc::new(RateLimiter::new(args.ask_rate_limit)),
+            describe_limiter: Arc::new(RateLimiter::new(args.describe_rate_limit)),
+            connections: Default::default(),
+            scheduler: Arc::new(scheduler),
+        })
+    }
+
+    /// Checks keys with `authorizer` instead of the configured `auth_key`.
+    pub fn with_authorizer(mut self, authorizer: impl Authorize + 'static) -> Self {
+        self.authorizer = Some(Arc::new(authorizer));
+        self
     }
 
     pub fn auth_key(&self) -> &str {
         &self.auth_key
     }
 
-    pub fn scheduler(&self) -> &Scheduler<OllamaRunTask> {
-        self.scheduler.as_ref()
+    pub fn authorizer(&self) -> Option<&dyn Authorize> {
+        self.authorizer.as_deref()
+    }
+
+    pub fn auth_enabled(&self) -> bool {
+        self.authorizer.is_some() || !self.auth_key.is_empty()
+    }
+
+    pub fn sync_timeout(&self) -> Duration {
+        self.sync_timeout
+    }
+
+    pub fn max_upload_size(&self) -> usize {
+        self.max_upload_size
+    }
+
+    /// Turns away a task the runner doesn't offer the quantization of, or
+    /// that would exceed `--memory-limit`, wherever it was submitted. The
+    /// reservation made for its upload is traded for one of what it holds.
+    pub async fn accept(
+        &self,
+        task: &OllamaTaskDescriptor,
+        reservation: Reservation,
+    ) -> Result<Reservation, CreateTaskError> {
+        self.scheduler.runner().check_quantization(task)?;
+        self.scheduler.admit(task, reservation).await
+    }
+
+    pub fn default_deadline(&self) -> Option<Duration> {
+        self.default_deadline
+    }
+
+    pub fn started_at(&self) -> Instant {
+        self.started_at
+    }
+
+    /// Prefix every route is served under, empty or starting with a slash.
+    pub fn base_path(&self) -> &str {
+        &self.base_path
+    }
+
+    pub fn ui_enabled(&self) -> bool {
+        self.ui_enabled
+    }
+
+    /// Whether `GET /capabilities` is answered without a key.
+    pub fn public_capabilities(&self) -> bool {
+        self.public_capabilities
+    }
+
+    pub(crate) fn config(&self) -> &LiveConfig {
+        &self.config
+    }
+
+    /// Counts the questions of `POST /task/{id}/ask` per key.
+    pub(crate) fn ask_limiter(&self) -> &RateLimiter {
+        &self.ask_limiter
+    }
+
+    /// Counts the descriptions of `POST /describe` per key.
+    pub(crate) fn describe_limiter(&self) -> &RateLimiter {
+        &self.describe_limiter
+    }
+
+    /// Where the listener counts the connections it turns away or cuts off,
+    /// to be shown by `/metrics`.
+    pub fn connections(&self) -> &Arc<ConnectionStats> {
+        &self.connections
+    }
+
+    pub fn scheduler(&self) -> &Arc<Scheduler<OllamaRunTask>> {
+        &self.scheduler
     }
 }
@@ -1,17 +1,82 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::BTreeMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        Arc, OnceLock, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use chrono::{DateTime, Utc};
 
 use serde::{Deserialize, Serialize, ser::SerializeStruct};
 use smol_str::SmolStr;
 use strum::Display;
+use tokio::sync::{broadcast, watch};
+use tracing::{Level, Span, field, span};
+
+use crate::{
+    bill::{Bill, BillV1, BillV2},
+    error::TaskError,
+    key,
+    logging::TASK_SPAN,
+    task::{Stage, StageSeeds, StageTexts, Token, TokenSender},
+};
 
-use crate::{bill::Bill, error::RunTaskError, key};
+pub(crate) const TOKEN_CHANNEL_CAPACITY: usize = 256;
 
 pub trait TaskDescriptor {
     fn images(&self) -> Vec<&[u8]>;
     fn category_names(&self) -> Vec<SmolStr>;
+
+    /// Where the runner records the output of each stage, if the task asked.
+    fn intermediates(&self) -> Option<&StageTexts> {
+        None
+    }
+
+    /// Where the runner records the rendered prompt of each stage, if it keeps them.
+    fn prompts(&self) -> Option<&StageTexts> {
+        None
+    }
+
+    /// Where the runner records the seed of each stage, if it fixes them.
+    fn seeds(&self) -> Option<&StageSeeds> {
+        None
+    }
+
+    /// A copy to run again as another task, recording to buffers of its own
+    /// rather than to those of the task it was taken from.
+    fn rerun(&self) -> Self
+    where
+        Self: Sized;
+
+    /// Bytes the task holds in memory, its images for the most part.
+    fn size(&self) -> usize {
+        self.images().iter().map(|image| image.len()).sum()
+    }
+
+    /// Feeds the options that change how the task runs to `hasher`, so only
+    /// submissions that would run the same are taken for duplicates.
+    fn hash_options(&self, _hasher: &mut DefaultHasher) {}
+
+    /// Tells submissions of the same images, categories and options apart
+    /// from others.
+    fn submission_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.images().hash(&mut hasher);
+        let mut categories = self.category_names();
+        categories.sort();
+        categories.dedup();
+        categories.hash(&mut hasher);
+        self.hash_options(&mut hasher);
+        hasher.finish()
+    }
 }
 
-#[derive(Debug, Clone, Display, Default)]
+/// Serializes whole, tagged by its name in `state` with the result of a
+/// finished task in `result`. Tasks in the API carry the name alone.
+#[derive(Debug, Clone, PartialEq, Display, Default, Serialize, Deserialize)]
+#[serde(tag = "state", content = "result", rename_all = "snake_case")]
 pub enum State {
     #[strum(to_string = "pending")]
     #[default]
@@ -19,63 +84,208 @@ pub enum State {
     #[strum(to_string = "running")]
     Running,
     #[strum(to_string = "finished")]
-    Finished(Result<Success, Arc<RunTaskError>>),
+    Finished(Result<Success, TaskError>),
 }
 
 #[derive(Debug, Clone)]
 pub struct TaskControlBlock {
     id: String,
-    state: Arc<RwLock<State>>,
+    state: Arc<watch::Sender<State>>,
+    tokens: TokenSender,
+    span: Span,
+    created_at: DateTime<Utc>,
+    finished_at: Arc<OnceLock<DateTime<Utc>>>,
+    tags: Arc<RwLock<Vec<SmolStr>>>,
+    deleted: Arc<AtomicBool>,
+    intermediates: Option<StageTexts>,
+    prompts: Option<StageTexts>,
+    seeds: Option<StageSeeds>,
+}
+
+fn task_span(id: &str) -> Span {
+    span!(Level::INFO, TASK_SPAN, id, debug = field::Empty)
+}
+
+impl Default for TaskControlBlock {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TaskControlBlock {
+    /// A pending task with a fresh random id.
     pub fn new() -> Self {
+        let id = key::generate_random_key();
         Self {
-            id: key::generate_random_key(),
-            state: Arc::new(RwLock::new(Default::default())),
+            span: task_span(&id),
+            id,
+            state: Arc::new(watch::Sender::new(Default::default())),
+            tokens: broadcast::Sender::new(TOKEN_CHANNEL_CAPACITY),
+            created_at: Utc::now(),
+            finished_at: Default::default(),
+            tags: Default::default(),
+            deleted: Default::default(),
+            intermediates: None,
+            prompts: None,
+            seeds: None,
         }
     }
 
+    /// Lets the task show what its stages generated, as the runner records
+    /// them to `intermediates`.
+    pub fn with_intermediates(mut self, intermediates: StageTexts) -> Self {
+        self.intermediates = Some(intermediates);
+        self
+    }
+
+    /// Output of each stage run so far, if the task asked to keep them and
+    /// wasn't swapped out since.
+    pub fn intermediates(&self) -> Option<BTreeMap<Stage, String>> {
+        self.intermediates.as_ref().map(StageTexts::texts)
+    }
+
+    /// Lets the task show the prompts its stages were given, as the runner
+    /// records them to `prompts`.
+    pub fn with_prompts(mut self, prompts: StageTexts) -> Self {
+        self.prompts = Some(prompts);
+        self
+    }
+
+    /// Rendered prompt of each stage run so far, none if the runner kept
+    /// nothing for this task.
+    pub fn prompts(&self) -> Option<BTreeMap<Stage, String>> {
+        self.prompts
+            .as_ref()
+            .map(StageTexts::texts)
+            .filter(|prompts| !prompts.is_empty())
+    }
+
+    /// Lets the task show the seeds its stages sampled with, as the runner
+    /// records them to `seeds`.
+    pub fn with_seeds(mut self, seeds: StageSeeds) -> Self {
+        self.seeds = Some(seeds);
+        self
+    }
+
+    /// Seed of each stage run so far, none unless the task ran deterministically.
+    pub fn seeds(&self) -> Option<BTreeMap<Stage, i32>> {
+        self.seeds
+            .as_ref()
+            .map(StageSeeds::seeds)
+            .filter(|seeds| !seeds.is_empty())
+    }
+
     pub fn id(&self) -> &str {
         &self.id
     }
 
     pub fn state(&self) -> State {
-        self.state.read().unwrap().clone()
+        self.state.borrow().clone()
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    /// When the task reached [State::Finished], if it has.
+    pub fn finished_at(&self) -> Option<DateTime<Utc>> {
+        self.finished_at.get().copied()
+    }
+
+    /// Waits until the task reaches [State::Finished] and returns its result.
+    pub async fn finished(&self) -> Result<Success, TaskError> {
+        let mut receiver = self.state.subscribe();
+        let state = receiver
+            .wait_for(|state| matches!(state, State::Finished(_)))
+            .await
+            .expect("sender is owned by the task itself");
+        match &*state {
+            State::Finished(result) => result.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Waits until the state differs from `observed`, returning the new one
+    /// right away if it already does.
+    pub async fn state_changed(&self, observed: &State) -> State {
+        let mut receiver = self.state.subscribe();
+        let state = receiver
+            .wait_for(|state| state != observed)
+            .await
+            .expect("sender is owned by the task itself");
+        state.clone()
+    }
+
+    /// Labels given to the task through the API, in the order they were given.
+    pub fn tags(&self) -> Vec<SmolStr> {
+        self.tags.read().unwrap().clone()
+    }
+
+    /// Replaces the tags of this task and every clone of it. Tasks that may have
+    /// been swapped out are edited through [crate::Scheduler::update_task] instead.
+    pub fn set_tags(&self, tags: Vec<SmolStr>) {
+        *self.tags.write().unwrap() = tags;
+    }
+
+    /// Whether the task was soft deleted, hiding it from listings and exports
+    /// while it can still be looked up.
+    pub fn is_deleted(&self) -> bool {
+        self.deleted.load(Ordering::Relaxed)
+    }
+
+    /// Marks the task and every clone of it deleted, see [Self::set_tags] for
+    /// tasks that may have been swapped out.
+    pub fn set_deleted(&self, deleted: bool) {
+        self.deleted.store(deleted, Ordering::Relaxed);
+    }
+
+    pub fn needs_review(&self) -> bool {
+        matches!(
+            &*self.state.borrow(),
+            State::Finished(Ok(Success(bill))) if bill.needs_review
+        )
+    }
+
+    /// Channel the runner publishes generated tokens to while the task runs.
+    pub fn tokens(&self) -> &TokenSender {
+        &self.tokens
+    }
+
+    pub fn subscribe_tokens(&self) -> broadcast::Receiver<Token> {
+        self.tokens.subscribe()
+    }
+
+    /// Span the task runs in.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Logs debug events of this task regardless of the global log level.
+    pub fn enable_debug(&self) {
+        self.span.record("debug", true);
     }
 
     pub fn set_state(&self, state: State) {
-        *self.state.write().unwrap() = state
+        if matches!(state, State::Finished(_)) {
+            self.finished_at.get_or_init(Utc::now);
+        }
+        self.state.send_replace(state);
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct Success(pub Bill);
 
-impl Serialize for State {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        serializer.serialize_str(self.to_string().as_str())
-    }
-}
-
-impl<'de> Deserialize<'de> for State {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        match s.as_str() {
-            "pending" => Ok(State::Pending),
-            "running" => Ok(State::Running),
-            "finished" => Ok(State::Finished(Err(Arc::new(RunTaskError::Runner(
-                anyhow::anyhow!("deserialized finished state without result"),
-            ))))),
-            _ => Err(serde::de::Error::custom(format!("unknown state: {}", s))),
-        }
+/// Tasks are equal when they read the same, whoever listens to them.
+impl PartialEq for TaskControlBlock {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+            && *self.state.borrow() == *other.state.borrow()
+            && self.created_at == other.created_at
+            && self.finished_at() == other.finished_at()
+            && self.tags() == other.tags()
+            && self.is_deleted() == other.is_deleted()
     }
 }
 
@@ -84,63 +294,455 @@ impl Serialize for TaskControlBlock {
     where
         S: serde::Serializer,
     {
-        let state = self.state.read().unwrap().clone();
-        let result = match &state {
-            State::Finished(result) => Some(result),
-            _ => None,
+        let state = self.state.borrow().clone();
+        let (success, error) = match &state {
+            State::Finished(Ok(success)) => (Some(success), None),
+            State::Finished(Err(err)) => (None, Some(err)),
+            _ => (None, None),
         };
-        let mut sstate = serializer.serialize_struct("Task", result.map(|_| 4).unwrap_or(2))?;
+        let mut sstate = serializer.serialize_struct("Task", 8)?;
         sstate.serialize_field("id", &self.id)?;
-        sstate.serialize_field("state", &state)?;
-        if let Some(result) = result {
-            sstate.serialize_field("success", &result.as_ref().ok().clone())?;
-            sstate.serialize_field(
-                "error",
-                &result.as_ref().err().map(|err| err.to_string()).clone(),
-            )?;
-        }
+        sstate.serialize_field("state", &state.to_string())?;
+        sstate.serialize_field("success", &success)?;
+        sstate.serialize_field("error", &error)?;
+        sstate.serialize_field("created_at", &self.created_at)?;
+        sstate.serialize_field("finished_at", &self.finished_at())?;
+        sstate.serialize_field("tags", &*self.tags.read().unwrap())?;
+        sstate.serialize_field("deleted", &self.is_deleted())?;
         sstate.end()
     }
 }
 
+/// Fields of a task in the API JSON. `Bill` differs in old swap layouts only.
+#[derive(Deserialize)]
+pub(crate) struct TaskData<Error, Bill = crate::bill::Bill> {
+    id: String,
+    state: String,
+    success: Option<Bill>,
+    error: Option<Error>,
+    #[serde(default)]
+    created_at: DateTime<Utc>,
+    #[serde(default)]
+    finished_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    tags: Vec<SmolStr>,
+    #[serde(default)]
+    deleted: bool,
+}
+
+impl<B: Into<Bill>> TaskData<TaskError, B> {
+    fn restore(self) -> Result<TaskControlBlock, String> {
+        let state = match self.state.as_str() {
+            "pending" => State::Pending,
+            "running" => State::Running,
+            "finished" => {
+                if let Some(bill) = self.success {
+                    State::Finished(Ok(Success(bill.into())))
+                } else if let Some(error) = self.error {
+                    State::Finished(Err(error))
+                } else {
+                    return Err("finished state without success or error".into());
+                }
+            }
+            _ => return Err(format!("unknown state: {}", self.state)),
+        };
+        Ok(SwappedTaskV4 {
+            id: self.id,
+            state: state.into(),
+            created_at: self.created_at,
+            finished_at: self.finished_at,
+            tags: self.tags,
+            deleted: self.deleted,
+        }
+        .into())
+    }
+}
+
 impl<'de> Deserialize<'de> for TaskControlBlock {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
+        /// `/v1` tasks carry only the message of their error.
         #[derive(Deserialize)]
-        struct TaskData {
-            id: String,
-            state: String,
-            success: Option<Success>,
-            error: Option<String>,
+        #[serde(untagged)]
+        enum JsonError {
+            Task(TaskError),
+            Message(String),
         }
 
-        let data = TaskData::deserialize(deserializer)?;
-        let state = match data.state.as_str() {
-            "pending" => State::Pending,
-            "running" => State::Running,
-            "finished" => {
-                if let Some(success) = data.success {
-                    State::Finished(Ok(success))
-                } else if let Some(error) = data.error {
-                    State::Finished(Err(Arc::new(RunTaskError::Runner(anyhow::anyhow!(error)))))
-                } else {
-                    return Err(serde::de::Error::custom(
-                        "finished state without success or error",
-                    ));
-                }
+        // only self-describing formats can tell the two apart, the swap never needs to
+        let data = if deserializer.is_human_readable() {
+            let data = TaskData::<JsonError>::deserialize(deserializer)?;
+            TaskData {
+                error: data.error.map(|error| match error {
+                    JsonError::Task(error) => error,
+                    JsonError::Message(message) => TaskError::from_message(message),
+                }),
+                id: data.id,
+                state: data.state,
+                success: data.success,
+                created_at: data.created_at,
+                finished_at: data.finished_at,
+                tags: data.tags,
+                deleted: data.deleted,
             }
-            _ => {
-                return Err(serde::de::Error::custom(format!(
-                    "unknown state: {}",
-                    data.state
-                )));
+        } else {
+            TaskData::<TaskError>::deserialize(deserializer)?
+        };
+        data.restore().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A task as written to the swap, version 7 of its layout. Unlike the API
+/// JSON, whose state is a string next to optional results, it keeps the
+/// [State] whole, so every task reads back the way it was written. `Bill`
+/// differs in version 6 only.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SwappedTask<Bill = crate::bill::Bill> {
+    id: String,
+    state: SwappedState<Bill>,
+    created_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+    tags: Vec<SmolStr>,
+    deleted: bool,
+    /// Empty unless the runner kept the prompts of the task
+    prompts: BTreeMap<Stage, String>,
+    /// Empty unless the task ran deterministically
+    seeds: BTreeMap<Stage, i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum SwappedState<Bill> {
+    Pending,
+    Running,
+    Finished(Result<Bill, TaskError>),
+}
+
+impl From<State> for SwappedState<Bill> {
+    fn from(state: State) -> Self {
+        match state {
+            State::Pending => SwappedState::Pending,
+            State::Running => SwappedState::Running,
+            State::Finished(result) => SwappedState::Finished(result.map(|success| success.0)),
+        }
+    }
+}
+
+impl From<&TaskControlBlock> for SwappedTask {
+    fn from(task: &TaskControlBlock) -> Self {
+        Self {
+            id: task.id.clone(),
+            state: task.state().into(),
+            created_at: task.created_at,
+            finished_at: task.finished_at(),
+            tags: task.tags(),
+            deleted: task.is_deleted(),
+            prompts: task.prompts().unwrap_or_default(),
+            seeds: task.seeds().unwrap_or_default(),
+        }
+    }
+}
+
+impl<B: Into<Bill>> From<SwappedTask<B>> for TaskControlBlock {
+    fn from(task: SwappedTask<B>) -> Self {
+        let seeds = (!task.seeds.is_empty()).then(|| StageSeeds::from(task.seeds));
+        TaskControlBlock {
+            seeds,
+            ..TaskControlBlock::from(SwappedTaskV5 {
+                id: task.id,
+                state: task.state,
+                created_at: task.created_at,
+                finished_at: task.finished_at,
+                tags: task.tags,
+                deleted: task.deleted,
+                prompts: task.prompts,
+            })
+        }
+    }
+}
+
+/// A task as swapped out before categories had a reason, version 6.
+pub(crate) type SwappedTaskV6 = SwappedTask<BillV2>;
+
+/// A task as swapped out before seeds were recorded, version 5.
+#[derive(Debug, Deserialize)]
+pub(crate) struct SwappedTaskV5<Bill = crate::bill::Bill> {
+    id: String,
+    state: SwappedState<Bill>,
+    created_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+    tags: Vec<SmolStr>,
+    deleted: bool,
+    prompts: BTreeMap<Stage, String>,
+}
+
+impl<B: Into<Bill>> From<SwappedTaskV5<B>> for TaskControlBlock {
+    fn from(task: SwappedTaskV5<B>) -> Self {
+        let prompts = (!task.prompts.is_empty()).then(|| StageTexts::from(task.prompts));
+        TaskControlBlock {
+            prompts,
+            ..TaskControlBlock::from(SwappedTaskV4 {
+                id: task.id,
+                state: task.state,
+                created_at: task.created_at,
+                finished_at: task.finished_at,
+                tags: task.tags,
+                deleted: task.deleted,
+            })
+        }
+    }
+}
+
+/// A task as swapped out before prompts could be kept, version 4.
+#[derive(Debug, Deserialize)]
+pub(crate) struct SwappedTaskV4<Bill = crate::bill::Bill> {
+    id: String,
+    state: SwappedState<Bill>,
+    created_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+    tags: Vec<SmolStr>,
+    deleted: bool,
+}
+
+impl<B: Into<Bill>> From<SwappedTaskV4<B>> for TaskControlBlock {
+    fn from(task: SwappedTaskV4<B>) -> Self {
+        let state = match task.state {
+            SwappedState::Pending => State::Pending,
+            SwappedState::Running => State::Running,
+            SwappedState::Finished(result) => {
+                State::Finished(result.map(|bill| Success(bill.into())))
             }
         };
-        Ok(TaskControlBlock {
-            id: data.id,
-            state: Arc::new(RwLock::new(state)),
-        })
+        TaskControlBlock {
+            span: task_span(&task.id),
+            id: task.id,
+            state: Arc::new(watch::Sender::new(state)),
+            tokens: broadcast::Sender::new(TOKEN_CHANNEL_CAPACITY),
+            created_at: task.created_at,
+            finished_at: Arc::new(task.finished_at.map(OnceLock::from).unwrap_or_default()),
+            tags: Arc::new(RwLock::new(task.tags)),
+            deleted: Arc::new(AtomicBool::new(task.deleted)),
+            intermediates: None,
+            prompts: None,
+            seeds: None,
+        }
+    }
+}
+
+/// A task as swapped out before bills had several categories, version 3.
+pub(crate) type SwappedTaskV3 = SwappedTaskV4<BillV1>;
+
+/// A task as swapped out before the swap had a layout of its own, version 2,
+/// which was the serde of [TaskControlBlock] itself.
+pub(crate) type SwappedTaskV2 = TaskData<TaskError, BillV1>;
+
+impl TryFrom<SwappedTaskV2> for TaskControlBlock {
+    type Error = String;
+
+    fn try_from(task: SwappedTaskV2) -> Result<Self, Self::Error> {
+        task.restore()
+    }
+}
+
+/// A task as swapped out before tasks had tags and could be soft deleted,
+/// version 1 of the swap layout. Postcard has no field names, so every change
+/// to the layout of [TaskControlBlock] gets a struct like this one to read the
+/// older swap chunks with.
+#[derive(Deserialize)]
+pub(crate) struct SwappedTaskV1 {
+    id: String,
+    state: String,
+    success: Option<BillV1>,
+    error: Option<TaskError>,
+    created_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<SwappedTaskV1> for TaskControlBlock {
+    type Error = String;
+
+    fn try_from(task: SwappedTaskV1) -> Result<Self, Self::Error> {
+        TaskData {
+            id: task.id,
+            state: task.state,
+            success: task.success,
+            error: task.error,
+            created_at: task.created_at,
+            finished_at: task.finished_at,
+            tags: Vec::new(),
+            deleted: false,
+        }
+        .restore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ollama_rs::error::OllamaError;
+
+    use super::*;
+    use crate::{error::RunTaskError, task::Stage};
+
+    fn states() -> Vec<State> {
+        let failures = [
+            RunTaskError::Prepare(OllamaError::Other("connection refused".into())),
+            RunTaskError::Runner(anyhow::anyhow!("runner gone")),
+            RunTaskError::Stage {
+                stage: Stage::Category,
+                model: "gemma4:e4b".into(),
+                source: OllamaError::Other("out of memory".into()),
+            },
+            RunTaskError::StageTimeout {
+                stage: Stage::Description,
+                model: "gemma4:e4b".into(),
+                timeout: std::time::Duration::from_secs(90),
+            },
+            RunTaskError::InvalidInputImage(image::ImageError::IoError(std::io::Error::other(
+                "truncated",
+            ))),
+            RunTaskError::TooManyImages { count: 3, limit: 2 },
+            RunTaskError::MissingChatTemplate("gemma4:e4b".into()),
+            RunTaskError::QuantizationNotOffered("q5_0".into()),
+            RunTaskError::InvalidOutput("amount".into()),
+            RunTaskError::EmptyModelResponse(Stage::Notes),
+        ];
+        let bills = [
+            Bill {
+                notes: "Coffee".into(),
+                amount: 4.2,
+                currency: Some("EUR".into()),
+                categories: vec!["Food".into()],
+                category_reason: None,
+                needs_review: false,
+            },
+            Bill {
+                notes: "".into(),
+                amount: 0.0,
+                currency: None,
+                categories: Vec::new(),
+                category_reason: None,
+                needs_review: true,
+            },
+        ];
+        [State::Pending, State::Running]
+            .into_iter()
+            .chain(bills.map(|bill| State::Finished(Ok(Success(bill)))))
+            .chain(failures.map(|err| State::Finished(Err(err.into()))))
+            .chain([State::Finished(Err(TaskError::cancelled()))])
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_state_changed() {
+        let tcb = TaskControlBlock::new();
+        let observed = tcb.state();
+        // changed between looking at the task and waiting on it
+        tcb.set_state(State::Running);
+        let changed = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            tcb.state_changed(&observed),
+        )
+        .await
+        .unwrap();
+        assert_eq!(changed, State::Running);
+
+        let waiting = tokio::spawn({
+            let tcb = tcb.clone();
+            async move { tcb.state_changed(&State::Running).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        tcb.set_state(State::Finished(Err(TaskError::cancelled())));
+        assert!(matches!(waiting.await.unwrap(), State::Finished(Err(_))));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for state in states() {
+            let tcb = TaskControlBlock::new();
+            tcb.set_state(state);
+            tcb.set_tags(vec!["groceries".into(), "shared".into()]);
+            tcb.set_deleted(matches!(tcb.state(), State::Finished(Err(_))));
+            let json: TaskControlBlock =
+                serde_json::from_str(&serde_json::to_string(&tcb).unwrap()).unwrap();
+            assert_eq!(json, tcb);
+            let swapped: SwappedTask =
+                postcard::from_bytes(&postcard::to_allocvec(&SwappedTask::from(&tcb)).unwrap())
+                    .unwrap();
+            assert_eq!(TaskControlBlock::from(swapped), tcb);
+        }
+
+        let seeds = BTreeMap::from([(Stage::Description, 42), (Stage::Amount, 42)]);
+        let tcb = TaskControlBlock::new().with_seeds(seeds.clone().into());
+        let swapped: SwappedTask =
+            postcard::from_bytes(&postcard::to_allocvec(&SwappedTask::from(&tcb)).unwrap())
+                .unwrap();
+        assert_eq!(TaskControlBlock::from(swapped).seeds(), Some(seeds));
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        for state in states() {
+            let json = serde_json::to_value(&state).unwrap();
+            assert_eq!(json["state"], state.to_string());
+            assert_eq!(serde_json::from_value::<State>(json).unwrap(), state);
+        }
+        let failed = State::Finished(Err(TaskError::from_message("out of memory")));
+        assert_eq!(
+            serde_json::to_value(&failed).unwrap()["result"]["Err"]["message"],
+            "out of memory"
+        );
+        let paid = serde_json::json!({
+            "state": "finished",
+            "result": {"Ok": {"notes": "Tea", "amount": 3.5, "currency": null, "category": null, "needs_review": false}}
+        });
+        let State::Finished(Ok(Success(bill))) = serde_json::from_value(paid).unwrap() else {
+            panic!("expected a successful task");
+        };
+        assert_eq!(bill.amount, 3.5);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let codes = states()
+            .into_iter()
+            .filter_map(|state| match state {
+                State::Finished(Err(err)) => Some((err.code.to_string(), err.retryable)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            [
+                ("prepare".to_string(), true),
+                ("runner".to_string(), true),
+                ("stage".to_string(), true),
+                ("stage_timeout".to_string(), true),
+                ("invalid_input_image".to_string(), false),
+                ("too_many_images".to_string(), false),
+                ("missing_chat_template".to_string(), false),
+                ("quantization_not_offered".to_string(), false),
+                ("invalid_output".to_string(), true),
+                ("empty_model_response".to_string(), true),
+                ("cancelled".to_string(), true),
+            ]
+        );
+
+        // codes of a newer server
+        let code: crate::error::TaskErrorCode = serde_json::from_str(r#""out_of_tokens""#).unwrap();
+        assert_eq!(code, crate::error::TaskErrorCode::Unknown);
+
+        // `/v1` only has the message
+        let v1 = r#"{"id": "x", "state": "finished", "success": null, "error": "invalid LLM output for amount"}"#;
+        let tcb: TaskControlBlock = serde_json::from_str(v1).unwrap();
+        let State::Finished(Err(err)) = tcb.state() else {
+            panic!("expected a failed task");
+        };
+        assert_eq!(
+            err,
+            TaskError::from_message("invalid LLM output for amount")
+        );
+        assert_eq!(err.code, crate::error::TaskErrorCode::Unknown);
     }
 }
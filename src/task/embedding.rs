@@ -0,0 +1,116 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use strum::{Display, EnumString};
+
+/// How the category stage picks the categories of a bill, chosen per task.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumString, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Categorizer {
+    /// The category model picks them in a constrained generation, giving a reason
+    #[default]
+    Lm,
+    /// The category whose embedding is nearest to the description's, falling
+    /// back to [Categorizer::Lm] when the embedding model can't be reached
+    Embedding,
+}
+
+/// Category embeddings kept at most. Tasks bring categories of their own, so
+/// the least recently used ones make way rather than piling up.
+pub const MAX_CATEGORY_EMBEDDINGS: usize = 1024;
+
+/// Embeddings of the categories by model and text, computed once and shared
+/// by every task choosing from them.
+#[derive(Debug, Clone)]
+pub struct CategoryEmbeddings(Arc<Mutex<EmbeddingsByText>>);
+
+type EmbeddingsByText = LruCache<(SmolStr, String), Arc<[f32]>>;
+
+impl Default for CategoryEmbeddings {
+    fn default() -> Self {
+        Self::with_capacity(NonZeroUsize::new(MAX_CATEGORY_EMBEDDINGS).unwrap())
+    }
+}
+
+impl CategoryEmbeddings {
+    pub fn with_capacity(capacity: NonZeroUsize) -> Self {
+        Self(Arc::new(Mutex::new(LruCache::new(capacity))))
+    }
+
+    pub fn get(&self, model: &str, text: &str) -> Option<Arc<[f32]>> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&(SmolStr::from(model), text.to_string()))
+            .cloned()
+    }
+
+    pub fn insert(&self, model: &SmolStr, text: String, embedding: Arc<[f32]>) {
+        self.0.lock().unwrap().put((model.clone(), text), embedding);
+    }
+}
+
+/// Cosine similarity of two embeddings, none if they differ in length or
+/// either is all zeros.
+pub fn similarity(a: &[f32], b: &[f32]) -> Option<f32> {
+    if a.len() != b.len() {
+        return None;
+    }
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    (norms > 0.0).then(|| dot / norms)
+}
+
+/// Index of the candidate most similar to `target` and its similarity, none if
+/// no candidate can be compared with it.
+pub fn nearest<'a>(
+    target: &[f32],
+    candidates: impl IntoIterator<Item = &'a [f32]>,
+) -> Option<(usize, f32)> {
+    candidates
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, candidate)| Some((index, similarity(target, candidate)?)))
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounded() {
+        let embeddings = CategoryEmbeddings::with_capacity(NonZeroUsize::new(2).unwrap());
+        let model = SmolStr::from("embed");
+        embeddings.insert(&model, "Food".into(), vec![1.0].into());
+        embeddings.insert(&model, "Rent".into(), vec![0.5].into());
+        assert!(embeddings.get("embed", "Food").is_some());
+        embeddings.insert(&model, "Toys".into(), vec![0.2].into());
+        assert!(embeddings.get("embed", "Rent").is_none());
+        assert!(embeddings.get("embed", "Food").is_some());
+        assert!(embeddings.get("other", "Food").is_none());
+    }
+
+    #[test]
+    fn test_nearest() {
+        assert_eq!(similarity(&[1.0, 0.0], &[2.0, 0.0]), Some(1.0));
+        assert_eq!(similarity(&[1.0, 0.0], &[0.0, 3.0]), Some(0.0));
+        assert_eq!(similarity(&[1.0, 0.0], &[0.0, 0.0]), None);
+        assert_eq!(similarity(&[1.0, 0.0], &[1.0]), None);
+
+        let candidates: [&[f32]; 3] = [&[0.0, 1.0], &[1.0], &[1.0, 0.2]];
+        let (index, similarity) = nearest(&[1.0, 0.1], candidates).unwrap();
+        assert_eq!(index, 2);
+        assert!(similarity > 0.99);
+        assert_eq!(nearest(&[1.0, 0.1], [&[1.0f32][..]]), None);
+    }
+}
@@ -0,0 +1,631 @@
+use std::{io::Cursor, str::FromStr, sync::RwLock, time::Duration};
+
+use image::{
+    AnimationDecoder, DynamicImage, Frames, ImageError, ImageFormat, Rgb, RgbImage,
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    error::{ImageFormatHint, UnsupportedError},
+};
+use serde::{Deserialize, Serialize};
+use strum::Display;
+use tokio::process::Command;
+use tracing::{Level, event};
+
+use crate::task::preprocess::{self, Operation, Preprocess};
+
+/// Command converting images the image crate can't decode, set once from
+/// `--transcode-command`.
+static TRANSCODER: RwLock<Option<Transcoder>> = RwLock::new(None);
+
+/// Longest a [Transcoder] may run before it's killed, so a malformed upload
+/// can't hold a worker forever.
+const TRANSCODE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Formats only readable through a [Transcoder].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum Foreign {
+    #[strum(to_string = "AVIF")]
+    Avif,
+    #[strum(to_string = "HEIC")]
+    Heic,
+}
+
+impl Foreign {
+    fn extension(self) -> &'static str {
+        match self {
+            Foreign::Avif => "avif",
+            Foreign::Heic => "heic",
+        }
+    }
+}
+
+/// Format of an upload, told apart by its magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Native(ImageFormat),
+    Foreign(Foreign),
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Native(format) => write!(f, "{}", format_name(*format)),
+            Format::Foreign(foreign) => write!(f, "{foreign}"),
+        }
+    }
+}
+
+impl Format {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Format::Native(format) => format.to_mime_type(),
+            Format::Foreign(Foreign::Avif) => "image/avif",
+            Format::Foreign(Foreign::Heic) => "image/heic",
+        }
+    }
+}
+
+fn format_name(format: ImageFormat) -> String {
+    format!("{format:?}").to_uppercase()
+}
+
+/// Brands of the `ftyp` box HEIF files open with, still images and sequences.
+const HEIC_BRANDS: [&[u8; 4]; 8] = [
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"hevm", b"hevs",
+];
+
+pub fn detect(buf: &[u8]) -> Option<Format> {
+    if buf.len() >= 12
+        && &buf[4..8] == b"ftyp"
+        && HEIC_BRANDS.contains(&&buf[8..12].try_into().unwrap())
+    {
+        return Some(Format::Foreign(Foreign::Heic));
+    }
+    match image::guess_format(buf).ok()? {
+        // the image crate decodes AVIF only with dav1d linked in, which it isn't
+        ImageFormat::Avif => Some(Format::Foreign(Foreign::Avif)),
+        format => Some(Format::Native(format)),
+    }
+}
+
+/// Formats uploads are accepted in, given the transcoder configured.
+pub fn supported() -> Vec<String> {
+    let mut names = ImageFormat::all()
+        .filter(|format| format.reading_enabled() && *format != ImageFormat::Avif)
+        .map(format_name)
+        .collect::<Vec<_>>();
+    if Transcoder::global().is_some() {
+        names.extend([Foreign::Avif, Foreign::Heic].map(|foreign| foreign.to_string()));
+    }
+    names
+}
+
+/// Checks `buf` is an image that [normalize] can read, naming its format if not.
+pub fn check(buf: &[u8]) -> Result<(), Option<Format>> {
+    match detect(buf) {
+        Some(Format::Native(format)) if format.reading_enabled() => Ok(()),
+        Some(Format::Foreign(_)) if Transcoder::global().is_some() => Ok(()),
+        detected => Err(detected),
+    }
+}
+
+/// External program turning AVIF or HEIC into something readable, like
+/// `heif-convert {input} {output}`. Both placeholders are substituted with
+/// temporary files, the output ending in `.png`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transcoder {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl Transcoder {
+    pub fn parse(command: &str) -> Result<Self, String> {
+        let mut words = command.split_whitespace().map(str::to_string);
+        let program = words.next().ok_or("empty transcode command")?;
+        let args = words.collect::<Vec<_>>();
+        if !args.iter().any(|arg| arg.contains("{output}")) {
+            return Err("the transcode command must write to {output}".into());
+        }
+        Ok(Self {
+            program,
+            args,
+            timeout: TRANSCODE_TIMEOUT,
+        })
+    }
+
+    pub fn global() -> Option<Self> {
+        TRANSCODER.read().unwrap().clone()
+    }
+
+    pub fn set_global(transcoder: Option<Self>) {
+        *TRANSCODER.write().unwrap() = transcoder;
+    }
+
+    /// Runs the command, blocking until it exits or is killed for taking
+    /// longer than [TRANSCODE_TIMEOUT]. Called from the blocking pool, so it
+    /// waits on a runtime of its own.
+    pub fn transcode(&self, buf: &[u8], format: Foreign) -> std::io::Result<Vec<u8>> {
+        let input = tempfile::Builder::new()
+            .suffix(&format!(".{}", format.extension()))
+            .tempfile()?;
+        std::fs::write(input.path(), buf)?;
+        let output = tempfile::Builder::new().suffix(".png").tempfile()?;
+        let args = self.args.iter().map(|arg| {
+            arg.replace("{input}", &input.path().to_string_lossy())
+                .replace("{output}", &output.path().to_string_lossy())
+        });
+        let status = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(async {
+                let mut child = Command::new(&self.program)
+                    .args(args)
+                    .kill_on_drop(true)
+                    .spawn()?;
+                match tokio::time::timeout(self.timeout, child.wait()).await {
+                    Ok(status) => status,
+                    Err(_) => {
+                        child.kill().await?;
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            format!("{} took longer than {:?}", self.program, self.timeout),
+                        ))
+                    }
+                }
+            })?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "{} exited with {status}",
+                self.program
+            )));
+        }
+        std::fs::read(output.path())
+    }
+}
+
+/// Decodes a still image, AVIF and HEIC through the [Transcoder].
+fn decode(buf: &[u8]) -> Result<DynamicImage, ImageError> {
+    match detect(buf) {
+        Some(Format::Foreign(foreign)) => {
+            let Some(transcoder) = Transcoder::global() else {
+                return Err(ImageError::Unsupported(UnsupportedError::from(
+                    ImageFormatHint::Name(foreign.to_string()),
+                )));
+            };
+            image::load_from_memory(&transcoder.transcode(buf, foreign)?)
+        }
+        _ => image::load_from_memory(buf),
+    }
+}
+
+/// Re-encodes as an 8-bit RGB PNG.
+///
+/// Transparent pixels are composited over white, and exotic pixel formats
+/// (16-bit, float, grayscale, palette, CMYK) are converted, so the VLM always
+/// sees the same kind of input.
+fn encode(image: &DynamicImage) -> Result<Vec<u8>, ImageError> {
+    let mut out = Cursor::new(Vec::new());
+    flatten(image).write_to(&mut out, ImageFormat::Png)?;
+    Ok(out.into_inner())
+}
+
+/// Frames taken from animated GIF, WebP and PNG uploads, each passed on as an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum FrameSelection {
+    #[default]
+    First,
+    Middle,
+    Last,
+    /// Every `step`th frame from the first on, at most `cap` of them
+    Every {
+        step: usize,
+        cap: usize,
+    },
+}
+
+/// Frames taken by `every:N` unless a cap is given.
+const DEFAULT_FRAME_CAP: usize = 4;
+/// Most frames `every:N:CAP` takes from one image, whatever the cap asks for.
+pub const MAX_FRAME_CAP: usize = 32;
+
+impl FromStr for FrameSelection {
+    type Err = String;
+
+    /// `first`, `middle`, `last`, `every:N` or `every:N:CAP`, the cap
+    /// clamped to [MAX_FRAME_CAP].
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid frame selection {value:?}, expected first, middle, last or every:N[:CAP]"
+            )
+        };
+        match value.trim().to_ascii_lowercase().as_str() {
+            "first" => Ok(Self::First),
+            "middle" => Ok(Self::Middle),
+            "last" => Ok(Self::Last),
+            every => {
+                let mut parts = every.strip_prefix("every:").ok_or_else(invalid)?.split(':');
+                let mut number = |default: Option<usize>| match parts.next() {
+                    Some(part) => part
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or_else(invalid),
+                    None => default.ok_or_else(invalid),
+                };
+                let step = number(None)?;
+                let cap = number(Some(DEFAULT_FRAME_CAP))?.min(MAX_FRAME_CAP);
+                if parts.next().is_some() {
+                    return Err(invalid());
+                }
+                Ok(Self::Every { step, cap })
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for FrameSelection {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl std::fmt::Display for FrameSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameSelection::First => write!(f, "first"),
+            FrameSelection::Middle => write!(f, "middle"),
+            FrameSelection::Last => write!(f, "last"),
+            FrameSelection::Every { step, cap } => write!(f, "every:{step}:{cap}"),
+        }
+    }
+}
+
+impl From<FrameSelection> for String {
+    fn from(value: FrameSelection) -> Self {
+        value.to_string()
+    }
+}
+
+/// Frames of an animated image, none for still ones.
+fn frames(buf: &[u8]) -> Result<Option<Frames<'_>>, ImageError> {
+    Ok(match image::guess_format(buf) {
+        Ok(ImageFormat::Gif) => Some(GifDecoder::new(Cursor::new(buf))?.into_frames()),
+        Ok(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(Cursor::new(buf))?;
+            decoder.has_animation().then(|| decoder.into_frames())
+        }
+        Ok(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(Cursor::new(buf))?;
+            if decoder.is_apng()? {
+                Some(decoder.apng()?.into_frames())
+            } else {
+                None
+            }
+        }
+        _ => None,
+    })
+}
+
+/// Turns an upload into the PNGs shown to the VLM: the frames `selection` picks
+/// out of an animated image or the image itself if it is still, each run
+/// through [preprocess::auto] first if asked to.
+pub fn prepare(
+    buf: &[u8],
+    selection: FrameSelection,
+    preprocessing: Preprocess,
+) -> Result<Vec<Vec<u8>>, ImageError> {
+    decode_frames(buf, selection)?
+        .into_iter()
+        .map(|image| match preprocessing {
+            Preprocess::Auto => {
+                let (image, operations) = preprocess::auto(image);
+                event!(
+                    Level::DEBUG,
+                    "preprocessed: {}",
+                    if operations.is_empty() {
+                        "nothing to do".to_string()
+                    } else {
+                        operations
+                            .iter()
+                            .map(Operation::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    }
+                );
+                encode(&image)
+            }
+            Preprocess::Off => encode(&image),
+        })
+        .collect()
+}
+
+fn decode_frames(buf: &[u8], selection: FrameSelection) -> Result<Vec<DynamicImage>, ImageError> {
+    let Some(all) = frames(buf)? else {
+        return Ok(vec![decode(buf)?]);
+    };
+    let picked: Vec<_> = match selection {
+        FrameSelection::First => all.enumerate().take(1).collect(),
+        FrameSelection::Last => all.enumerate().last().into_iter().collect(),
+        FrameSelection::Middle => {
+            // counted first, holding every frame of a long recording would take too much memory
+            let count = all.count();
+            let again = frames(buf)?.into_iter().flatten();
+            again.enumerate().nth(count / 2).into_iter().collect()
+        }
+        FrameSelection::Every { step, cap } => all.enumerate().step_by(step).take(cap).collect(),
+    };
+    event!(
+        Level::DEBUG,
+        "animated image, using frames {:?} ({selection})",
+        picked.iter().map(|(i, _)| i).collect::<Vec<_>>()
+    );
+    picked
+        .into_iter()
+        .map(|(_, frame)| Ok(DynamicImage::ImageRgba8(frame?.into_buffer())))
+        .collect()
+}
+
+/// [prepare]s every image on the blocking pool, keeping decoding off the threads
+/// serving requests.
+pub async fn normalize_all(
+    bufs: Vec<Vec<u8>>,
+    selection: FrameSelection,
+    preprocessing: Preprocess,
+) -> Result<Vec<Vec<u8>>, ImageError> {
+    // the task span decides whether debug events are logged
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        let mut normalized = Vec::with_capacity(bufs.len());
+        for buf in &bufs {
+            normalized.extend(prepare(buf, selection, preprocessing)?);
+        }
+        Ok(normalized)
+    })
+    .await
+    .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+/// Converts to 8-bit RGB, compositing any alpha channel over white.
+pub fn flatten(image: &DynamicImage) -> RgbImage {
+    if !image.color().has_alpha() {
+        return image.to_rgb8();
+    }
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let alpha = a as u32;
+        let over_white = |c: u8| ((c as u32 * alpha + 255 * (255 - alpha) + 127) / 255) as u8;
+        Rgb([over_white(r), over_white(g), over_white(b)])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, ImageBuffer, Luma, LumaA, Rgb, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn normalize(buf: &[u8]) -> Result<Vec<u8>, ImageError> {
+        super::encode(&decode(buf)?)
+    }
+
+    fn normalize_frames(buf: &[u8], selection: FrameSelection) -> Result<Vec<Vec<u8>>, ImageError> {
+        prepare(buf, selection, Preprocess::Off)
+    }
+
+    fn encode(image: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        image.write_to(&mut out, format).unwrap();
+        out.into_inner()
+    }
+
+    fn decode_normalized(buf: &[u8]) -> RgbImage {
+        let normalized = normalize(buf).unwrap();
+        assert_eq!(image::guess_format(&normalized).unwrap(), ImageFormat::Png);
+        match image::load_from_memory(&normalized).unwrap() {
+            DynamicImage::ImageRgb8(image) => image,
+            other => panic!("expected 8-bit RGB, got {:?}", other.color()),
+        }
+    }
+
+    #[test]
+    fn test_normalize_pixel_formats() {
+        let inputs = [
+            encode(
+                DynamicImage::ImageLuma8(GrayImage::from_pixel(4, 3, Luma([200]))),
+                ImageFormat::Png,
+            ),
+            encode(
+                DynamicImage::ImageLumaA8(ImageBuffer::from_pixel(4, 3, LumaA([0, 0]))),
+                ImageFormat::Png,
+            ),
+            encode(
+                DynamicImage::ImageRgb16(ImageBuffer::from_pixel(4, 3, Rgb([65535, 0, 0]))),
+                ImageFormat::Png,
+            ),
+            encode(
+                DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 3, Rgb([10, 20, 30]))),
+                ImageFormat::Jpeg,
+            ),
+            encode(
+                DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 3, Rgba([0, 0, 255, 255]))),
+                ImageFormat::Bmp,
+            ),
+        ];
+        for input in inputs {
+            let image = decode_normalized(&input);
+            assert_eq!(image.dimensions(), (4, 3));
+        }
+    }
+
+    #[test]
+    fn test_alpha_over_white() {
+        let mut rgba = RgbaImage::from_pixel(2, 1, Rgba([0, 0, 0, 0]));
+        rgba.put_pixel(1, 0, Rgba([0, 0, 0, 128]));
+        let image = decode_normalized(&encode(DynamicImage::ImageRgba8(rgba), ImageFormat::Png));
+        assert_eq!(image.get_pixel(0, 0), &Rgb([255, 255, 255]));
+        assert_eq!(image.get_pixel(1, 0), &Rgb([127, 127, 127]));
+    }
+
+    const WEBP: &[u8] = include_bytes!("../../asset/thumbnail.webp");
+    const AVIF: &[u8] = include_bytes!("../../asset/thumbnail.avif");
+    /// Just the `ftyp` box, no HEVC encoder around to make a real one
+    const HEIC: &[u8] = b"\0\0\0\x18ftypheic\0\0\0\0mif1heic";
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(WEBP), Some(Format::Native(ImageFormat::WebP)));
+        assert_eq!(decode_normalized(WEBP).dimensions(), (82, 96));
+        assert_eq!(detect(AVIF), Some(Format::Foreign(Foreign::Avif)));
+        assert_eq!(detect(HEIC), Some(Format::Foreign(Foreign::Heic)));
+        assert_eq!(detect(b"definitely not an image"), None);
+    }
+
+    /// Only test touching the global transcoder, so the others can't race it.
+    #[test]
+    fn test_transcode() {
+        assert_eq!(check(AVIF), Err(Some(Format::Foreign(Foreign::Avif))));
+        assert!(!supported().contains(&"HEIC".to_string()));
+        assert!(normalize(HEIC).is_err());
+
+        let png = tempfile::Builder::new().suffix(".png").tempfile().unwrap();
+        std::fs::write(
+            png.path(),
+            encode(
+                DynamicImage::ImageRgb8(RgbImage::new(5, 7)),
+                ImageFormat::Png,
+            ),
+        )
+        .unwrap();
+        let command = format!("cp {} {{output}}", png.path().display());
+        Transcoder::set_global(Some(Transcoder::parse(&command).unwrap()));
+        assert_eq!(check(AVIF), Ok(()));
+        assert!(supported().contains(&"HEIC".to_string()));
+        assert_eq!(decode_normalized(HEIC).dimensions(), (5, 7));
+
+        Transcoder::set_global(Some(Transcoder::parse("false {output}").unwrap()));
+        assert!(normalize(HEIC).is_err());
+
+        let hanging = Transcoder {
+            timeout: Duration::from_millis(100),
+            ..Transcoder::parse("tail -f {output}").unwrap()
+        };
+        let started = std::time::Instant::now();
+        let err = hanging.transcode(HEIC, Foreign::Heic).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Transcoder::set_global(None);
+        assert!(Transcoder::parse("heif-convert {input}").is_err());
+    }
+
+    fn animation() -> Vec<u8> {
+        use image::{Delay, Frame, codecs::gif::GifEncoder};
+
+        // a blank frame first, like screen recordings starting before the app shows up
+        let shades = [255, 10, 20, 30, 40];
+        let mut out = Vec::new();
+        GifEncoder::new(&mut out)
+            .encode_frames(shades.map(|shade| {
+                Frame::from_parts(
+                    RgbaImage::from_pixel(3, 2, Rgba([shade, shade, shade, 255])),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(100, 1),
+                )
+            }))
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_animation_frames() {
+        let gif = animation();
+        let shades = |selection: &str| {
+            normalize_frames(&gif, selection.parse().unwrap())
+                .unwrap()
+                .iter()
+                .map(|frame| decode_normalized(frame).get_pixel(0, 0).0[0])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(shades("first"), [255]);
+        assert_eq!(shades("middle"), [20]);
+        assert_eq!(shades("LAST"), [40]);
+        assert_eq!(shades("every:2"), [255, 20, 40]);
+        assert_eq!(shades("every:1:2"), [255, 10]);
+        let still = encode(
+            DynamicImage::ImageRgb8(RgbImage::new(4, 3)),
+            ImageFormat::Png,
+        );
+        assert_eq!(
+            normalize_frames(&still, FrameSelection::Last)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        for invalid in ["every", "every:0", "every:2:3:4", "sometimes"] {
+            assert!(invalid.parse::<FrameSelection>().is_err(), "{invalid}");
+        }
+        let every = FrameSelection::Every { step: 3, cap: 4 };
+        assert_eq!(every.to_string().parse::<FrameSelection>(), Ok(every));
+        assert_eq!(
+            "every:1:1000000".parse::<FrameSelection>(),
+            Ok(FrameSelection::Every {
+                step: 1,
+                cap: MAX_FRAME_CAP
+            })
+        );
+    }
+
+    #[test]
+    fn test_prepare_preprocessed() {
+        let receipt = include_bytes!("../../asset/dim-receipt.png");
+        let [original] = &prepare(receipt, FrameSelection::First, Preprocess::Off).unwrap()[..]
+        else {
+            panic!("expected one image");
+        };
+        assert_eq!(decode_normalized(original).dimensions(), (120, 160));
+        let [processed] = &prepare(receipt, FrameSelection::First, Preprocess::Auto).unwrap()[..]
+        else {
+            panic!("expected one image");
+        };
+        let processed = decode_normalized(processed);
+        assert_eq!(processed.dimensions(), (80, 128));
+        assert!(processed.pixels().all(|Rgb([r, g, b])| r == g && g == b));
+    }
+
+    #[test]
+    fn test_reject_garbage() {
+        assert!(normalize(b"definitely not an image").is_err());
+    }
+
+    /// Decoding a batch of large images must not stall the only worker thread.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_normalize_keeps_workers_responsive() {
+        let large = encode(
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(1024, 1024, Rgba([1, 2, 3, 4]))),
+            ImageFormat::Png,
+        );
+        let work = tokio::spawn(normalize_all(
+            vec![large; 4],
+            FrameSelection::First,
+            Preprocess::Off,
+        ));
+        let mut worst = std::time::Duration::ZERO;
+        while !work.is_finished() {
+            let start = std::time::Instant::now();
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            worst = worst.max(start.elapsed());
+        }
+        assert_eq!(work.await.unwrap().unwrap().len(), 4);
+        assert!(
+            worst < std::time::Duration::from_millis(200),
+            "worker stalled for {worst:?}"
+        );
+    }
+}
@@ -1,6 +1,10 @@
 mod descriptor;
-mod run;
+pub mod embedding;
+pub mod imaging;
 pub mod ollama;
+pub mod preprocess;
+pub mod quiet;
+mod run;
 
 pub use descriptor::*;
 pub use run::*;
@@ -2,34 +2,54 @@ use axum::RequestExt;
 use axum::extract::Multipart;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
+use chrono::Utc;
 use encoding_rs::UTF_8;
+use futures::{StreamExt, TryStreamExt};
 use ollama_rs::Ollama;
 use ollama_rs::error::OllamaError;
+use ollama_rs::generation::completion::GenerationResponse;
 use ollama_rs::generation::completion::request::GenerationRequest;
+use ollama_rs::generation::embeddings::request::{EmbeddingsInput, GenerateEmbeddingsRequest};
 use ollama_rs::generation::images::Image;
 use ollama_rs::generation::parameters::{
     FormatType, JsonSchema, JsonStructure, KeepAlive, TimeUnit,
 };
 use ollama_rs::models::create::CreateModelRequest;
-use schemars::json_schema;
+use ollama_rs::models::pull::PullModelStatus;
+use rand::RngExt;
+use schemars::{Schema, json_schema};
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Display;
+use std::hash::{DefaultHasher, Hash};
 use std::io::{Cursor, Read};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
 use tracing::{Level, event};
 use zip::result::ZipError;
 
 use axum::{body::Bytes, extract::FromRequest};
 use ollama_rs::models::ModelOptions;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use smol_str::{SmolStr, ToSmolStr};
 use zip::ZipArchive;
 
+use crate::args::ChatTemplate;
 use crate::bill::Category;
 use crate::ext::FromEnvVars;
 use crate::{
+    amount::{AmountBounds, currency_code},
     bill::Bill,
-    error::{CreateTaskError, RunTaskError},
-    task::{RunTask, TaskDescriptor},
+    error::{CreateTaskError, Names, PinModelError, RunTaskError, StartupError},
+    events::{EventBus, SchedulerEvent},
+    task::{
+        PromptRetention, RunTask, Stage, StageSeeds, StageTexts, TaskDescriptor, Token, TokenKind,
+        TokenSender,
+        embedding::{self, Categorizer, CategoryEmbeddings},
+        imaging::{self, FrameSelection},
+        preprocess::Preprocess,
+        quiet::{QuietHours, Residency},
+    },
 };
 
 #[derive(Debug, Clone)]
@@ -37,7 +57,256 @@ pub struct OllamaRunTask {
     pub ollama: Ollama,
     pub caption_model: SmolStr,
     pub extract_model: SmolStr,
+    /// Models stages run on instead of the caption or extract model
+    pub stage_models: HashMap<Stage, SmolStr>,
+    /// How tasks are categorized unless they say otherwise
+    pub categorizer: Categorizer,
+    /// Model the [Categorizer::Embedding] strategy embeds descriptions and categories with
+    pub embedding_model: SmolStr,
+    pub category_embeddings: CategoryEmbeddings,
     pub offline: bool,
+    pub pulls: PullTracker,
+    pub chat_templates: ChatTemplates,
+    /// Steers the model during a stage, none by default
+    pub system_prompts: HashMap<Stage, Arc<str>>,
+    /// How much of the rendered prompts is kept on tasks, nothing by default
+    pub prompt_retention: PromptRetention,
+    /// Bytes of a rendered prompt logged or kept at most
+    pub max_prompt_size: usize,
+    /// Longest a stage may generate for before it fails, unlimited for stages left out
+    pub stage_timeouts: HashMap<Stage, Duration>,
+    /// Most images a single task may carry, unlimited if absent
+    pub max_images: Option<usize>,
+    /// Amounts outside have the bill flagged for review
+    pub amount_bounds: AmountBounds,
+    /// Delay before retrying a failed pull, doubling with each attempt
+    pub pull_backoff: Duration,
+    /// Attempts at pulling a model before the error is given to the task
+    pub pull_attempts: u32,
+    /// Models downloaded at the same time at most, the others wait their turn
+    pub concurrent_pulls: usize,
+    /// How long Ollama keeps a model loaded after a request
+    pub keep_alive: KeepAlives,
+    /// Fraction of [Self::keep_alive] randomly added to each request, so models
+    /// loaded together don't all expire and reload at once
+    pub keep_alive_jitter: f64,
+    /// Hours in which models are kept loaded only briefly, pinned ones aside
+    pub quiet_hours: Option<QuietHours>,
+    pub pinned: PinnedModels,
+    /// Frames of animated images shown to the VLM unless the task says otherwise
+    pub animation_frames: FrameSelection,
+    /// Samples with [DETERMINISTIC_SEED] unless the task says otherwise
+    pub deterministic: bool,
+    /// Levels tasks may pick, the first one by default. Models run as configured if empty
+    pub quantizations: Vec<Quantization>,
+    pub metrics: ModelMetrics,
+    /// Where model loads and evictions are published, usually the scheduler's
+    pub events: EventBus,
+}
+
+/// Quantization levels Ollama can create models in.
+const QUANTIZATIONS: [&str; 14] = [
+    "q2_K", "q3_K_L", "q3_K_M", "q3_K_S", "q4_0", "q4_1", "q4_K_M", "q4_K_S", "q5_0", "q5_1",
+    "q5_K_M", "q5_K_S", "q6_K", "q8_0",
+];
+
+/// One of [QUANTIZATIONS], matched regardless of case.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "SmolStr")]
+pub struct Quantization(SmolStr);
+
+impl std::str::FromStr for Quantization {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        QUANTIZATIONS
+            .iter()
+            .find(|level| level.eq_ignore_ascii_case(value.trim()))
+            .map(|level| Self(SmolStr::new_static(level)))
+            .ok_or_else(|| {
+                format!(
+                    "unsupported quantization {value}, expected one of {}",
+                    QUANTIZATIONS.join(", ")
+                )
+            })
+    }
+}
+
+impl TryFrom<String> for Quantization {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Quantization> for SmolStr {
+    fn from(value: Quantization) -> Self {
+        value.0
+    }
+}
+
+impl Display for Quantization {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Quantization {
+    /// Name of `model` quantized to this level, following the `name/level:tag`
+    /// convention [OllamaRunTask::pull_models] creates models by.
+    pub fn apply(&self, model: &str) -> SmolStr {
+        let (name, tag) = match model.split_once(':') {
+            Some((name, tag)) => (name, Some(tag)),
+            None => (model, None),
+        };
+        // a level already in the name is replaced
+        let name = name.split_once('/').map_or(name, |(name, _)| name);
+        match tag {
+            Some(tag) => format!("{name}/{self}:{tag}").into(),
+            None => format!("{name}/{self}").into(),
+        }
+    }
+}
+
+/// How long Ollama keeps models loaded after a request, tunable while serving.
+/// Shared by every clone of the runner.
+#[derive(Debug, Clone, Default)]
+pub struct KeepAlives(Arc<std::sync::RwLock<KeepAliveSettings>>);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeepAliveSettings {
+    /// Keep alive of models not listed in [Self::models], Ollama's own default if absent
+    pub default: Option<Duration>,
+    pub models: HashMap<SmolStr, Duration>,
+}
+
+impl KeepAlives {
+    pub fn new(default: Option<Duration>) -> Self {
+        Self(Arc::new(std::sync::RwLock::new(KeepAliveSettings {
+            default,
+            models: HashMap::new(),
+        })))
+    }
+
+    pub fn settings(&self) -> KeepAliveSettings {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Applies to requests made from now on, a model already loaded keeps the
+    /// keep alive of its latest request until the next one.
+    pub fn set(&self, settings: KeepAliveSettings) {
+        *self.0.write().unwrap() = settings;
+    }
+
+    fn of(&self, model: &str) -> Option<Duration> {
+        let settings = self.0.read().unwrap();
+        settings.models.get(model).copied().or(settings.default)
+    }
+}
+
+/// Models kept loaded for good, whatever the keep alive. Shared by every clone of
+/// the runner, so pins made through the API apply to running tasks too.
+#[derive(Debug, Clone, Default)]
+pub struct PinnedModels(Arc<std::sync::Mutex<HashSet<SmolStr>>>);
+
+impl PinnedModels {
+    pub fn new(models: impl IntoIterator<Item = SmolStr>) -> Self {
+        Self(Arc::new(std::sync::Mutex::new(
+            models.into_iter().collect(),
+        )))
+    }
+
+    pub fn contains(&self, model: &str) -> bool {
+        self.0.lock().unwrap().contains(model)
+    }
+
+    fn set(&self, model: &SmolStr, pinned: bool) {
+        let mut models = self.0.lock().unwrap();
+        if pinned {
+            models.insert(model.clone());
+        } else {
+            models.remove(model);
+        }
+    }
+}
+
+/// How often generations found their model loaded in Ollama, per model. Shared
+/// by every clone of the runner.
+#[derive(Debug, Clone, Default)]
+pub struct ModelMetrics(Arc<std::sync::Mutex<BTreeMap<SmolStr, ModelCounters>>>);
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ModelCounters {
+    /// Generations served by the model already in memory
+    pub hits: u64,
+    /// Generations that had Ollama load the model first
+    pub misses: u64,
+    /// Misses after the model had been loaded before, so Ollama let it go in between
+    pub evictions: u64,
+    /// Time spent loading the model over all misses
+    pub load_seconds: f64,
+    /// Time the latest load took
+    pub last_load_seconds: Option<f64>,
+}
+
+impl ModelMetrics {
+    pub fn snapshot(&self) -> BTreeMap<SmolStr, ModelCounters> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Counts a generation, returning whether it found the model evicted
+    /// if it had to be loaded.
+    fn record(&self, model: &SmolStr, was_loaded: bool, load: Duration) -> Option<bool> {
+        let mut models = self.0.lock().unwrap();
+        let seen = models.contains_key(model);
+        let counters = models.entry(model.clone()).or_default();
+        if was_loaded {
+            counters.hits += 1;
+            return None;
+        }
+        counters.misses += 1;
+        if seen {
+            counters.evictions += 1;
+        }
+        counters.load_seconds += load.as_secs_f64();
+        counters.last_load_seconds = Some(load.as_secs_f64());
+        Some(seen)
+    }
+}
+
+/// Prompt templates used instead of the ones bundled with the models.
+#[derive(Debug, Clone, Default)]
+pub struct ChatTemplates {
+    /// Applies to models without a template of their own here
+    pub fallback: Option<Arc<str>>,
+    pub per_model: HashMap<SmolStr, Arc<str>>,
+}
+
+/// Shares model pull progress between concurrent tasks, making sure only one of them
+/// asks Ollama to pull at a time.
+#[derive(Debug, Clone, Default)]
+pub struct PullTracker {
+    progress: Arc<std::sync::Mutex<HashMap<SmolStr, PullProgress>>>,
+    single_flight: Arc<tokio::sync::Mutex<()>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PullProgress {
+    pub status: String,
+    pub digest: Option<String>,
+    pub completed: Option<u64>,
+    pub total: Option<u64>,
+    /// Failed attempts so far
+    pub retries: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelStatus {
+    pub id: SmolStr,
+    pub roles: Vec<&'static str>,
+    pub pinned: bool,
+    pub pull: Option<PullProgress>,
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
@@ -46,329 +315,1567 @@ pub struct OllamaTaskDescriptor {
     lm_options: Option<ModelOptions>,
     vlm_options: Option<ModelOptions>,
     categories: Option<Vec<SmolStr>>,
+    animation_frames: Option<FrameSelection>,
+    quantization: Option<Quantization>,
+    preprocess: Option<Preprocess>,
+    /// Runs the category stage, unless the form says `categorize=false`
+    categorize: Option<bool>,
+    /// Present if the form says `categorizer`, the runner's default otherwise
+    categorizer: Option<Categorizer>,
+    /// Present if the form says `intermediates=true`
+    #[serde(skip)]
+    intermediates: Option<StageTexts>,
+    /// Left empty unless the runner keeps prompts
+    #[serde(skip)]
+    prompts: StageTexts,
+    /// Present if the form says `deterministic`, the runner's default otherwise
+    deterministic: Option<bool>,
+    /// Left empty unless the task runs deterministically
+    #[serde(skip)]
+    seeds: StageSeeds,
 }
 
 pub const GEMMA_4_E4B_Q4KM: &str = "gemma4:e4b";
+pub const DEFAULT_EMBEDDING_MODEL: &str = "embeddinggemma";
+/// Role of the embedding model among the served models.
+const EMBEDDING_ROLE: &str = "embedding";
+/// Fields accepted in the multipart form of a task.
+const FORM_FIELDS: [&str; 11] = [
+    "image",
+    "lm_options",
+    "vlm_options",
+    "categories",
+    "animation_frames",
+    "quantization",
+    "preprocess",
+    "categorize",
+    "categorizer",
+    "intermediates",
+    "deterministic",
+];
+/// Seed every stage of deterministic tasks samples with.
+pub const DETERMINISTIC_SEED: i32 = 42;
+/// Bytes of a rendered prompt logged or kept unless configured otherwise.
+pub const DEFAULT_MAX_PROMPT_SIZE: usize = 16 * 1024;
+/// Stands in for the description and notes in prompts kept redacted.
+const REDACTED: &str = "[redacted]";
+const DESCRIPTION_PROMPT: &str = include_str!("../../prompt/description.md");
+/// Bytes of a task upload accepted unless configured otherwise.
+pub const DEFAULT_MAX_UPLOAD_SIZE: usize = 64 * 1024 * 1024;
+/// Bytes of an image enough to tell its format by.
+const SNIFF_LEN: usize = 16;
+pub const DEFAULT_PULL_BACKOFF: Duration = Duration::from_secs(2);
+pub const DEFAULT_PULL_ATTEMPTS: u32 = 5;
+/// Models pulled at once unless configured otherwise, one so a cold start
+/// doesn't split the bandwidth and disk between several large downloads.
+pub const DEFAULT_CONCURRENT_PULLS: usize = 1;
+const MAX_PULL_BACKOFF: Duration = Duration::from_mins(1);
 
+/// Talks to the Ollama at `OLLAMA_ENDPOINT`, or the local one if it's unset or invalid.
 impl Default for OllamaRunTask {
     fn default() -> Self {
         Self {
-            ollama: Ollama::from_env_vars(),
+            ollama: Ollama::from_env_vars().unwrap_or_default(),
             caption_model: GEMMA_4_E4B_Q4KM.into(),
             extract_model: GEMMA_4_E4B_Q4KM.into(),
+            stage_models: Default::default(),
+            categorizer: Categorizer::Lm,
+            embedding_model: DEFAULT_EMBEDDING_MODEL.into(),
+            category_embeddings: Default::default(),
             offline: false,
+            pulls: Default::default(),
+            chat_templates: Default::default(),
+            system_prompts: Default::default(),
+            prompt_retention: PromptRetention::Off,
+            max_prompt_size: DEFAULT_MAX_PROMPT_SIZE,
+            stage_timeouts: Default::default(),
+            max_images: None,
+            amount_bounds: Default::default(),
+            pull_backoff: DEFAULT_PULL_BACKOFF,
+            pull_attempts: DEFAULT_PULL_ATTEMPTS,
+            concurrent_pulls: DEFAULT_CONCURRENT_PULLS,
+            keep_alive: Default::default(),
+            keep_alive_jitter: 0.0,
+            quiet_hours: None,
+            pinned: Default::default(),
+            animation_frames: Default::default(),
+            deterministic: false,
+            quantizations: Vec::new(),
+            metrics: Default::default(),
+            events: Default::default(),
         }
     }
 }
 
-impl OllamaRunTask {
-    pub async fn pull_models(&self) -> Result<(), OllamaError> {
-        futures::future::try_join_all(
-            [self.extract_model.clone(), self.caption_model.clone()]
-                .into_iter()
-                .map(async |model| -> Result<(), OllamaError> {
-                    if !self
-                        .ollama
-                        .list_local_models()
-                        .await?
-                        .iter()
-                        .any(|m| m.name == model)
-                    {
-                        if let Some((name, quant)) = model.split_once("/") {
-                            let (name, quant) = if let Some((quant, size)) = quant.split_once(":") {
-                                (format!("{name}:{size}"), quant)
-                            } else {
-                                (name.to_string(), quant)
-                            };
-                            self.ollama.pull_model(name.clone(), false).await?;
-                            self.ollama
-                                .create_model(
-                                    CreateModelRequest::new(model.clone().into())
-                                        .from_model(name)
-                                        .quantize(serde_plain::from_str(quant).unwrap()),
-                                )
-                                .await?;
-                        } else {
-                            self.ollama.pull_model(model.into(), false).await?;
-                        }
-                    }
-                    Ok(())
-                }),
-        )
-        .await?;
-        Ok(())
+impl PullTracker {
+    pub fn get(&self, model: &str) -> Option<PullProgress> {
+        self.progress.lock().unwrap().get(model).cloned()
     }
 
-    pub async fn unload_models(&self) -> Result<(), OllamaError> {
-        self.ollama
-            .generate(
-                GenerationRequest::new(self.caption_model.clone().into(), "").keep_alive(
-                    KeepAlive::Until {
-                        time: 0,
-                        unit: TimeUnit::Seconds,
-                    },
-                ),
-            )
-            .await?;
-        self.ollama
-            .generate(
-                GenerationRequest::new(self.extract_model.clone().into(), "").keep_alive(
-                    KeepAlive::Until {
-                        time: 0,
-                        unit: TimeUnit::Seconds,
-                    },
-                ),
-            )
-            .await?;
-        Ok(())
+    fn update(&self, model: &SmolStr, status: PullModelStatus) {
+        let mut progress = self.progress.lock().unwrap();
+        let entry = progress.entry(model.clone()).or_default();
+        entry.status = status.message;
+        if status.digest.is_some() {
+            entry.digest = status.digest;
+        }
+        if status.total.is_some() {
+            entry.total = status.total;
+        }
+        if status.completed.is_some() {
+            entry.completed = status.completed;
+        }
+    }
+
+    fn retry(&self, model: &SmolStr, err: &OllamaError) {
+        let mut progress = self.progress.lock().unwrap();
+        let entry = progress.entry(model.clone()).or_default();
+        entry.status = format!("retrying: {err}");
+        entry.retries += 1;
+    }
+
+    fn fail(&self, model: &SmolStr, err: &OllamaError) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.entry(model.clone()).or_default().status = format!("failed: {err}");
     }
 }
 
-impl RunTask for OllamaRunTask {
-    type TaskDescriptor = OllamaTaskDescriptor;
+impl ChatTemplates {
+    pub fn from_args(templates: &[ChatTemplate]) -> Self {
+        let mut result = Self::default();
+        for ChatTemplate { model, template } in templates {
+            match model {
+                Some(model) => {
+                    result
+                        .per_model
+                        .insert(model.into(), template.as_str().into());
+                }
+                None => result.fallback = Some(template.as_str().into()),
+            }
+        }
+        result
+    }
 
-    async fn extract(&self, task: &Self::TaskDescriptor) -> Result<Bill, RunTaskError> {
-        if !self.offline {
-            event!(Level::INFO, "pulling models");
-            self.pull_models().await?;
+    pub fn get(&self, model: &str) -> Option<&Arc<str>> {
+        self.per_model.get(model).or(self.fallback.as_ref())
+    }
+
+    /// Makes sure an Ollama template actually renders the prompt it is given.
+    pub fn validate(template: &str) -> Result<(), String> {
+        if template.contains(".Prompt") || template.contains(".Messages") {
+            Ok(())
+        } else {
+            Err("template references neither .Prompt nor .Messages".into())
         }
+    }
+}
 
-        let prompt = include_str!("../../prompt/description.md");
-        let ims = task
-            .images()
-            .iter()
-            .map(|buf| BASE64_STANDARD.encode(buf))
-            .map(Image::from_base64)
-            .collect::<Vec<_>>();
-        let caption = self
-            .ollama
-            .generate({
-                let r = GenerationRequest::new(self.caption_model.clone().into(), prompt)
-                    .images(ims.clone())
-                    .think(true);
-                if let Some(lm_options) = task.lm_options() {
-                    r.options(lm_options.clone())
+impl OllamaRunTask {
+    pub async fn pull_models(&self) -> Result<(), OllamaError> {
+        let _single_flight = self.pulls.single_flight.lock().await;
+        futures::stream::iter(self.missing_models().await?)
+            .map(async |model| -> Result<(), OllamaError> {
+                if let Some((name, quant)) = model.split_once("/") {
+                    let (name, quant) = if let Some((quant, size)) = quant.split_once(":") {
+                        (format!("{name}:{size}"), quant)
+                    } else {
+                        (name.to_string(), quant)
+                    };
+                    self.pull_model(&model, name.clone()).await?;
+                    self.ollama
+                        .create_model(
+                            CreateModelRequest::new(model.clone().into())
+                                .from_model(name)
+                                .quantize(serde_plain::from_str(quant).unwrap()),
+                        )
+                        .await?;
                 } else {
-                    r
+                    self.pull_model(&model, model.to_string()).await?;
                 }
+                Ok(())
             })
+            .buffer_unordered(self.concurrent_pulls.max(1))
+            .try_collect()
             .await
-            .map_err(|err| RunTaskError::Runner(err.into()))?;
-        assert!(caption.done);
-        event!(Level::DEBUG, "caption: {}", caption.response);
-        let prompt = format!(
-            include_str!("../../prompt/note_taking.md"),
-            caption.response
-        );
-        let notes = self
-            .ollama
-            .generate({
-                let r = GenerationRequest::new(self.caption_model.clone().into(), prompt)
-                    .images(ims)
-                    .think(true)
-                    .format(FormatType::StructuredJson(Box::new(JsonStructure::new::<
-                        Notes,
-                    >(
-                    ))));
-                if let Some(options) = task.vlm_options() {
-                    r.options(options.clone())
-                } else {
-                    r
+    }
+
+    /// Configured models that are not present in Ollama's local store.
+    pub async fn missing_models(&self) -> Result<Vec<SmolStr>, OllamaError> {
+        let local_models = self.ollama.list_local_models().await?;
+        let mut missing = Vec::new();
+        for (model, _) in self.served_models() {
+            if !local_models.iter().any(|m| m.name == *model) && !missing.contains(&model) {
+                missing.push(model);
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Makes sure offline mode can serve tasks without pulling anything.
+    pub async fn check_local_models(&self) -> Result<(), StartupError> {
+        let missing = self.missing_models().await?;
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(StartupError::MissingModels(missing))
+        }
+    }
+
+    /// Pulls `name` for `model`, retrying transient failures with backoff. Ollama
+    /// keeps partially downloaded layers, so retries pick up where they stopped.
+    async fn pull_model(&self, model: &SmolStr, name: String) -> Result<(), OllamaError> {
+        let mut attempt = 1;
+        loop {
+            event!(
+                Level::INFO,
+                "pulling {name}, attempt {attempt} of {}",
+                self.pull_attempts
+            );
+            let pull = async {
+                let mut stream = self.ollama.pull_model_stream(name.clone(), false).await?;
+                while let Some(status) = stream.next().await {
+                    self.pulls.update(model, status?);
                 }
-            })
-            .await
-            .map_err(|err| RunTaskError::Runner(err.into()))?;
-        assert!(notes.done);
-        event!(Level::DEBUG, "notes: {}", notes.response);
-        #[derive(JsonSchema, Deserialize)]
-        struct Amount {
-            amount: f32,
+                Ok(())
+            };
+            match pull.await {
+                Err(err) if attempt < self.pull_attempts && is_transient(&err) => {
+                    let delay = pull_backoff(self.pull_backoff, attempt);
+                    event!(
+                        Level::WARN,
+                        "pulling {name} failed ({err}), retry {attempt} of {} in {delay:?}",
+                        self.pull_attempts - 1
+                    );
+                    self.pulls.retry(model, &err);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result.inspect_err(|err| self.pulls.fail(model, err)),
+            }
         }
-        #[derive(JsonSchema, Deserialize)]
-        struct Category {
-            category: Option<String>,
+    }
+
+    /// Makes sure every configured model can be prompted, which requires either
+    /// a template bundled with the model or a configured fallback.
+    pub async fn check_chat_templates(&self) -> Result<(), RunTaskError> {
+        for (model, role) in self.served_models() {
+            // embedding models aren't prompted
+            if role == EMBEDDING_ROLE || self.chat_templates.get(&model).is_some() {
+                continue;
+            }
+            let info = self.ollama.show_model_info(model.to_string()).await?;
+            if info.template.trim().is_empty() {
+                return Err(RunTaskError::MissingChatTemplate(model));
+            }
         }
-        let notes = if let Ok(structured_notes) =
-            serde_json::from_str::<Notes>(notes.response.as_str())
-        {
-            structured_notes.to_string()
+        Ok(())
+    }
+
+    /// Model each stage runs on, quantized to `quantization` if given.
+    fn models(&self, quantization: Option<&Quantization>) -> BTreeMap<Stage, SmolStr> {
+        use strum::IntoEnumIterator;
+
+        Stage::iter()
+            .map(|stage| {
+                let model = self.stage_models.get(&stage).unwrap_or(match stage {
+                    Stage::Description | Stage::Notes => &self.caption_model,
+                    Stage::Amount | Stage::Category => &self.extract_model,
+                });
+                let model = quantization.map_or_else(|| model.clone(), |level| level.apply(model));
+                (stage, model)
+            })
+            .collect()
+    }
+
+    /// What a model running `stage` is listed as: caption or extract, or the
+    /// stage itself if it was given a model of its own.
+    fn role(&self, stage: Stage) -> &'static str {
+        match stage {
+            _ if self.stage_models.contains_key(&stage) => stage.into(),
+            Stage::Description | Stage::Notes => "caption",
+            Stage::Amount | Stage::Category => "extract",
+        }
+    }
+
+    /// Every model a task may run on with its role, in each offered quantization,
+    /// and the embedding model if tasks are categorized by embeddings by default.
+    fn served_models(&self) -> Vec<(SmolStr, &'static str)> {
+        let levels = if self.quantizations.is_empty() {
+            vec![None]
         } else {
-            event!(target: "ollama_run_task",Level::WARN,  "invalid notes JSON: {}", notes.response);
-            notes.response
-        };
-        let category_schema = json_schema!({
-            "description": "Category of the goods",
-            "type": "object",
-            "properties": {
-                "category": {
-                    "enum": task.category_names()
-                }
-            },
-        });
-        let (amount, category) = futures::try_join!(
-            self.ollama.generate({
-                let r = GenerationRequest::new(
-                    self.extract_model.clone().into(),
-                    format!(
-                        include_str!("../../prompt/amount_extraction.md"),
-                        notes, caption.response
-                    ),
-                )
-                .think(true)
-                .format(FormatType::StructuredJson(Box::new(JsonStructure::new::<
-                    Amount,
-                >())));
-                if let Some(options) = task.lm_options() {
-                    r.options(options.clone())
-                } else {
-                    r
+            self.quantizations.iter().map(Some).collect()
+        };
+        let mut served = Vec::new();
+        for level in levels {
+            for (stage, model) in self.models(level) {
+                let model = (model, self.role(stage));
+                if !served.contains(&model) {
+                    served.push(model);
                 }
-            },),
-            self.ollama.generate({
-                let r = GenerationRequest::new(
-                    self.extract_model.clone().into(),
-                    format!(
-                        include_str!("../../prompt/categorization.md"),
-                        notes,
-                        caption.response,
-                        task.category_names()
+            }
+        }
+        if self.categorizer == Categorizer::Embedding {
+            served.push((self.embedding_model.clone(), EMBEDDING_ROLE));
+        }
+        served
+    }
+
+    /// The images of `task` as the VLM sees them.
+    pub async fn prepare_images(
+        &self,
+        task: &OllamaTaskDescriptor,
+    ) -> Result<Vec<Vec<u8>>, image::ImageError> {
+        imaging::normalize_all(
+            task.images().into_iter().map(<[u8]>::to_vec).collect(),
+            task.animation_frames.unwrap_or(self.animation_frames),
+            task.preprocess.unwrap_or_default(),
+        )
+        .await
+    }
+
+    /// Rejects tasks asking for a quantization that isn't offered.
+    pub fn check_quantization(&self, task: &OllamaTaskDescriptor) -> Result<(), CreateTaskError> {
+        match &task.quantization {
+            Some(level) if !self.quantizations.contains(level) => {
+                Err(CreateTaskError::UnsupportedQuantization {
+                    requested: level.to_string(),
+                    supported: Names(
+                        self.quantizations
                             .iter()
-                            .map(|c| format!("- {}", c))
-                            .collect::<Vec<_>>()
-                            .join("\n")
+                            .map(Quantization::to_string)
+                            .collect(),
                     ),
-                )
-                .think(true)
-                .format(FormatType::StructuredJson(Box::new(
-                    JsonStructure::new_for_schema(category_schema),
-                )));
-                if let Some(options) = task.lm_options() {
-                    r.options(options.clone())
-                } else {
-                    r
-                }
-            })
-        )
-        .map_err(|err| RunTaskError::Runner(err.into()))?;
-        event!(Level::DEBUG, "amount: {}", amount.response);
-        event!(Level::DEBUG, "category: {}", category.response);
-        let structured_amount = serde_json::from_str::<Amount>(amount.response.as_str())
-            .map_err(|_| RunTaskError::InvalidOutput("price".into()))?;
-        let structured_category = serde_json::from_str::<Category>(category.response.as_str())
-            .map_err(|_| RunTaskError::InvalidOutput("category".into()))?;
+                })
+            }
+            _ => Ok(()),
+        }
+    }
 
-        Ok(Bill {
-            notes: notes.into(),
-            amount: structured_amount.amount,
-            category: structured_category.category.map(|n| n.into()),
-        })
+    fn request<'a>(
+        &self,
+        stage: Stage,
+        model: &SmolStr,
+        prompt: impl Into<Cow<'a, str>>,
+    ) -> GenerationRequest<'a> {
+        let mut request = self.model_request(model, prompt);
+        if let Some(system) = self.system_prompts.get(&stage) {
+            request = request.system(system.to_string());
+        }
+        request
     }
-}
 
-impl TaskDescriptor for OllamaTaskDescriptor {
-    fn images(&self) -> Vec<&[u8]> {
-        self.images_buf
-            .iter()
-            .map(|buf| buf.as_slice())
-            .collect::<Vec<_>>()
+    /// Logs the rendered `prompt` of `stage` and keeps it on `task` as
+    /// [Self::prompt_retention] says, or `redacted` in its place, either cut
+    /// to [Self::max_prompt_size].
+    fn keep_prompt(&self, task: &impl TaskDescriptor, stage: Stage, prompt: &str, redacted: &str) {
+        event!(
+            Level::DEBUG,
+            "{stage} prompt: {}",
+            truncate(prompt, self.max_prompt_size)
+        );
+        let kept = match self.prompt_retention {
+            PromptRetention::Off => return,
+            PromptRetention::Redacted => redacted,
+            PromptRetention::Full => prompt,
+        };
+        if let Some(prompts) = task.prompts() {
+            prompts.record(stage, truncate(kept, self.max_prompt_size));
+        }
     }
 
-    fn category_names(&self) -> Vec<SmolStr> {
-        self.categories.clone().unwrap_or_else(|| {
-            Category::all_cases()
-                .iter()
-                .map(|c| c.name().into())
-                .collect::<Vec<_>>()
-        })
+    /// A request to `model` with its chat template and keep alive, outside of
+    /// any stage.
+    fn model_request<'a>(
+        &self,
+        model: &SmolStr,
+        prompt: impl Into<Cow<'a, str>>,
+    ) -> GenerationRequest<'a> {
+        let mut request = GenerationRequest::new(model.to_string(), prompt);
+        if let Some(template) = self.chat_templates.get(model) {
+            request = request.template(template.to_string());
+        }
+        if let Some(keep_alive) = self.keep_alive_of(model) {
+            request = request.keep_alive(keep_alive);
+        }
+        request
     }
-}
 
-impl OllamaTaskDescriptor {
-    pub fn lm_options(&self) -> Option<&ModelOptions> {
-        self.lm_options.as_ref()
+    /// Answers `question` about a finished bill on the extract model, from the
+    /// notes the task kept of its receipt and its `description`, if the task
+    /// kept that too. The bill stays as it is.
+    pub async fn ask(
+        &self,
+        bill: &Bill,
+        description: Option<&str>,
+        question: &str,
+    ) -> Result<String, OllamaError> {
+        let facts = serde_json::json!({
+            "amount": bill.amount,
+            "currency": bill.currency,
+            "categories": bill.categories,
+        });
+        let request = self.model_request(
+            &self.extract_model,
+            format!(
+                include_str!("../../prompt/ask.md"),
+                description.unwrap_or("not kept"),
+                bill.notes,
+                facts,
+                question
+            ),
+        );
+        let was_loaded = self.is_loaded(&self.extract_model).await;
+        let response = self.ollama.generate(request).await?;
+        self.record_load(&self.extract_model, was_loaded, &response);
+        Ok(strip_reasoning(&response.response))
     }
 
-    pub fn vlm_options(&self) -> Option<&ModelOptions> {
-        self.vlm_options.as_ref()
+    /// Whether models are kept loaded as configured or only briefly right now.
+    pub fn residency(&self) -> Residency {
+        self.quiet_hours
+            .map_or(Residency::Normal, |quiet| quiet.residency_at(Utc::now()))
     }
-}
 
-impl<S> FromRequest<S> for OllamaTaskDescriptor
-where
-    S: Send + Sync,
-{
-    type Rejection = CreateTaskError;
+    /// Forever for pinned models, the quiet hours' keep alive for the others
+    /// during them, otherwise their jittered timeout.
+    fn keep_alive_of(&self, model: &str) -> Option<KeepAlive> {
+        if self.pinned.contains(model) {
+            return Some(KeepAlive::Indefinitely);
+        }
+        if let Some(quiet) = self.quiet_hours
+            && quiet.residency_at(Utc::now()) == Residency::Quiet
+        {
+            return Some(KeepAlive::Until {
+                time: quiet.keep_alive.as_secs(),
+                unit: TimeUnit::Seconds,
+            });
+        }
+        self.keep_alive
+            .of(model)
+            .map(|keep_alive| KeepAlive::Until {
+                time: keep_alive_jitter(keep_alive, self.keep_alive_jitter).as_secs(),
+                unit: TimeUnit::Seconds,
+            })
+    }
 
-    async fn from_request(req: axum::extract::Request, _: &S) -> Result<Self, Self::Rejection> {
-        fn get_images_buf(source: Bytes, mime: &str) -> Result<Vec<Vec<u8>>, CreateTaskError> {
-            if mime.starts_with("image/") {
-                return Ok(vec![source.to_vec()]);
-            } else if !mime.starts_with("application/") {
-                return Err(CreateTaskError::UnspecificContentType(mime.into()));
-            }
-            let mut bufs = Vec::new();
-            let type_name = mime.split_once('/').unwrap().1;
-            match type_name {
-                "zip" | "zip-compressed" => {
-                    let mut archive = ZipArchive::new(Cursor::new(source))?;
-                    for i in 0..archive.len() {
-                        let item = archive.by_index(i)?;
-                        if item.is_file() {
-                            bufs.push(item.bytes().collect::<Result<Vec<_>, _>>()?);
-                        } else {
-                            return Err(ZipError::InvalidArchive(Cow::Owned(
-                                "accept files only, got dir / symlink".into(),
-                            ))
-                            .into());
-                        }
-                    }
-                }
-                _ => return Err(CreateTaskError::UnsupportedFileType(mime.into())),
+    /// Sends an empty prompt, which has Ollama load `model` and restart its keep alive.
+    async fn load(&self, model: &SmolStr) -> Result<(), OllamaError> {
+        // embedding models can't generate, so they are loaded by embedding nothing
+        if self.categorizer == Categorizer::Embedding && *model == self.embedding_model {
+            let mut request = GenerateEmbeddingsRequest::new(model.to_string(), "".into());
+            if let Some(keep_alive) = self.keep_alive_of(model) {
+                request = request.keep_alive(keep_alive);
             }
-            Ok(bufs)
+            self.ollama.generate_embeddings(request).await?;
+            return Ok(());
+        }
+        let mut request = GenerationRequest::new(model.to_string(), "");
+        if let Some(keep_alive) = self.keep_alive_of(model) {
+            request = request.keep_alive(keep_alive);
         }
+        let was_loaded = self.is_loaded(model).await;
+        let response = self.ollama.generate(request).await?;
+        self.record_load(model, was_loaded, &response);
+        Ok(())
+    }
 
-        let content_type = UTF_8
-            .decode(req.headers().get("Content-Type").unwrap().as_bytes())
-            .0;
-        event!(Level::DEBUG, "receiving {}", content_type);
+    /// Has Ollama let go of `model`, embedding nothing for the embedding model
+    /// like [Self::load] does.
+    async fn unload(&self, model: &SmolStr) -> Result<(), OllamaError> {
+        let keep_alive = KeepAlive::Until {
+            time: 0,
+            unit: TimeUnit::Seconds,
+        };
+        if self.categorizer == Categorizer::Embedding && *model == self.embedding_model {
+            let request =
+                GenerateEmbeddingsRequest::new(model.to_string(), "".into()).keep_alive(keep_alive);
+            self.ollama.generate_embeddings(request).await?;
+            return Ok(());
+        }
+        let request = GenerationRequest::new(model.to_string(), "").keep_alive(keep_alive);
+        self.ollama.generate(request).await?;
+        Ok(())
+    }
 
-        let mut images_buf = None;
-        let (mut lm_options, mut vlm_options, mut categories) = (None, None, None);
+    /// Served models in every quantization Ollama has in memory, but the
+    /// pinned ones. Unloading the others would load them first.
+    async fn unloadable_models(&self) -> Vec<SmolStr> {
+        let mut unloadable = Vec::new();
+        for (model, _) in self.served_models() {
+            if !unloadable.contains(&model)
+                && !self.pinned.contains(&model)
+                && self.is_loaded(&model).await == Some(true)
+            {
+                unloadable.push(model);
+            }
+        }
+        unloadable
+    }
+
+    /// Whether Ollama has `model` in memory, or None if it couldn't tell.
+    async fn is_loaded(&self, model: &str) -> Option<bool> {
+        #[derive(Deserialize)]
+        struct Running {
+            models: Vec<RunningModel>,
+        }
+        #[derive(Deserialize)]
+        struct RunningModel {
+            name: String,
+        }
+        static HTTP: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+        let url = self.ollama.url().join("api/ps").ok()?;
+        let response = HTTP.get(url).send().await.ok()?.error_for_status().ok()?;
+        let running: Running = serde_json::from_slice(&response.bytes().await.ok()?).ok()?;
+        let untagged = format!("{model}:latest");
+        Some(
+            running
+                .models
+                .iter()
+                .any(|running| running.name == model || running.name == untagged),
+        )
+    }
+
+    fn record_load(
+        &self,
+        model: &SmolStr,
+        was_loaded: Option<bool>,
+        response: &GenerationResponse,
+    ) {
+        let Some(was_loaded) = was_loaded else {
+            return;
+        };
+        let load = Duration::from_nanos(response.load_duration.unwrap_or_default());
+        if let Some(evicted) = self.metrics.record(model, was_loaded, load) {
+            if evicted {
+                self.events.publish(SchedulerEvent::ModelEvicted {
+                    model: model.clone(),
+                });
+            }
+            self.events.publish(SchedulerEvent::ModelLoaded {
+                model: model.clone(),
+                seconds: load.as_secs_f64(),
+            });
+        }
+    }
+
+    /// Pins or unpins a configured model. Pinned models are loaded right away,
+    /// unpinned ones expire after the usual timeout from now on.
+    pub async fn pin(&self, model: &str, pinned: bool) -> Result<ModelStatus, PinModelError> {
+        let status = self
+            .model_status()
+            .into_iter()
+            .find(|status| status.id == model)
+            .ok_or_else(|| PinModelError::NotConfigured(model.to_string()))?;
+        self.pinned.set(&status.id, pinned);
+        if pinned && !self.offline {
+            self.pull_models().await?;
+        }
+        self.load(&status.id).await?;
+        event!(
+            Level::INFO,
+            "{} {}",
+            if pinned { "pinned" } else { "unpinned" },
+            status.id
+        );
+        Ok(ModelStatus { pinned, ..status })
+    }
+
+    /// Loads the models pinned from the command line, pulling them first unless offline.
+    pub async fn preload_pinned(&self) -> Result<(), OllamaError> {
+        let configured = self.model_status();
+        for model in self.pinned.0.lock().unwrap().iter() {
+            if !configured.iter().any(|status| &status.id == model) {
+                event!(
+                    Level::WARN,
+                    "{model} is pinned but not configured, ignoring"
+                );
+            }
+        }
+        let pinned = configured
+            .into_iter()
+            .filter(|status| status.pinned)
+            .collect::<Vec<_>>();
+        if pinned.is_empty() {
+            return Ok(());
+        }
+        if !self.offline {
+            self.pull_models().await?;
+        }
+        for status in pinned {
+            self.load(&status.id).await?;
+            event!(Level::INFO, "preloaded pinned model {}", status.id);
+        }
+        Ok(())
+    }
+
+    /// Runs a generation, streaming it to `tokens` while anyone is subscribed.
+    /// Failures name the stage and model they happened in. Generations that
+    /// end before they're done, or with nothing but reasoning, fail as well.
+    async fn generate(
+        &self,
+        stage: Stage,
+        tokens: &TokenSender,
+        request: GenerationRequest<'_>,
+    ) -> Result<GenerationResponse, RunTaskError> {
+        let model = SmolStr::from(&request.model_name);
+        let was_loaded = self.is_loaded(&model).await;
+        let generation = self.stream_generation(stage, tokens, request);
+        let generation = match self.stage_timeouts.get(&stage) {
+            // dropping the request closes its connection, which has Ollama stop generating
+            Some(&timeout) => tokio::time::timeout(timeout, generation)
+                .await
+                .map_err(|_| RunTaskError::StageTimeout {
+                    stage,
+                    model: model.clone(),
+                    timeout,
+                })?,
+            None => generation.await,
+        };
+        let mut response = generation.map_err(|source| RunTaskError::Stage {
+            stage,
+            model: model.clone(),
+            source,
+        })?;
+        self.record_load(&model, was_loaded, &response);
+        if !response.done {
+            return Err(RunTaskError::Stage {
+                stage,
+                model,
+                source: OllamaError::Other("generation ended before it was done".into()),
+            });
+        }
+        response.response = strip_reasoning(&response.response);
+        if response.response.is_empty() {
+            event!(target: "ollama_run_task", Level::WARN, "{model} gave an empty {stage} response");
+            return Err(RunTaskError::EmptyModelResponse(stage));
+        }
+        Ok(response)
+    }
+
+    async fn stream_generation(
+        &self,
+        stage: Stage,
+        tokens: &TokenSender,
+        request: GenerationRequest<'_>,
+    ) -> Result<GenerationResponse, OllamaError> {
+        let _ = tokens.send(Token {
+            stage,
+            kind: TokenKind::Started,
+            text: String::new(),
+        });
+        if tokens.receiver_count() == 0 {
+            return self.ollama.generate(request).await;
+        }
+        let publish = |kind, text: &str| {
+            // nobody listening is fine, the stream is best effort
+            let _ = tokens.send(Token {
+                stage,
+                kind,
+                text: text.to_string(),
+            });
+        };
+        let mut stream = self.ollama.generate_stream(request).await?;
+        let (mut response, mut thinking) = (String::new(), String::new());
+        let mut last = None;
+        while let Some(chunk) = stream.next().await {
+            for part in chunk? {
+                if let Some(text) = &part.thinking {
+                    publish(TokenKind::Thinking, text);
+                    thinking.push_str(text);
+                }
+                publish(TokenKind::Response, &part.response);
+                response.push_str(&part.response);
+                last = Some(part);
+            }
+        }
+        publish(TokenKind::Done, "");
+        let mut last = last.ok_or_else(|| OllamaError::Other("empty generation stream".into()))?;
+        last.response = response;
+        last.thinking = (!thinking.is_empty()).then_some(thinking);
+        Ok(last)
+    }
+
+    pub fn model_status(&self) -> Vec<ModelStatus> {
+        let mut models: Vec<ModelStatus> = Vec::new();
+        for (id, role) in self.served_models() {
+            if let Some(status) = models.iter_mut().find(|m| m.id == id) {
+                status.roles.push(role);
+            } else {
+                models.push(ModelStatus {
+                    pinned: self.pinned.contains(&id),
+                    pull: self.pulls.get(&id),
+                    id,
+                    roles: vec![role],
+                });
+            }
+        }
+        models
+    }
+
+    /// Unloads the models Ollama has loaded, like [RunTask::release_memory]
+    /// but stopping at the first failure.
+    pub async fn unload_models(&self) -> Result<(), OllamaError> {
+        for model in self.unloadable_models().await {
+            self.unload(&model).await?;
+        }
+        Ok(())
+    }
+}
+
+impl OllamaRunTask {
+    /// Checks `task` against the limits, pulls the models and prepares the
+    /// images, leaving the model each stage of the task runs on.
+    async fn prepare_run(
+        &self,
+        task: &OllamaTaskDescriptor,
+    ) -> Result<(BTreeMap<Stage, SmolStr>, Vec<Image>), RunTaskError> {
+        if let Some(limit) = self.max_images
+            && task.images_buf.len() > limit
+        {
+            return Err(RunTaskError::TooManyImages {
+                count: task.images_buf.len(),
+                limit,
+            });
+        }
+        // would have Ollama pull and create a model nobody configured
+        if let Some(level) = &task.quantization
+            && !self.quantizations.contains(level)
+        {
+            return Err(RunTaskError::QuantizationNotOffered(level.to_smolstr()));
+        }
+        // before pulling, so a task failing on its images doesn't wait for downloads
+        let prepared = self.prepare_images(task).await?;
+        // frames picked out of animated images count as images of their own
+        if let Some(limit) = self.max_images
+            && prepared.len() > limit
+        {
+            return Err(RunTaskError::TooManyImages {
+                count: prepared.len(),
+                limit,
+            });
+        }
+        if !self.offline {
+            event!(Level::INFO, "pulling models");
+            self.pull_models().await?;
+        }
+        self.check_chat_templates().await?;
+
+        let models = self.models(task.quantization.as_ref().or(self.quantizations.first()));
+        let ims = prepared
+            .into_iter()
+            .map(|buf| Image::from_base64(BASE64_STANDARD.encode(buf)))
+            .collect::<Vec<_>>();
+        Ok((models, ims))
+    }
+
+    fn description_request(
+        &self,
+        task: &OllamaTaskDescriptor,
+        caption_model: &SmolStr,
+        ims: Vec<Image>,
+        options: Option<&ModelOptions>,
+    ) -> GenerationRequest<'static> {
+        let r = self
+            .request(Stage::Description, caption_model, DESCRIPTION_PROMPT)
+            .images(ims)
+            .think(true);
+        self.sample(task, Stage::Description, r, options)
+    }
+
+    /// Has `request` sample with `options`, its seed fixed to
+    /// [DETERMINISTIC_SEED] and recorded if the task runs deterministically.
+    fn sample<'a>(
+        &self,
+        task: &OllamaTaskDescriptor,
+        stage: Stage,
+        request: GenerationRequest<'a>,
+        options: Option<&ModelOptions>,
+    ) -> GenerationRequest<'a> {
+        if !task.deterministic.unwrap_or(self.deterministic) {
+            return match options {
+                Some(options) => request.options(options.clone()),
+                None => request,
+            };
+        }
+        task.seeds.record(stage, DETERMINISTIC_SEED);
+        request.options(
+            options
+                .cloned()
+                .unwrap_or_default()
+                .seed(DETERMINISTIC_SEED),
+        )
+    }
+
+    /// The category of `names` nearest to `description` by embedding similarity,
+    /// and that similarity.
+    async fn nearest_category(
+        &self,
+        description: &str,
+        names: &[SmolStr],
+        hints: &BTreeMap<String, String>,
+    ) -> Result<(SmolStr, f32), OllamaError> {
+        self.pull_embedding_model().await?;
+        let texts = names
+            .iter()
+            .map(|name| category_text(name, hints))
+            .collect::<Vec<_>>();
+        let (description, categories) = self.embed_categories(&texts, Some(description)).await?;
+        let description = description.expect("the description is embedded");
+        let (index, similarity) = embedding::nearest(
+            &description,
+            categories.iter().map(|embedding| &embedding[..]),
+        )
+        .ok_or_else(|| {
+            OllamaError::Other("embeddings of unequal dimensions or all zeros".into())
+        })?;
+        Ok((names[index].clone(), similarity))
+    }
+
+    /// Pulls the embedding model for tasks asking for embeddings on a server
+    /// that doesn't categorize with them by default, unless offline. Servers
+    /// that do pull it along with the other models.
+    async fn pull_embedding_model(&self) -> Result<(), OllamaError> {
+        if self.offline || self.categorizer == Categorizer::Embedding {
+            return Ok(());
+        }
+        let _single_flight = self.pulls.single_flight.lock().await;
+        let local_models = self.ollama.list_local_models().await?;
+        if !local_models.iter().any(|m| m.name == *self.embedding_model) {
+            self.pull_model(&self.embedding_model, self.embedding_model.to_string())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Embeddings of the category `texts` in order, embedding those that aren't
+    /// yet and keeping them for later tasks, and of `description` if given, in
+    /// the same request.
+    async fn embed_categories(
+        &self,
+        texts: &[String],
+        description: Option<&str>,
+    ) -> Result<(Option<Vec<f32>>, Vec<Arc<[f32]>>), OllamaError> {
+        let mut cached = texts
+            .iter()
+            .map(|text| self.category_embeddings.get(&self.embedding_model, text))
+            .collect::<Vec<_>>();
+        let missing = texts
+            .iter()
+            .zip(&cached)
+            .filter(|(_, embedding)| embedding.is_none())
+            .map(|(text, _)| text.clone())
+            .collect::<Vec<_>>();
+        let input = description
+            .map(str::to_string)
+            .into_iter()
+            .chain(missing.iter().cloned())
+            .collect::<Vec<_>>();
+        if input.is_empty() {
+            return Ok((None, cached.into_iter().flatten().collect()));
+        }
+        let count = input.len();
+        let mut request = GenerateEmbeddingsRequest::new(
+            self.embedding_model.to_string(),
+            EmbeddingsInput::Multiple(input),
+        );
+        if let Some(keep_alive) = self.keep_alive_of(&self.embedding_model) {
+            request = request.keep_alive(keep_alive);
+        }
+        let mut embeddings = self.ollama.generate_embeddings(request).await?.embeddings;
+        if embeddings.len() != count || embeddings.iter().any(Vec::is_empty) {
+            return Err(OllamaError::Other(format!(
+                "expected {count} embeddings from {}, got {}",
+                self.embedding_model,
+                embeddings.len()
+            )));
+        }
+        let description = description.map(|_| embeddings.remove(0));
+        let mut embeddings = missing
+            .into_iter()
+            .zip(embeddings)
+            .map(|(text, embedding)| {
+                let embedding = Arc::<[f32]>::from(embedding);
+                self.category_embeddings
+                    .insert(&self.embedding_model, text, embedding.clone());
+                embedding
+            });
+        for slot in cached.iter_mut().filter(|slot| slot.is_none()) {
+            *slot = embeddings.next();
+        }
+        Ok((description, cached.into_iter().flatten().collect()))
+    }
+
+    /// Embeds the default categories ahead of the first task categorized by
+    /// embeddings, pulling the models first unless offline.
+    pub async fn precompute_category_embeddings(&self) -> Result<(), OllamaError> {
+        if self.categorizer != Categorizer::Embedding {
+            return Ok(());
+        }
+        if !self.offline {
+            self.pull_models().await?;
+        }
+        let hints = Category::hints();
+        let texts = Category::all_cases()
+            .into_iter()
+            .filter_map(|category| category.name())
+            .map(|name| category_text(&name, &hints))
+            .collect::<Vec<_>>();
+        self.embed_categories(&texts, None).await?;
+        event!(
+            Level::INFO,
+            "embedded {} categories with {}",
+            texts.len(),
+            self.embedding_model
+        );
+        Ok(())
+    }
+
+    /// Runs the description stage alone, the caption [RunTask::extract] takes
+    /// its notes from, sampling with the task's `vlm_options`.
+    pub async fn describe(
+        &self,
+        task: &OllamaTaskDescriptor,
+        tokens: &TokenSender,
+    ) -> Result<String, RunTaskError> {
+        let (models, ims) = self.prepare_run(task).await?;
+        let caption = self
+            .generate(
+                Stage::Description,
+                tokens,
+                self.description_request(
+                    task,
+                    &models[&Stage::Description],
+                    ims,
+                    task.vlm_options(),
+                ),
+            )
+            .await?;
+        Ok(caption.response)
+    }
+}
+
+impl RunTask for OllamaRunTask {
+    type TaskDescriptor = OllamaTaskDescriptor;
+
+    async fn extract(
+        &self,
+        task: &Self::TaskDescriptor,
+        tokens: &TokenSender,
+    ) -> Result<Bill, RunTaskError> {
+        let (models, ims) = self.prepare_run(task).await?;
+        self.keep_prompt(
+            task,
+            Stage::Description,
+            DESCRIPTION_PROMPT,
+            DESCRIPTION_PROMPT,
+        );
+        let caption = self
+            .generate(
+                Stage::Description,
+                tokens,
+                self.description_request(
+                    task,
+                    &models[&Stage::Description],
+                    ims.clone(),
+                    task.lm_options(),
+                ),
+            )
+            .await?;
+        event!(Level::DEBUG, "caption: {}", caption.response);
+        task.record(Stage::Description, &caption);
+        let prompt = note_prompt(&caption.response);
+        self.keep_prompt(task, Stage::Notes, &prompt, &note_prompt(REDACTED));
+        let notes = self
+            .generate(Stage::Notes, tokens, {
+                let r = self
+                    .request(Stage::Notes, &models[&Stage::Notes], prompt)
+                    .images(ims)
+                    .think(true)
+                    .format(FormatType::StructuredJson(Box::new(JsonStructure::new::<
+                        Notes,
+                    >(
+                    ))));
+                self.sample(task, Stage::Notes, r, task.vlm_options())
+            })
+            .await?;
+        event!(Level::DEBUG, "notes: {}", notes.response);
+        task.record(Stage::Notes, &notes);
+        #[derive(JsonSchema, Deserialize)]
+        struct Amount {
+            amount: f32,
+            /// ISO 4217 code of the currency
+            currency: Option<String>,
+        }
+        let (notes, structured_notes) = if let Ok(structured_notes) =
+            serde_json::from_str::<Notes>(notes.response.as_str())
+        {
+            (structured_notes.to_string(), true)
+        } else {
+            event!(target: "ollama_run_task",Level::WARN,  "invalid notes JSON: {}", notes.response);
+            (notes.response, false)
+        };
+        // an empty enum would leave the model no valid output
+        let no_categories = task.categorize() && task.category_names().is_empty();
+        if no_categories {
+            event!(
+                target: "ollama_run_task",
+                Level::WARN,
+                "no categories to choose from, leaving the bill uncategorized"
+            );
+        }
+        let categorize = async {
+            if !task.categorize() || no_categories {
+                event!(Level::DEBUG, "skipping the category stage");
+                return Ok((Vec::new(), None));
+            }
+            let names = task.category_names();
+            let hints = Category::hints();
+            if task.categorizer.unwrap_or(self.categorizer) == Categorizer::Embedding {
+                let nearest = self.nearest_category(&caption.response, &names, &hints);
+                let nearest = match self.stage_timeouts.get(&Stage::Category) {
+                    Some(&timeout) => tokio::time::timeout(timeout, nearest)
+                        .await
+                        .unwrap_or_else(|_| Err(OllamaError::Other("timed out".into()))),
+                    None => nearest.await,
+                };
+                match nearest {
+                    Ok((category, similarity)) => {
+                        event!(
+                            Level::DEBUG,
+                            "nearest category: {category}, similarity {similarity:.3}"
+                        );
+                        return Ok((vec![category], None));
+                    }
+                    Err(err) => event!(
+                        target: "ollama_run_task",
+                        Level::WARN,
+                        "embeddings unavailable, categorizing with the LM: {err}"
+                    ),
+                }
+            }
+            let prompt = category_prompt(&notes, &caption.response, &names, &hints);
+            self.keep_prompt(
+                task,
+                Stage::Category,
+                &prompt,
+                &category_prompt(REDACTED, REDACTED, &names, &hints),
+            );
+            let categories = self
+                .generate(Stage::Category, tokens, {
+                    let r = self
+                        .request(Stage::Category, &models[&Stage::Category], prompt)
+                        .think(true)
+                        .format(FormatType::StructuredJson(Box::new(
+                            JsonStructure::new_for_schema(category_schema(&names)),
+                        )));
+                    self.sample(task, Stage::Category, r, task.lm_options())
+                })
+                .await?;
+            event!(Level::DEBUG, "categories: {}", categories.response);
+            task.record(Stage::Category, &categories);
+            parse_categories(&categories.response)
+        };
+        let prompt = amount_prompt(&notes, &caption.response);
+        self.keep_prompt(
+            task,
+            Stage::Amount,
+            &prompt,
+            &amount_prompt(REDACTED, REDACTED),
+        );
+        let (amount, (categories, category_reason)) = futures::try_join!(
+            self.generate(Stage::Amount, tokens, {
+                let r = self
+                    .request(Stage::Amount, &models[&Stage::Amount], prompt)
+                    .think(true)
+                    .format(FormatType::StructuredJson(Box::new(JsonStructure::new::<
+                        Amount,
+                    >(
+                    ))));
+                self.sample(task, Stage::Amount, r, task.lm_options())
+            }),
+            categorize,
+        )?;
+        event!(Level::DEBUG, "amount: {}", amount.response);
+        task.record(Stage::Amount, &amount);
+        let structured_amount = serde_json::from_str::<Amount>(amount.response.as_str())
+            .map_err(|_| RunTaskError::InvalidOutput("price".into()))?;
+        if let Some(reason) = &category_reason {
+            event!(Level::DEBUG, "category reason: {reason}");
+        }
+        let amount = structured_amount.amount;
+        let currency = structured_amount
+            .currency
+            .as_deref()
+            .and_then(currency_code);
+        let plausible_amount = amount.is_finite()
+            && amount > 0f32
+            && {
+                let within = self.amount_bounds.contains(amount);
+                if !within {
+                    event!(target: "ollama_run_task", Level::WARN, "amount {amount} is out of the plausible bounds");
+                }
+                within
+            };
+        // nothing to review about a category that wasn't asked for
+        let known_category = !task.categorize()
+            || no_categories
+            || !categories.is_empty()
+                && categories.iter().all(|c| task.category_names().contains(c));
+        let needs_review = !(structured_notes && plausible_amount && known_category);
+        if needs_review {
+            event!(target: "ollama_run_task", Level::INFO, "flagging bill for review");
+        }
+
+        Ok(Bill {
+            notes: notes.into(),
+            amount,
+            currency,
+            categories,
+            category_reason,
+            needs_review,
+        })
+    }
+
+    /// Unloads the models Ollama has loaded, pinned ones aside. Models that
+    /// aren't loaded are left alone, as asking to unload one would load it.
+    async fn release_memory(&self) {
+        for model in self.unloadable_models().await {
+            match self.unload(&model).await {
+                Ok(_) => event!(Level::INFO, "unloaded {model} under memory pressure"),
+                Err(err) => event!(Level::WARN, "failed to unload {model}: {err}"),
+            }
+        }
+    }
+}
+
+/// Tags reasoning models wrap their chain of thought in when Ollama doesn't
+/// separate it from the response.
+const REASONING_TAGS: [&str; 3] = ["think", "thinking", "reasoning"];
+
+/// `response` without the reasoning blocks of [REASONING_TAGS], trimmed. A
+/// closing tag without its opening one ends reasoning opened by the chat
+/// template, and an unclosed block runs to the end of a cut off response.
+fn strip_reasoning(response: &str) -> String {
+    let mut text = response.to_string();
+    for tag in REASONING_TAGS {
+        let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+        if let Some(end) = text.find(&close)
+            && !text[..end].contains(&open)
+        {
+            text.replace_range(..end + close.len(), "");
+        }
+        while let Some(start) = text.find(&open) {
+            let end = text[start..]
+                .find(&close)
+                .map_or(text.len(), |end| start + end + close.len());
+            text.replace_range(start..end, "");
+        }
+    }
+    text.trim().to_string()
+}
+
+/// Constrains the category stage to `names`, which are JSON strings whatever
+/// characters they contain.
+fn category_schema(names: &[SmolStr]) -> Schema {
+    json_schema!({
+        "description": "Categories of the goods, the best matching first",
+        "type": "object",
+        "properties": {
+            "categories": {
+                "type": "array",
+                "items": {
+                    "enum": names
+                },
+                "minItems": 1
+            },
+            "reason": {
+                "description": "Why the first category fits, in one short sentence",
+                "type": "string",
+                "maxLength": MAX_CATEGORY_REASON_LEN
+            }
+        },
+        "required": ["categories", "reason"]
+    })
+}
+
+/// Longest reason for the primary category kept on a bill, in bytes.
+const MAX_CATEGORY_REASON_LEN: usize = 200;
+
+/// `reason` on a single line of at most [MAX_CATEGORY_REASON_LEN] bytes, none
+/// if nothing but whitespace is left.
+fn category_reason(reason: &str) -> Option<String> {
+    let line = reason
+        .split(|c: char| c.is_whitespace() || c.is_control())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let line = truncate(&line, MAX_CATEGORY_REASON_LEN).trim_end();
+    (!line.is_empty()).then(|| line.to_string())
+}
+
+/// At most `max` bytes of `text`, cut at a character boundary.
+fn truncate(text: &str, max: usize) -> &str {
+    &text[..text.floor_char_boundary(max)]
+}
+
+/// Names as a Markdown list for the category prompt, one per line as
+/// [crate::NamedCategory::check] ensures, followed by their hint if any.
+fn category_list(names: &[SmolStr], hints: &BTreeMap<String, String>) -> String {
+    names
+        .iter()
+        .map(|name| match hints.get(name.as_str()) {
+            Some(hint) => format!("- {name}: {hint}"),
+            None => format!("- {name}"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// What a category is embedded as, its name followed by its hint if any.
+fn category_text(name: &str, hints: &BTreeMap<String, String>) -> String {
+    match hints.get(name) {
+        Some(hint) => format!("{name}: {hint}"),
+        None => name.to_string(),
+    }
+}
+
+fn note_prompt(description: &str) -> String {
+    format!(include_str!("../../prompt/note_taking.md"), description)
+}
+
+fn amount_prompt(notes: &str, description: &str) -> String {
+    format!(
+        include_str!("../../prompt/amount_extraction.md"),
+        notes, description
+    )
+}
+
+fn category_prompt(
+    notes: &str,
+    description: &str,
+    names: &[SmolStr],
+    hints: &BTreeMap<String, String>,
+) -> String {
+    format!(
+        include_str!("../../prompt/categorization.md"),
+        notes,
+        description,
+        category_list(names, hints)
+    )
+}
+
+/// Categories of the category stage's output, the primary one first and each
+/// only once, and the reason given for the primary one. Outputs without a
+/// reason, or with one that isn't a string, still give their categories.
+fn parse_categories(response: &str) -> Result<(Vec<SmolStr>, Option<String>), RunTaskError> {
+    #[derive(Deserialize)]
+    struct Categories {
+        categories: Vec<String>,
+        #[serde(default)]
+        reason: Option<serde_json::Value>,
+    }
+    let parsed = serde_json::from_str::<Categories>(response)
+        .map_err(|_| RunTaskError::InvalidOutput("category".into()))?;
+    let mut categories = Vec::<SmolStr>::with_capacity(parsed.categories.len());
+    for category in parsed.categories {
+        if !categories.iter().any(|c| c == &category) {
+            categories.push(category.into());
+        }
+    }
+    let reason = parsed
+        .reason
+        .as_ref()
+        .and_then(serde_json::Value::as_str)
+        .and_then(category_reason);
+    Ok((categories, reason))
+}
+
+/// Messages of registry errors that another try won't fix, like a model that
+/// doesn't exist or needs credentials. Ollama only relays them as text.
+const PERMANENT_PULL_ERRORS: [&str; 6] = [
+    "file does not exist",
+    "not found",
+    "unauthorized",
+    "forbidden",
+    "invalid model name",
+    "denied",
+];
+
+/// Failures worth another try: the connection, an error status, or Ollama giving
+/// up on the registry, unless it said the model is missing or off limits.
+/// Malformed responses won't get any better.
+fn is_transient(err: &OllamaError) -> bool {
+    let message = match err {
+        OllamaError::ReqwestError(_) => return true,
+        OllamaError::InternalError(err) => &err.message,
+        OllamaError::Other(message) => message,
+        _ => return false,
+    };
+    let message = message.to_lowercase();
+    !PERMANENT_PULL_ERRORS
+        .iter()
+        .any(|permanent| message.contains(permanent))
+}
+
+/// Exponential backoff with equal jitter, so concurrent pulls don't retry in lockstep.
+fn pull_backoff(base: Duration, attempt: u32) -> Duration {
+    let delay = base
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(MAX_PULL_BACKOFF);
+    delay / 2 + delay.mul_f64(rand::rng().random::<f64>() / 2.0)
+}
+
+/// Somewhere between `keep_alive` and `keep_alive * (1 + jitter)`, drawn anew for
+/// every request.
+fn keep_alive_jitter(keep_alive: Duration, jitter: f64) -> Duration {
+    keep_alive + keep_alive.mul_f64(jitter * rand::rng().random::<f64>())
+}
+
+impl TaskDescriptor for OllamaTaskDescriptor {
+    fn images(&self) -> Vec<&[u8]> {
+        self.images_buf
+            .iter()
+            .map(|buf| buf.as_slice())
+            .collect::<Vec<_>>()
+    }
+
+    fn category_names(&self) -> Vec<SmolStr> {
+        self.categories.clone().unwrap_or_else(|| {
+            Category::all_cases()
+                .iter()
+                .filter_map(|c| c.name().map(SmolStr::from))
+                .collect::<Vec<_>>()
+        })
+    }
+
+    fn intermediates(&self) -> Option<&StageTexts> {
+        self.intermediates.as_ref()
+    }
+
+    fn prompts(&self) -> Option<&StageTexts> {
+        Some(&self.prompts)
+    }
+
+    fn seeds(&self) -> Option<&StageSeeds> {
+        Some(&self.seeds)
+    }
+
+    fn rerun(&self) -> Self {
+        Self {
+            intermediates: self.intermediates.as_ref().map(|_| StageTexts::default()),
+            prompts: StageTexts::default(),
+            seeds: StageSeeds::default(),
+            ..self.clone()
+        }
+    }
+
+    fn hash_options(&self, hasher: &mut DefaultHasher) {
+        // the model options don't hash, their JSON does
+        serde_json::to_string(&(
+            &self.lm_options,
+            &self.vlm_options,
+            self.animation_frames,
+            &self.quantization,
+            self.preprocess,
+            self.categorizer,
+        ))
+        .unwrap_or_default()
+        .hash(hasher);
+        (
+            self.categorize(),
+            self.deterministic,
+            self.intermediates.is_some(),
+        )
+            .hash(hasher);
+    }
+}
+
+/// Descriptor of images alone, as restored from a backup.
+impl From<Vec<Vec<u8>>> for OllamaTaskDescriptor {
+    fn from(images_buf: Vec<Vec<u8>>) -> Self {
+        Self::from_images(images_buf)
+    }
+}
+
+impl OllamaTaskDescriptor {
+    pub fn from_images(images_buf: Vec<Vec<u8>>) -> Self {
+        Self {
+            images_buf,
+            ..Default::default()
+        }
+    }
+
+    /// Descriptor of a single image uploaded other than by a form, checked
+    /// against `limit` and by its magic bytes like a form upload is.
+    pub fn from_upload(image: Vec<u8>, limit: usize) -> Result<Self, CreateTaskError> {
+        Upload::new(limit).take(image.len())?;
+        Ok(Self::from_images(checked(vec![image])?))
+    }
+
+    pub fn lm_options(&self) -> Option<&ModelOptions> {
+        self.lm_options.as_ref()
+    }
+
+    pub fn vlm_options(&self) -> Option<&ModelOptions> {
+        self.vlm_options.as_ref()
+    }
+
+    /// Skips the category stage if `categorize` is false, leaving the bill
+    /// without a category and saving a model call.
+    pub fn with_categorization(mut self, categorize: bool) -> Self {
+        self.categorize = Some(categorize);
+        self
+    }
+
+    pub fn categorize(&self) -> bool {
+        self.categorize.unwrap_or(true)
+    }
+
+    /// Samples every stage with [DETERMINISTIC_SEED], or not, whatever the
+    /// runner does by default.
+    pub fn with_determinism(mut self, deterministic: bool) -> Self {
+        self.deterministic = Some(deterministic);
+        self
+    }
+
+    /// Keeps what each stage generated on the task, see [StageTexts].
+    pub fn with_intermediates(mut self) -> Self {
+        self.intermediates = Some(StageTexts::default());
+        self
+    }
+
+    fn record(&self, stage: Stage, response: &GenerationResponse) {
+        if let Some(intermediates) = &self.intermediates {
+            intermediates.record(stage, &response.response);
+        }
+    }
+}
+
+impl<S> FromRequest<S> for OllamaTaskDescriptor
+where
+    S: Send + Sync,
+{
+    type Rejection = CreateTaskError;
+
+    async fn from_request(req: axum::extract::Request, _: &S) -> Result<Self, Self::Rejection> {
+        fn get_images_buf(source: Bytes, mime: &str) -> Result<Vec<Vec<u8>>, CreateTaskError> {
+            if mime.starts_with("image/") {
+                return checked(vec![source.to_vec()]);
+            } else if !mime.starts_with("application/") {
+                return Err(CreateTaskError::UnspecificContentType(mime.into()));
+            }
+            let mut bufs = Vec::new();
+            let type_name = mime.split_once('/').unwrap().1;
+            match type_name {
+                "zip" | "zip-compressed" => {
+                    let mut archive = ZipArchive::new(Cursor::new(source))?;
+                    for i in 0..archive.len() {
+                        let mut item = archive.by_index(i)?;
+                        if item.is_file() {
+                            let mut buf = Vec::with_capacity(item.size() as usize);
+                            item.read_to_end(&mut buf)?;
+                            bufs.push(buf);
+                        } else {
+                            return Err(ZipError::InvalidArchive(Cow::Owned(
+                                "accept files only, got dir / symlink".into(),
+                            ))
+                            .into());
+                        }
+                    }
+                }
+                _ => return Err(CreateTaskError::UnsupportedFileType(mime.into())),
+            }
+            checked(bufs)
+        }
+
+        let content_type = UTF_8
+            .decode(req.headers().get("Content-Type").unwrap().as_bytes())
+            .0
+            .into_owned();
+        event!(Level::DEBUG, "receiving {}", content_type);
+        let mut upload = Upload::new(
+            req.extensions()
+                .get::<UploadLimit>()
+                .map_or(DEFAULT_MAX_UPLOAD_SIZE, |limit| limit.0),
+        );
+        let offered = req.extensions().get::<OfferedQuantizations>().map_or_else(
+            || QUANTIZATIONS.map(str::to_string).to_vec(),
+            |offered| offered.0.iter().map(Quantization::to_string).collect(),
+        );
+
+        let mut images_buf: Option<Vec<Vec<u8>>> = None;
+        let (mut lm_options, mut vlm_options, mut categories) = (None, None, None);
+        let (mut animation_frames, mut quantization, mut preprocess) = (None, None, None);
+        let (mut categorize, mut categorizer) = (None, None);
+        let mut intermediates = None;
+        let mut deterministic = None;
+        let mut received = Names::default();
         if content_type.starts_with("multipart/form-data") {
+            // fields are read chunk by chunk, giving up on the rest of the body
+            // as soon as it can't make a valid task
             let mut form: Multipart = req.extract().await?;
-            while let Some(field) = form.next_field().await? {
-                let name = field.name().unwrap().to_string();
+            let mut fields = Vec::new();
+            while let Some(mut field) = form.next_field().await? {
+                let name = field.name().unwrap_or_default().to_string();
+                let mime = field.content_type().map(str::to_string);
+                received.0.push(name.clone());
+                if !FORM_FIELDS.contains(&name.as_str()) {
+                    return Err(CreateTaskError::unknown_field(name, &FORM_FIELDS, received));
+                }
+                let mut data = Vec::new();
+                let mut sniffed = name != "image";
+                while let Some(chunk) = field.chunk().await? {
+                    upload.take(chunk.len())?;
+                    data.extend_from_slice(&chunk);
+                    if !sniffed && data.len() >= SNIFF_LEN {
+                        sniff(&data, mime.as_deref())?;
+                        sniffed = true;
+                    }
+                }
+                event!(
+                    Level::DEBUG,
+                    "received field {name:?}, {} bytes of {}",
+                    data.len(),
+                    mime.as_deref().unwrap_or("unspecified type")
+                );
+                fields.push((name, mime, Bytes::from(data)));
+            }
+
+            for (name, mime, data) in fields {
                 match name.as_str() {
                     "image" => {
-                        let mime = field
-                            .content_type()
-                            .ok_or(CreateTaskError::UnspecificContentType("image".to_string()))?
-                            .to_string();
-                        images_buf = Some(get_images_buf(field.bytes().await?, &mime)?);
+                        let mime = mime
+                            .ok_or(CreateTaskError::UnspecificContentType("image".to_string()))?;
+                        let bufs = get_images_buf(data, &mime)?;
+                        images_buf.get_or_insert_default().extend(bufs);
                     }
                     "lm_options" | "vlm_options" => {
-                        if let Some(mime) = field.content_type()
-                            && mime != "application/json"
-                        {
-                            return Err(CreateTaskError::InvalidField(name.to_string()));
+                        if mime.is_some_and(|mime| mime != "application/json") {
+                            return Err(CreateTaskError::InvalidField(name));
                         }
-                        let value: ModelOptions =
-                            serde_json::from_str(field.text().await?.as_str())?;
-                        if name.starts_with("lm") {
-                            lm_options = Some(value)
+                        let slot = if name.starts_with("lm") {
+                            &mut lm_options
                         } else {
-                            vlm_options = Some(value)
+                            &mut vlm_options
+                        };
+                        if slot.is_some() {
+                            return Err(CreateTaskError::DuplicateField(name));
                         }
+                        let value: ModelOptions = serde_json::from_slice(&data)?;
+                        *slot = Some(value);
                     }
                     "categories" => {
-                        if let Some(mime) = field.content_type()
-                            && mime != "application/json"
-                        {
-                            return Err(CreateTaskError::InvalidField(name.to_string()));
+                        if mime.is_some_and(|mime| mime != "application/json") {
+                            return Err(CreateTaskError::InvalidField(name));
+                        }
+                        if categories.is_some() {
+                            return Err(CreateTaskError::DuplicateField(name));
+                        }
+                        let value: Vec<String> = serde_json::from_slice(&data)?;
+                        if value.iter().any(|name| Category::check_name(name).is_err()) {
+                            return Err(CreateTaskError::InvalidField(name));
                         }
-                        let value: Vec<String> =
-                            serde_json::from_str(field.text().await?.as_str())?;
                         categories = Some(
                             value
                                 .into_iter()
@@ -376,53 +1883,1994 @@ where
                                 .collect::<Vec<_>>(),
                         );
                     }
-                    _ => {
-                        return Err(CreateTaskError::UnknownField(name.to_string()));
+                    "animation_frames" => {
+                        if animation_frames.is_some() {
+                            return Err(CreateTaskError::DuplicateField(name));
+                        }
+                        let value = std::str::from_utf8(&data)
+                            .ok()
+                            .and_then(|value| value.parse::<FrameSelection>().ok())
+                            .ok_or(CreateTaskError::InvalidField(name))?;
+                        animation_frames = Some(value);
+                    }
+                    "quantization" => {
+                        if quantization.is_some() {
+                            return Err(CreateTaskError::DuplicateField(name));
+                        }
+                        let value = std::str::from_utf8(&data)
+                            .map_err(|_| CreateTaskError::InvalidField(name))?;
+                        quantization = Some(value.parse::<Quantization>().map_err(|_| {
+                            let offered = offered.iter().map(String::as_str).collect::<Vec<_>>();
+                            CreateTaskError::unknown_quantization(value.to_string(), &offered)
+                        })?);
+                    }
+                    "preprocess" => {
+                        if preprocess.is_some() {
+                            return Err(CreateTaskError::DuplicateField(name));
+                        }
+                        let value = std::str::from_utf8(&data)
+                            .ok()
+                            .and_then(|value| value.parse::<Preprocess>().ok())
+                            .ok_or(CreateTaskError::InvalidField(name))?;
+                        preprocess = Some(value);
+                    }
+                    "categorize" => {
+                        if categorize.is_some() {
+                            return Err(CreateTaskError::DuplicateField(name));
+                        }
+                        let value = std::str::from_utf8(&data)
+                            .ok()
+                            .and_then(|value| value.trim().parse::<bool>().ok())
+                            .ok_or(CreateTaskError::InvalidField(name))?;
+                        categorize = Some(value);
+                    }
+                    "categorizer" => {
+                        if categorizer.is_some() {
+                            return Err(CreateTaskError::DuplicateField(name));
+                        }
+                        let value = std::str::from_utf8(&data)
+                            .ok()
+                            .and_then(|value| value.trim().parse::<Categorizer>().ok())
+                            .ok_or(CreateTaskError::InvalidField(name))?;
+                        categorizer = Some(value);
+                    }
+                    "intermediates" => {
+                        if intermediates.is_some() {
+                            return Err(CreateTaskError::DuplicateField(name));
+                        }
+                        let value = std::str::from_utf8(&data)
+                            .ok()
+                            .and_then(|value| value.trim().parse::<bool>().ok())
+                            .ok_or(CreateTaskError::InvalidField(name))?;
+                        intermediates = Some(value);
+                    }
+                    "deterministic" => {
+                        if deterministic.is_some() {
+                            return Err(CreateTaskError::DuplicateField(name));
+                        }
+                        let value = std::str::from_utf8(&data)
+                            .ok()
+                            .and_then(|value| value.trim().parse::<bool>().ok())
+                            .ok_or(CreateTaskError::InvalidField(name))?;
+                        deterministic = Some(value);
+                    }
+                    _ => unreachable!("unknown fields are rejected as they arrive"),
+                }
+            }
+        } else {
+            let mut body = req.into_body().into_data_stream();
+            let mut buf = Vec::new();
+            let mut sniffed = false;
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk?;
+                upload.take(chunk.len())?;
+                buf.extend_from_slice(&chunk);
+                if !sniffed && buf.len() >= SNIFF_LEN {
+                    sniff(&buf, Some(&content_type))?;
+                    sniffed = true;
+                }
+            }
+            images_buf = Some(get_images_buf(buf.into(), &content_type)?);
+        }
+        if images_buf.is_none() {
+            return Err(CreateTaskError::MissingField {
+                name: "image".to_string(),
+                received,
+            });
+        }
+
+        Ok(Self {
+            images_buf: images_buf.unwrap(),
+            lm_options,
+            vlm_options,
+            categories,
+            animation_frames,
+            quantization,
+            preprocess,
+            categorize,
+            categorizer,
+            intermediates: intermediates.unwrap_or_default().then(StageTexts::default),
+            prompts: StageTexts::default(),
+            deterministic,
+            seeds: StageSeeds::default(),
+        })
+    }
+}
+
+/// Most bytes the body of a task upload may have, given to
+/// [OllamaTaskDescriptor::from_request] as a request extension.
+#[derive(Debug, Clone, Copy)]
+pub struct UploadLimit(pub usize);
+
+/// Quantization levels the runner offers, given to
+/// [OllamaTaskDescriptor::from_request] as a request extension, so an unknown
+/// level is answered with these rather than every level Ollama knows.
+#[derive(Debug, Clone)]
+pub struct OfferedQuantizations(pub Arc<[Quantization]>);
+
+/// Bytes of an upload left before it's too large.
+struct Upload {
+    limit: usize,
+    left: usize,
+}
+
+impl Upload {
+    fn new(limit: usize) -> Self {
+        Self { limit, left: limit }
+    }
+
+    fn take(&mut self, len: usize) -> Result<(), CreateTaskError> {
+        self.left = self
+            .left
+            .checked_sub(len)
+            .ok_or(CreateTaskError::TooLarge { limit: self.limit })?;
+        Ok(())
+    }
+}
+
+/// Rejects images in a format that can't be read.
+fn checked(bufs: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, CreateTaskError> {
+    for buf in &bufs {
+        if let Err(detected) = imaging::check(buf) {
+            return Err(CreateTaskError::UnsupportedImageFormat {
+                detected: detected.map_or("unknown".to_string(), |format| format.to_string()),
+                supported: Names(imaging::supported()),
+            });
+        }
+    }
+    Ok(bufs)
+}
+
+/// Rejects an image upload by its first bytes, before the rest arrives.
+fn sniff(head: &[u8], mime: Option<&str>) -> Result<(), CreateTaskError> {
+    let mime = mime.ok_or(CreateTaskError::UnspecificContentType("image".to_string()))?;
+    if mime.starts_with("image/") {
+        return imaging::check(head).map_err(|detected| CreateTaskError::UnsupportedImageFormat {
+            detected: detected.map_or("unknown".to_string(), |format| format.to_string()),
+            supported: Names(imaging::supported()),
+        });
+    }
+    match mime.strip_prefix("application/") {
+        Some("zip" | "zip-compressed") if head.starts_with(b"PK") => Ok(()),
+        Some("zip" | "zip-compressed") => {
+            Err(ZipError::InvalidArchive(Cow::Borrowed("not a zip archive")).into())
+        }
+        Some(_) => Err(CreateTaskError::UnsupportedFileType(mime.into())),
+        None => Err(CreateTaskError::UnspecificContentType(mime.into())),
+    }
+}
+
+#[derive(JsonSchema, Deserialize)]
+struct Notes {
+    name: String,
+    #[schemars(rename = "type")]
+    #[serde(rename = "type")]
+    type_: String,
+    retailer: Option<String>,
+}
+
+impl Display for Notes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(retailer) = &self.retailer {
+            write!(f, "{} \"{}\" from {}", self.type_, self.name, retailer)
+        } else {
+            write!(f, "{} \"{}\"", self.type_, self.name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::{body::Body, http::StatusCode, response::IntoResponse};
+    use reqwest::multipart::{Form, Part};
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    const SCREENSHOT: &[u8] = include_bytes!("../../asset/second-hand-horse-screenshot.jpeg");
+
+    fn image_part() -> Part {
+        Part::bytes(SCREENSHOT)
+            .file_name("screenshot.jpeg")
+            .mime_str("image/jpeg")
+            .unwrap()
+    }
+
+    fn json_part(json: &'static str) -> Part {
+        Part::text(json).mime_str("application/json").unwrap()
+    }
+
+    async fn parse_form(form: Form) -> Result<OllamaTaskDescriptor, CreateTaskError> {
+        let request = axum::extract::Request::builder()
+            .method("POST")
+            .uri("/create_task")
+            .header(
+                "Content-Type",
+                format!("multipart/form-data; boundary={}", form.boundary()),
+            )
+            .body(Body::from_stream(form.into_stream()))
+            .unwrap();
+        OllamaTaskDescriptor::from_request(request, &()).await
+    }
+
+    async fn serve_stub(router: axum::Router) -> Ollama {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        Ollama::from_url(reqwest::Url::parse(&url).unwrap())
+    }
+
+    #[test]
+    fn test_strip_reasoning() {
+        assert_eq!(strip_reasoning(r#" {"amount": 12} "#), r#"{"amount": 12}"#);
+        assert_eq!(
+            strip_reasoning(
+                "<think>\nThe total is 21888? No, that's the model number.\n</think>\n{\"amount\": 21.88}"
+            ),
+            r#"{"amount": 21.88}"#
+        );
+        // opened by the chat template
+        assert_eq!(
+            strip_reasoning("X200 is a model number.</think>{\"amount\": 21.88}"),
+            r#"{"amount": 21.88}"#
+        );
+        assert_eq!(
+            strip_reasoning(
+                "<thinking>a</thinking>{\"categories\": [<reasoning>b</reasoning>\"Food\"]}"
+            ),
+            r#"{"categories": ["Food"]}"#
+        );
+        assert_eq!(strip_reasoning("<think>cut off mid-thought"), "");
+        let (categories, _) = parse_categories(&strip_reasoning(
+            "<think>Groceries and a lamp.</think>\n{\"categories\": [\"Food\", \"Household\"]}",
+        ))
+        .unwrap();
+        assert_eq!(categories, ["Food", "Household"]);
+    }
+
+    #[test]
+    fn test_parse_categories() {
+        assert_eq!(
+            parse_categories(r#"{"categories": ["Food"]}"#).unwrap(),
+            (vec!["Food".into()], None)
+        );
+        assert_eq!(
+            parse_categories(r#"{"categories": ["Food", "Household", "Food"]}"#)
+                .unwrap()
+                .0,
+            ["Food", "Household"]
+        );
+        assert_eq!(
+            parse_categories(
+                r#"{"categories": ["Food"], "reason": "  A pizza\nfrom a\u0007 restaurant. "}"#
+            )
+            .unwrap(),
+            (
+                vec!["Food".into()],
+                Some("A pizza from a restaurant.".into())
+            )
+        );
+        let (_, long) = parse_categories(
+            &serde_json::json!({ "categories": ["Food"], "reason": "é".repeat(150) }).to_string(),
+        )
+        .unwrap();
+        assert_eq!(long.unwrap(), "é".repeat(MAX_CATEGORY_REASON_LEN / 2));
+        for unusable in [r#""  ""#, "null", "42"] {
+            let response = format!(r#"{{"categories": ["Food"], "reason": {unusable}}}"#);
+            assert_eq!(parse_categories(&response).unwrap().1, None);
+        }
+        assert!(matches!(
+            parse_categories(r#"{"category": "Food"}"#),
+            Err(RunTaskError::InvalidOutput(_))
+        ));
+    }
+
+    #[test]
+    fn test_category_hints() {
+        let names = ["Food", "Transport"].map(SmolStr::from);
+        let hints = BTreeMap::from([("Transport".to_string(), "taxis, buses, fuel".to_string())]);
+        let prompt = category_prompt("Taxi fare", "A taxi receipt", &names, &hints);
+        assert!(
+            prompt.contains("- Food\n- Transport: taxis, buses, fuel\n"),
+            "{prompt}"
+        );
+        let plain = category_prompt("Taxi fare", "A taxi receipt", &names, &BTreeMap::new());
+        assert!(plain.contains("- Food\n- Transport\n"), "{plain}");
+    }
+
+    #[test]
+    fn test_special_category_names() {
+        let names = [
+            "Food & Drink (Out)",
+            "\"Quoted\" 'names'",
+            "Back\\slash",
+            "Pipes | and / slashes",
+            "[Brackets] {braces} <angles>",
+            "Regex .*+?^$",
+            "- Looks like an item",
+            "# Looks like a heading",
+            "Ünïcödé 餐饮 🍜",
+        ]
+        .map(SmolStr::from);
+        let schema = serde_json::to_value(category_schema(&names)).unwrap();
+        assert_eq!(
+            schema["properties"]["categories"]["items"]["enum"],
+            serde_json::json!(names)
+        );
+        let list = category_list(&names, &BTreeMap::new());
+        assert_eq!(list.lines().count(), names.len());
+        for (line, name) in list.lines().zip(&names) {
+            assert_eq!(line.strip_prefix("- "), Some(name.as_str()));
+        }
+        for name in &names {
+            assert!(Category::check_name(name).is_ok(), "{name}");
+            let response = serde_json::json!({ "categories": [name] }).to_string();
+            assert_eq!(parse_categories(&response).unwrap().0, [name.as_str()]);
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_pull_progress() {
+        let router = axum::Router::new()
+            .route(
+                "/api/tags",
+                axum::routing::get(async || r#"{"models": []}"#),
+            )
+            .route(
+                "/api/pull",
+                axum::routing::post(async || {
+                    [
+                        r#"{"status": "pulling manifest"}"#,
+                        r#"{"status": "pulling 1b2c", "digest": "sha256:1b2c", "total": 100, "completed": 42}"#,
+                        r#"{"status": "pulling 1b2c", "digest": "sha256:1b2c", "total": 100, "completed": 100}"#,
+                        r#"{"status": "success"}"#,
+                    ]
+                    .join("\n")
+                        + "\n"
+                }),
+            );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            caption_model: "caption".into(),
+            extract_model: "extract".into(),
+            ..Default::default()
+        };
+        runner.pull_models().await.unwrap();
+
+        let models = runner.model_status();
+        assert_eq!(models.len(), 2);
+        for model in models {
+            let pull = model.pull.unwrap();
+            assert_eq!(pull.status, "success");
+            assert_eq!(pull.digest.as_deref(), Some("sha256:1b2c"));
+            assert_eq!((pull.completed, pull.total), (Some(100), Some(100)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_pulls() {
+        async fn most_at_once(concurrent_pulls: usize) -> usize {
+            let (running, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+            let router = axum::Router::new()
+                .route(
+                    "/api/tags",
+                    axum::routing::get(async || r#"{"models": []}"#),
+                )
+                .route(
+                    "/api/pull",
+                    axum::routing::post({
+                        let (running, most) = (running.clone(), most.clone());
+                        async move || {
+                            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                            most.fetch_max(now, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            running.fetch_sub(1, Ordering::SeqCst);
+                            "{\"status\": \"success\"}\n"
+                        }
+                    }),
+                );
+            let runner = OllamaRunTask {
+                ollama: serve_stub(router).await,
+                caption_model: "caption".into(),
+                extract_model: "extract".into(),
+                concurrent_pulls,
+                ..Default::default()
+            };
+            runner.pull_models().await.unwrap();
+            most.load(Ordering::SeqCst)
+        }
+
+        assert_eq!(most_at_once(1).await, 1);
+        assert_eq!(most_at_once(2).await, 2);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_pull_retries() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let router = axum::Router::new()
+            .route(
+                "/api/tags",
+                axum::routing::get(async || r#"{"models": []}"#),
+            )
+            .route(
+                "/api/pull",
+                axum::routing::post({
+                    let calls = calls.clone();
+                    async move || {
+                        if calls.fetch_add(1, Ordering::SeqCst) < 2 {
+                            (StatusCode::BAD_GATEWAY, "registry unavailable").into_response()
+                        } else {
+                            "{\"status\": \"success\"}\n".into_response()
+                        }
+                    }
+                }),
+            );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            caption_model: "model".into(),
+            extract_model: "model".into(),
+            pull_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        runner.pull_models().await.unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        let pull = runner.model_status().remove(0).pull.unwrap();
+        assert_eq!(pull.status, "success");
+        assert_eq!(pull.retries, 2);
+        assert!(logs_contain("retry 2 of 4"));
+        assert!(logs_contain("attempt 3 of 5"));
+    }
+
+    #[tokio::test]
+    async fn test_pull_attempts() {
+        async fn pull_calls(error: &'static str, pull_attempts: u32) -> usize {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let router = axum::Router::new()
+                .route(
+                    "/api/tags",
+                    axum::routing::get(async || r#"{"models": []}"#),
+                )
+                .route(
+                    "/api/pull",
+                    axum::routing::post({
+                        let calls = calls.clone();
+                        async move || {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            (StatusCode::INTERNAL_SERVER_ERROR, error)
+                        }
+                    }),
+                );
+            let runner = OllamaRunTask {
+                ollama: serve_stub(router).await,
+                caption_model: "model".into(),
+                extract_model: "model".into(),
+                pull_backoff: Duration::from_millis(1),
+                pull_attempts,
+                ..Default::default()
+            };
+            runner.pull_models().await.unwrap_err();
+            calls.load(Ordering::SeqCst)
+        }
+
+        assert_eq!(pull_calls("registry unavailable", 2).await, 2);
+        // Ollama drops a corrupted layer itself, so pulling again downloads it anew
+        assert_eq!(
+            pull_calls(
+                r#"{"error":"digest mismatch, file must be downloaded again"}"#,
+                2
+            )
+            .await,
+            2
+        );
+        assert_eq!(
+            pull_calls(r#"{"error":"pull model manifest: file does not exist"}"#, 5).await,
+            1
+        );
+        assert_eq!(
+            pull_calls("pull model manifest: 401: Unauthorized", 5).await,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_model_metrics() {
+        // what /api/ps lists before each generation: nothing, the model, nothing again
+        let running = Arc::new(std::sync::Mutex::new(vec![
+            r#"{"models": []}"#,
+            r#"{"models": [{"name": "m:latest"}]}"#,
+            r#"{"models": []}"#,
+        ]));
+        let router = axum::Router::new()
+            .route(
+                "/api/ps",
+                axum::routing::get(async move || running.lock().unwrap().remove(0)),
+            )
+            .route(
+                "/api/generate",
+                axum::routing::post(async || {
+                    r#"{"model": "m", "created_at": "", "response": "12", "done": true, "load_duration": 1500000000}"#
+                }),
+            );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            ..Default::default()
+        };
+        let sender = tokio::sync::broadcast::Sender::new(1);
+        for _ in 0..3 {
+            runner
+                .generate(
+                    Stage::Amount,
+                    &sender,
+                    GenerationRequest::new("m".into(), "p"),
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            runner.metrics.snapshot()["m"],
+            ModelCounters {
+                hits: 1,
+                misses: 2,
+                evictions: 1,
+                load_seconds: 3.0,
+                last_load_seconds: Some(1.5),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_release_memory() {
+        let unloaded = Arc::new(std::sync::Mutex::new(Vec::<serde_json::Value>::new()));
+        let record = |response: &'static str| {
+            let unloaded = unloaded.clone();
+            axum::routing::post(async move |body: String| {
+                unloaded
+                    .lock()
+                    .unwrap()
+                    .push(serde_json::from_str(&body).unwrap());
+                response
+            })
+        };
+        let router = axum::Router::new()
+            .route(
+                "/api/ps",
+                axum::routing::get(async || {
+                    r#"{"models": [{"name": "caption/q8_0:latest"}, {"name": "extract/q8_0:latest"}, {"name": "embed:latest"}]}"#
+                }),
+            )
+            .route(
+                "/api/generate",
+                record(r#"{"model": "m", "created_at": "", "response": "", "done": true}"#),
+            )
+            .route("/api/embed", record(r#"{"embeddings": []}"#));
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            caption_model: "caption".into(),
+            extract_model: "extract".into(),
+            quantizations: vec!["q4_K_M".parse().unwrap(), "q8_0".parse().unwrap()],
+            categorizer: Categorizer::Embedding,
+            embedding_model: "embed".into(),
+            pinned: PinnedModels::new(["extract/q8_0".into()]),
+            ..Default::default()
+        };
+        runner.release_memory().await;
+        runner.unload_models().await.unwrap();
+        let unloaded = unloaded.lock().unwrap();
+        assert!(unloaded.iter().all(|body| body["keep_alive"] == "0s"));
+        let models = unloaded
+            .iter()
+            .map(|body| body["model"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        // extract/q8_0 is pinned and the q4_K_M models aren't loaded
+        assert_eq!(models, ["caption/q8_0", "embed", "caption/q8_0", "embed"]);
+    }
+
+    #[tokio::test]
+    async fn test_stage_timeout() {
+        let router = axum::Router::new().route(
+            "/api/generate",
+            axum::routing::post(async |body: String| {
+                // the amount prompt hangs, like a stuck generation
+                if body.contains("stuck") {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+                r#"{"model": "m", "created_at": "", "response": "12", "done": true}"#
+            }),
+        );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            stage_timeouts: HashMap::from([
+                (Stage::Amount, Duration::from_millis(100)),
+                (Stage::Description, Duration::from_secs(30)),
+            ]),
+            ..Default::default()
+        };
+        let sender = tokio::sync::broadcast::Sender::new(1);
+        let started = std::time::Instant::now();
+        let err = runner
+            .generate(
+                Stage::Amount,
+                &sender,
+                GenerationRequest::new("m".into(), "stuck"),
+            )
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(
+            matches!(
+                err,
+                RunTaskError::StageTimeout {
+                    stage: Stage::Amount,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        assert!(err.retryable());
+        assert_eq!(err.stage(), Some(Stage::Amount));
+
+        for stage in [Stage::Description, Stage::Category] {
+            let response = runner
+                .generate(stage, &sender, GenerationRequest::new("m".into(), "p"))
+                .await
+                .unwrap();
+            assert_eq!(response.response, "12");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generate_streams_tokens() {
+        let router = axum::Router::new().route(
+            "/api/generate",
+            axum::routing::post(async || {
+                [
+                    r#"{"model": "m", "created_at": "", "response": "", "thinking": "hmm", "done": false}"#,
+                    r#"{"model": "m", "created_at": "", "response": "Hello", "done": false}"#,
+                    r#"{"model": "m", "created_at": "", "response": " world", "done": true}"#,
+                ]
+                .join("\n")
+                    + "\n"
+            }),
+        );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            ..Default::default()
+        };
+        let sender = tokio::sync::broadcast::Sender::new(16);
+        let mut receiver = sender.subscribe();
+        let response = runner
+            .generate(
+                Stage::Description,
+                &sender,
+                GenerationRequest::new("m".into(), "p"),
+            )
+            .await
+            .unwrap();
+        assert!(response.done);
+        assert_eq!(response.response, "Hello world");
+        assert_eq!(response.thinking.as_deref(), Some("hmm"));
+
+        let mut received = Vec::new();
+        while let Ok(token) = receiver.try_recv() {
+            assert_eq!(token.stage, Stage::Description);
+            received.push((token.kind, token.text));
+        }
+        assert_eq!(received[0], (TokenKind::Started, String::new()));
+        assert_eq!(received[1], (TokenKind::Thinking, "hmm".to_string()));
+        assert!(received.contains(&(TokenKind::Response, "Hello".to_string())));
+        assert_eq!(received.last(), Some(&(TokenKind::Done, String::new())));
+    }
+
+    #[tokio::test]
+    async fn test_offline_missing_models() {
+        let router = axum::Router::new().route(
+            "/api/tags",
+            axum::routing::get(
+                async || r#"{"models": [{"name": "caption", "modified_at": "", "size": 0}]}"#,
+            ),
+        );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            caption_model: "caption".into(),
+            extract_model: "extract".into(),
+            offline: true,
+            ..Default::default()
+        };
+        let err = runner.check_local_models().await.unwrap_err();
+        assert!(
+            matches!(&err, StartupError::MissingModels(missing) if missing == &["extract"]),
+            "{err}"
+        );
+        assert!(err.to_string().contains("extract"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_chat_template() {
+        let router = axum::Router::new().route(
+            "/api/show",
+            axum::routing::post(async |body: String| {
+                if body.contains("bare") {
+                    r#"{"template": ""}"#
+                } else {
+                    r#"{"template": "{{ .Prompt }}"}"#
+                }
+            }),
+        );
+        let mut runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            caption_model: "templated".into(),
+            extract_model: "bare".into(),
+            ..Default::default()
+        };
+        let err = runner.check_chat_templates().await.unwrap_err();
+        assert!(
+            matches!(&err, RunTaskError::MissingChatTemplate(model) if model == "bare"),
+            "{err}"
+        );
+
+        runner.chat_templates = ChatTemplates::from_args(&[ChatTemplate {
+            model: Some("bare".into()),
+            template: "{{ .Prompt }}".into(),
+        }]);
+        runner.check_chat_templates().await.unwrap();
+        let request = runner.request(Stage::Amount, &runner.extract_model, "p");
+        assert_eq!(request.template.as_deref(), Some("{{ .Prompt }}"));
+        let request = runner.request(Stage::Description, &runner.caption_model, "p");
+        assert_eq!(request.template, None);
+
+        assert!(ChatTemplates::validate("{{ .System }}").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_too_many_images() {
+        let runner = OllamaRunTask {
+            // nothing may be reached before the limit is checked
+            ollama: Ollama::from_url(reqwest::Url::parse("http://127.0.0.1:9").unwrap()),
+            max_images: Some(2),
+            ..Default::default()
+        };
+        let task = OllamaTaskDescriptor::from_images(vec![SCREENSHOT.to_vec(); 3]);
+        let err = runner
+            .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, RunTaskError::TooManyImages { count: 3, limit: 2 }),
+            "{err}"
+        );
+        assert!(err.to_string().contains("at most 2"));
+        // a recording within the limit as an image, but not in the frames it expands to
+        let mut recording = Vec::new();
+        image::codecs::gif::GifEncoder::new(&mut recording)
+            .encode_frames((0..5).map(|shade| {
+                image::Frame::from_parts(
+                    image::RgbaImage::from_pixel(3, 2, image::Rgba([shade, shade, shade, 255])),
+                    0,
+                    0,
+                    image::Delay::from_numer_denom_ms(100, 1),
+                )
+            }))
+            .unwrap();
+        let mut task = OllamaTaskDescriptor::from_images(vec![recording]);
+        task.animation_frames = Some(FrameSelection::Every { step: 1, cap: 4 });
+        let err = runner
+            .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, RunTaskError::TooManyImages { count: 4, limit: 2 }),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_system_prompts() {
+        let runner = OllamaRunTask {
+            system_prompts: HashMap::from([(Stage::Amount, "Answer in CNY.".into())]),
+            ..Default::default()
+        };
+        let request = runner.request(Stage::Amount, &runner.extract_model, "p");
+        assert_eq!(request.system.as_deref(), Some("Answer in CNY."));
+        let request = runner.request(Stage::Category, &runner.extract_model, "p");
+        assert_eq!(request.system, None);
+    }
+
+    #[tokio::test]
+    async fn test_shuffled_form_fields() {
+        let forms = [
+            Form::new()
+                .part("categories", json_part(r#"["Food"]"#))
+                .part("vlm_options", json_part(r#"{"temperature": 0.1}"#))
+                .part("image", image_part())
+                .part("lm_options", json_part(r#"{"temperature": 0.2}"#)),
+            Form::new()
+                .part("lm_options", json_part(r#"{"temperature": 0.2}"#))
+                .part("image", image_part())
+                .part("categories", json_part(r#"["Food"]"#))
+                .part("vlm_options", json_part(r#"{"temperature": 0.1}"#)),
+        ];
+        for form in forms {
+            let descriptor = parse_form(form).await.unwrap();
+            assert_eq!(descriptor.images().len(), 1);
+            assert!(descriptor.lm_options().is_some());
+            assert!(descriptor.vlm_options().is_some());
+            assert_eq!(descriptor.category_names(), vec![SmolStr::from("Food")]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repeated_form_fields() {
+        let descriptor = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .part("categories", json_part(r#"["Food"]"#))
+                .part("image", image_part()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(descriptor.images().len(), 2);
+
+        let err = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .part("lm_options", json_part("{}"))
+                .part("lm_options", json_part("{}")),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, CreateTaskError::DuplicateField(name) if name == "lm_options"));
+    }
+
+    #[test]
+    fn test_keep_alive_jitter() {
+        let runner = OllamaRunTask {
+            keep_alive: KeepAlives::new(Some(Duration::from_mins(5))),
+            keep_alive_jitter: 0.2,
+            ..Default::default()
+        };
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..100 {
+            let request = runner.request(Stage::Description, &"m".into(), "p");
+            let Some(KeepAlive::Until {
+                time,
+                unit: TimeUnit::Seconds,
+            }) = request.keep_alive
+            else {
+                panic!("expected a keep alive in seconds");
+            };
+            assert!((300..=360).contains(&time), "{time}");
+            seen.insert(time);
+        }
+        assert!(seen.len() > 1);
+        let runner = OllamaRunTask::default();
+        let request = runner.request(Stage::Description, &"m".into(), "p");
+        assert!(request.keep_alive.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pin_model() {
+        let loads = Arc::new(std::sync::Mutex::new(Vec::<serde_json::Value>::new()));
+        let router = axum::Router::new().route(
+            "/api/generate",
+            axum::routing::post({
+                let loads = loads.clone();
+                async move |body: String| {
+                    loads
+                        .lock()
+                        .unwrap()
+                        .push(serde_json::from_str(&body).unwrap());
+                    r#"{"model": "m", "created_at": "", "response": "", "done": true}"#
+                }
+            }),
+        );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            caption_model: "caption".into(),
+            extract_model: "extract".into(),
+            offline: true,
+            keep_alive: KeepAlives::new(Some(Duration::from_mins(5))),
+            pinned: PinnedModels::new(["extract".into()]),
+            ..Default::default()
+        };
+        runner.preload_pinned().await.unwrap();
+        let status = runner.pin("caption", true).await.unwrap();
+        assert!(status.pinned);
+        assert!(runner.model_status().iter().all(|status| status.pinned));
+        let request = runner
+            .clone()
+            .request(Stage::Description, &"caption".into(), "p");
+        assert!(matches!(request.keep_alive, Some(KeepAlive::Indefinitely)));
+
+        assert!(!runner.pin("caption", false).await.unwrap().pinned);
+        assert!(matches!(
+            runner.pin("other", true).await,
+            Err(PinModelError::NotConfigured(model)) if model == "other"
+        ));
+        let loads = loads.lock().unwrap();
+        let loaded = loads
+            .iter()
+            .map(|body| (body["model"].as_str().unwrap(), body["keep_alive"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            loaded,
+            [
+                ("extract", serde_json::json!(-1)),
+                ("caption", serde_json::json!(-1)),
+                ("caption", serde_json::json!("300s")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_image_format() {
+        let part = Part::bytes(&b"definitely not an image"[..])
+            .file_name("receipt.heic")
+            .mime_str("image/heic")
+            .unwrap();
+        let err = parse_form(Form::new().part("image", part))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("unsupported image format unknown, supported: GIF, ICO, JPEG, PNG"),
+            "{err}"
+        );
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let webp = Part::bytes(&include_bytes!("../../asset/thumbnail.webp")[..])
+            .mime_str("image/webp")
+            .unwrap();
+        let task = parse_form(Form::new().part("image", webp)).await.unwrap();
+        assert_eq!(task.images().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_animation_frames_field() {
+        let task = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .text("animation_frames", "every:2"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            task.animation_frames,
+            Some(FrameSelection::Every { step: 2, cap: 4 })
+        );
+        let err = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .text("animation_frames", "often"),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, CreateTaskError::InvalidField(name) if name == "animation_frames"));
+    }
+
+    #[tokio::test]
+    async fn test_submission_hash() {
+        let form = || Form::new().part("image", image_part());
+        let plain = parse_form(form()).await.unwrap().submission_hash();
+        assert_eq!(parse_form(form()).await.unwrap().submission_hash(), plain);
+        // categorize is true unless told otherwise
+        assert_eq!(
+            parse_form(form().text("categorize", "true"))
+                .await
+                .unwrap()
+                .submission_hash(),
+            plain
+        );
+        for (name, value) in [
+            ("categorize", "false"),
+            ("deterministic", "true"),
+            ("intermediates", "true"),
+            ("quantization", "q8_0"),
+            ("categorizer", "embedding"),
+            ("animation_frames", "first"),
+        ] {
+            let task = parse_form(form().text(name, value)).await.unwrap();
+            assert_ne!(task.submission_hash(), plain, "{name}={value}");
+        }
+        let categories = |json| form().part("categories", json_part(json));
+        assert_eq!(
+            parse_form(categories(r#"["Food", "Rent"]"#))
+                .await
+                .unwrap()
+                .submission_hash(),
+            parse_form(categories(r#"["Rent", "Food", "Rent"]"#))
+                .await
+                .unwrap()
+                .submission_hash()
+        );
+        assert_ne!(
+            parse_form(categories(r#"["Food"]"#))
+                .await
+                .unwrap()
+                .submission_hash(),
+            parse_form(categories(r#"["Rent"]"#))
+                .await
+                .unwrap()
+                .submission_hash()
+        );
+        let options = parse_form(form().part("lm_options", json_part(r#"{"temperature": 0.5}"#)))
+            .await
+            .unwrap();
+        assert_ne!(options.submission_hash(), plain);
+    }
+
+    #[tokio::test]
+    async fn test_quantization() {
+        let q8: Quantization = "Q8_0".parse().unwrap();
+        assert_eq!(q8.to_string(), "q8_0");
+        assert!("q9_K".parse::<Quantization>().is_err());
+        assert_eq!(q8.apply("gemma4:e4b"), "gemma4/q8_0:e4b");
+        assert_eq!(q8.apply("llava"), "llava/q8_0");
+        assert_eq!(q8.apply("gemma4/q4_K_M:e4b"), "gemma4/q8_0:e4b");
+
+        let runner = OllamaRunTask {
+            caption_model: "caption:2b".into(),
+            extract_model: "extract".into(),
+            quantizations: vec!["q4_K_M".parse().unwrap(), q8],
+            ..Default::default()
+        };
+        let models = runner.model_status();
+        let ids = models
+            .iter()
+            .map(|status| status.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [
+                "caption/q4_K_M:2b",
+                "extract/q4_K_M",
+                "caption/q8_0:2b",
+                "extract/q8_0"
+            ]
+        );
+
+        let task = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .text("quantization", "q8_0"),
+        )
+        .await
+        .unwrap();
+        runner.check_quantization(&task).unwrap();
+        let task = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .text("quantization", "q5_0"),
+        )
+        .await
+        .unwrap();
+        let err = runner.check_quantization(&task).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "quantization q5_0 is not offered, supported: q4_K_M, q8_0"
+        );
+        // reaching the runner anyway fails the task instead of pulling q5_0
+        let err = runner
+            .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RunTaskError::QuantizationNotOffered(level) if level == "q5_0"));
+        assert_eq!(
+            runner
+                .check_quantization(&task)
+                .unwrap_err()
+                .into_response()
+                .status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        let err = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .text("quantization", "tiny"),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(err, CreateTaskError::UnknownQuantization { ref suggestion, .. } if suggestion.0.is_none())
+        );
+        let err = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .text("quantization", "q4km"),
+        )
+        .await
+        .unwrap_err();
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(
+            &axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(body["error"].as_str().unwrap().starts_with(
+            "unknown quantization q4km (did you mean q4_K_M?), expected one of q2_K,"
+        ));
+        assert_eq!(body["suggestion"], "q4_K_M");
+        assert_eq!(
+            body["supported"].as_array().unwrap().len(),
+            QUANTIZATIONS.len()
+        );
+
+        // only the offered levels are listed and suggested
+        let form = Form::new()
+            .part("image", image_part())
+            .text("quantization", "q4km");
+        let mut request = axum::extract::Request::builder()
+            .method("POST")
+            .uri("/create_task")
+            .header(
+                "Content-Type",
+                format!("multipart/form-data; boundary={}", form.boundary()),
+            )
+            .body(Body::from_stream(form.into_stream()))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(OfferedQuantizations(Arc::from(["q8_0".parse().unwrap()])));
+        let err = OllamaTaskDescriptor::from_request(request, &())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown quantization q4km, expected one of q8_0"
+        );
+    }
+
+    /// Sends `head` followed by endless chunks of `filler` with `content_type`,
+    /// returning the outcome and how many chunks were pulled from the body.
+    async fn parse_endless(
+        content_type: &str,
+        head: Vec<u8>,
+        filler: u8,
+        limit: usize,
+    ) -> (Result<OllamaTaskDescriptor, CreateTaskError>, usize) {
+        const CHUNK: usize = 16 * 1024;
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let chunks = futures::stream::iter(
+            std::iter::once(head).chain(std::iter::repeat(vec![filler; CHUNK])),
+        )
+        .then({
+            let pulled = pulled.clone();
+            move |chunk| {
+                pulled.fetch_add(1, Ordering::SeqCst);
+                // arrives piecemeal like a network body, or the multipart parser reads ahead
+                async move {
+                    tokio::task::yield_now().await;
+                    Ok::<_, std::io::Error>(chunk)
+                }
+            }
+        });
+        let mut request = axum::extract::Request::builder()
+            .method("POST")
+            .uri("/create_task")
+            .header("Content-Type", content_type)
+            .body(Body::from_stream(chunks))
+            .unwrap();
+        request.extensions_mut().insert(UploadLimit(limit));
+        let result = OllamaTaskDescriptor::from_request(request, &()).await;
+        (result, pulled.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_upload_aborted_early() {
+        let limit = 256 * 1024;
+        let (result, pulled) =
+            parse_endless("image/jpeg", SCREENSHOT[..1024].to_vec(), 0, limit).await;
+        assert!(matches!(
+            result,
+            Err(CreateTaskError::TooLarge { limit: 262144 })
+        ));
+        assert!(pulled <= limit / (16 * 1024) + 2, "pulled {pulled} chunks");
+
+        let form = |name: &str, content_type: &str, head: &str| {
+            format!(
+                "--X\r\nContent-Disposition: form-data; name=\"{name}\"; filename=\"f\"\r\nContent-Type: {content_type}\r\n\r\n{head}"
+            )
+            .into_bytes()
+        };
+        let multipart = "multipart/form-data; boundary=X";
+        let (result, pulled) = parse_endless(
+            multipart,
+            form("image", "image/png", "not an image at all"),
+            b'x',
+            usize::MAX,
+        )
+        .await;
+        assert!(
+            matches!(result, Err(CreateTaskError::UnsupportedImageFormat { .. })),
+            "{result:?}"
+        );
+        assert!(pulled < 8, "pulled {pulled} chunks");
+
+        let (result, pulled) =
+            parse_endless(multipart, form("photo", "image/png", ""), b'x', usize::MAX).await;
+        assert!(
+            matches!(result, Err(CreateTaskError::UnknownField { ref name, .. }) if name == "photo"),
+            "{result:?}"
+        );
+        assert!(pulled < 8, "pulled {pulled} chunks");
+
+        let response = CreateTaskError::TooLarge { limit }.into_response();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(response.headers()["connection"], "close");
+    }
+
+    #[tokio::test]
+    async fn test_ask() {
+        let prompts = Arc::new(std::sync::Mutex::new(Vec::<serde_json::Value>::new()));
+        let router = axum::Router::new().route(
+            "/api/generate",
+            axum::routing::post({
+                let prompts = prompts.clone();
+                async move |body: String| {
+                    prompts
+                        .lock()
+                        .unwrap()
+                        .push(serde_json::from_str(&body).unwrap());
+                    r#"{"model": "m", "created_at": "", "response": " Yes, 2 EUR. ", "done": true}"#
+                }
+            }),
+        );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            extract_model: "extract".into(),
+            ..Default::default()
+        };
+        let bill = Bill {
+            notes: "Pizza, delivery fee 2 EUR".into(),
+            amount: 18.5,
+            currency: Some("EUR".into()),
+            categories: vec!["Food".into()],
+            category_reason: None,
+            needs_review: false,
+        };
+        let answer = runner
+            .ask(
+                &bill,
+                Some("A receipt from Pizzeria Roma"),
+                "Was there a delivery fee?",
+            )
+            .await
+            .unwrap();
+        assert_eq!(answer, "Yes, 2 EUR.");
+        let prompts = prompts.lock().unwrap();
+        assert_eq!(prompts[0]["model"], "extract");
+        let prompt = prompts[0]["prompt"].as_str().unwrap();
+        assert!(prompt.contains("Pizza, delivery fee 2 EUR"));
+        assert!(prompt.contains("Pizzeria Roma"));
+        assert!(prompt.contains("Was there a delivery fee?"));
+        assert!(prompt.contains(r#""amount":18.5"#));
+    }
+
+    /// Runner answering every generation with notes and an amount alike,
+    /// recording the prompts.
+    async fn extraction_stub() -> (OllamaRunTask, Arc<std::sync::Mutex<Vec<String>>>) {
+        let prompts = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+        let router = axum::Router::new()
+            .route(
+                "/api/show",
+                axum::routing::post(async || r#"{"template": "{{ .Prompt }}"}"#),
+            )
+            .route(
+                "/api/generate",
+                axum::routing::post({
+                    let prompts = prompts.clone();
+                    async move |body: String| {
+                        let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+                        prompts
+                            .lock()
+                            .unwrap()
+                            .push(request["prompt"].as_str().unwrap().to_string());
+                        // notes and amount alike
+                        let response = r#"{"name": "Horse", "type": "toy", "amount": 21.88, "currency": "CNY"}"#;
+                        serde_json::json!({
+                            "model": "m",
+                            "created_at": "",
+                            "response": response,
+                            "done": true
+                        })
+                        .to_string()
+                    }
+                }),
+            );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            offline: true,
+            ..Default::default()
+        };
+        (runner, prompts)
+    }
+
+    #[tokio::test]
+    async fn test_skip_categorization() {
+        let (runner, prompts) = extraction_stub().await;
+        let task = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .text("categorize", "false"),
+        )
+        .await
+        .unwrap();
+        assert!(!task.categorize());
+        let bill = runner
+            .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap();
+        assert_eq!(bill.amount, 21.88);
+        assert!(bill.categories.is_empty());
+        assert!(!bill.needs_review);
+        let prompts = prompts.lock().unwrap().clone();
+        assert_eq!(prompts.len(), 3, "description, notes and amount only");
+        let category_prompt = &include_str!("../../prompt/categorization.md")[..40];
+        assert!(
+            !prompts
+                .iter()
+                .any(|prompt| prompt.contains(category_prompt))
+        );
+        let bounded = OllamaRunTask {
+            amount_bounds: AmountBounds {
+                min: Some(1.0),
+                max: Some(20.0),
+            },
+            ..runner.clone()
+        };
+        let bill = bounded
+            .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap();
+        assert_eq!(bill.amount, 21.88, "kept, but for review");
+        assert!(bill.needs_review);
+
+        let err = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .text("categorize", "sometimes"),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, CreateTaskError::InvalidField(name) if name == "categorize"));
+    }
+
+    #[tokio::test]
+    async fn test_intermediates() {
+        let (runner, _) = extraction_stub().await;
+        let scheduler = crate::Scheduler::new(1, 16, Duration::from_mins(5), runner).unwrap();
+        let task = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .text("categorize", "false")
+                .text("intermediates", "true"),
+        )
+        .await
+        .unwrap();
+        let tcb = scheduler.create_task(task).await;
+        tcb.finished().await.unwrap();
+        let outputs = tcb.intermediates().unwrap();
+        assert_eq!(
+            outputs.keys().copied().collect::<Vec<_>>(),
+            [Stage::Description, Stage::Notes, Stage::Amount]
+        );
+        assert!(outputs[&Stage::Amount].contains("21.88"));
+
+        let task = parse_form(Form::new().part("image", image_part()))
+            .await
+            .unwrap();
+        assert!(scheduler.create_task(task).await.intermediates().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_prompts() {
+        let (runner, _) = extraction_stub().await;
+        let form = || {
+            parse_form(
+                Form::new()
+                    .part("image", image_part())
+                    .text("categorize", "false"),
+            )
+        };
+        let task = form().await.unwrap();
+        let scheduler = crate::Scheduler::new(1, 16, Duration::from_mins(5), runner).unwrap();
+        let tcb = scheduler.create_task(task).await;
+        tcb.finished().await.unwrap();
+        assert!(tcb.prompts().is_none(), "off by default");
+
+        let (runner, sent) = extraction_stub().await;
+        let runner = OllamaRunTask {
+            prompt_retention: PromptRetention::Redacted,
+            ..runner
+        };
+        let task = form().await.unwrap();
+        runner
+            .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap();
+        let kept = task.prompts().unwrap().texts();
+        assert_eq!(
+            kept.keys().copied().collect::<Vec<_>>(),
+            [Stage::Description, Stage::Notes, Stage::Amount]
+        );
+        assert_eq!(kept[&Stage::Description], DESCRIPTION_PROMPT);
+        assert_eq!(kept[&Stage::Notes], note_prompt(REDACTED));
+        assert!(kept.values().all(|prompt| !prompt.contains("Horse")));
+        assert!(sent.lock().unwrap().iter().any(|p| p.contains("Horse")));
+
+        let runner = OllamaRunTask {
+            prompt_retention: PromptRetention::Full,
+            max_prompt_size: 64,
+            ..runner
+        };
+        let task = form().await.unwrap();
+        runner
+            .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap();
+        let kept = task.prompts().unwrap().texts();
+        assert_eq!(kept[&Stage::Description], truncate(DESCRIPTION_PROMPT, 64));
+        assert!(kept.values().all(|prompt| prompt.len() <= 64));
+    }
+
+    #[tokio::test]
+    async fn test_deterministic() {
+        let seeds = Arc::new(std::sync::Mutex::new(Vec::<serde_json::Value>::new()));
+        let router = axum::Router::new()
+            .route(
+                "/api/show",
+                axum::routing::post(async || r#"{"template": "{{ .Prompt }}"}"#),
+            )
+            .route(
+                "/api/generate",
+                axum::routing::post({
+                    let seeds = seeds.clone();
+                    async move |body: String| {
+                        let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+                        seeds.lock().unwrap().push(request["options"]["seed"].clone());
+                        let response = r#"{"name": "Horse", "type": "toy", "amount": 21.88, "currency": "CNY"}"#;
+                        serde_json::json!({
+                            "model": "m",
+                            "created_at": "",
+                            "response": response,
+                            "done": true
+                        })
+                        .to_string()
                     }
-                }
+                }),
+            );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            offline: true,
+            ..Default::default()
+        };
+        let form = |deterministic: Option<&'static str>| {
+            let mut form = Form::new()
+                .part("image", image_part())
+                .part("lm_options", json_part(r#"{"temperature": 0.9}"#))
+                .text("categorize", "false");
+            if let Some(deterministic) = deterministic {
+                form = form.text("deterministic", deterministic);
             }
-        } else {
-            let mime = content_type.to_string();
-            let buf: Bytes = req.extract().await?;
-            images_buf = Some(get_images_buf(buf, &mime)?);
+            parse_form(form)
+        };
+        let mut bills = Vec::new();
+        for _ in 0..2 {
+            let task = form(Some("true")).await.unwrap();
+            let bill = runner
+                .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+                .await
+                .unwrap();
+            bills.push(serde_json::to_string(&bill).unwrap());
+            assert_eq!(
+                task.seeds().unwrap().seeds(),
+                BTreeMap::from([
+                    (Stage::Description, DETERMINISTIC_SEED),
+                    (Stage::Notes, DETERMINISTIC_SEED),
+                    (Stage::Amount, DETERMINISTIC_SEED),
+                ])
+            );
         }
-        if images_buf.is_none() {
-            return Err(CreateTaskError::MissingField("image".to_string()));
+        assert_eq!(bills[0], bills[1]);
+        let sent = std::mem::take(&mut *seeds.lock().unwrap());
+        assert_eq!(sent.len(), 6);
+        assert!(sent.iter().all(|seed| *seed == DETERMINISTIC_SEED));
+
+        // the server default, which tasks may turn off
+        let runner = OllamaRunTask {
+            deterministic: true,
+            ..runner
+        };
+        let task = form(None).await.unwrap();
+        runner
+            .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap();
+        let task = form(Some("false")).await.unwrap();
+        runner
+            .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap();
+        assert!(task.seeds().unwrap().seeds().is_empty());
+        let sent = std::mem::take(&mut *seeds.lock().unwrap());
+        assert!(sent[..3].iter().all(|seed| *seed == DETERMINISTIC_SEED));
+        assert!(sent[3..].iter().all(serde_json::Value::is_null));
+
+        let err = form(Some("maybe")).await.unwrap_err();
+        assert!(matches!(err, CreateTaskError::InvalidField(name) if name == "deterministic"));
+    }
+
+    /// Needs Ollama with the default models, and the receipt in `RECEIPT`.
+    #[tokio::test]
+    #[ignore = "runs the default models on a live Ollama"]
+    async fn test_deterministic_live() {
+        let receipt = std::fs::read(std::env::var("RECEIPT").unwrap()).unwrap();
+        let runner = OllamaRunTask {
+            deterministic: true,
+            ..Default::default()
+        };
+        let mut bills = Vec::new();
+        for _ in 0..2 {
+            let task = OllamaTaskDescriptor::from_images(vec![receipt.clone()]);
+            let bill = runner
+                .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+                .await
+                .unwrap();
+            bills.push(serde_json::to_string(&bill).unwrap());
         }
+        assert_eq!(bills[0], bills[1]);
+    }
 
-        Ok(Self {
-            images_buf: images_buf.unwrap(),
-            lm_options,
-            vlm_options,
-            categories,
-        })
+    #[tokio::test]
+    async fn test_stage_models() {
+        let models = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+        let router = axum::Router::new()
+            .route(
+                "/api/show",
+                axum::routing::post(async || r#"{"template": "{{ .Prompt }}"}"#),
+            )
+            .route(
+                "/api/generate",
+                axum::routing::post({
+                    let models = models.clone();
+                    async move |body: String| {
+                        let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+                        let model = request["model"].as_str().unwrap().to_string();
+                        let response = if model == "small" {
+                            r#"{"categories": ["Food"], "reason": "Tea is a drink."}"#
+                        } else {
+                            r#"{"name": "Tea", "type": "drink", "amount": 3.5, "currency": "EUR"}"#
+                        };
+                        models.lock().unwrap().push(model);
+                        serde_json::json!({
+                            "model": "m",
+                            "created_at": "",
+                            "response": response,
+                            "done": true
+                        })
+                        .to_string()
+                    }
+                }),
+            );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            caption_model: "caption".into(),
+            extract_model: "extract".into(),
+            stage_models: HashMap::from([(Stage::Category, "small".into())]),
+            offline: true,
+            ..Default::default()
+        };
+        let roles = runner
+            .model_status()
+            .into_iter()
+            .map(|status| (status.id.to_string(), status.roles))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            roles,
+            [
+                ("caption".to_string(), vec!["caption"]),
+                ("extract".to_string(), vec!["extract"]),
+                ("small".to_string(), vec!["category"]),
+            ]
+        );
+
+        let task = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .part("categories", json_part(r#"["Food", "Rent"]"#)),
+        )
+        .await
+        .unwrap();
+        let bill = runner
+            .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap();
+        assert_eq!(bill.amount, 3.5);
+        assert_eq!(bill.categories, ["Food"]);
+        assert_eq!(bill.category_reason.as_deref(), Some("Tea is a drink."));
+        let mut models = models.lock().unwrap().clone();
+        models.sort();
+        assert_eq!(models, ["caption", "caption", "extract", "small"]);
     }
-}
 
-#[derive(JsonSchema, Deserialize)]
-struct Notes {
-    name: String,
-    #[schemars(rename = "type")]
-    #[serde(rename = "type")]
-    type_: String,
-    retailer: Option<String>,
-}
+    #[tokio::test]
+    async fn test_embedding_categorizer() {
+        let embedded = Arc::new(std::sync::Mutex::new(Vec::<usize>::new()));
+        let generate = async |body: String| {
+            let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+            let response = if request["format"]["properties"]["categories"].is_object() {
+                r#"{"categories": ["Rent"], "reason": "Asked the LM."}"#
+            } else {
+                r#"{"name": "Tea", "type": "drink", "amount": 3.5, "currency": "EUR"}"#
+            };
+            serde_json::json!({
+                "model": "m",
+                "created_at": "",
+                "response": response,
+                "done": true
+            })
+            .to_string()
+        };
+        let lm_only = axum::Router::new()
+            .route(
+                "/api/show",
+                axum::routing::post(async || r#"{"template": "{{ .Prompt }}"}"#),
+            )
+            .route("/api/generate", axum::routing::post(generate));
+        let with_embeddings = lm_only.clone().route(
+            "/api/embed",
+            axum::routing::post({
+                let embedded = embedded.clone();
+                async move |body: String| {
+                    let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+                    let input = request["input"].as_array().unwrap();
+                    embedded.lock().unwrap().push(input.len());
+                    let embeddings = input
+                        .iter()
+                        .map(|text| match text.as_str().unwrap() {
+                            "Food" => [0.9, 0.1],
+                            "Rent" => [0.0, 1.0],
+                            _ => [1.0, 0.0],
+                        })
+                        .collect::<Vec<_>>();
+                    serde_json::json!({ "embeddings": embeddings }).to_string()
+                }
+            }),
+        );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(with_embeddings.clone()).await,
+            caption_model: "caption".into(),
+            extract_model: "extract".into(),
+            categorizer: Categorizer::Embedding,
+            embedding_model: "embed".into(),
+            offline: true,
+            ..Default::default()
+        };
+        assert!(
+            runner
+                .model_status()
+                .iter()
+                .any(|status| status.id == "embed" && status.roles == [EMBEDDING_ROLE])
+        );
+        runner.check_chat_templates().await.unwrap();
 
-impl Display for Notes {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if let Some(retailer) = &self.retailer {
-            write!(f, "{} \"{}\" from {}", self.type_, self.name, retailer)
-        } else {
-            write!(f, "{} \"{}\"", self.type_, self.name)
+        let task = || async {
+            parse_form(
+                Form::new()
+                    .part("image", image_part())
+                    .part("categories", json_part(r#"["Food", "Rent"]"#)),
+            )
+            .await
+            .unwrap()
+        };
+        for _ in 0..2 {
+            let bill = runner
+                .extract(&task().await, &tokio::sync::broadcast::Sender::new(1))
+                .await
+                .unwrap();
+            assert_eq!(bill.categories, ["Food"]);
+            assert_eq!(bill.category_reason, None);
+            assert!(!bill.needs_review);
         }
+        // the categories are embedded once, along with the first description
+        assert_eq!(*embedded.lock().unwrap(), [3, 1]);
+
+        // without embeddings the LM picks the category
+        let runner = OllamaRunTask {
+            ollama: serve_stub(lm_only).await,
+            ..runner
+        };
+        let bill = runner
+            .extract(&task().await, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap();
+        assert_eq!(bill.categories, ["Rent"]);
+        assert_eq!(bill.category_reason.as_deref(), Some("Asked the LM."));
+
+        // tasks asking for embeddings on a server categorizing with the LM pull the model
+        let pulled = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+        let router = with_embeddings
+            .route(
+                "/api/tags",
+                axum::routing::get(async || {
+                    r#"{"models": [{"name": "caption", "modified_at": "", "size": 0}, {"name": "extract", "modified_at": "", "size": 0}]}"#
+                }),
+            )
+            .route(
+                "/api/pull",
+                axum::routing::post({
+                    let pulled = pulled.clone();
+                    async move |body: String| {
+                        let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+                        pulled
+                            .lock()
+                            .unwrap()
+                            .push(request["name"].as_str().unwrap().to_string());
+                        "{\"status\": \"success\"}\n"
+                    }
+                }),
+            );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            categorizer: Categorizer::Lm,
+            offline: false,
+            ..runner
+        };
+        assert!(
+            runner
+                .model_status()
+                .iter()
+                .all(|status| status.id != "embed")
+        );
+        let task = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .part("categories", json_part(r#"["Food", "Rent"]"#))
+                .text("categorizer", "embedding"),
+        )
+        .await
+        .unwrap();
+        let bill = runner
+            .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap();
+        assert_eq!(bill.categories, ["Food"]);
+        assert_eq!(*pulled.lock().unwrap(), ["embed"]);
+
+        let task = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .text("categorizer", "lm"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(task.categorizer, Some(Categorizer::Lm));
+        let err = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .text("categorizer", "nearest"),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, CreateTaskError::InvalidField(name) if name == "categorizer"));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use tracing_test::traced_test;
+    #[tokio::test]
+    async fn test_empty_responses() {
+        use strum::IntoEnumIterator;
 
-    use super::*;
+        /// Stage a generation request is for, told apart by its output format.
+        fn stage_of(request: &serde_json::Value) -> Stage {
+            let properties = &request["format"]["properties"];
+            if properties.is_null() {
+                Stage::Description
+            } else if properties["categories"].is_object() {
+                Stage::Category
+            } else if properties["amount"].is_object() {
+                Stage::Amount
+            } else {
+                Stage::Notes
+            }
+        }
+
+        for empty in Stage::iter() {
+            let router = axum::Router::new()
+                .route(
+                    "/api/show",
+                    axum::routing::post(async || r#"{"template": "{{ .Prompt }}"}"#),
+                )
+                .route(
+                    "/api/generate",
+                    axum::routing::post(async move |body: String| {
+                        let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+                        let response = match stage_of(&request) {
+                            stage if stage == empty => "<think>nothing to say</think>",
+                            Stage::Category => r#"{"categories": ["Food"], "reason": "Tea."}"#,
+                            _ => r#"{"name": "Tea", "type": "drink", "amount": 3.5}"#,
+                        };
+                        serde_json::json!({
+                            "model": "m",
+                            "created_at": "",
+                            "response": response,
+                            "done": true
+                        })
+                        .to_string()
+                    }),
+                );
+            let runner = OllamaRunTask {
+                ollama: serve_stub(router).await,
+                offline: true,
+                ..Default::default()
+            };
+            let task = parse_form(
+                Form::new()
+                    .part("image", image_part())
+                    .part("categories", json_part(r#"["Food"]"#)),
+            )
+            .await
+            .unwrap();
+            let err = runner
+                .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(err, RunTaskError::EmptyModelResponse(stage) if stage == empty),
+                "{empty}: {err}"
+            );
+            assert_eq!(err.stage(), Some(empty));
+            assert!(err.retryable());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_no_categories() {
+        let (runner, prompts) = extraction_stub().await;
+        let task = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .part("categories", json_part("[]")),
+        )
+        .await
+        .unwrap();
+        assert!(task.categorize());
+        assert!(task.category_names().is_empty());
+        let bill = runner
+            .extract(&task, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap();
+        assert!(bill.categories.is_empty());
+        assert!(!bill.needs_review, "nothing to review without categories");
+        assert_eq!(prompts.lock().unwrap().len(), 3, "no category stage");
+
+        let err = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .part("categories", json_part(r#"["Food", "Rent\nTravel"]"#)),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, CreateTaskError::InvalidField(name) if name == "categories"));
+    }
+
+    #[tokio::test]
+    async fn test_describe() {
+        let requests = Arc::new(std::sync::Mutex::new(Vec::<serde_json::Value>::new()));
+        let router = axum::Router::new()
+            .route(
+                "/api/show",
+                axum::routing::post(async || r#"{"template": "{{ .Prompt }}"}"#),
+            )
+            .route(
+                "/api/generate",
+                axum::routing::post({
+                    let requests = requests.clone();
+                    async move |body: String| {
+                        requests
+                            .lock()
+                            .unwrap()
+                            .push(serde_json::from_str(&body).unwrap());
+                        serde_json::json!({
+                            "model": "m",
+                            "created_at": "",
+                            "response": "<think>a receipt</think>A receipt of a toy horse",
+                            "done": true
+                        })
+                        .to_string()
+                    }
+                }),
+            );
+        let runner = OllamaRunTask {
+            ollama: serve_stub(router).await,
+            offline: true,
+            ..Default::default()
+        };
+        let task = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .part("lm_options", json_part(r#"{"temperature": 0.9}"#))
+                .part("vlm_options", json_part(r#"{"temperature": 0.1}"#)),
+        )
+        .await
+        .unwrap();
+        let description = runner
+            .describe(&task, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap();
+        assert_eq!(description, "A receipt of a toy horse");
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 1, "the description stage only");
+        assert_eq!(
+            requests[0]["prompt"],
+            include_str!("../../prompt/description.md")
+        );
+        assert_eq!(requests[0]["options"]["temperature"], 0.1);
+        assert_eq!(requests[0]["images"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_form_field_hints() {
+        let err = parse_form(Form::new().part("Image", image_part()))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown field: Image (did you mean image?), expected image, lm_options, vlm_options, categories, animation_frames, quantization, preprocess, categorize, categorizer, intermediates, deterministic, received Image"
+        );
+        let err = parse_form(
+            Form::new()
+                .part("categories", json_part(r#"["Food"]"#))
+                .part("photo", image_part()),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            &err,
+            CreateTaskError::UnknownField { name, suggestion, received, .. }
+                if name == "photo" && suggestion.0.is_none() && received.0 == ["categories", "photo"]
+        ));
+        let err = parse_form(Form::new().part("categories", json_part(r#"["Food"]"#)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "missing field: image, received categories");
+        let err = parse_form(
+            Form::new()
+                .part("image", image_part())
+                .text("animation-frame", "first"),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            CreateTaskError::UnknownField { suggestion, .. }
+                if suggestion.0.as_deref() == Some("animation_frames")
+        ));
+    }
 
     #[tokio::test]
     #[traced_test]
@@ -437,9 +3885,21 @@ mod tests {
                 "Transport".into(),
                 "Rent".into(),
             ]),
+            animation_frames: None,
+            quantization: None,
+            preprocess: None,
+            categorize: None,
+            categorizer: None,
+            intermediates: None,
+            prompts: StageTexts::default(),
+            deterministic: None,
+            seeds: StageSeeds::default(),
         };
         let runner = OllamaRunTask::default();
-        let bill = runner.extract(&req).await.unwrap();
+        let bill = runner
+            .extract(&req, &tokio::sync::broadcast::Sender::new(1))
+            .await
+            .unwrap();
         event!(Level::INFO, "{:#?}", bill);
     }
 }
@@ -0,0 +1,268 @@
+use std::str::FromStr;
+
+use image::{DynamicImage, GenericImageView, GrayImage, Luma, Rgba};
+use serde::{Deserialize, Serialize};
+use strum::Display;
+
+/// Whether uploads are cleaned up before the VLM sees them, chosen per task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Preprocess {
+    /// Crops borders, and enhances photos of paper receipts
+    Auto,
+    #[default]
+    Off,
+}
+
+impl FromStr for Preprocess {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "auto" => Ok(Self::Auto),
+            "off" => Ok(Self::Off),
+            other => Err(format!(
+                "invalid preprocessing {other:?}, expected auto or off"
+            )),
+        }
+    }
+}
+
+/// A step [auto] took, logged so the result can be told apart from the upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    Grayscale,
+    /// Luma from `low` to `high` spread over the full range
+    ContrastStretch {
+        low: u8,
+        high: u8,
+    },
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => write!(f, "crop to {width}x{height} at {x},{y}"),
+            Operation::Grayscale => write!(f, "grayscale"),
+            Operation::ContrastStretch { low, high } => {
+                write!(f, "contrast stretch of {low}..={high}")
+            }
+        }
+    }
+}
+
+/// Channel spread below which a pixel counts as colorless.
+const GRAY_CHROMA: u8 = 40;
+/// Share of colorless pixels that makes an image look like paper rather than a screenshot.
+const PAPER_GRAY_SHARE: f32 = 0.9;
+/// Difference to the corner color still counted as the same border.
+const BORDER_TOLERANCE: u8 = 12;
+/// Share of a side a border must take up to be cropped.
+const MIN_BORDER_SHARE: f32 = 0.05;
+/// Luma range narrower than this is worth stretching.
+const MAX_STRETCHED_RANGE: u8 = 200;
+/// Share of the darkest and brightest pixels ignored when finding the luma range.
+const STRETCH_CLIP: f32 = 0.01;
+
+/// Crops large uniform borders, then turns dim photos of paper receipts, nearly
+/// colorless and low in contrast, into contrast-stretched grayscale. Other
+/// images keep their colors.
+pub fn auto(image: DynamicImage) -> (DynamicImage, Vec<Operation>) {
+    let mut operations = Vec::new();
+    let mut image = image;
+    if let Some(
+        crop @ Operation::Crop {
+            x,
+            y,
+            width,
+            height,
+        },
+    ) = border_crop(&image)
+    {
+        image = image.crop_imm(x, y, width, height);
+        operations.push(crop);
+    }
+    // rendered screenshots are mostly gray too, but span the whole luma range
+    if looks_like_paper(&image) {
+        let gray = image.to_luma8();
+        if let Some((low, high)) = luma_range(&gray)
+            && high - low < MAX_STRETCHED_RANGE
+        {
+            operations.push(Operation::Grayscale);
+            operations.push(Operation::ContrastStretch { low, high });
+            image = DynamicImage::ImageLuma8(stretch(gray, low, high));
+        }
+    }
+    (image, operations)
+}
+
+/// Whether nearly every pixel is some shade of gray, as paper and ink are.
+pub fn looks_like_paper(image: &DynamicImage) -> bool {
+    let rgb = image.to_rgb8();
+    let total = rgb.pixels().len();
+    if total == 0 {
+        return false;
+    }
+    let gray = rgb
+        .pixels()
+        .filter(|pixel| {
+            let [r, g, b] = pixel.0;
+            r.max(g).max(b) - r.min(g).min(b) < GRAY_CHROMA
+        })
+        .count();
+    gray as f32 / total as f32 >= PAPER_GRAY_SHARE
+}
+
+/// Luma of the darkest and brightest pixels, leaving out the extreme [STRETCH_CLIP].
+pub fn luma_range(gray: &GrayImage) -> Option<(u8, u8)> {
+    let mut histogram = [0usize; 256];
+    for Luma([luma]) in gray.pixels() {
+        histogram[*luma as usize] += 1;
+    }
+    let total = gray.pixels().len();
+    let clipped = (total as f32 * STRETCH_CLIP) as usize;
+    let percentile = |levels: &mut dyn Iterator<Item = usize>| {
+        let mut seen = 0;
+        for level in levels {
+            seen += histogram[level];
+            if seen > clipped {
+                return Some(level);
+            }
+        }
+        None
+    };
+    let low = percentile(&mut (0..256))?;
+    let high = percentile(&mut (0..256).rev())?;
+    (low < high).then_some((low as u8, high as u8))
+}
+
+/// Spreads luma from `low` to `high` over 0 to 255, clamping what lies outside.
+pub fn stretch(mut gray: GrayImage, low: u8, high: u8) -> GrayImage {
+    let span = (high - low) as u32;
+    for Luma([luma]) in gray.pixels_mut() {
+        let offset = (*luma).clamp(low, high) - low;
+        *luma = ((offset as u32 * 255 + span / 2) / span) as u8;
+    }
+    gray
+}
+
+/// The part inside borders of the corner color, if they are wide enough to matter.
+pub fn border_crop(image: &DynamicImage) -> Option<Operation> {
+    let (width, height) = image.dimensions();
+    if width < 3 || height < 3 {
+        return None;
+    }
+    let corner = image.get_pixel(0, 0);
+    let same = |x, y| close(image.get_pixel(x, y), corner);
+    let uniform_row = |y| (0..width).all(|x| same(x, y));
+    let uniform_column = |x| (0..height).all(|y| same(x, y));
+
+    let top = (0..height).take_while(|y| uniform_row(*y)).count() as u32;
+    if top == height {
+        return None;
+    }
+    let bottom = (0..height).rev().take_while(|y| uniform_row(*y)).count() as u32;
+    let left = (0..width).take_while(|x| uniform_column(*x)).count() as u32;
+    let right = (0..width).rev().take_while(|x| uniform_column(*x)).count() as u32;
+    let large = |border: u32, side: u32| border as f32 >= side as f32 * MIN_BORDER_SHARE;
+    if !(large(top + bottom, height) || large(left + right, width)) {
+        return None;
+    }
+    Some(Operation::Crop {
+        x: left,
+        y: top,
+        width: width - left - right,
+        height: height - top - bottom,
+    })
+}
+
+fn close(a: Rgba<u8>, b: Rgba<u8>) -> bool {
+    a.0.iter()
+        .zip(b.0)
+        .all(|(a, b)| a.abs_diff(b) <= BORDER_TOLERANCE)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    const DIM_RECEIPT: &[u8] = include_bytes!("../../asset/dim-receipt.png");
+    const SCREENSHOT: &[u8] = include_bytes!("../../asset/second-hand-horse-screenshot.jpeg");
+
+    #[test]
+    fn test_dim_receipt() {
+        let receipt = image::load_from_memory(DIM_RECEIPT).unwrap();
+        let (processed, operations) = auto(receipt.clone());
+        assert_eq!(
+            operations,
+            [
+                Operation::Crop {
+                    x: 20,
+                    y: 16,
+                    width: 80,
+                    height: 128
+                },
+                Operation::Grayscale,
+                Operation::ContrastStretch { low: 42, high: 88 },
+            ]
+        );
+        let gray = processed.as_luma8().unwrap();
+        assert_eq!(gray.dimensions(), (80, 128));
+        assert_eq!(luma_range(gray), Some((0, 255)));
+        // the upload itself is left alone
+        assert_eq!(receipt.dimensions(), (120, 160));
+    }
+
+    #[test]
+    fn test_screenshot_untouched() {
+        let screenshot = image::load_from_memory(SCREENSHOT).unwrap();
+        let (processed, operations) = auto(screenshot.clone());
+        assert!(operations.is_empty(), "{operations:?}");
+        assert_eq!(processed, screenshot);
+    }
+
+    #[test]
+    fn test_border_crop() {
+        let mut image = RgbImage::from_pixel(40, 40, Rgb([0, 0, 0]));
+        image.put_pixel(10, 12, Rgb([200, 0, 0]));
+        image.put_pixel(29, 30, Rgb([200, 0, 0]));
+        assert_eq!(
+            border_crop(&DynamicImage::ImageRgb8(image)),
+            Some(Operation::Crop {
+                x: 10,
+                y: 12,
+                width: 20,
+                height: 19
+            })
+        );
+        let blank = DynamicImage::ImageRgb8(RgbImage::new(40, 40));
+        assert_eq!(border_crop(&blank), None);
+        let mut thin = RgbImage::from_pixel(100, 100, Rgb([255, 255, 255]));
+        thin.put_pixel(1, 1, Rgb([0, 0, 0]));
+        thin.put_pixel(98, 98, Rgb([0, 0, 0]));
+        assert_eq!(border_crop(&DynamicImage::ImageRgb8(thin)), None);
+    }
+
+    #[test]
+    fn test_stretch() {
+        let gray = GrayImage::from_fn(3, 1, |x, _| Luma([[40, 65, 90][x as usize]]));
+        let stretched = stretch(gray, 40, 90);
+        assert_eq!(stretched.as_raw(), &[0, 128, 255]);
+        assert_eq!("auto".parse(), Ok(Preprocess::Auto));
+        assert!("sharpen".parse::<Preprocess>().is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use std::{str::FromStr, time::Duration};
+
+use chrono::{DateTime, FixedOffset, NaiveTime, Utc};
+use serde::Serialize;
+use strum::Display;
+
+/// Keep alive of models used during quiet hours unless configured otherwise,
+/// long enough for the stages of one task to share a load.
+pub const DEFAULT_QUIET_KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// How long models stay loaded in Ollama right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Residency {
+    /// The configured keep alive
+    Normal,
+    /// The short keep alive of [QuietHours]
+    Quiet,
+}
+
+/// Daily span of `HH:MM-HH:MM`, wrapping past midnight if it ends before it
+/// starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DailySpan {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl FromStr for DailySpan {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid span {value:?}, expected HH:MM-HH:MM");
+        let (start, end) = value.trim().split_once('-').ok_or_else(invalid)?;
+        let time =
+            |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid());
+        let span = Self {
+            start: time(start)?,
+            end: time(end)?,
+        };
+        if span.start == span.end {
+            return Err(format!("span {value:?} is empty"));
+        }
+        Ok(span)
+    }
+}
+
+impl DailySpan {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Hours of the day in which models are let go of quickly, so an idle GPU can
+/// cool down. Tasks run as usual, only how long their models stay loaded changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuietHours {
+    pub span: DailySpan,
+    /// Offset of the clock `span` is read on
+    pub offset: FixedOffset,
+    /// Keep alive of models used during the span
+    pub keep_alive: Duration,
+}
+
+impl QuietHours {
+    pub fn residency_at(&self, now: DateTime<Utc>) -> Residency {
+        if self.span.contains(now.with_timezone(&self.offset).time()) {
+            Residency::Quiet
+        } else {
+            Residency::Normal
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quiet_hours() {
+        let quiet = QuietHours {
+            span: "01:00-07:00".parse().unwrap(),
+            offset: "+08:00".parse().unwrap(),
+            keep_alive: Duration::ZERO,
+        };
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().to_utc();
+        assert_eq!(
+            quiet.residency_at(at("2026-03-01T16:59:59Z")),
+            Residency::Normal
+        );
+        assert_eq!(
+            quiet.residency_at(at("2026-03-01T17:00:00Z")),
+            Residency::Quiet
+        );
+        assert_eq!(
+            quiet.residency_at(at("2026-03-01T22:59:59Z")),
+            Residency::Quiet
+        );
+        assert_eq!(
+            quiet.residency_at(at("2026-03-01T23:00:00Z")),
+            Residency::Normal
+        );
+
+        let overnight = QuietHours {
+            span: "23:30-06:00".parse().unwrap(),
+            offset: "+00:00".parse().unwrap(),
+            ..quiet
+        };
+        assert_eq!(
+            overnight.residency_at(at("2026-03-01T23:45:00Z")),
+            Residency::Quiet
+        );
+        assert_eq!(
+            overnight.residency_at(at("2026-03-02T05:59:00Z")),
+            Residency::Quiet
+        );
+        assert_eq!(
+            overnight.residency_at(at("2026-03-02T12:00:00Z")),
+            Residency::Normal
+        );
+
+        assert!("07:00-07:00".parse::<DailySpan>().is_err());
+        assert!("1am-7am".parse::<DailySpan>().is_err());
+    }
+}
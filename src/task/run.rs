@@ -1,7 +1,131 @@
-use crate::{bill::Bill, error::RunTaskError};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter, EnumString, IntoStaticStr};
+use tokio::sync::broadcast;
+
+use crate::{bill::Bill, error::RunTaskError, task::TaskDescriptor};
 
 #[trait_variant::make(Send)]
 pub trait RunTask {
-    type TaskDescriptor;
-    async fn extract(&self, task: &Self::TaskDescriptor) -> Result<Bill, RunTaskError>;
+    type TaskDescriptor: TaskDescriptor;
+    async fn extract(
+        &self,
+        task: &Self::TaskDescriptor,
+        tokens: &TokenSender,
+    ) -> Result<Bill, RunTaskError>;
+
+    /// Lets go of what the runner holds while idle, asked when the memory
+    /// usage nears `--memory-limit` and no task runs.
+    fn release_memory(&self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+/// Pipeline stages a task goes through, in order.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Display,
+    EnumString,
+    EnumIter,
+    IntoStaticStr,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Stage {
+    Description,
+    Notes,
+    Amount,
+    Category,
+}
+
+/// A piece of model output published while a stage is generating.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Token {
+    pub stage: Stage,
+    pub kind: TokenKind,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum TokenKind {
+    /// The stage began, `text` is empty. Sent even if nobody listens yet
+    Started,
+    Thinking,
+    Response,
+    /// The stage completed, `text` is empty
+    Done,
+}
+
+pub type TokenSender = broadcast::Sender<Token>;
+
+/// Text kept per stage of a task, like what each stage generated for tasks
+/// asking to see it or the prompts it was given. Shared by the descriptor the
+/// runner writes to and the task read from.
+#[derive(Debug, Clone, Default)]
+pub struct StageTexts(Arc<Mutex<BTreeMap<Stage, String>>>);
+
+impl StageTexts {
+    /// Keeps `text` as the one of `stage`, replacing that of a former run.
+    pub fn record(&self, stage: Stage, text: &str) {
+        self.0.lock().unwrap().insert(stage, text.to_string());
+    }
+
+    pub fn texts(&self) -> BTreeMap<Stage, String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl From<BTreeMap<Stage, String>> for StageTexts {
+    fn from(texts: BTreeMap<Stage, String>) -> Self {
+        Self(Arc::new(Mutex::new(texts)))
+    }
+}
+
+/// Seed each stage of a task sampled with, recorded for tasks run
+/// deterministically. Shared like [StageTexts].
+#[derive(Debug, Clone, Default)]
+pub struct StageSeeds(Arc<Mutex<BTreeMap<Stage, i32>>>);
+
+impl StageSeeds {
+    pub fn record(&self, stage: Stage, seed: i32) {
+        self.0.lock().unwrap().insert(stage, seed);
+    }
+
+    pub fn seeds(&self) -> BTreeMap<Stage, i32> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl From<BTreeMap<Stage, i32>> for StageSeeds {
+    fn from(seeds: BTreeMap<Stage, i32>) -> Self {
+        Self(Arc::new(Mutex::new(seeds)))
+    }
+}
+
+/// How much of the rendered prompts is kept on tasks for `GET /task/{id}/prompts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum PromptRetention {
+    /// Nothing is kept
+    #[default]
+    Off,
+    /// The prompts with the description and notes of the receipt, which may
+    /// carry personal data, left out
+    Redacted,
+    Full,
 }
@@ -0,0 +1,69 @@
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Redirect},
+    routing::get,
+};
+
+use crate::state::AppState;
+
+const INDEX: &str = include_str!("../ui/index.html");
+const APP_JS: &str = include_str!("../ui/app.js");
+
+/// Review page driven by the `/v1` API. The page itself is public, the key it asks
+/// for is kept in localStorage and sent along with every API call.
+pub fn router() -> axum::Router<AppState> {
+    axum::Router::new()
+        .route(
+            "/ui",
+            get(async |state: State<AppState>| {
+                Redirect::permanent(&format!("{}/ui/", state.base_path()))
+            }),
+        )
+        .route("/ui/", get(index))
+        .route("/ui/app.js", get(app_js))
+}
+
+async fn index() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/html; charset=utf-8")], INDEX)
+}
+
+async fn app_js() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/javascript; charset=utf-8")],
+        APP_JS,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, extract::Request, http::StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::args;
+
+    async fn get_status(enable_ui: bool, uri: &str) -> StatusCode {
+        let state = AppState::new(&args::App {
+            enable_ui,
+            ..Default::default()
+        })
+        .unwrap();
+        crate::server::app(state)
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_ui_gated() {
+        assert_eq!(get_status(true, "/ui/").await, StatusCode::OK);
+        assert_eq!(get_status(true, "/ui/app.js").await, StatusCode::OK);
+        assert_eq!(
+            get_status(true, "/ui").await,
+            StatusCode::PERMANENT_REDIRECT
+        );
+        assert_eq!(get_status(false, "/ui/").await, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,798 @@
+use std::{
+    collections::BTreeMap,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use axum::{
+    Extension,
+    body::Bytes,
+    extract::FromRequestParts,
+    http::{HeaderMap, HeaderValue, StatusCode, header, request::Parts},
+    response::{IntoResponse, Response},
+};
+use serde::{Serialize, ser::SerializeStruct};
+
+use crate::{
+    bill::Bill,
+    error::{GetTaskError, Names},
+    export,
+    mapping::BillMapping,
+    task::{self, Stage, TaskControlBlock},
+};
+
+/// Shape of the task JSON, chosen by the route prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ApiVersion {
+    /// `{id, state}`, plus `success` and `error` once finished
+    #[default]
+    V1,
+    /// Every field is always present, and errors are objects
+    V2,
+}
+
+impl ApiVersion {
+    pub fn prefix(self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "/v1",
+            ApiVersion::V2 => "/v2",
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for ApiVersion
+where
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Extension::<ApiVersion>::from_request_parts(parts, state)
+            .await
+            .map(|Extension(version)| version)
+            .unwrap_or_default())
+    }
+}
+
+/// Marks a response as coming from the legacy unprefixed routes.
+pub async fn deprecated(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .insert("Deprecation", HeaderValue::from_static("true"));
+    response
+}
+
+/// A task, or a list of them, serialized the way the API version expects.
+pub struct TaskJson<T>(pub ApiVersion, pub T);
+
+/// Representations `get_task` and the task listing offer, picked by the
+/// `Accept` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TaskFormat {
+    /// The task JSON of the API version
+    Json,
+    /// The CSV export's header, then a row for each successfully finished task
+    Csv,
+    /// [TaskControlBlock]s in MessagePack, as maps of their fields
+    Msgpack,
+}
+
+impl TaskFormat {
+    /// In the order preferred when the header accepts several alike.
+    pub const ALL: [TaskFormat; 3] = [TaskFormat::Json, TaskFormat::Csv, TaskFormat::Msgpack];
+
+    pub fn media_type(self) -> &'static str {
+        match self {
+            TaskFormat::Json => "application/json",
+            TaskFormat::Csv => "text/csv",
+            TaskFormat::Msgpack => "application/msgpack",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            TaskFormat::Csv => "text/csv; charset=utf-8",
+            format => format.media_type(),
+        }
+    }
+
+    /// The offered format with the highest quality in the `Accept` header,
+    /// JSON without one. Fails if it accepts none of them.
+    pub fn negotiate(headers: &HeaderMap) -> Result<Self, GetTaskError> {
+        let accept = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        if accept.trim().is_empty() {
+            return Ok(TaskFormat::Json);
+        }
+        let mut best = None;
+        for format in Self::ALL {
+            let quality = quality(&accept, format.media_type());
+            if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+                best = Some((format, quality));
+            }
+        }
+        best.map(|(format, _)| format).ok_or_else(|| {
+            GetTaskError::NotAcceptable(Names(
+                Self::ALL
+                    .iter()
+                    .map(|format| format.media_type().to_string())
+                    .collect(),
+            ))
+        })
+    }
+}
+
+/// Quality `accept` gives `media_type` through its most specific matching
+/// range, `type/subtype` before `type/*` before `*/*`. Zero if none matches,
+/// and for ranges whose `q` isn't a number from 0 to 1.
+fn quality(accept: &str, media_type: &str) -> f32 {
+    let (kind, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+    accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let range = params.next()?.trim().to_ascii_lowercase();
+            let specificity = if range == media_type {
+                2
+            } else if range.strip_suffix("/*") == Some(kind) {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                return None;
+            };
+            let quality = params
+                .filter_map(|param| param.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .map_or(Some(1.0), |(_, value)| {
+                    value
+                        .trim()
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|q| (0.0..=1.0).contains(q))
+                })
+                .unwrap_or(0.0);
+            Some((specificity, quality))
+        })
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map_or(0.0, |(_, quality)| quality)
+}
+
+/// What the task endpoints serve, one task or a list of them.
+pub trait TaskBody {
+    fn tasks(&self) -> &[TaskControlBlock];
+    fn json(&self, version: ApiVersion) -> serde_json::Result<Vec<u8>>;
+    fn msgpack(&self) -> Result<Vec<u8>, rmp_serde::encode::Error>;
+}
+
+impl TaskBody for TaskControlBlock {
+    fn tasks(&self) -> &[TaskControlBlock] {
+        std::slice::from_ref(self)
+    }
+
+    fn json(&self, version: ApiVersion) -> serde_json::Result<Vec<u8>> {
+        match version {
+            ApiVersion::V1 => serde_json::to_vec(&TaskV1(self, None)),
+            ApiVersion::V2 => serde_json::to_vec(&TaskV2(self, None)),
+        }
+    }
+
+    fn msgpack(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+        msgpack(self)
+    }
+}
+
+impl TaskBody for Vec<TaskControlBlock> {
+    fn tasks(&self) -> &[TaskControlBlock] {
+        self
+    }
+
+    fn json(&self, version: ApiVersion) -> serde_json::Result<Vec<u8>> {
+        match version {
+            ApiVersion::V1 => serde_json::to_vec(
+                &self
+                    .iter()
+                    .map(|task| TaskV1(task, None))
+                    .collect::<Vec<_>>(),
+            ),
+            ApiVersion::V2 => serde_json::to_vec(
+                &self
+                    .iter()
+                    .map(|task| TaskV2(task, None))
+                    .collect::<Vec<_>>(),
+            ),
+        }
+    }
+
+    fn msgpack(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+        msgpack(self)
+    }
+}
+
+/// MessagePack as clients read it: structs as maps, and the bill with the
+/// fields it only writes for clients, like `formatted_amount`.
+fn msgpack(value: &impl Serialize) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    let mut buf = Vec::new();
+    value.serialize(
+        &mut rmp_serde::Serializer::new(&mut buf)
+            .with_struct_map()
+            .with_human_readable(),
+    )?;
+    Ok(buf)
+}
+
+/// A task whose bill reads in the client's schema in JSON. The other formats
+/// keep their canonical shape.
+pub struct MappedTask(pub TaskControlBlock, pub Option<BillMapping>);
+
+impl TaskBody for MappedTask {
+    fn tasks(&self) -> &[TaskControlBlock] {
+        self.0.tasks()
+    }
+
+    fn json(&self, version: ApiVersion) -> serde_json::Result<Vec<u8>> {
+        let mapping = self.1.as_ref();
+        match version {
+            ApiVersion::V1 => serde_json::to_vec(&TaskV1(&self.0, mapping)),
+            ApiVersion::V2 => serde_json::to_vec(&TaskV2(&self.0, mapping)),
+        }
+    }
+
+    fn msgpack(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+        self.0.msgpack()
+    }
+}
+
+/// The bill of a task, through the mapping if there is one.
+fn serialize_bill<S: SerializeStruct>(
+    sstate: &mut S,
+    key: &'static str,
+    bill: Option<&Bill>,
+    mapping: Option<&BillMapping>,
+) -> Result<(), S::Error> {
+    match mapping {
+        Some(mapping) => sstate.serialize_field(key, &bill.map(|bill| mapping.apply(bill))),
+        None => sstate.serialize_field(key, &bill),
+    }
+}
+
+/// The body of `tasks` in `format`, which every response of tasks goes through.
+pub fn encode(version: ApiVersion, format: TaskFormat, tasks: &impl TaskBody) -> Vec<u8> {
+    match format {
+        TaskFormat::Json => tasks.json(version).expect("tasks serialize to JSON"),
+        TaskFormat::Csv => export::csv(tasks.tasks()),
+        TaskFormat::Msgpack => tasks.msgpack().expect("tasks serialize to MessagePack"),
+    }
+}
+
+impl<T: TaskBody> TaskJson<T> {
+    /// The tasks in `format` instead of JSON, telling caches the body depends
+    /// on `Accept`.
+    pub fn negotiated(self, format: TaskFormat) -> Response {
+        (
+            [
+                (header::CONTENT_TYPE, format.content_type()),
+                (header::VARY, "accept"),
+            ],
+            encode(self.0, format, &self.1),
+        )
+            .into_response()
+    }
+}
+
+/// Stage outputs of a successful task that asked to keep them.
+fn succeeded_intermediates(task: &TaskControlBlock) -> Option<BTreeMap<Stage, String>> {
+    matches!(task.state(), task::State::Finished(Ok(_)))
+        .then(|| task.intermediates())
+        .flatten()
+}
+
+struct TaskV1<'a>(&'a TaskControlBlock, Option<&'a BillMapping>);
+
+impl Serialize for TaskV1<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let state = self.0.state();
+        let result = match &state {
+            task::State::Finished(result) => Some(result),
+            _ => None,
+        };
+        let deleted = self.0.is_deleted();
+        let intermediates = succeeded_intermediates(self.0);
+        let seeds = self.0.seeds();
+        let len = result.map_or(2, |_| 4)
+            + usize::from(deleted)
+            + usize::from(intermediates.is_some())
+            + usize::from(seeds.is_some());
+        let mut sstate = serializer.serialize_struct("Task", len)?;
+        sstate.serialize_field("id", self.0.id())?;
+        sstate.serialize_field("state", &state.to_string())?;
+        if let Some(result) = result {
+            let bill = result.as_ref().ok().map(|success| &success.0);
+            serialize_bill(&mut sstate, "success", bill, self.1)?;
+            sstate.serialize_field("error", &result.as_ref().err().map(|err| err.to_string()))?;
+        }
+        // only present once deleted, leaving the shape of other tasks alone
+        if deleted {
+            sstate.serialize_field("deleted", &true)?;
+        }
+        if let Some(intermediates) = intermediates {
+            sstate.serialize_field("intermediates", &intermediates)?;
+        }
+        if let Some(seeds) = seeds {
+            sstate.serialize_field("seeds", &seeds)?;
+        }
+        sstate.end()
+    }
+}
+
+struct TaskV2<'a>(&'a TaskControlBlock, Option<&'a BillMapping>);
+
+impl Serialize for TaskV2<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let state = self.0.state();
+        let (bill, error) = match &state {
+            task::State::Finished(Ok(success)) => (Some(&success.0), None),
+            task::State::Finished(Err(err)) => (None, Some(err)),
+            _ => (None, None),
+        };
+        let intermediates = succeeded_intermediates(self.0);
+        let seeds = self.0.seeds();
+        let mut sstate = serializer.serialize_struct(
+            "Task",
+            9 + usize::from(intermediates.is_some()) + usize::from(seeds.is_some()),
+        )?;
+        sstate.serialize_field("id", self.0.id())?;
+        sstate.serialize_field("state", &state.to_string())?;
+        sstate.serialize_field("needs_review", &self.0.needs_review())?;
+        serialize_bill(&mut sstate, "bill", bill, self.1)?;
+        sstate.serialize_field("error", &error)?;
+        sstate.serialize_field("created_at", &self.0.created_at())?;
+        sstate.serialize_field("finished_at", &self.0.finished_at())?;
+        sstate.serialize_field("tags", &self.0.tags())?;
+        sstate.serialize_field("deleted", &self.0.is_deleted())?;
+        // only for tasks that asked, keeping the others small
+        if let Some(intermediates) = intermediates {
+            sstate.serialize_field("intermediates", &intermediates)?;
+        }
+        if let Some(seeds) = seeds {
+            sstate.serialize_field("seeds", &seeds)?;
+        }
+        sstate.end()
+    }
+}
+
+impl IntoResponse for TaskJson<TaskControlBlock> {
+    fn into_response(self) -> Response {
+        (
+            [(header::CONTENT_TYPE, TaskFormat::Json.content_type())],
+            encode(self.0, TaskFormat::Json, &self.1),
+        )
+            .into_response()
+    }
+}
+
+impl<T: TaskBody> TaskJson<T> {
+    /// Finished tasks only change when patched, so they carry a strong ETag of
+    /// their body in `format`, see [Rendered::respond]. None unless every task
+    /// finished, pending and running ones being served as usual, without one.
+    pub fn rendered(&self, format: TaskFormat) -> Option<Rendered> {
+        let finished = |task: &TaskControlBlock| matches!(task.state(), task::State::Finished(_));
+        if !self.1.tasks().iter().all(finished) {
+            return None;
+        }
+        let body = encode(self.0, format, &self.1);
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        Some(Rendered {
+            body: body.into(),
+            etag: format!("\"{:016x}\"", hasher.finish()),
+        })
+    }
+}
+
+/// Body of finished tasks and its strong ETag, kept by the scheduler so
+/// repeated polls are answered without serializing the tasks again.
+#[derive(Debug, Clone)]
+pub struct Rendered {
+    body: Bytes,
+    etag: String,
+}
+
+impl Rendered {
+    /// Tells the bodies of one task apart, by the API version, the format and
+    /// the bill mapping they were rendered with.
+    pub fn variant(version: ApiVersion, format: TaskFormat, mapping: Option<&BillMapping>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (version, format, mapping).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The body, or `304` to a matching `If-None-Match`.
+    pub fn respond(self, format: TaskFormat, headers: &HeaderMap) -> Response {
+        let etag_header = (header::ETAG, self.etag.clone());
+        if headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == self.etag || tag == "*")
+        {
+            return (StatusCode::NOT_MODIFIED, [etag_header]).into_response();
+        }
+        (
+            [
+                (header::CONTENT_TYPE, format.content_type().to_string()),
+                (header::VARY, "accept".to_string()),
+                etag_header,
+            ],
+            self.body,
+        )
+            .into_response()
+    }
+}
+
+impl IntoResponse for TaskJson<Vec<TaskControlBlock>> {
+    fn into_response(self) -> Response {
+        (
+            [(header::CONTENT_TYPE, TaskFormat::Json.content_type())],
+            encode(self.0, TaskFormat::Json, &self.1),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use crate::{bill::Bill, error::RunTaskError};
+
+    use super::*;
+
+    async fn body(response: Response) -> Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_task_shapes() {
+        let tcb = TaskControlBlock::new();
+        let id = tcb.id().to_string();
+        let shapes = |tcb: &TaskControlBlock| {
+            let tcb = tcb.clone();
+            async move {
+                (
+                    body(TaskJson(ApiVersion::V1, tcb.clone()).into_response()).await,
+                    body(TaskJson(ApiVersion::V2, tcb).into_response()).await,
+                )
+            }
+        };
+
+        let created_at = serde_json::to_value(tcb.created_at()).unwrap();
+        let (v1, v2) = shapes(&tcb).await;
+        assert_eq!(v1, json!({ "id": id, "state": "pending" }));
+        assert_eq!(
+            v2,
+            json!({
+                "id": id,
+                "state": "pending",
+                "needs_review": false,
+                "bill": null,
+                "error": null,
+                "created_at": created_at,
+                "finished_at": null,
+                "tags": [],
+                "deleted": false
+            })
+        );
+
+        tcb.set_state(task::State::Finished(Ok(task::Success(Bill {
+            notes: "Toy".into(),
+            amount: 12.5,
+            currency: None,
+            categories: vec!["Shopping".into()],
+            category_reason: Some("Sold as a toy.".into()),
+            needs_review: true,
+        }))));
+        let bill = json!({
+            "notes": "Toy",
+            "amount": 12.5,
+            "currency": null,
+            "formatted_amount": "12.50",
+            "category": "Shopping",
+            "categories": ["Shopping"],
+            "category_reason": "Sold as a toy.",
+            "needs_review": true
+        });
+        let finished_at = serde_json::to_value(tcb.finished_at().unwrap()).unwrap();
+        let (v1, v2) = shapes(&tcb).await;
+        assert_eq!(
+            v1,
+            json!({ "id": id, "state": "finished", "success": bill, "error": null })
+        );
+        assert_eq!(
+            v2,
+            json!({
+                "id": id,
+                "state": "finished",
+                "needs_review": true,
+                "bill": bill,
+                "error": null,
+                "created_at": created_at,
+                "finished_at": finished_at,
+                "tags": [],
+                "deleted": false
+            })
+        );
+
+        tcb.set_state(task::State::Finished(Err(RunTaskError::InvalidOutput(
+            "price".into(),
+        )
+        .into())));
+        let (v1, v2) = shapes(&tcb).await;
+        assert_eq!(
+            v1,
+            json!({ "id": id, "state": "finished", "success": null, "error": "invalid LLM output for price" })
+        );
+        assert_eq!(
+            v2,
+            json!({
+                "id": id,
+                "state": "finished",
+                "needs_review": false,
+                "bill": null,
+                "error": {
+                    "code": "invalid_output",
+                    "message": "invalid LLM output for price",
+                    "retryable": true,
+                    "stage": null
+                },
+                "created_at": created_at,
+                "finished_at": finished_at,
+                "tags": [],
+                "deleted": false
+            })
+        );
+
+        tcb.set_state(task::State::Finished(Err(RunTaskError::Stage {
+            stage: task::Stage::Amount,
+            model: "gemma4:e4b".into(),
+            source: ollama_rs::error::OllamaError::Other("model ran out of memory".into()),
+        }
+        .into())));
+        let (v1, v2) = shapes(&tcb).await;
+        let message = "amount stage on gemma4:e4b failed: model ran out of memory";
+        assert_eq!(v1["error"], message);
+        assert_eq!(
+            v2["error"],
+            json!({ "code": "stage", "message": message, "retryable": true, "stage": "amount" })
+        );
+
+        tcb.set_deleted(true);
+        let (v1, v2) = shapes(&tcb).await;
+        assert_eq!(v1["deleted"], true);
+        assert_eq!(v2["deleted"], true);
+    }
+
+    /// Answers like `get_task`, with an ETag once the tasks finished.
+    fn conditional<T: TaskBody>(
+        task: TaskJson<T>,
+        format: TaskFormat,
+        headers: &HeaderMap,
+    ) -> Response {
+        match task.rendered(format) {
+            Some(rendered) => rendered.respond(format, headers),
+            None => task.negotiated(format),
+        }
+    }
+
+    #[test]
+    fn test_etag() {
+        let request = |tcb: &TaskControlBlock, if_none_match: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(tag) = if_none_match {
+                headers.insert(header::IF_NONE_MATCH, tag.parse().unwrap());
+            }
+            conditional(
+                TaskJson(ApiVersion::V2, tcb.clone()),
+                TaskFormat::Json,
+                &headers,
+            )
+        };
+        let tcb = TaskControlBlock::new();
+        assert!(request(&tcb, None).headers().get(header::ETAG).is_none());
+
+        let bill = Bill {
+            notes: "Toy".into(),
+            amount: 12.5,
+            currency: None,
+            categories: vec!["Shopping".into()],
+            category_reason: None,
+            needs_review: true,
+        };
+        tcb.set_state(task::State::Finished(Ok(task::Success(bill.clone()))));
+        let response = request(&tcb, None);
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(request(&tcb, None).headers()[header::ETAG], etag.as_str());
+
+        let response = request(&tcb, Some(&format!("\"other\", {etag}")));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        assert_eq!(
+            request(&tcb, Some(&format!("W/{etag}"))).status(),
+            StatusCode::NOT_MODIFIED
+        );
+
+        tcb.set_state(task::State::Finished(Ok(task::Success(Bill {
+            category_reason: None,
+            needs_review: false,
+            ..bill
+        }))));
+        let response = request(&tcb, Some(&etag));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[header::ETAG], etag.as_str());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let negotiate = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, accept.parse().unwrap());
+            TaskFormat::negotiate(&headers)
+        };
+        assert_eq!(
+            TaskFormat::negotiate(&HeaderMap::new()).unwrap(),
+            TaskFormat::Json
+        );
+        assert_eq!(negotiate("*/*").unwrap(), TaskFormat::Json);
+        assert_eq!(negotiate("text/csv").unwrap(), TaskFormat::Csv);
+        assert_eq!(negotiate("text/*").unwrap(), TaskFormat::Csv);
+        assert_eq!(
+            negotiate("application/json;q=0.5, application/msgpack").unwrap(),
+            TaskFormat::Msgpack
+        );
+        assert_eq!(
+            negotiate("application/json; q=0.2, text/csv;q=0.8, */*;q=0.1").unwrap(),
+            TaskFormat::Csv
+        );
+        // the most specific range decides, even with a lower quality
+        assert_eq!(
+            negotiate("*/*;q=0.9, application/json;q=0.1").unwrap(),
+            TaskFormat::Csv
+        );
+        assert_eq!(negotiate("TEXT/CSV").unwrap(), TaskFormat::Csv);
+        assert_eq!(
+            negotiate("application/json;q=2, text/csv;q=0.3").unwrap(),
+            TaskFormat::Csv
+        );
+        assert!(matches!(
+            negotiate("image/png, application/json;q=0"),
+            Err(GetTaskError::NotAcceptable(_))
+        ));
+        assert_eq!(quality("application/json;q=0.25", "application/json"), 0.25);
+        assert_eq!(quality("text/html", "application/json"), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_representations() {
+        let bill = Bill {
+            notes: "Taxi, \"airport\"".into(),
+            amount: 42.0,
+            currency: Some("EUR".into()),
+            categories: vec!["Transport".into()],
+            category_reason: None,
+            needs_review: false,
+        };
+        let finished = TaskControlBlock::new();
+        finished.set_state(task::State::Finished(Ok(task::Success(bill.clone()))));
+        let pending = TaskControlBlock::new();
+        let bytes = |response: Response| async {
+            axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap()
+        };
+
+        let response = conditional(
+            TaskJson(ApiVersion::V2, finished.clone()),
+            TaskFormat::Csv,
+            &HeaderMap::new(),
+        );
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/csv; charset=utf-8"
+        );
+        assert_eq!(response.headers()[header::VARY], "accept");
+        let csv = String::from_utf8(bytes(response).await.to_vec()).unwrap();
+        let mut rows = csv.lines();
+        assert!(
+            rows.next()
+                .unwrap()
+                .starts_with("id,created_at,finished_at,notes,amount")
+        );
+        let row = rows.next().unwrap();
+        assert!(row.starts_with(finished.id()), "{row}");
+        assert!(row.contains(",\"Taxi, \"\"airport\"\"\",42,EUR,"), "{row}");
+        assert!(rows.next().is_none());
+
+        let tasks = vec![finished.clone(), pending.clone()];
+        let listed =
+            bytes(TaskJson(ApiVersion::V1, tasks.clone()).negotiated(TaskFormat::Csv)).await;
+        assert_eq!(String::from_utf8_lossy(&listed).lines().count(), 2);
+        let pending_csv =
+            bytes(TaskJson(ApiVersion::V1, pending.clone()).negotiated(TaskFormat::Csv)).await;
+        assert_eq!(String::from_utf8_lossy(&pending_csv).lines().count(), 1);
+
+        let response = TaskJson(ApiVersion::V1, tasks).negotiated(TaskFormat::Msgpack);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/msgpack"
+        );
+        let msgpack = bytes(response).await;
+        let value = rmp_serde::from_slice::<Value>(&msgpack).unwrap();
+        assert_eq!(
+            value[0]["success"]["formatted_amount"],
+            json!(bill.formatted_amount())
+        );
+        let decoded = rmp_serde::from_slice::<Vec<TaskControlBlock>>(&msgpack).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].id(), finished.id());
+        assert_eq!(decoded[0].finished().await.unwrap().0, bill);
+        assert_eq!(decoded[1].state(), task::State::Pending);
+
+        let response = conditional(
+            TaskJson(ApiVersion::V2, finished.clone()),
+            TaskFormat::Json,
+            &HeaderMap::new(),
+        );
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(body(response).await["bill"]["amount"], 42.0);
+    }
+
+    #[tokio::test]
+    async fn test_legacy_routes_deprecated() {
+        use tower::ServiceExt;
+
+        let app = crate::server::app(crate::state::AppState::new(&Default::default()).unwrap());
+        for (uri, deprecated) in [
+            ("/get_task/missing", true),
+            ("/v1/get_task/missing", false),
+            ("/v2/get_task/missing", false),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    axum::extract::Request::get(uri)
+                        .body(axum::body::Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                axum::http::StatusCode::NOT_FOUND,
+                "{uri}"
+            );
+            assert_eq!(
+                response.headers().contains_key("Deprecation"),
+                deprecated,
+                "{uri}"
+            );
+        }
+    }
+}
@@ -0,0 +1,111 @@
+"use strict";
+
+const api = "../v1";
+const keyInput = document.getElementById("key");
+const status = document.getElementById("status");
+keyInput.value = localStorage.getItem("key") || "";
+
+async function call(path, init = {}) {
+  const headers = new Headers(init.headers);
+  if (keyInput.value) {
+    headers.set("Authorization", `Bearer ${keyInput.value}`);
+  }
+  const response = await fetch(`${api}${path}`, { ...init, headers });
+  const body = await response.json();
+  if (!response.ok) {
+    throw new Error(body.error || response.statusText);
+  }
+  return body;
+}
+
+function cell(row, text, className) {
+  const td = row.insertCell();
+  td.textContent = text ?? "";
+  if (className) {
+    td.className = className;
+  }
+  return td;
+}
+
+async function refresh() {
+  let tasks;
+  try {
+    tasks = await call("/tasks");
+  } catch (err) {
+    status.textContent = err.message;
+    return;
+  }
+  const body = document.getElementById("tasks");
+  body.replaceChildren();
+  for (const task of tasks.reverse()) {
+    const row = body.insertRow();
+    const bill = task.success;
+    if (bill && bill.needs_review) {
+      row.className = "review";
+    }
+    cell(row, task.id.slice(0, 8));
+    cell(row, task.state);
+    if (task.error) {
+      cell(row, task.error, "error").colSpan = 3;
+    } else {
+      cell(row, bill?.notes);
+      cell(row, bill?.formatted_amount ?? bill?.amount);
+      cell(row, bill?.categories?.join(", "));
+    }
+    const actions = row.insertCell();
+    if (bill && bill.needs_review) {
+      const button = document.createElement("button");
+      button.textContent = "Reviewed";
+      button.onclick = async () => {
+        await call(`/task/${task.id}`, {
+          method: "PATCH",
+          headers: { "Content-Type": "application/json" },
+          body: JSON.stringify({ needs_review: false }),
+        });
+        refresh();
+      };
+      actions.append(button);
+    }
+  }
+}
+
+async function loadCategories() {
+  const select = document.getElementById("categories");
+  try {
+    select.replaceChildren(...(await call("/categories")).map((name) => new Option(name)));
+  } catch (err) {
+    status.textContent = err.message;
+  }
+}
+
+document.getElementById("key-form").onsubmit = (event) => {
+  event.preventDefault();
+  localStorage.setItem("key", keyInput.value);
+  status.textContent = "";
+  loadCategories();
+  refresh();
+};
+
+document.getElementById("upload-form").onsubmit = async (event) => {
+  event.preventDefault();
+  const form = new FormData();
+  for (const file of document.getElementById("images").files) {
+    form.append("image", file);
+  }
+  const categories = [...document.getElementById("categories").selectedOptions].map((o) => o.value);
+  if (categories.length) {
+    form.append("categories", new Blob([JSON.stringify(categories)], { type: "application/json" }));
+  }
+  try {
+    const task = await call("/create_task", { method: "POST", body: form });
+    status.textContent = `Created task ${task.id}`;
+    event.target.reset();
+  } catch (err) {
+    status.textContent = err.message;
+  }
+  refresh();
+};
+
+loadCategories();
+refresh();
+setInterval(refresh, 3000);